@@ -0,0 +1,59 @@
+//! Compares the instructions of many small stable memory writes and reads,
+//! with and without a buffer.
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::instruction_counter;
+use ic_cdk::api::stable::{BufferedStableReader, BufferedStableWriter, StableReader, StableWriter};
+use ic_cdk_macros::update;
+use std::io::{Read, Write};
+
+const RECORD_COUNT: usize = 10_000;
+const RECORD_SIZE: usize = 16;
+
+#[derive(CandidType, Deserialize)]
+struct BenchmarkResult {
+    name: String,
+    instructions: u64,
+}
+
+#[update]
+fn run_benchmarks() -> Vec<BenchmarkResult> {
+    vec![
+        measure(
+            "write_unbuffered",
+            || write_records(StableWriter::default()),
+        ),
+        measure("write_buffered", || {
+            write_records(BufferedStableWriter::default())
+        }),
+        measure("read_unbuffered", || read_records(StableReader::default())),
+        measure("read_buffered", || {
+            read_records(BufferedStableReader::default())
+        }),
+    ]
+}
+
+fn measure(name: &str, workload: impl FnOnce()) -> BenchmarkResult {
+    let start = instruction_counter();
+    workload();
+    BenchmarkResult {
+        name: name.to_string(),
+        instructions: instruction_counter().saturating_sub(start),
+    }
+}
+
+fn write_records(mut writer: impl Write) {
+    for i in 0..RECORD_COUNT {
+        writer.write_all(&[i as u8; RECORD_SIZE]).unwrap();
+    }
+    writer.flush().unwrap();
+}
+
+fn read_records(mut reader: impl Read) {
+    let mut record = [0; RECORD_SIZE];
+    for i in 0..RECORD_COUNT {
+        reader.read_exact(&mut record).unwrap();
+        if record[0] != i as u8 {
+            ic_cdk::trap("unexpected record");
+        }
+    }
+}
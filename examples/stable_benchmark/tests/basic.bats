@@ -0,0 +1,23 @@
+# Executed before each test.
+setup() {
+  cd examples/stable_benchmark
+  # Make sure the directory is clean.
+  dfx start --clean --background --host "127.0.0.1:0"
+  local webserver_port=$(cat .dfx/webserver-port)
+  cp dfx.json dfx.json.bk
+  cat <<<$(jq .networks.local.bind=\"127.0.0.1:${webserver_port}\" dfx.json) >dfx.json
+}
+
+# executed after each test
+teardown() {
+  dfx stop
+  mv dfx.json.bk dfx.json
+}
+
+@test "Can run the stable memory benchmarks (stable_benchmark_rs)" {
+  dfx deploy
+  run dfx canister call stable_benchmark run_benchmarks
+  [ "$status" -eq 0 ]
+  [[ "$output" == *'"write_buffered"'* ]]
+  [[ "$output" == *'"read_buffered"'* ]]
+}
@@ -0,0 +1,205 @@
+use proc_macro2::{Ident, TokenStream};
+use quote::quote;
+use syn::{
+    spanned::Spanned, Error, FnArg, ItemTrait, Lit, Meta, Pat, PatIdent, PatType, ReturnType,
+    TraitItem, Type,
+};
+
+/// Generates a client struct with one async method per function of the
+/// trait, each of which calls the method of the same name on the canister.
+pub(crate) fn canister_client(input: TokenStream) -> Result<TokenStream, Error> {
+    let item = syn::parse2::<ItemTrait>(input)?;
+    if !item.generics.params.is_empty() {
+        return Err(Error::new(
+            item.generics.span(),
+            "canister_client! must be given a trait with no generic parameters.",
+        ));
+    }
+
+    let vis = &item.vis;
+    let name = &item.ident;
+    let attrs = &item.attrs;
+
+    let mut methods = vec![];
+    for trait_item in &item.items {
+        let method = match trait_item {
+            TraitItem::Method(method) => method,
+            other => {
+                return Err(Error::new(
+                    other.span(),
+                    "canister_client! traits can only contain functions.",
+                ))
+            }
+        };
+        let signature = &method.sig;
+        if signature.asyncness.is_none() {
+            return Err(Error::new(
+                signature.span(),
+                "canister_client! functions must be async.",
+            ));
+        }
+        if !signature.generics.params.is_empty() {
+            return Err(Error::new(
+                signature.generics.span(),
+                "canister_client! functions cannot have generic parameters.",
+            ));
+        }
+
+        // `#[name = "..."]` calls a method whose name isn't a Rust identifier.
+        let mut method_name = signature.ident.to_string();
+        let mut method_attrs = vec![];
+        for attr in &method.attrs {
+            if !attr.path.is_ident("name") {
+                method_attrs.push(attr);
+                continue;
+            }
+            match attr.parse_meta()? {
+                Meta::NameValue(syn::MetaNameValue {
+                    lit: Lit::Str(lit), ..
+                }) => method_name = lit.value(),
+                meta => {
+                    return Err(Error::new(
+                        meta.span(),
+                        "expected #[name = \"method_name\"].",
+                    ))
+                }
+            }
+        }
+
+        let mut arg_names: Vec<Ident> = vec![];
+        let mut arg_types: Vec<&Type> = vec![];
+        for (i, arg) in signature.inputs.iter().enumerate() {
+            match arg {
+                // The receiver is optional, the methods always take `&self`.
+                FnArg::Receiver(_) => {}
+                FnArg::Typed(PatType { pat, ty, .. }) => {
+                    let ident = match pat.as_ref() {
+                        Pat::Ident(PatIdent { ident, .. }) => ident.clone(),
+                        _ => Ident::new(&format!("arg_{}", i), pat.span()),
+                    };
+                    arg_names.push(ident);
+                    arg_types.push(ty);
+                }
+            }
+        }
+
+        // Like for exported functions, a tuple return type stands for several
+        // return values.
+        let (return_type, decode) = match &signature.output {
+            ReturnType::Default => (quote! { () }, quote! { call_tuple::<()>() }),
+            ReturnType::Type(_, ty) => match ty.as_ref() {
+                Type::Tuple(_) => (quote! { #ty }, quote! { call_tuple::<#ty>() }),
+                _ => (quote! { #ty }, quote! { call::<#ty>() }),
+            },
+        };
+
+        let ident = &signature.ident;
+        methods.push(quote! {
+            #(#method_attrs)*
+            #vis async fn #ident(&self #(, #arg_names: #arg_types )*)
+                -> Result<#return_type, ic_cdk::api::call::CallError>
+            {
+                ic_cdk::api::call::Call::new(self.canister_id, #method_name)
+                    .with_args(( #( #arg_names, )* ))
+                    .#decode
+                    .await
+            }
+        });
+    }
+
+    Ok(quote! {
+        #(#attrs)*
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        #vis struct #name {
+            canister_id: ic_cdk::export::Principal,
+        }
+
+        impl #name {
+            /// Creates a client of the canister `canister_id`.
+            #vis fn new(canister_id: ic_cdk::export::Principal) -> Self {
+                Self { canister_id }
+            }
+
+            /// Returns the id of the canister this client calls.
+            #vis fn canister_id(&self) -> ic_cdk::export::Principal {
+                self.canister_id
+            }
+
+            #(#methods)*
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn generates_typed_methods() {
+        let generated = canister_client(quote! {
+            pub trait Ledger {
+                async fn fee() -> u64;
+                #[name = "account-balance"]
+                async fn balance(&self, account: Account) -> Tokens;
+                async fn transfer(from: Account, to: Account, amount: Tokens);
+                async fn bounds() -> (u64, u64);
+            }
+        })
+        .unwrap();
+        let parsed = syn::parse2::<syn::File>(generated).unwrap();
+        assert_eq!(parsed.items.len(), 2);
+        let methods = match &parsed.items[1] {
+            syn::Item::Impl(item) => &item.items,
+            _ => panic!("not an impl"),
+        };
+
+        let expected = quote! {
+            pub async fn balance(&self, account: Account)
+                -> Result<Tokens, ic_cdk::api::call::CallError>
+            {
+                ic_cdk::api::call::Call::new(self.canister_id, "account-balance")
+                    .with_args((account,))
+                    .call::<Tokens>()
+                    .await
+            }
+        };
+        let expected = syn::parse2::<syn::ImplItem>(expected).unwrap();
+        assert_eq!(methods[3], expected);
+
+        let expected = quote! {
+            pub async fn transfer(&self, from: Account, to: Account, amount: Tokens)
+                -> Result<(), ic_cdk::api::call::CallError>
+            {
+                ic_cdk::api::call::Call::new(self.canister_id, "transfer")
+                    .with_args((from, to, amount,))
+                    .call_tuple::<()>()
+                    .await
+            }
+        };
+        let expected = syn::parse2::<syn::ImplItem>(expected).unwrap();
+        assert_eq!(methods[4], expected);
+
+        let expected = quote! {
+            pub async fn bounds(&self)
+                -> Result<(u64, u64), ic_cdk::api::call::CallError>
+            {
+                ic_cdk::api::call::Call::new(self.canister_id, "bounds")
+                    .with_args(())
+                    .call_tuple::<(u64, u64)>()
+                    .await
+            }
+        };
+        let expected = syn::parse2::<syn::ImplItem>(expected).unwrap();
+        assert_eq!(methods[5], expected);
+    }
+
+    #[test]
+    fn rejects_non_async_functions() {
+        assert!(canister_client(quote! {
+            trait Ledger {
+                fn fee() -> u64;
+            }
+        })
+        .is_err());
+    }
+}
@@ -3,15 +3,33 @@ use quote::quote;
 use serde::Deserialize;
 use serde_tokenstream::from_tokenstream;
 use std::fmt::Formatter;
+use syn::fold::Fold;
 use syn::Error;
-use syn::{spanned::Spanned, FnArg, ItemFn, Pat, PatIdent, PatType, ReturnType, Signature, Type};
+use syn::{
+    spanned::Spanned, FnArg, GenericParam, ItemFn, Lifetime, Pat, PatIdent, PatType, ReturnType,
+    Signature, Type, TypeReference,
+};
 
 #[derive(Default, Deserialize)]
 struct ExportAttributes {
     pub name: Option<String>,
     pub guard: Option<String>,
     #[serde(default)]
+    pub guards: Vec<String>,
+    pub async_guard: Option<String>,
+    #[serde(default)]
+    pub async_guards: Vec<String>,
+    #[serde(default)]
     pub manual_reply: bool,
+    #[serde(default)]
+    pub composite: bool,
+    #[serde(default)]
+    pub accept_cycles: bool,
+    #[serde(default)]
+    pub hidden: bool,
+    #[serde(default)]
+    pub instrument: bool,
+    pub with: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -23,6 +41,7 @@ enum MethodType {
     Query,
     Heartbeat,
     InspectMessage,
+    GlobalTimer,
 }
 
 impl MethodType {
@@ -34,6 +53,18 @@ impl MethodType {
                 | MethodType::PostUpgrade
                 | MethodType::Heartbeat
                 | MethodType::InspectMessage
+                | MethodType::GlobalTimer
+        )
+    }
+
+    /// Lifecycle methods that the system calls without any argument.
+    pub fn takes_no_args(&self) -> bool {
+        matches!(
+            self,
+            MethodType::PreUpgrade
+                | MethodType::Heartbeat
+                | MethodType::InspectMessage
+                | MethodType::GlobalTimer
         )
     }
 }
@@ -48,10 +79,26 @@ impl std::fmt::Display for MethodType {
             MethodType::Update => f.write_str("update"),
             MethodType::Heartbeat => f.write_str("heartbeat"),
             MethodType::InspectMessage => f.write_str("inspect_message"),
+            MethodType::GlobalTimer => f.write_str("global_timer"),
         }
     }
 }
 
+/// Replaces every lifetime, named or elided, with 'static.
+struct StaticLifetimes;
+
+impl Fold for StaticLifetimes {
+    fn fold_lifetime(&mut self, lifetime: Lifetime) -> Lifetime {
+        Lifetime::new("'static", lifetime.span())
+    }
+
+    fn fold_type_reference(&mut self, mut reference: TypeReference) -> TypeReference {
+        reference.lifetime = Some(Lifetime::new("'static", reference.and_token.span()));
+        reference.elem = Box::new(self.fold_type(*reference.elem));
+        reference
+    }
+}
+
 fn get_args(method: MethodType, signature: &Signature) -> Result<Vec<(Ident, Box<Type>)>, Error> {
     // We only need the tuple of arguments, not their types. Magic of type inference.
     let mut args = vec![];
@@ -100,11 +147,16 @@ fn dfn_macro(
     let signature = &fun.sig;
     let generics = &signature.generics;
 
-    if !generics.params.is_empty() {
+    // Lifetimes are fine: the result is encoded before the borrows end.
+    if let Some(param) = generics
+        .params
+        .iter()
+        .find(|param| !matches!(param, GenericParam::Lifetime(_)))
+    {
         return Err(Error::new(
-            generics.span(),
+            param.span(),
             format!(
-                "#[{}] must be above a function with no generic parameters.",
+                "#[{}] must be above a function with no generic parameters other than lifetimes.",
                 method
             ),
         ));
@@ -127,8 +179,53 @@ fn dfn_macro(
         ));
     }
 
-    let (arg_tuple, _): (Vec<Ident>, Vec<Box<Type>>) =
-        get_args(method, signature)?.iter().cloned().unzip();
+    if method.takes_no_args() && !signature.inputs.is_empty() {
+        return Err(Error::new(
+            signature.inputs.span(),
+            format!("#[{}] function cannot have arguments.", method),
+        ));
+    }
+
+    let mut args = get_args(method, signature)?;
+
+    // With an accessor, the first parameter is the state it lends out rather
+    // than an argument of the call.
+    let accessor = match &attrs.with {
+        Some(accessor) => {
+            if method != MethodType::Query && method != MethodType::Update {
+                return Err(Error::new(
+                    Span::call_site(),
+                    format!(
+                        "#[{}] cannot borrow state, only #[query] and #[update] can.",
+                        method
+                    ),
+                ));
+            }
+            if is_async {
+                return Err(Error::new(
+                    signature.asyncness.span(),
+                    format!(
+                        "#[{}] cannot borrow state in an async function, the borrow cannot be held across awaits.",
+                        method
+                    ),
+                ));
+            }
+            if args.is_empty() {
+                return Err(Error::new(
+                    signature.span(),
+                    format!(
+                        "#[{}(with = ...)] function must take the state as its first parameter.",
+                        method
+                    ),
+                ));
+            }
+            args.remove(0);
+            Some(syn::parse_str::<syn::Path>(accessor)?)
+        }
+        None => None,
+    };
+
+    let (arg_tuple, arg_types): (Vec<Ident>, Vec<Box<Type>>) = args.into_iter().unzip();
     let name = &signature.ident;
 
     let outer_function_ident = Ident::new(
@@ -136,14 +233,102 @@ fn dfn_macro(
         Span::call_site(),
     );
 
+    if attrs.composite && method != MethodType::Query {
+        return Err(Error::new(
+            Span::call_site(),
+            format!("#[{}] cannot be composite, only #[query] can.", method),
+        ));
+    }
+
+    let async_guards: Vec<String> = attrs
+        .async_guard
+        .into_iter()
+        .chain(attrs.async_guards)
+        .collect();
+    if !async_guards.is_empty() && method != MethodType::Update && !attrs.composite {
+        return Err(Error::new(
+            Span::call_site(),
+            format!(
+                "#[{}] cannot have async guards, only #[update] and composite #[query] can.",
+                method
+            ),
+        ));
+    }
+
+    if attrs.accept_cycles && method != MethodType::Update {
+        return Err(Error::new(
+            Span::call_site(),
+            format!("#[{}] cannot accept cycles, only #[update] can.", method),
+        ));
+    }
+
+    let method_name = attrs.name.unwrap_or_else(|| name.to_string());
+    if attrs.hidden && method != MethodType::Query && method != MethodType::Update {
+        return Err(Error::new(
+            Span::call_site(),
+            format!(
+                "#[{}] cannot be hidden, only #[query] and #[update] can.",
+                method
+            ),
+        ));
+    }
+
+    if attrs.instrument && method != MethodType::Query && method != MethodType::Update {
+        return Err(Error::new(
+            Span::call_site(),
+            format!(
+                "#[{}] cannot be instrumented, only #[query] and #[update] can.",
+                method
+            ),
+        ));
+    }
+
     let export_name = if method.is_lifecycle() {
         format!("canister_{}", method)
+    } else if attrs.composite {
+        format!("canister_composite_query {}", method_name)
     } else {
-        format!(
-            "canister_{0} {1}",
-            method,
-            attrs.name.unwrap_or_else(|| name.to_string())
-        )
+        format!("canister_{0} {1}", method, method_name)
+    };
+
+    // Registers the signature for `export_candid!`.
+    let candid_method = match method {
+        _ if attrs.hidden => None,
+        MethodType::Query => Some(quote! {
+            #[ic_cdk::export::candid::candid_method(query, rename = #method_name)]
+        }),
+        MethodType::Update => Some(quote! {
+            #[ic_cdk::export::candid::candid_method(update, rename = #method_name)]
+        }),
+        MethodType::Init => Some(quote! {
+            #[ic_cdk::export::candid::candid_method(init)]
+        }),
+        _ => None,
+    };
+    // The signature is registered as written, unless it borrows: then it is
+    // registered through a stub with the arguments of the call only, and
+    // 'static lifetimes.
+    let borrows = accessor.is_some() || !generics.params.is_empty();
+    let (candid_method, candid_stub) = match candid_method {
+        Some(candid_method) if borrows => {
+            let stub_ident = Ident::new(
+                &format!("__candid_{}_{}", name.to_string(), crate::id()),
+                Span::call_site(),
+            );
+            let arg_types = arg_types
+                .iter()
+                .map(|ty| StaticLifetimes.fold_type(ty.as_ref().clone()));
+            let output = StaticLifetimes.fold_return_type(signature.output.clone());
+            let stub = quote! {
+                #candid_method
+                #[allow(dead_code, unused_variables, non_snake_case)]
+                fn #stub_ident( #( #arg_tuple: #arg_types ),* ) #output {
+                    unreachable!()
+                }
+            };
+            (quote! {}, stub)
+        }
+        candid_method => (quote! { #candid_method }, quote! {}),
     };
 
     let function_call = if is_async {
@@ -167,26 +352,80 @@ fn dfn_macro(
     // On initialization we can actually not receive any input and it's okay, only if
     // we don't have any arguments either.
     // If the data we receive is not empty, then try to unwrap it as if it's DID.
+    // Init and post_upgrade may be called without an argument even though they
+    // take some, in which case their optional parameters are None.
     let arg_decode = if method.is_lifecycle() && arg_count == 0 {
         quote! {}
+    } else if method.is_lifecycle() {
+        quote! { let ( #( #arg_tuple, )* ) = ic_cdk::api::call::lifecycle_arg_data(); }
     } else {
         quote! { let ( #( #arg_tuple, )* ) = ic_cdk::api::call::arg_data(); }
     };
 
-    let guard = if let Some(guard_name) = attrs.guard {
+    // Guards run in order, and the first one that fails rejects the call.
+    let guards = attrs
+        .guard
+        .into_iter()
+        .chain(attrs.guards)
+        .map(|guard_name| {
+            let guard_ident = syn::Ident::new(&guard_name, Span::call_site());
+
+            quote! {
+                let r: Result<(), String> = #guard_ident ();
+                if let Err(e) = r {
+                    ic_cdk::api::call::reject(&e);
+                    return;
+                }
+            }
+        });
+    let guard = quote! { #(#guards)* };
+
+    // Async guards run in the task, once the arguments are decoded: they are
+    // no longer available after the first await.
+    let async_guards = async_guards.into_iter().map(|guard_name| {
         let guard_ident = syn::Ident::new(&guard_name, Span::call_site());
 
         quote! {
-            let r: Result<(), String> = #guard_ident ();
+            let r: Result<(), String> = #guard_ident ().await;
             if let Err(e) = r {
                 ic_cdk::api::call::reject(&e);
                 return;
             }
         }
+    });
+    let async_guard = quote! { #(#async_guards)* };
+
+    let accept_cycles = if attrs.accept_cycles {
+        quote! { ic_cdk::api::call::accept_all_cycles(); }
     } else {
         quote! {}
     };
 
+    let (instrument_before, instrument_after) = if attrs.instrument {
+        (
+            quote! { ic_cdk::api::instrument::__before(#method_name); },
+            quote! { ic_cdk::api::instrument::__after(#method_name); },
+        )
+    } else {
+        (quote! {}, quote! {})
+    };
+
+    // A result borrowing from the state is encoded while the accessor lends it.
+    let call_and_reply = match accessor {
+        Some(accessor) => quote! {
+            #accessor(|__ic_cdk_state| {
+                let result = #name(__ic_cdk_state, #(#arg_tuple),*);
+                #instrument_after
+                #return_encode
+            });
+        },
+        None => quote! {
+            let result = #function_call;
+            #instrument_after
+            #return_encode
+        },
+    };
+
     Ok(quote! {
         #[export_name = #export_name]
         fn #outer_function_ident() {
@@ -195,13 +434,17 @@ fn dfn_macro(
             #guard
 
             ic_cdk::spawn(async {
+                #instrument_before
                 #arg_decode
-                let result = #function_call;
-                #return_encode
+                #async_guard
+                #accept_cycles
+                #call_and_reply
             });
         }
 
+        #candid_method
         #item
+        #candid_stub
     })
 }
 
@@ -213,6 +456,32 @@ pub(crate) fn ic_update(attr: TokenStream, item: TokenStream) -> Result<TokenStr
     dfn_macro(MethodType::Update, attr, item)
 }
 
+/// A query exported like with `#[query]`, whose signature is checked against
+/// the one the management canister calls transform functions with.
+pub(crate) fn ic_transform(attr: TokenStream, item: TokenStream) -> Result<TokenStream, Error> {
+    let fun = syn::parse2::<syn::ItemFn>(item.clone()).map_err(|e| {
+        Error::new(
+            item.span(),
+            format!("#[transform] must be above a function. \n{}", e),
+        )
+    })?;
+    if fun.sig.asyncness.is_some() {
+        return Err(Error::new(
+            fun.sig.asyncness.span(),
+            "#[transform] must be above a function that is not async.",
+        ));
+    }
+    let name = &fun.sig.ident;
+    let exported = dfn_macro(MethodType::Query, attr, item)?;
+    Ok(quote! {
+        #exported
+
+        const _: fn(
+            ic_cdk::api::management_canister::http_request::TransformArgs,
+        ) -> ic_cdk::api::management_canister::http_request::HttpResponse = #name;
+    })
+}
+
 #[derive(Default, Deserialize)]
 struct InitAttributes {}
 
@@ -239,6 +508,10 @@ pub(crate) fn ic_inspect_message(
     dfn_macro(MethodType::InspectMessage, attr, item)
 }
 
+pub(crate) fn ic_global_timer(attr: TokenStream, item: TokenStream) -> Result<TokenStream, Error> {
+    dfn_macro(MethodType::GlobalTimer, attr, item)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -501,4 +774,383 @@ mod test {
             _ => panic!("not a function"),
         };
     }
+
+    #[test]
+    fn ic_query_composite() {
+        let generated = ic_query(
+            quote!(composite = true),
+            quote! {
+                async fn query() {}
+            },
+        )
+        .unwrap();
+        let parsed = syn::parse2::<syn::File>(generated).unwrap();
+        let fn_name = match parsed.items[0] {
+            syn::Item::Fn(ref f) => &f.sig.ident,
+            _ => panic!("Incorrect parsed AST."),
+        };
+
+        let expected = quote! {
+            #[export_name = "canister_composite_query query"]
+            fn #fn_name() {
+                ic_cdk::setup();
+                ic_cdk::spawn(async {
+                    let () = ic_cdk::api::call::arg_data();
+                    let result = query().await;
+                    ic_cdk::api::call::reply(())
+                });
+            }
+        };
+        let expected = syn::parse2::<syn::ItemFn>(expected).unwrap();
+
+        assert!(parsed.items.len() == 2);
+        match &parsed.items[0] {
+            syn::Item::Fn(f) => {
+                assert_eq!(*f, expected);
+            }
+            _ => panic!("not a function"),
+        };
+    }
+
+    #[test]
+    fn ic_update_composite() {
+        assert!(ic_update(
+            quote!(composite = true),
+            quote! {
+                fn update() {}
+            },
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn ic_update_accept_cycles() {
+        let generated = ic_update(
+            quote!(accept_cycles = true),
+            quote! {
+                fn update() {}
+            },
+        )
+        .unwrap();
+        let parsed = syn::parse2::<syn::File>(generated).unwrap();
+        let fn_name = match parsed.items[0] {
+            syn::Item::Fn(ref f) => &f.sig.ident,
+            _ => panic!("Incorrect parsed AST."),
+        };
+
+        let expected = quote! {
+            #[export_name = "canister_update update"]
+            fn #fn_name() {
+                ic_cdk::setup();
+                ic_cdk::spawn(async {
+                    let () = ic_cdk::api::call::arg_data();
+                    ic_cdk::api::call::accept_all_cycles();
+                    let result = update();
+                    ic_cdk::api::call::reply(())
+                });
+            }
+        };
+        let expected = syn::parse2::<syn::ItemFn>(expected).unwrap();
+
+        match &parsed.items[0] {
+            syn::Item::Fn(f) => {
+                assert_eq!(*f, expected);
+            }
+            _ => panic!("not a function"),
+        };
+    }
+
+    #[test]
+    fn ic_query_accept_cycles() {
+        assert!(ic_query(
+            quote!(accept_cycles = true),
+            quote! {
+                fn query() {}
+            },
+        )
+        .is_err());
+    }
+    #[test]
+    fn ic_query_hidden() {
+        let candid_attrs = |attr| {
+            let generated = ic_query(
+                attr,
+                quote! {
+                    fn query() {}
+                },
+            )
+            .unwrap();
+            match syn::parse2::<syn::File>(generated).unwrap().items[1] {
+                syn::Item::Fn(ref f) => f.attrs.len(),
+                _ => panic!("not a function"),
+            }
+        };
+        assert_eq!(candid_attrs(quote!()), 1);
+        assert_eq!(candid_attrs(quote!(hidden = true)), 0);
+    }
+
+    #[test]
+    fn ic_update_guards() {
+        let generated = ic_update(
+            quote!(guard = "first", guards = ["second", "third"]),
+            quote! {
+                fn update() {}
+            },
+        )
+        .unwrap();
+        let parsed = syn::parse2::<syn::File>(generated).unwrap();
+        let fn_name = match parsed.items[0] {
+            syn::Item::Fn(ref f) => &f.sig.ident,
+            _ => panic!("Incorrect parsed AST."),
+        };
+
+        let expected = quote! {
+            #[export_name = "canister_update update"]
+            fn #fn_name() {
+                ic_cdk::setup();
+                let r: Result<(), String> = first();
+                if let Err(e) = r {
+                    ic_cdk::api::call::reject(&e);
+                    return;
+                }
+                let r: Result<(), String> = second();
+                if let Err(e) = r {
+                    ic_cdk::api::call::reject(&e);
+                    return;
+                }
+                let r: Result<(), String> = third();
+                if let Err(e) = r {
+                    ic_cdk::api::call::reject(&e);
+                    return;
+                }
+                ic_cdk::spawn(async {
+                    let () = ic_cdk::api::call::arg_data();
+                    let result = update();
+                    ic_cdk::api::call::reply(())
+                });
+            }
+        };
+        let expected = syn::parse2::<syn::ItemFn>(expected).unwrap();
+
+        match &parsed.items[0] {
+            syn::Item::Fn(f) => {
+                assert_eq!(*f, expected);
+            }
+            _ => panic!("not a function"),
+        };
+    }
+
+    #[test]
+    fn ic_update_async_guard() {
+        let generated = ic_update(
+            quote!(
+                guard = "first",
+                async_guard = "second",
+                accept_cycles = true
+            ),
+            quote! {
+                fn update(a: u32) {}
+            },
+        )
+        .unwrap();
+        let parsed = syn::parse2::<syn::File>(generated).unwrap();
+        let fn_name = match parsed.items[0] {
+            syn::Item::Fn(ref f) => &f.sig.ident,
+            _ => panic!("Incorrect parsed AST."),
+        };
+
+        let expected = quote! {
+            #[export_name = "canister_update update"]
+            fn #fn_name() {
+                ic_cdk::setup();
+                let r: Result<(), String> = first();
+                if let Err(e) = r {
+                    ic_cdk::api::call::reject(&e);
+                    return;
+                }
+                ic_cdk::spawn(async {
+                    let (a, ) = ic_cdk::api::call::arg_data();
+                    let r: Result<(), String> = second().await;
+                    if let Err(e) = r {
+                        ic_cdk::api::call::reject(&e);
+                        return;
+                    }
+                    ic_cdk::api::call::accept_all_cycles();
+                    let result = update(a);
+                    ic_cdk::api::call::reply(())
+                });
+            }
+        };
+        let expected = syn::parse2::<syn::ItemFn>(expected).unwrap();
+
+        match &parsed.items[0] {
+            syn::Item::Fn(f) => {
+                assert_eq!(*f, expected);
+            }
+            _ => panic!("not a function"),
+        };
+    }
+
+    #[test]
+    fn ic_query_async_guard() {
+        assert!(ic_query(
+            quote!(async_guard = "guard"),
+            quote! {
+                fn query() {}
+            },
+        )
+        .is_err());
+        assert!(ic_query(
+            quote!(async_guard = "guard", composite = true),
+            quote! {
+                fn query() {}
+            },
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn ic_init_typed_arg() {
+        let generated = ic_init(
+            quote!(),
+            quote! {
+                fn init(config: Option<Config>) {}
+            },
+        )
+        .unwrap();
+        let parsed = syn::parse2::<syn::File>(generated).unwrap();
+        let fn_name = match parsed.items[0] {
+            syn::Item::Fn(ref f) => &f.sig.ident,
+            _ => panic!("Incorrect parsed AST."),
+        };
+
+        let expected = quote! {
+            #[export_name = "canister_init"]
+            fn #fn_name() {
+                ic_cdk::setup();
+                ic_cdk::spawn(async {
+                    let (config, ) = ic_cdk::api::call::lifecycle_arg_data();
+                    let result = init(config);
+                });
+            }
+        };
+        let expected = syn::parse2::<syn::ItemFn>(expected).unwrap();
+
+        assert!(parsed.items.len() == 2);
+        match &parsed.items[0] {
+            syn::Item::Fn(f) => {
+                assert_eq!(*f, expected);
+            }
+            _ => panic!("not a function"),
+        };
+    }
+
+    #[test]
+    fn ic_update_instrument() {
+        let generated = ic_update(
+            quote!(instrument = true),
+            quote! {
+                fn update(a: u32) {}
+            },
+        )
+        .unwrap();
+        let parsed = syn::parse2::<syn::File>(generated).unwrap();
+        let fn_name = match parsed.items[0] {
+            syn::Item::Fn(ref f) => &f.sig.ident,
+            _ => panic!("Incorrect parsed AST."),
+        };
+
+        let expected = quote! {
+            #[export_name = "canister_update update"]
+            fn #fn_name() {
+                ic_cdk::setup();
+                ic_cdk::spawn(async {
+                    ic_cdk::api::instrument::__before("update");
+                    let (a, ) = ic_cdk::api::call::arg_data();
+                    let result = update(a);
+                    ic_cdk::api::instrument::__after("update");
+                    ic_cdk::api::call::reply(())
+                });
+            }
+        };
+        let expected = syn::parse2::<syn::ItemFn>(expected).unwrap();
+
+        assert!(parsed.items.len() == 2);
+        match &parsed.items[0] {
+            syn::Item::Fn(f) => {
+                assert_eq!(*f, expected);
+            }
+            _ => panic!("not a function"),
+        };
+
+        assert!(ic_init(
+            quote!(instrument = true),
+            quote! {
+                fn init() {}
+            },
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn ic_query_with_accessor() {
+        let generated = ic_query(
+            quote!(with = "state::with"),
+            quote! {
+                fn prefix<'a>(state: &'a State, len: u32) -> Cow<'a, [u8]> {}
+            },
+        )
+        .unwrap();
+        let parsed = syn::parse2::<syn::File>(generated).unwrap();
+        let fn_name = match parsed.items[0] {
+            syn::Item::Fn(ref f) => &f.sig.ident,
+            _ => panic!("Incorrect parsed AST."),
+        };
+
+        let expected = quote! {
+            #[export_name = "canister_query prefix"]
+            fn #fn_name() {
+                ic_cdk::setup();
+                ic_cdk::spawn(async {
+                    let (len, ) = ic_cdk::api::call::arg_data();
+                    state::with(|__ic_cdk_state| {
+                        let result = prefix(__ic_cdk_state, len);
+                        ic_cdk::api::call::reply((result,))
+                    });
+                });
+            }
+        };
+        let expected = syn::parse2::<syn::ItemFn>(expected).unwrap();
+
+        assert!(parsed.items.len() == 3);
+        match &parsed.items[0] {
+            syn::Item::Fn(f) => {
+                assert_eq!(*f, expected);
+            }
+            _ => panic!("not a function"),
+        };
+        // The signature is registered without the state and its lifetime.
+        match &parsed.items[2] {
+            syn::Item::Fn(f) => {
+                let (inputs, output) = (&f.sig.inputs, &f.sig.output);
+                assert_eq!(quote!(#inputs).to_string(), quote!(len: u32).to_string());
+                assert_eq!(
+                    quote!(#output).to_string(),
+                    quote!(-> Cow<'static, [u8]>).to_string()
+                );
+            }
+            _ => panic!("not a function"),
+        };
+    }
+
+    #[test]
+    fn ic_init_hidden() {
+        assert!(ic_init(
+            quote!(hidden = true),
+            quote! {
+                fn init() {}
+            },
+        )
+        .is_err());
+    }
 }
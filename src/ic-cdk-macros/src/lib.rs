@@ -13,19 +13,30 @@
 //! * [`post_upgrade`](attr.post_upgrade.html)
 //! * [`inspect_message`](attr.inspect_message.html)
 //! * [`heartbeat`](attr.heartbeat.html)
+//! * [`global_timer`](attr.global_timer.html)
 //! * [`update`](attr.update.html)
 //! * [`query`](attr.query.html)
 //!
+//! The Candid interface of these entry points is exported with
+//! [`export_candid`](macro.export_candid.html).
+//!
+//! ## Persist state across upgrades
+//!
+//! * [`StableStorage`](derive.StableStorage.html)
+//!
 //! ## Import another canister as a rust struct
 //!
 //! * [`import`](attr.import.html)
+//! * [`canister_client`](macro.canister_client.html)
 
 use proc_macro::TokenStream;
 use std::sync::atomic::{AtomicU32, Ordering};
 use syn::Error;
 
+mod client;
 mod export;
 mod import;
+mod stable_storage;
 
 // To generate unique identifiers for functions and arguments
 static NEXT_ID: AtomicU32 = AtomicU32::new(0);
@@ -63,10 +74,44 @@ where
     result.map_or_else(|e| e.to_compile_error().into(), Into::into)
 }
 
+/// Generates `__export_service()`, which returns the Candid service
+/// definition of the canister, built from the signatures of the `#[init]`,
+/// `#[query]` and `#[update]` functions of the crate.
+///
+/// The functions are collected while the crate is compiled, so the macro must
+/// come after all of them, at the end of the crate root. The generated code
+/// refers to the `candid` crate, which has to be a dependency of the canister.
+///
+/// ```rust,ignore
+/// #[query]
+/// fn greet(name: String) -> String {
+///     format!("Hello, {}!", name)
+/// }
+///
+/// ic_cdk_macros::export_candid!();
+///
+/// #[test]
+/// fn interface_is_up_to_date() {
+///     let expected = std::fs::read_to_string("greet.did").unwrap();
+///     assert_eq!(__export_service(), expected);
+/// }
+/// ```
+#[proc_macro]
+pub fn export_candid(input: TokenStream) -> TokenStream {
+    let input = proc_macro2::TokenStream::from(input);
+    if !input.is_empty() {
+        return Error::new_spanned(input, "export_candid! takes no arguments")
+            .to_compile_error()
+            .into();
+    }
+    quote::quote!(ic_cdk::export::candid::export_service!();).into()
+}
+
 /// Register a query call entry point.
 ///
 /// This attribute macro will export a function with name `canister_query <name>`
-/// in the canister module.
+/// in the canister module, or `canister_composite_query <name>` for composite
+/// queries.
 ///
 /// # Example
 ///
@@ -106,6 +151,45 @@ where
 /// }
 /// ```
 ///
+/// Setting `composite` to `true` exports a composite query instead, under
+/// `canister_composite_query <name>`. Composite queries can be async and
+/// await calls to the queries of other canisters on the same subnet.
+///
+/// ```rust
+/// # use ic_cdk_macros::query;
+/// #[query(composite = true)]
+/// async fn composite_query_function() {
+///     // ...
+/// # unimplemented!()
+/// }
+/// ```
+///
+/// The return value can borrow from the arguments of the function, which may
+/// declare lifetime parameters. To return data borrowed from thread-local
+/// state without cloning it, set `with` to an accessor that lends the state
+/// to a closure. The state is then passed as the first parameter, and the
+/// result is encoded before the accessor returns. This doesn't work with
+/// async functions. The same goes for `#[update]`.
+///
+/// ```rust
+/// # use ic_cdk_macros::query;
+/// # use std::borrow::Cow;
+/// # use std::cell::RefCell;
+/// # struct State { data: Vec<u8> }
+/// thread_local! {
+///     static STATE: RefCell<State> = RefCell::new(State { data: vec![] });
+/// }
+///
+/// fn with_state<R>(f: impl FnOnce(&State) -> R) -> R {
+///     STATE.with(|s| f(&s.borrow()))
+/// }
+///
+/// #[query(with = "with_state")]
+/// fn prefix<'a>(state: &'a State, len: usize) -> Cow<'a, [u8]> {
+///     Cow::Borrowed(&state.data[..len])
+/// }
+/// ```
+///
 /// [`reply`]: ic_cdk::api::call::reply
 #[proc_macro_attribute]
 pub fn query(attr: TokenStream, item: TokenStream) -> TokenStream {
@@ -155,12 +239,114 @@ pub fn query(attr: TokenStream, item: TokenStream) -> TokenStream {
 /// }
 /// ```
 ///
+/// `guard` names a function returning `Result<(), String>` that runs before
+/// the arguments are decoded, and rejects the call with the error if it fails.
+/// More guards can be listed with `guards`; they run in order after `guard`,
+/// and the first one that fails rejects the call. The same goes for `#[query]`.
+///
+/// ```rust
+/// # use ic_cdk_macros::update;
+/// # fn is_authorized() -> Result<(), String> { Ok(()) }
+/// # fn not_frozen() -> Result<(), String> { Ok(()) }
+/// #[update(guards = ["is_authorized", "not_frozen"])]
+/// fn set_config() {
+///     // ...
+/// }
+/// ```
+///
+/// Guards that need to call other canisters, e.g. an access control registry,
+/// are `async` functions listed with `async_guard` or `async_guards`. They
+/// run after the other guards and after the arguments are decoded, so state
+/// changes made before them are committed even if they reject the call. Only
+/// updates and composite queries can have them.
+///
+/// ```rust
+/// # use ic_cdk_macros::update;
+/// # async fn is_registered() -> Result<(), String> { Ok(()) }
+/// #[update(async_guard = "is_registered")]
+/// fn register_asset() {
+///     // ...
+/// }
+/// ```
+///
+/// Setting `accept_cycles` to `true` accepts all the cycles attached to the
+/// call before the function runs, once the guards, if any, let the call
+/// through. Functions that need to know the amount should call
+/// [`accept_all_cycles`] themselves instead.
+///
+/// ```rust
+/// # use ic_cdk_macros::update;
+/// #[update(accept_cycles = true)]
+/// fn deposit() {
+///     // ...
+/// }
+/// ```
+///
+/// Setting `hidden` to `true` leaves the function out of the interface
+/// exported by [`export_candid`](macro.export_candid.html). It can still be
+/// called, which suits experimental or internal endpoints. The same goes for
+/// `#[query]`.
+///
+/// ```rust
+/// # use ic_cdk_macros::update;
+/// #[update(hidden = true)]
+/// fn experimental() {
+///     // ...
+/// }
+/// ```
+///
+/// Setting `instrument` to `true` runs the hooks of [`instrument`] around the
+/// function, which by default log the call and record the instructions it
+/// executed. The same goes for `#[query]`.
+///
+/// ```rust
+/// # use ic_cdk_macros::update;
+/// #[update(instrument = true)]
+/// fn transfer() {
+///     // ...
+/// }
+/// ```
+///
 /// [`reply`]: ic_cdk::api::call::reply
+/// [`instrument`]: ic_cdk::api::instrument
+/// [`accept_all_cycles`]: ic_cdk::api::call::accept_all_cycles
 #[proc_macro_attribute]
 pub fn update(attr: TokenStream, item: TokenStream) -> TokenStream {
     handle_debug_and_errors(export::ic_update, "ic_update", attr, item)
 }
 
+/// Register a transform function for HTTPS outcalls.
+///
+/// The function is exported as a query, like with [`query`](macro@query),
+/// and must take the
+/// [`TransformArgs`](ic_cdk::api::management_canister::http_request::TransformArgs)
+/// the management canister calls it with and return the transformed
+/// [`HttpResponse`](ic_cdk::api::management_canister::http_request::HttpResponse),
+/// which is checked at compile time. Name it in a
+/// [`TransformContext`](ic_cdk::api::management_canister::http_request::TransformContext)
+/// to apply it to the response of an outcall.
+///
+/// # Example
+///
+/// ```rust
+/// # use ic_cdk_macros::transform;
+/// use ic_cdk::api::management_canister::http_request::{HttpResponse, TransformArgs};
+///
+/// #[transform]
+/// fn drop_headers(args: TransformArgs) -> HttpResponse {
+///     HttpResponse {
+///         headers: vec![],
+///         ..args.response
+///     }
+/// }
+/// ```
+///
+/// It accepts the same options as `#[query]`, e.g. `name` or `hidden`.
+#[proc_macro_attribute]
+pub fn transform(attr: TokenStream, item: TokenStream) -> TokenStream {
+    handle_debug_and_errors(export::ic_transform, "ic_transform", attr, item)
+}
+
 /// Register the `canister_init` entry point of a canister.
 ///
 /// This attribute macro will export the function `canister_init`
@@ -180,6 +366,25 @@ pub fn update(attr: TokenStream, item: TokenStream) -> TokenStream {
 /// # unimplemented!()
 /// }
 /// ```
+///
+/// The function can take arguments, which are decoded from the Candid
+/// argument of the install. When the canister is installed without an
+/// argument, `Option` arguments are `None`.
+///
+/// ```rust
+/// # use ic_cdk_macros::init;
+/// # use ic_cdk::export::candid::{CandidType, Deserialize};
+/// #[derive(CandidType, Deserialize)]
+/// struct Config {
+///     owner: String,
+/// }
+///
+/// #[init]
+/// fn init_function(config: Option<Config>) {
+///     // ...
+/// # unimplemented!()
+/// }
+/// ```
 #[proc_macro_attribute]
 pub fn init(attr: TokenStream, item: TokenStream) -> TokenStream {
     handle_debug_and_errors(export::ic_init, "ic_init", attr, item)
@@ -190,7 +395,7 @@ pub fn init(attr: TokenStream, item: TokenStream) -> TokenStream {
 /// This attribute macro will export the function `canister_pre_upgrade`
 /// in the canister module.
 ///
-/// The function under this attribute must have no return value.
+/// The function under this attribute must have no arguments and no return value.
 ///
 /// Each canister can only have one `canister_pre_upgrade` entry point.
 ///
@@ -228,6 +433,25 @@ pub fn pre_upgrade(attr: TokenStream, item: TokenStream) -> TokenStream {
 /// # unimplemented!()
 /// }
 /// ```
+///
+/// The function can take arguments, which are decoded from the Candid
+/// argument of the upgrade. When the canister is upgraded without an
+/// argument, `Option` arguments are `None`.
+///
+/// ```rust
+/// # use ic_cdk_macros::post_upgrade;
+/// # use ic_cdk::export::candid::{CandidType, Deserialize};
+/// #[derive(CandidType, Deserialize)]
+/// struct Config {
+///     owner: String,
+/// }
+///
+/// #[post_upgrade]
+/// fn post_upgrade_function(config: Option<Config>) {
+///     // ...
+/// # unimplemented!()
+/// }
+/// ```
 #[proc_macro_attribute]
 pub fn post_upgrade(attr: TokenStream, item: TokenStream) -> TokenStream {
     handle_debug_and_errors(export::ic_post_upgrade, "ic_post_upgrade", attr, item)
@@ -238,7 +462,7 @@ pub fn post_upgrade(attr: TokenStream, item: TokenStream) -> TokenStream {
 /// This attribute macro will export the function `canister_heartbeat`
 /// in the canister module.
 ///
-/// The function under this attribute must have no return value.
+/// The function under this attribute must have no arguments and no return value.
 ///
 /// Each canister can only have one `canister_heartbeat` entry point.
 ///
@@ -252,6 +476,19 @@ pub fn post_upgrade(attr: TokenStream, item: TokenStream) -> TokenStream {
 /// # unimplemented!()
 /// }
 /// ```
+///
+/// The function can be async, to make inter-canister calls. The heartbeat
+/// returns as soon as the function awaits, and the rest of it runs when the
+/// replies come back.
+///
+/// ```rust
+/// # use ic_cdk_macros::heartbeat;
+/// #[heartbeat]
+/// async fn heartbeat_function() {
+///     // ...
+/// # unimplemented!()
+/// }
+/// ```
 #[proc_macro_attribute]
 pub fn heartbeat(attr: TokenStream, item: TokenStream) -> TokenStream {
     handle_debug_and_errors(export::ic_heartbeat, "ic_heartbeat", attr, item)
@@ -262,7 +499,7 @@ pub fn heartbeat(attr: TokenStream, item: TokenStream) -> TokenStream {
 /// This attribute macro will export the function `canister_inspect_message`
 /// in the canister module.
 ///
-/// The function under this attribute must have no return value.
+/// The function under this attribute must have no arguments and no return value.
 ///
 /// Each canister can only have one `canister_inspect_message` entry point.
 ///
@@ -281,6 +518,67 @@ pub fn inspect_message(attr: TokenStream, item: TokenStream) -> TokenStream {
     handle_debug_and_errors(export::ic_inspect_message, "ic_inspect_message", attr, item)
 }
 
+/// Register the `canister_global_timer` entry point of a canister.
+///
+/// This attribute macro will export the function `canister_global_timer`
+/// in the canister module. The system calls it once the time set with
+/// `ic_cdk::api::set_global_timer` is reached.
+///
+/// The function under this attribute must have no arguments and no return value.
+///
+/// Each canister can only have one `canister_global_timer` entry point, so
+/// this can't be used together with the `timers` feature of `ic-cdk`, which
+/// exports it already.
+///
+/// # Example
+///
+/// ```rust
+/// # use ic_cdk_macros::global_timer;
+/// #[global_timer]
+/// fn global_timer_function() {
+///     // ...
+/// # unimplemented!()
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn global_timer(attr: TokenStream, item: TokenStream) -> TokenStream {
+    handle_debug_and_errors(export::ic_global_timer, "ic_global_timer", attr, item)
+}
+
+/// Generate a client of another canister from a trait that describes its
+/// interface.
+///
+/// The trait becomes a struct holding the id of the canister, with one async
+/// method per function of the trait. Each method calls the canister method of
+/// the same name, or the one given with `#[name = "..."]`, with the
+/// arguments of the function, and decodes the reply into the return type. A
+/// tuple return type stands for several return values, like for `#[update]`.
+/// The methods return `Result<_, ic_cdk::api::call::CallError>`.
+///
+/// Unlike [`import`](attr.import.html), the types come from the crate, so the
+/// canister and its callers can share them and be type-checked against the
+/// same definitions.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// ic_cdk_macros::canister_client! {
+///     /// The ledger of the tokens.
+///     pub trait Ledger {
+///         async fn balance(account: Account) -> Tokens;
+///         #[name = "transfer_from"]
+///         async fn transfer(from: Account, to: Account, amount: Tokens);
+///     }
+/// }
+///
+/// let ledger = Ledger::new(ledger_id);
+/// let tokens = ledger.balance(account).await?;
+/// ```
+#[proc_macro]
+pub fn canister_client(input: TokenStream) -> TokenStream {
+    client::canister_client(input.into()).map_or_else(|e| e.to_compile_error().into(), Into::into)
+}
+
 /// Import another canister as a rust struct.
 ///
 /// All public interfaces defined in corresponding candid file can be accessed through the annotated struct.
@@ -310,3 +608,37 @@ pub fn inspect_message(attr: TokenStream, item: TokenStream) -> TokenStream {
 pub fn import(attr: TokenStream, item: TokenStream) -> TokenStream {
     handle_debug_and_errors(import::ic_import, "ic_import", attr, item)
 }
+
+/// Implement `ic_cdk::storage::StableStorage` for a state type, so it can be
+/// saved to the stable memory in `pre_upgrade` and restored in `post_upgrade`.
+///
+/// The type must also implement `CandidType` and `Deserialize`. Its version,
+/// stored along with the value, is set with `#[stable_storage(version = N)]`
+/// and defaults to 1.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// # use ic_cdk_macros::{post_upgrade, pre_upgrade, StableStorage};
+/// #[derive(Default, CandidType, Deserialize, StableStorage)]
+/// #[stable_storage(version = 2)]
+/// struct State {
+///     owners: Vec<Principal>,
+/// }
+///
+/// #[pre_upgrade]
+/// fn pre_upgrade() {
+///     STATE.with(|s| ic_cdk::storage::save_for_upgrade(&*s.borrow()));
+/// }
+///
+/// #[post_upgrade]
+/// fn post_upgrade() {
+///     let state: State = ic_cdk::storage::restore_after_upgrade();
+///     STATE.with(|s| *s.borrow_mut() = state);
+/// }
+/// ```
+#[proc_macro_derive(StableStorage, attributes(stable_storage))]
+pub fn derive_stable_storage(input: TokenStream) -> TokenStream {
+    stable_storage::derive_stable_storage(input.into())
+        .map_or_else(|e| e.to_compile_error().into(), Into::into)
+}
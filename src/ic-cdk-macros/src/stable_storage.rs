@@ -0,0 +1,93 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use serde::Deserialize;
+use serde_tokenstream::from_tokenstream;
+use syn::{DeriveInput, Error};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StableStorageAttributes {
+    #[serde(default = "default_version")]
+    pub version: u32,
+}
+
+fn default_version() -> u32 {
+    1
+}
+
+pub(crate) fn derive_stable_storage(input: TokenStream) -> Result<TokenStream, Error> {
+    let input = syn::parse2::<DeriveInput>(input)?;
+
+    let mut version = default_version();
+    let mut seen = false;
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("stable_storage"))
+    {
+        if seen {
+            return Err(Error::new_spanned(
+                attr,
+                "#[stable_storage] can only be given once.",
+            ));
+        }
+        seen = true;
+        let attrs = from_tokenstream::<StableStorageAttributes>(&attr.parse_args()?)?;
+        version = attrs.version;
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ic_cdk::storage::StableStorage for #name #ty_generics #where_clause {
+            const VERSION: u32 = #version;
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_version_is_1() {
+        let generated = derive_stable_storage(quote! {
+            struct State {
+                count: u64,
+            }
+        })
+        .unwrap();
+        let expected = quote! {
+            impl ic_cdk::storage::StableStorage for State {
+                const VERSION: u32 = 1u32;
+            }
+        };
+        assert_eq!(generated.to_string(), expected.to_string());
+    }
+
+    #[test]
+    fn version_and_generics() {
+        let generated = derive_stable_storage(quote! {
+            #[stable_storage(version = 3)]
+            struct State<T: Clone> {
+                items: Vec<T>,
+            }
+        })
+        .unwrap();
+        let expected = quote! {
+            impl<T: Clone> ic_cdk::storage::StableStorage for State<T> {
+                const VERSION: u32 = 3u32;
+            }
+        };
+        assert_eq!(generated.to_string(), expected.to_string());
+    }
+
+    #[test]
+    fn unknown_attribute() {
+        assert!(derive_stable_storage(quote! {
+            #[stable_storage(versoin = 3)]
+            struct State;
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,12 @@
+use ic_cdk_macros::query;
+
+struct State;
+
+fn with_state<R>(f: impl FnOnce(&State) -> R) -> R {
+    f(&State)
+}
+
+#[query(composite = true, with = "with_state")]
+async fn method(_state: &State) {}
+
+fn main() {}
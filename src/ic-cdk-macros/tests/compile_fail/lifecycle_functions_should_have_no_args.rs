@@ -0,0 +1,15 @@
+use ic_cdk_macros::*;
+
+#[pre_upgrade]
+fn pre_upgrade(_: u32) {}
+
+#[heartbeat]
+fn heartbeat(_: u32) {}
+
+#[inspect_message]
+fn inspect_message(_: u32) {}
+
+#[global_timer]
+fn global_timer(_: u32) {}
+
+fn main() {}
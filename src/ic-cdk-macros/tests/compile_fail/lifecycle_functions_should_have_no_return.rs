@@ -15,4 +15,7 @@ fn heartbeat() -> u32 {}
 #[inspect_message]
 fn inspect_message() -> u32 {}
 
+#[global_timer]
+fn global_timer() -> u32 {}
+
 fn main() {}
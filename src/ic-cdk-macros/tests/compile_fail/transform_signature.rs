@@ -0,0 +1,9 @@
+use ic_cdk::api::management_canister::http_request::HttpResponse;
+use ic_cdk_macros::*;
+
+#[transform]
+fn transform(body: Vec<u8>) -> HttpResponse {
+    unimplemented!()
+}
+
+fn main() {}
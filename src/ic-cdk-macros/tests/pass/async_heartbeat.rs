@@ -0,0 +1,6 @@
+use ic_cdk_macros::*;
+
+#[heartbeat]
+async fn heartbeat() {}
+
+fn main() {}
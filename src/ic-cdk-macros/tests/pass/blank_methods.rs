@@ -21,4 +21,7 @@ fn heartbeat() {}
 #[inspect_message]
 fn inspect_message() {}
 
+#[global_timer]
+fn global_timer() {}
+
 fn main() {}
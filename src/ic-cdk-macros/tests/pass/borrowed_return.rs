@@ -0,0 +1,41 @@
+use ic_cdk_macros::*;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+struct State {
+    data: Vec<u8>,
+    name: String,
+}
+
+thread_local! {
+    static STATE: RefCell<State> = RefCell::new(State {
+        data: vec![],
+        name: String::new(),
+    });
+}
+
+fn with_state<R>(f: impl FnOnce(&State) -> R) -> R {
+    STATE.with(|s| f(&s.borrow()))
+}
+
+#[query(with = "with_state")]
+fn data(state: &State) -> &[u8] {
+    &state.data
+}
+
+#[query(with = "with_state")]
+fn prefix<'a>(state: &'a State, len: usize) -> Cow<'a, [u8]> {
+    Cow::Borrowed(&state.data[..len])
+}
+
+#[update(with = "with_state")]
+fn name_and_data(state: &State) -> (&str, &[u8]) {
+    (&state.name, &state.data)
+}
+
+#[query]
+fn greeting<'a>() -> Cow<'a, str> {
+    Cow::Borrowed("hello")
+}
+
+fn main() {}
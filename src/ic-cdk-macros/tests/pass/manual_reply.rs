@@ -0,0 +1,14 @@
+use ic_cdk::api::call::ManualReply;
+use ic_cdk_macros::*;
+
+#[query(manual_reply = true)]
+fn query() -> ManualReply<u32> {
+    ManualReply::one(1u32)
+}
+
+#[update(manual_reply = true)]
+async fn update() -> ManualReply<u32> {
+    ManualReply::reject("not implemented")
+}
+
+fn main() {}
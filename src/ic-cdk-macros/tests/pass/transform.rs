@@ -0,0 +1,20 @@
+use ic_cdk::api::management_canister::http_request::{HttpResponse, TransformArgs};
+use ic_cdk_macros::*;
+
+#[transform]
+fn transform(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        headers: vec![],
+        ..args.response
+    }
+}
+
+#[transform(name = "strip_body", hidden = true)]
+fn strip_body(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        body: vec![],
+        ..args.response
+    }
+}
+
+fn main() {}
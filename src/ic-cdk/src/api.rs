@@ -2,7 +2,23 @@
 use crate::export::Principal;
 use std::convert::{TryFrom, TryInto};
 
+/// Evaluates `$mock` on the host with the `mock` feature, `$system` otherwise.
+macro_rules! mock_or_system {
+    ($mock:expr, $system:expr) => {{
+        #[cfg(all(feature = "mock", not(target_arch = "wasm32")))]
+        let result = $mock;
+        #[cfg(not(all(feature = "mock", not(target_arch = "wasm32"))))]
+        let result = $system;
+        result
+    }};
+}
+
 pub mod call;
+pub mod instrument;
+pub mod management_canister;
+#[cfg(all(feature = "mock", not(target_arch = "wasm32")))]
+pub mod mock;
+pub mod profiling;
 pub mod stable;
 
 mod ic0;
@@ -17,25 +33,106 @@ pub fn print<S: std::convert::AsRef<str>>(s: S) {
 
 /// Traps with the given message.
 pub fn trap(message: &str) -> ! {
-    unsafe {
-        ic0::trap(message.as_ptr() as i32, message.len() as i32);
+    #[cfg(all(feature = "mock", not(target_arch = "wasm32")))]
+    {
+        mock::trap(message)
+    }
+    #[cfg(not(all(feature = "mock", not(target_arch = "wasm32"))))]
+    {
+        unsafe {
+            ic0::trap(message.as_ptr() as i32, message.len() as i32);
+        }
+        unreachable!()
+    }
+}
+
+/// Traps with `message`, tagged with the error code `code`.
+///
+/// The trap message is `[code] message`, so callers can tell expected errors
+/// apart from bugs with [trap_code] instead of matching the text. Codes
+/// should be made of ASCII letters, digits and underscores.
+pub fn trap_with(code: &str, message: &str) -> ! {
+    trap(&format!("[{}] {}", code, message))
+}
+
+/// A panic payload carrying an error code.
+///
+/// Panicking with it, through `std::panic::panic_any`, traps like
+/// [trap_with] would, with the location of the panic added to the message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodedError {
+    /// The error code, made of ASCII letters, digits and underscores.
+    pub code: String,
+    /// The description of the error.
+    pub message: String,
+}
+
+impl CodedError {
+    /// Creates an error with the given code and message.
+    pub fn new(code: impl Into<String>, message: impl Into<String>) -> Self {
+        CodedError {
+            code: code.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for CodedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for CodedError {}
+
+/// Returns the error code of a trap raised by [trap_with] or by a
+/// [CodedError] panic.
+///
+/// `message` is either the trap message or the reject message of a call to a
+/// canister that trapped.
+pub fn trap_code(message: &str) -> Option<&str> {
+    const TRAPPED: &str = "trapped explicitly: ";
+    let text = match message.find(TRAPPED) {
+        Some(index) => &message[index + TRAPPED.len()..],
+        None => message,
+    };
+    let rest = text.strip_prefix('[')?;
+    let code = &rest[..rest.find(']')?];
+    if !code.is_empty() && code.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Some(code)
+    } else {
+        None
     }
-    unreachable!()
 }
 
 /// Get current timestamp
 pub fn time() -> u64 {
-    unsafe { ic0::time() as u64 }
+    mock_or_system!(mock::time(), unsafe { ic0::time() as u64 })
 }
 
 /// Returns the caller of the current call.
 pub fn caller() -> Principal {
-    let len: u32 = unsafe { ic0::msg_caller_size() as u32 };
-    let mut bytes = vec![0; len as usize];
-    unsafe {
-        ic0::msg_caller_copy(bytes.as_mut_ptr() as i32, 0, len as i32);
+    mock_or_system!(mock::caller(), {
+        let len: u32 = unsafe { ic0::msg_caller_size() as u32 };
+        let mut bytes = vec![0; len as usize];
+        unsafe {
+            ic0::msg_caller_copy(bytes.as_mut_ptr() as i32, 0, len as i32);
+        }
+        Principal::try_from(&bytes).unwrap()
+    })
+}
+
+/// Returns the deadline of the current call, in nanoseconds since
+/// 1970-01-01, or `None` if the caller waits for the response without a
+/// deadline.
+///
+/// Past the deadline, the caller gets a `SysUnknown` reject whatever this
+/// canister replies.
+pub fn msg_deadline() -> Option<u64> {
+    match unsafe { ic0::msg_deadline() } {
+        0 => None,
+        deadline => Some(deadline as u64),
     }
-    Principal::try_from(&bytes).unwrap()
 }
 
 /// Returns the canister id as a blob.
@@ -61,6 +158,49 @@ pub fn canister_balance128() -> u128 {
     u128::from_le_bytes(buf.try_into().unwrap())
 }
 
+/// Burns up to `amount` cycles from the balance of this canister, and returns
+/// the amount actually burned.
+///
+/// Cycles needed to keep the canister from freezing are not burned.
+pub fn cycles_burn128(amount: u128) -> u128 {
+    let high = (amount >> 64) as u64;
+    let low = (amount & u64::MAX as u128) as u64;
+    let mut buf = [0u8; 16];
+    unsafe { ic0::cycles_burn128(high as i64, low as i64, buf.as_mut_ptr() as i32) };
+    u128::from_le_bytes(buf)
+}
+
+/// Returns the version of this canister.
+///
+/// The version starts at 0 and increases with every code installation and
+/// settings change, so data tagged with it can be traced back to the code that
+/// produced it.
+pub fn canister_version() -> u64 {
+    unsafe { ic0::canister_version() as u64 }
+}
+
+/// Returns whether `principal` is a controller of this canister.
+pub fn is_controller(principal: &Principal) -> bool {
+    let slice = principal.as_slice();
+    unsafe { ic0::is_controller(slice.as_ptr() as i32, slice.len() as i32) != 0 }
+}
+
+/// A guard that only lets the controllers of this canister through.
+///
+/// ```rust,ignore
+/// use ic_cdk::api::caller_is_controller;
+///
+/// #[update(guard = "caller_is_controller")]
+/// fn admin_only() {}
+/// ```
+pub fn caller_is_controller() -> Result<(), String> {
+    if is_controller(&caller()) {
+        Ok(())
+    } else {
+        Err("Caller is not a controller".to_string())
+    }
+}
+
 /// Sets the certified data of this canister.
 ///
 /// Canisters can store up to 32 bytes of data that is certified by
@@ -80,22 +220,76 @@ pub fn canister_balance128() -> u128 {
 /// * This function traps if it's called from an illegal context
 ///   (e.g., from a query call).
 pub fn set_certified_data(data: &[u8]) {
-    unsafe { ic0::certified_data_set(data.as_ptr() as i32, data.len() as i32) }
+    mock_or_system!(mock::set_certified_data(data), unsafe {
+        ic0::certified_data_set(data.as_ptr() as i32, data.len() as i32)
+    })
 }
 
 /// When called from a query call, returns the data certificate authenticating
 /// certified_data set by this canister.
 ///
-/// Returns None if called not from a query call.
+/// Returns None if called not from a query call, which includes queries
+/// executed as update calls. In a composite query, the certificate is only
+/// available before the first call to another canister: callbacks get None.
 pub fn data_certificate() -> Option<Vec<u8>> {
-    if unsafe { ic0::data_certificate_present() } == 0 {
-        return None;
-    }
+    mock_or_system!(mock::data_certificate(), {
+        if unsafe { ic0::data_certificate_present() } == 0 {
+            return None;
+        }
 
-    let n = unsafe { ic0::data_certificate_size() };
-    let mut buf = vec![0u8; n as usize];
-    unsafe {
-        ic0::data_certificate_copy(buf.as_mut_ptr() as i32, 0i32, n);
-    }
-    Some(buf)
+        let n = unsafe { ic0::data_certificate_size() };
+        let mut buf = vec![0u8; n as usize];
+        unsafe {
+            ic0::data_certificate_copy(buf.as_mut_ptr() as i32, 0i32, n);
+        }
+        Some(buf)
+    })
+}
+
+/// Returns whether the current message is executed by every replica of the
+/// subnet, as updates are, rather than by a single one, as queries called as
+/// queries are.
+///
+/// Responses of replicated executions are certified by the subnet, while the
+/// others have to carry their own certification, e.g. built with
+/// [data_certificate].
+pub fn in_replicated_execution() -> bool {
+    mock_or_system!(mock::in_replicated_execution(), unsafe {
+        ic0::in_replicated_execution() != 0
+    })
+}
+
+/// Sets the global timer of the canister to `timestamp`, in nanoseconds since
+/// 1970-01-01, and returns its previous value.
+///
+/// When the time is reached, the system calls the `canister_global_timer`
+/// export of the canister once. Passing `0` deactivates the timer.
+pub fn set_global_timer(timestamp: u64) -> u64 {
+    mock_or_system!(mock::set_global_timer(timestamp), unsafe {
+        ic0::global_timer_set(timestamp as i64) as u64
+    })
+}
+
+/// Returns the value of the performance counter `counter_type`.
+///
+/// Counter `0` is the number of WebAssembly instructions the canister has
+/// executed since the beginning of the current message, counter `1` since the
+/// beginning of the current call context. Comparing two readings measures the
+/// instructions spent in between.
+pub fn performance_counter(counter_type: u32) -> u64 {
+    mock_or_system!(mock::performance_counter(counter_type), unsafe {
+        ic0::performance_counter(counter_type as i32) as u64
+    })
+}
+
+/// Returns the number of instructions executed since the beginning of the
+/// current message.
+pub fn instruction_counter() -> u64 {
+    performance_counter(0)
+}
+
+/// Returns the number of instructions executed since the beginning of the
+/// current call context, across all the messages of an async method.
+pub fn call_context_instruction_counter() -> u64 {
+    performance_counter(1)
 }
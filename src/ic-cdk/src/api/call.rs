@@ -9,6 +9,11 @@ use std::marker::PhantomData;
 use std::pin::Pin;
 use std::task::{Context, Poll, Waker};
 
+mod builder;
+mod join;
+pub use builder::{Call, CallError};
+pub use join::{join_all, join_all_bounded, try_join_all, try_join_all_bounded, JoinAll};
+
 #[cfg(target_arch = "wasm32-unknown-unknown")]
 #[allow(dead_code)]
 mod rc {
@@ -138,6 +143,7 @@ pub enum RejectionCode {
     DestinationInvalid = 3,
     CanisterReject = 4,
     CanisterError = 5,
+    SysUnknown = 6,
 
     Unknown,
 }
@@ -151,6 +157,7 @@ impl From<i32> for RejectionCode {
             3 => RejectionCode::DestinationInvalid,
             4 => RejectionCode::CanisterReject,
             5 => RejectionCode::CanisterError,
+            6 => RejectionCode::SysUnknown,
             _ => RejectionCode::Unknown,
         }
     }
@@ -204,7 +211,7 @@ fn callback(state_ptr: *const InnerCell<CallFutureState<Vec<u8>>>) {
     // Make sure to un-borrow_mut the state.
     {
         state.borrow_mut().result = Some(match reject_code() {
-            RejectionCode::NoError => unsafe { Ok(arg_data_raw()) },
+            RejectionCode::NoError => Ok(arg_data_raw()),
             n => Err((n, reject_message())),
         });
     }
@@ -216,6 +223,17 @@ fn callback(state_ptr: *const InnerCell<CallFutureState<Vec<u8>>>) {
     }
 }
 
+/// Runs instead of the reply or reject callback when it trapped, after the
+/// system rolled the callback back. The future waiting for the call can't
+/// make progress anymore, so its task is dropped.
+fn cleanup(state_ptr: *const InnerCell<CallFutureState<Vec<u8>>>) {
+    let state = unsafe { WasmCell::from_raw(state_ptr) };
+    let w = state.borrow_mut().waker.take();
+    if let Some(waker) = w {
+        crate::futures::cleanup(waker);
+    }
+}
+
 /// Similar to `call`, but without serialization.
 pub fn call_raw(
     id: Principal,
@@ -223,6 +241,76 @@ pub fn call_raw(
     args_raw: Vec<u8>,
     payment: u64,
 ) -> impl Future<Output = CallResult<Vec<u8>>> {
+    call_raw_internal(id, method, args_raw, move || {
+        if payment > 0 {
+            unsafe { ic0::call_cycles_add(payment as i64) };
+        }
+    })
+}
+
+/// Similar to `call_raw`, but the call is a bounded-wait call.
+///
+/// If no response arrives within `timeout_seconds`, the call is rejected
+/// with [RejectionCode::SysUnknown]. The callee may or may not have executed
+/// the call in that case.
+pub fn call_raw_with_timeout(
+    id: Principal,
+    method: &str,
+    args_raw: Vec<u8>,
+    payment: u64,
+    timeout_seconds: u32,
+) -> impl Future<Output = CallResult<Vec<u8>>> {
+    call_raw_internal(id, method, args_raw, move || unsafe {
+        if payment > 0 {
+            ic0::call_cycles_add(payment as i64);
+        }
+        ic0::call_with_best_effort_response(timeout_seconds as i32);
+    })
+}
+
+/// Similar to `call_raw`, with a 128-bit payment.
+pub fn call_raw128(
+    id: Principal,
+    method: &str,
+    args_raw: Vec<u8>,
+    payment: u128,
+) -> impl Future<Output = CallResult<Vec<u8>>> {
+    call_raw_internal(id, method, args_raw, move || add_cycles128(payment))
+}
+
+/// Attaches `cycles` to the call being built.
+fn add_cycles128(cycles: u128) {
+    if cycles > 0 {
+        let high = (cycles >> 64) as u64;
+        let low = (cycles & u64::MAX as u128) as u64;
+        unsafe { ic0::call_cycles_add128(high as i64, low as i64) };
+    }
+}
+
+fn call_raw_internal(
+    id: Principal,
+    method: &str,
+    args_raw: Vec<u8>,
+    configure: impl FnOnce(),
+) -> impl Future<Output = CallResult<Vec<u8>>> {
+    send(id, method, &args_raw, configure).unwrap_or_else(|err_code| CallFuture {
+        state: WasmCell::new(CallFutureState {
+            result: Some(Err((err_code, "Couldn't send message".to_string()))),
+            waker: None,
+        }),
+    })
+}
+
+/// Sends the call, after `configure` added cycles or other options to it.
+///
+/// Fails with the error code of `call_perform` if the call couldn't be sent,
+/// in which case the callee doesn't execute it.
+fn send(
+    id: Principal,
+    method: &str,
+    args_raw: &[u8],
+    configure: impl FnOnce(),
+) -> Result<CallFuture<Vec<u8>>, RejectionCode> {
     let callee = id.as_slice();
     let state = WasmCell::new(CallFutureState {
         result: None,
@@ -240,23 +328,18 @@ pub fn call_raw(
             callback as usize as i32,
             state_ptr as i32,
         );
+        ic0::call_on_cleanup(cleanup as usize as i32, state_ptr as i32);
 
         ic0::call_data_append(args_raw.as_ptr() as i32, args_raw.len() as i32);
-        if payment > 0 {
-            ic0::call_cycles_add(payment as i64);
-        }
+        configure();
         ic0::call_perform()
     };
 
     // 0 is a special error code meaning call_simple call succeeded.
     if err_code != 0 {
-        let mut state = state.borrow_mut();
-        state.result = Some(Err((
-            RejectionCode::from(err_code),
-            "Couldn't send message".to_string(),
-        )));
+        return Err(RejectionCode::from(err_code));
     }
-    CallFuture { state }
+    Ok(CallFuture { state })
 }
 
 /// Performs an asynchronous call to another canister via ic0.
@@ -282,14 +365,90 @@ pub async fn call_with_payment<T: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a
     decode_args(&bytes).map_err(|err| trap(&format!("{:?}", err)))
 }
 
+/// Performs an asynchronous bounded-wait call to another canister via ic0.
+///
+/// If no response arrives within `timeout_seconds`, the call fails with
+/// [RejectionCode::SysUnknown], and the callee may or may not have executed
+/// it.
+pub async fn call_with_timeout<T: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a>>(
+    id: Principal,
+    method: &str,
+    args: T,
+    timeout_seconds: u32,
+) -> CallResult<R> {
+    let args_raw = encode_args(args).expect("Failed to encode arguments.");
+    let bytes = call_raw_with_timeout(id, method, args_raw, 0, timeout_seconds).await?;
+    decode_args(&bytes).map_err(|err| trap(&format!("{:?}", err)))
+}
+
+/// Performs an asynchronous call to another canister and pay cycles at the same time,
+/// with a 128-bit amount of cycles.
+pub async fn call_with_payment128<T: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a>>(
+    id: Principal,
+    method: &str,
+    args: T,
+    cycles: u128,
+) -> CallResult<R> {
+    let args_raw = encode_args(args).expect("Failed to encode arguments.");
+    let bytes = call_raw128(id, method, args_raw, cycles).await?;
+    decode_args(&bytes).map_err(|err| trap(&format!("{:?}", err)))
+}
+
+/// Sends a one-way message to another canister, without waiting for a reply.
+///
+/// The reply or rejection of the callee is ignored. An error is only returned
+/// if the message couldn't be enqueued.
+pub fn notify<T: ArgumentEncoder>(
+    id: Principal,
+    method: &str,
+    args: T,
+) -> Result<(), RejectionCode> {
+    let args_raw = encode_args(args).expect("Failed to encode arguments.");
+    notify_raw(id, method, &args_raw, 0)
+}
+
+/// Similar to `notify`, but without serialization.
+pub fn notify_raw(
+    id: Principal,
+    method: &str,
+    args_raw: &[u8],
+    payment: u64,
+) -> Result<(), RejectionCode> {
+    let callee = id.as_slice();
+    // An invalid table index as callback makes the system drop the response.
+    let err_code = unsafe {
+        ic0::call_new(
+            callee.as_ptr() as i32,
+            callee.len() as i32,
+            method.as_ptr() as i32,
+            method.len() as i32,
+            -1,
+            -1,
+            -1,
+            -1,
+        );
+
+        ic0::call_data_append(args_raw.as_ptr() as i32, args_raw.len() as i32);
+        if payment > 0 {
+            ic0::call_cycles_add(payment as i64);
+        }
+        ic0::call_perform()
+    };
+    match err_code {
+        0 => Ok(()),
+        c => Err(RejectionCode::from(c)),
+    }
+}
+
 /// Returns a result that maps over the call
 ///
 /// It will be Ok(T) if the call succeeded (with T being the arg_data),
 /// and [reject_message()] if it failed.
 pub fn result<T: for<'a> ArgumentDecoder<'a>>() -> Result<T, String> {
     match reject_code() {
-        RejectionCode::NoError => decode_args(&unsafe { arg_data_raw() })
-            .map_err(|e| format!("Failed to decode arguments: {}", e)),
+        RejectionCode::NoError => {
+            decode_args(&arg_data_raw()).map_err(|e| format!("Failed to decode arguments: {}", e))
+        }
         _ => Err(reject_message()),
     }
 }
@@ -312,9 +471,15 @@ pub fn reject_message() -> String {
 
 /// Rejects the current call with the message.
 pub fn reject(message: &str) {
-    let err_message = message.as_bytes();
+    reject_raw(message.as_bytes())
+}
+
+/// Rejects the current call with a message given as bytes.
+///
+/// The message must be valid UTF-8, or the system traps.
+pub fn reject_raw(message: &[u8]) {
     unsafe {
-        ic0::msg_reject(err_message.as_ptr() as i32, err_message.len() as i32);
+        ic0::msg_reject(message.as_ptr() as i32, message.len() as i32);
     }
 }
 
@@ -342,12 +507,32 @@ pub fn reply<T: ArgumentEncoder>(reply: T) {
     }
 }
 
+/// Replies to the current call with already encoded bytes.
+///
+/// Bytes written with [CallReplyWriter] beforehand are sent ahead of `buf`.
+pub fn reply_raw(buf: &[u8]) {
+    unsafe {
+        if !buf.is_empty() {
+            ic0::msg_reply_data_append(buf.as_ptr() as i32, buf.len() as i32);
+        }
+        ic0::msg_reply();
+    }
+}
+
 /// Returns the amount of cycles that were transferred by the caller
 /// of the current call, and is still available in this message.
 pub fn msg_cycles_available() -> u64 {
     unsafe { ic0::msg_cycles_available() as u64 }
 }
 
+/// Returns the amount of cycles that were transferred by the caller
+/// of the current call, and is still available in this message.
+pub fn msg_cycles_available128() -> u128 {
+    let mut buf = [0u8; 16];
+    unsafe { ic0::msg_cycles_available128(buf.as_mut_ptr() as i32) };
+    u128::from_le_bytes(buf)
+}
+
 /// Returns the amount of cycles that came back with the response as a refund.
 ///
 /// The refund has already been added to the canister balance automatically.
@@ -355,6 +540,15 @@ pub fn msg_cycles_refunded() -> u64 {
     unsafe { ic0::msg_cycles_refunded() as u64 }
 }
 
+/// Returns the amount of cycles that came back with the response as a refund.
+///
+/// The refund has already been added to the canister balance automatically.
+pub fn msg_cycles_refunded128() -> u128 {
+    let mut buf = [0u8; 16];
+    unsafe { ic0::msg_cycles_refunded128(buf.as_mut_ptr() as i32) };
+    u128::from_le_bytes(buf)
+}
+
 /// Moves cycles from the call to the canister balance.
 ///
 /// The actual amounts moved will be returned
@@ -363,17 +557,64 @@ pub fn msg_cycles_accept(max_amount: u64) -> u64 {
     unsafe { ic0::msg_cycles_accept(max_amount as i64) as u64 }
 }
 
-/// Returns the argument data as bytes.
-pub(crate) unsafe fn arg_data_raw() -> Vec<u8> {
-    let len: usize = ic0::msg_arg_data_size() as usize;
-    let mut bytes = vec![0u8; len as usize];
-    ic0::msg_arg_data_copy(bytes.as_mut_ptr() as i32, 0, len as i32);
+/// Moves cycles from the call to the canister balance.
+///
+/// The actual amounts moved will be returned
+pub fn msg_cycles_accept128(max_amount: u128) -> u128 {
+    let high = (max_amount >> 64) as u64;
+    let low = (max_amount & u64::MAX as u128) as u64;
+    let mut buf = [0u8; 16];
+    unsafe { ic0::msg_cycles_accept128(high as i64, low as i64, buf.as_mut_ptr() as i32) };
+    u128::from_le_bytes(buf)
+}
+
+/// Accepts up to `max_amount` of the cycles attached to the call, and returns
+/// the amount accepted.
+pub fn accept_cycles_up_to(max_amount: u128) -> u128 {
+    msg_cycles_accept128(std::cmp::min(max_amount, msg_cycles_available128()))
+}
+
+/// Accepts all the cycles attached to the call, and returns their amount.
+pub fn accept_all_cycles() -> u128 {
+    msg_cycles_accept128(msg_cycles_available128())
+}
+
+/// Returns the argument data as bytes, without decoding them.
+///
+/// In a reply callback, these are the bytes of the reply.
+pub fn arg_data_raw() -> Vec<u8> {
+    let len = arg_data_raw_size();
+    let mut bytes = vec![0u8; len];
+    unsafe {
+        ic0::msg_arg_data_copy(bytes.as_mut_ptr() as i32, 0, len as i32);
+    }
     bytes
 }
 
+/// Returns the size of the argument data, in bytes.
+pub fn arg_data_raw_size() -> usize {
+    unsafe { ic0::msg_arg_data_size() as usize }
+}
+
 /// Returns the argument data in the current call.
 pub fn arg_data<R: for<'a> ArgumentDecoder<'a>>() -> R {
-    let bytes = unsafe { arg_data_raw() };
+    let bytes = arg_data_raw();
+
+    match decode_args(&bytes) {
+        Err(e) => trap(&format!("{:?}", e)),
+        Ok(r) => r,
+    }
+}
+
+/// Returns the argument of `canister_init` or `canister_post_upgrade`.
+///
+/// Unlike [arg_data], an install or upgrade without any argument is decoded
+/// as an empty argument list, so `Option` parameters are `None`.
+pub fn lifecycle_arg_data<R: for<'a> ArgumentDecoder<'a>>() -> R {
+    let mut bytes = arg_data_raw();
+    if bytes.is_empty() {
+        bytes = encode_args(()).expect("failed to encode an empty argument list");
+    }
 
     match decode_args(&bytes) {
         Err(e) => trap(&format!("{:?}", e)),
@@ -401,8 +642,9 @@ pub fn method_name() -> String {
 /// Pretends to have the Candid type `T`, but unconditionally errors
 /// when serialized.
 ///
-/// Usable, but not required, as metadata when using `#[query(reply = false)]`,
-/// so an accurate Candid file can still be generated.
+/// Usable, but not required, as metadata when using `#[query(manual_reply = true)]`
+/// or `#[update(manual_reply = true)]`, so an accurate Candid file can still be
+/// generated.
 #[derive(Debug, Copy, Clone, Default)]
 pub struct ManualReply<T: ?Sized>(PhantomData<T>);
 
@@ -430,6 +672,12 @@ impl<T: ?Sized> ManualReply<T> {
         reply((value,));
         Self::empty()
     }
+    /// Rejects the call with the given message and returns a new
+    /// `ManualReply`, for a useful reject-then-return shortcut.
+    pub fn reject(message: impl AsRef<str>) -> Self {
+        reject(message.as_ref());
+        Self::empty()
+    }
 }
 
 impl<T> CandidType for ManualReply<T>
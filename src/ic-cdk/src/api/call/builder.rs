@@ -0,0 +1,181 @@
+//! A builder for inter-canister calls.
+use super::{add_cycles128, send, RejectionCode};
+use crate::api::ic0;
+use crate::export::Principal;
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
+use candid::{decode_args, decode_one, encode_args, encode_one, CandidType};
+use serde::de::DeserializeOwned;
+use std::fmt;
+use std::time::Duration;
+
+/// A call to another canister, configured step by step.
+///
+/// ```rust,ignore
+/// use ic_cdk::api::call::Call;
+///
+/// let balance: u64 = Call::new(ledger, "balance")
+///     .with_arg(account)
+///     .with_cycles(1_000_000)
+///     .with_timeout(Duration::from_secs(60))
+///     .call()
+///     .await?;
+/// ```
+///
+/// The call is only sent when one of the `call` methods is awaited.
+#[derive(Debug, Clone)]
+pub struct Call<'a> {
+    canister: Principal,
+    method: &'a str,
+    args: Result<Vec<u8>, String>,
+    cycles: u128,
+    timeout_seconds: Option<u32>,
+}
+
+impl<'a> Call<'a> {
+    /// Starts building a call to `method` of `canister`, with no arguments.
+    pub fn new(canister: Principal, method: &'a str) -> Self {
+        Call {
+            canister,
+            method,
+            args: encode_args(()).map_err(|err| err.to_string()),
+            cycles: 0,
+            timeout_seconds: None,
+        }
+    }
+
+    /// Sets the argument of the call, when the method takes exactly one.
+    pub fn with_arg<T: CandidType>(mut self, arg: T) -> Self {
+        self.args = encode_one(arg).map_err(|err| err.to_string());
+        self
+    }
+
+    /// Sets the arguments of the call, as a tuple.
+    pub fn with_args<T: ArgumentEncoder>(mut self, args: T) -> Self {
+        self.args = encode_args(args).map_err(|err| err.to_string());
+        self
+    }
+
+    /// Sets the arguments of the call, already encoded.
+    pub fn with_raw_args(mut self, args: Vec<u8>) -> Self {
+        self.args = Ok(args);
+        self
+    }
+
+    /// Attaches `cycles` to the call. The cycles the callee doesn't accept are
+    /// refunded, see [msg_cycles_refunded128](super::msg_cycles_refunded128).
+    pub fn with_cycles(mut self, cycles: u128) -> Self {
+        self.cycles = cycles;
+        self
+    }
+
+    /// Makes the call a bounded-wait call: if no response arrives within
+    /// `timeout`, rounded down to whole seconds, the call fails with
+    /// [CallError::Unknown].
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout_seconds = Some(std::cmp::min(timeout.as_secs(), u32::MAX as u64) as u32);
+        self
+    }
+
+    /// Sends the call and decodes the single value it returns.
+    pub async fn call<R: CandidType + DeserializeOwned>(self) -> Result<R, CallError> {
+        let bytes = self.call_raw().await?;
+        decode_one(&bytes).map_err(|err| CallError::Decode(err.to_string()))
+    }
+
+    /// Sends the call and decodes the values it returns, as a tuple.
+    pub async fn call_tuple<R: for<'b> ArgumentDecoder<'b>>(self) -> Result<R, CallError> {
+        let bytes = self.call_raw().await?;
+        decode_args(&bytes).map_err(|err| CallError::Decode(err.to_string()))
+    }
+
+    /// Sends the call and decodes the reply with `decode`, for replies that
+    /// are not Candid or need special handling.
+    pub async fn call_and_decode<R, E: fmt::Display>(
+        self,
+        decode: impl FnOnce(&[u8]) -> Result<R, E>,
+    ) -> Result<R, CallError> {
+        let bytes = self.call_raw().await?;
+        decode(&bytes).map_err(|err| CallError::Decode(err.to_string()))
+    }
+
+    /// Sends the call and returns the reply without decoding it.
+    pub async fn call_raw(self) -> Result<Vec<u8>, CallError> {
+        let args = self.args.map_err(CallError::Encode)?;
+        let cycles = self.cycles;
+        let timeout_seconds = self.timeout_seconds;
+        let future = send(self.canister, self.method, &args, move || {
+            add_cycles128(cycles);
+            if let Some(timeout_seconds) = timeout_seconds {
+                unsafe { ic0::call_with_best_effort_response(timeout_seconds as i32) };
+            }
+        })
+        .map_err(CallError::NotSent)?;
+        future.await.map_err(CallError::from)
+    }
+}
+
+/// The ways a [Call] can fail.
+#[derive(Debug, Clone)]
+pub enum CallError {
+    /// The arguments couldn't be encoded. Nothing was sent.
+    Encode(String),
+    /// The call couldn't be sent, e.g. because the output queue is full. The
+    /// callee didn't execute it, so it can be retried right away.
+    NotSent(RejectionCode),
+    /// The system rejected the call, e.g. because the callee doesn't exist or
+    /// is stopped. The callee didn't execute it.
+    SysReject(RejectionCode, String),
+    /// The callee rejected the call explicitly.
+    CanisterReject(String),
+    /// The callee trapped. Its state changes were rolled back.
+    CanisterError(String),
+    /// The outcome is unknown: the deadline of a bounded-wait call passed, and
+    /// the callee may or may not have executed the call.
+    Unknown(String),
+    /// The reply couldn't be decoded. The callee executed the call.
+    Decode(String),
+}
+
+impl CallError {
+    /// Returns the rejection code of the call, if it was rejected.
+    pub fn reject_code(&self) -> Option<RejectionCode> {
+        match self {
+            CallError::NotSent(code) | CallError::SysReject(code, _) => Some(*code),
+            CallError::CanisterReject(_) => Some(RejectionCode::CanisterReject),
+            CallError::CanisterError(_) => Some(RejectionCode::CanisterError),
+            CallError::Unknown(_) => Some(RejectionCode::SysUnknown),
+            CallError::Encode(_) | CallError::Decode(_) => None,
+        }
+    }
+}
+
+impl From<(RejectionCode, String)> for CallError {
+    fn from((code, message): (RejectionCode, String)) -> Self {
+        match code {
+            RejectionCode::CanisterReject => CallError::CanisterReject(message),
+            RejectionCode::CanisterError => CallError::CanisterError(message),
+            RejectionCode::SysUnknown => CallError::Unknown(message),
+            code => CallError::SysReject(code, message),
+        }
+    }
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallError::Encode(err) => write!(f, "failed to encode the arguments: {}", err),
+            CallError::NotSent(code) => write!(f, "the call couldn't be sent: {:?}", code),
+            CallError::SysReject(code, message) => {
+                write!(f, "the call was rejected ({:?}): {}", code, message)
+            }
+            CallError::CanisterReject(message) => {
+                write!(f, "the callee rejected the call: {}", message)
+            }
+            CallError::CanisterError(message) => write!(f, "the callee trapped: {}", message),
+            CallError::Unknown(message) => write!(f, "the outcome is unknown: {}", message),
+            CallError::Decode(err) => write!(f, "failed to decode the reply: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for CallError {}
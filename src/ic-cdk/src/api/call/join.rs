@@ -0,0 +1,230 @@
+//! Awaiting several calls at once.
+//!
+//! Awaiting calls one after the other makes every call wait for the previous
+//! response, which takes a round of consensus or more each. The combinators
+//! here poll the futures together instead, so the calls are in flight at the
+//! same time. The futures are only taken out of the iterator when they can
+//! run, so at most `limit` calls are outstanding, which keeps fan-outs within
+//! the limits of the output queues.
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// The future returned by [join_all] and [join_all_bounded].
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct JoinAll<I>
+where
+    I: Iterator,
+    I::Item: Future,
+{
+    futures: I,
+    running: Vec<(usize, Pin<Box<I::Item>>)>,
+    outputs: Vec<Option<<I::Item as Future>::Output>>,
+    limit: usize,
+}
+
+// The futures are boxed and the iterator is never pinned.
+impl<I> Unpin for JoinAll<I>
+where
+    I: Iterator,
+    I::Item: Future,
+{
+}
+
+impl<I> Future for JoinAll<I>
+where
+    I: Iterator,
+    I::Item: Future,
+{
+    type Output = Vec<<I::Item as Future>::Output>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            while this.running.len() < this.limit {
+                match this.futures.next() {
+                    Some(future) => {
+                        this.running.push((this.outputs.len(), Box::pin(future)));
+                        this.outputs.push(None);
+                    }
+                    None => break,
+                }
+            }
+            if this.running.is_empty() {
+                let outputs = std::mem::take(&mut this.outputs);
+                return Poll::Ready(outputs.into_iter().map(Option::unwrap).collect());
+            }
+
+            let running = this.running.len();
+            let mut i = 0;
+            while i < this.running.len() {
+                let (index, future) = &mut this.running[i];
+                match future.as_mut().poll(context) {
+                    Poll::Ready(output) => {
+                        this.outputs[*index] = Some(output);
+                        this.running.swap_remove(i);
+                    }
+                    Poll::Pending => i += 1,
+                }
+            }
+            // Finished futures made room for new ones, which have to be polled
+            // before waiting.
+            if this.running.len() == running {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+/// Polls all the futures together and returns their outputs, in the order of
+/// the futures.
+pub fn join_all<I>(futures: I) -> JoinAll<I::IntoIter>
+where
+    I: IntoIterator,
+    I::Item: Future,
+{
+    join_all_bounded(futures, usize::MAX)
+}
+
+/// Like [join_all], with at most `limit` futures running at the same time.
+///
+/// # Panics
+///
+/// Panics if `limit` is 0.
+pub fn join_all_bounded<I>(futures: I, limit: usize) -> JoinAll<I::IntoIter>
+where
+    I: IntoIterator,
+    I::Item: Future,
+{
+    assert!(limit > 0, "join_all_bounded needs a limit of at least 1");
+    JoinAll {
+        futures: futures.into_iter(),
+        running: vec![],
+        outputs: vec![],
+        limit,
+    }
+}
+
+/// Runs all the futures like [join_all] and returns either all the values, or
+/// all the errors along with the position of the future that failed.
+///
+/// Unlike `futures::try_join_all`, the other futures still run to completion
+/// after an error: their calls are already sent and can't be taken back.
+pub async fn try_join_all<I, T, E>(futures: I) -> Result<Vec<T>, Vec<(usize, E)>>
+where
+    I: IntoIterator,
+    I::Item: Future<Output = Result<T, E>>,
+{
+    try_join_all_bounded(futures, usize::MAX).await
+}
+
+/// Like [try_join_all], with at most `limit` futures running at the same time.
+///
+/// # Panics
+///
+/// Panics if `limit` is 0.
+pub async fn try_join_all_bounded<I, T, E>(
+    futures: I,
+    limit: usize,
+) -> Result<Vec<T>, Vec<(usize, E)>>
+where
+    I: IntoIterator,
+    I::Item: Future<Output = Result<T, E>>,
+{
+    let mut values = vec![];
+    let mut errors = vec![];
+    for (index, result) in join_all_bounded(futures, limit)
+        .await
+        .into_iter()
+        .enumerate()
+    {
+        match result {
+            Ok(value) => values.push(value),
+            Err(err) => errors.push((index, err)),
+        }
+    }
+    if errors.is_empty() {
+        Ok(values)
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::task::{RawWaker, RawWakerVTable, Waker};
+
+    fn noop_waker() -> Waker {
+        fn clone(_: *const ()) -> RawWaker {
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        unsafe { Waker::from_raw(clone(std::ptr::null())) }
+    }
+
+    fn poll<F: Future + Unpin>(future: &mut F) -> Poll<F::Output> {
+        Pin::new(future).poll(&mut Context::from_waker(&noop_waker()))
+    }
+
+    /// A future that is pending until its gate is opened.
+    struct Gate(Rc<Cell<bool>>);
+
+    impl Future for Gate {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<()> {
+            if self.0.get() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+
+    #[test]
+    fn runs_at_most_limit_futures_and_keeps_the_order() {
+        let gates: Vec<_> = (0..5).map(|_| Rc::new(Cell::new(false))).collect();
+        let started = Rc::new(Cell::new(0));
+        let futures = gates.iter().enumerate().map(|(i, gate)| {
+            let gate = Gate(gate.clone());
+            let started = started.clone();
+            async move {
+                started.set(started.get() + 1);
+                gate.await;
+                i
+            }
+        });
+        let mut joined = join_all_bounded(futures, 2);
+
+        assert_eq!(poll(&mut joined), Poll::Pending);
+        assert_eq!(started.get(), 2);
+
+        gates[1].set(true);
+        assert_eq!(poll(&mut joined), Poll::Pending);
+        assert_eq!(started.get(), 3);
+
+        for gate in gates.iter() {
+            gate.set(true);
+        }
+        assert_eq!(poll(&mut joined), Poll::Ready(vec![0, 1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn errors_are_aggregated() {
+        let results = vec![Ok(0), Err("a"), Ok(2), Err("b")];
+        let futures = results.into_iter().map(|r| async move { r });
+        let mut joined = Box::pin(try_join_all_bounded(futures, 1));
+        assert_eq!(
+            poll(&mut joined),
+            Poll::Ready(Err(vec![(1, "a"), (3, "b")]))
+        );
+
+        let futures = (0..3).map(|i| async move { Ok::<_, ()>(i) });
+        let mut joined = Box::pin(try_join_all(futures));
+        assert_eq!(poll(&mut joined), Poll::Ready(Ok(vec![0, 1, 2])));
+    }
+}
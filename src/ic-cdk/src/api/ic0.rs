@@ -101,7 +101,10 @@ ic0_module! {
     ic0.canister_cycle_balance : () -> i64;                                     // *
     ic0.canister_cycle_balance128 : (dst : i32) -> ();                          // *
     ic0.canister_status : () -> i32;                                            // *
+    ic0.canister_version : () -> i64;                                           // *
+    ic0.is_controller : (src : i32, size : i32) -> (result : i32);              // * s
 
+    ic0.msg_deadline : () -> i64;                                               // U Q Ry Rt
     ic0.msg_method_name_size : () -> i32;                                       // F
     ic0.msg_method_name_copy : (dst : i32, offset : i32, size : i32) -> ();     // F
     ic0.accept_message : () -> ();                                              // F
@@ -120,8 +123,12 @@ ic0_module! {
     ic0.call_data_append : (src : i32, size : i32) -> ();                       // U Ry Rt H
     ic0.call_cycles_add : ( amount : i64 ) -> ();                               // U Ry Rt H
     ic0.call_cycles_add128 : ( amount_high : i64, amount_low: i64 ) -> ();      // U Ry Rt H
+    ic0.call_with_best_effort_response : (timeout_seconds : i32) -> ();         // U Ry Rt H
     ic0.call_perform : () -> ( err_code : i32 );                                // U Ry Rt H
 
+    ic0.cycles_burn128 : ( amount_high : i64, amount_low : i64, dst : i32 )
+                       -> ();                                                   // I G U Ry Rt C T
+
     ic0.stable_size : () -> (page_count : i32);                                 // *
     ic0.stable_grow : (new_pages : i32) -> (old_page_count : i32);              // *
     ic0.stable_write : (offset : i32, src : i32, size : i32) -> ();             // *
@@ -135,9 +142,11 @@ ic0_module! {
     ic0.data_certificate_present : () -> i32;                                   // *
     ic0.data_certificate_size : () -> i32;                                      // *
     ic0.data_certificate_copy : (dst: i32, offset: i32, size: i32) -> ();       // *
+    ic0.in_replicated_execution : () -> (result : i32);                         // *
 
     ic0.time : () -> (timestamp : i64);                                         // *
-    ic0.performance_counter : () -> (counter : i64);                            // * s
+    ic0.global_timer_set : (timestamp : i64) -> i64;                            // I U Ry Rt C
+    ic0.performance_counter : (counter_type : i32) -> (counter : i64);          // * s
 
     ic0.debug_print : (src : i32, size : i32) -> ();                            // * s
     ic0.trap : (src : i32, size : i32) -> ();                                   // * s
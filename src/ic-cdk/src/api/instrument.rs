@@ -0,0 +1,72 @@
+//! Hooks run around the methods exported with `instrument = true`.
+//!
+//! ```rust,ignore
+//! #[update(instrument = true)]
+//! fn transfer(to: Principal, amount: u64) { ... }
+//! ```
+//!
+//! The `before` hook runs when the method is called, before its arguments
+//! are decoded. The `after` hook runs once the method returned, with the
+//! instructions executed by the whole call so far, awaits included. By
+//! default, the calls are logged with [log!](crate::log!) and their
+//! instructions are added to the [profiling](super::profiling) statistics
+//! under the name of the method. [set_hooks] replaces that, e.g. to count the
+//! calls in the canister's own metrics.
+//!
+//! The hooks don't run if the method traps, and what they record during a
+//! query is discarded with the rest of its changes.
+use crate::api::{call_context_instruction_counter, profiling};
+use crate::log::Level;
+use std::cell::Cell;
+
+/// Runs before an instrumented method, with the name of the method.
+pub type BeforeHook = fn(method: &str);
+
+/// Runs after an instrumented method, with the name of the method and the
+/// instructions executed by the call.
+pub type AfterHook = fn(method: &str, instructions: u64);
+
+thread_local! {
+    static HOOKS: Cell<(BeforeHook, AfterHook)> = Cell::new((default_before, default_after));
+}
+
+/// Replaces the hooks run around instrumented methods.
+///
+/// The hooks live on the heap, so they are reset by upgrades.
+pub fn set_hooks(before: BeforeHook, after: AfterHook) {
+    HOOKS.with(|hooks| hooks.set((before, after)))
+}
+
+/// Goes back to [default_before] and [default_after].
+pub fn reset_hooks() {
+    set_hooks(default_before, default_after)
+}
+
+/// Logs the call at the [Level::Trace] level.
+pub fn default_before(method: &str) {
+    crate::log!(Level::Trace, "{} called", method);
+}
+
+/// Adds the instructions to the profiling statistics of `method` and logs
+/// them at the [Level::Debug] level.
+pub fn default_after(method: &str, instructions: u64) {
+    profiling::record(method, instructions);
+    crate::log!(
+        Level::Debug,
+        "{} executed {} instructions",
+        method,
+        instructions
+    );
+}
+
+#[doc(hidden)]
+pub fn __before(method: &str) {
+    let (before, _) = HOOKS.with(|hooks| hooks.get());
+    before(method)
+}
+
+#[doc(hidden)]
+pub fn __after(method: &str) {
+    let (_, after) = HOOKS.with(|hooks| hooks.get());
+    after(method, call_context_instruction_counter())
+}
@@ -0,0 +1,395 @@
+//! Typed interface to the management canister (`aaaaa-aa`).
+//!
+//! The functions in this module call the methods of the [IC management
+//! canister](https://smartcontracts.org/docs/interface-spec/index.html#ic-management-canister)
+//! with the argument and result types of its Candid interface.
+use crate::api::call::{call, call_with_payment128, CallResult};
+use crate::export::Principal;
+use candid::{CandidType, Deserialize, Nat};
+
+pub mod bitcoin;
+pub mod ecdsa;
+pub mod http_request;
+
+/// The id of a canister.
+pub type CanisterId = Principal;
+
+/// The settings of a canister. Settings left to `None` keep their current
+/// value, or the default one on creation.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct CanisterSettings {
+    /// The principals allowed to manage the canister.
+    pub controllers: Option<Vec<Principal>>,
+    /// The guaranteed share of a core, in percent.
+    pub compute_allocation: Option<Nat>,
+    /// The reserved memory, in bytes.
+    pub memory_allocation: Option<Nat>,
+    /// How long, in seconds, the canister can keep running on its balance
+    /// before it gets frozen.
+    pub freezing_threshold: Option<Nat>,
+}
+
+/// The argument of [create_canister].
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct CreateCanisterArgument {
+    /// The settings of the new canister.
+    pub settings: Option<CanisterSettings>,
+}
+
+/// The argument or result of the methods that only take a canister id.
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CanisterIdRecord {
+    /// The id of the canister.
+    pub canister_id: CanisterId,
+}
+
+/// The argument of [update_settings].
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UpdateSettingsArgument {
+    /// The canister to update.
+    pub canister_id: CanisterId,
+    /// The settings to change.
+    pub settings: CanisterSettings,
+}
+
+/// How [install_code] treats the current state of the canister.
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CanisterInstallMode {
+    /// Installs code on an empty canister.
+    #[serde(rename = "install")]
+    Install,
+    /// Replaces the code and wipes the state of the canister.
+    #[serde(rename = "reinstall")]
+    Reinstall,
+    /// Upgrades the code, keeping the stable memory of the canister.
+    #[serde(rename = "upgrade")]
+    Upgrade,
+}
+
+/// The argument of [install_code].
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InstallCodeArgument {
+    /// How to treat the current state of the canister.
+    pub mode: CanisterInstallMode,
+    /// The canister to install the code on.
+    pub canister_id: CanisterId,
+    /// The WebAssembly module to install.
+    pub wasm_module: Vec<u8>,
+    /// The Candid-encoded argument of `canister_init` or `canister_post_upgrade`.
+    pub arg: Vec<u8>,
+}
+
+/// The SHA-256 of a chunk in the chunk store of a canister.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChunkHash {
+    /// The hash.
+    pub hash: Vec<u8>,
+}
+
+/// The argument of [upload_chunk].
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UploadChunkArgument {
+    /// The canister whose chunk store receives the chunk.
+    pub canister_id: CanisterId,
+    /// The chunk, up to 1MiB.
+    pub chunk: Vec<u8>,
+}
+
+/// The argument of [install_chunked_code].
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InstallChunkedCodeArgument {
+    /// How to treat the current state of the canister.
+    pub mode: CanisterInstallMode,
+    /// The canister to install the code on.
+    pub target_canister: CanisterId,
+    /// The canister holding the chunks. Defaults to `target_canister`, and
+    /// must be on the same subnet.
+    pub store_canister: Option<CanisterId>,
+    /// The chunks that make up the module, in order.
+    pub chunk_hashes_list: Vec<ChunkHash>,
+    /// The SHA-256 of the whole module.
+    pub wasm_module_hash: Vec<u8>,
+    /// The Candid-encoded argument of `canister_init` or `canister_post_upgrade`.
+    pub arg: Vec<u8>,
+}
+
+/// The running status of a canister.
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CanisterStatusType {
+    /// The canister is running.
+    #[serde(rename = "running")]
+    Running,
+    /// The canister is stopping, and waits for its outstanding responses.
+    #[serde(rename = "stopping")]
+    Stopping,
+    /// The canister is stopped.
+    #[serde(rename = "stopped")]
+    Stopped,
+}
+
+/// The settings of a canister, as reported by [canister_status].
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DefiniteCanisterSettings {
+    /// The principals allowed to manage the canister.
+    pub controllers: Vec<Principal>,
+    /// The guaranteed share of a core, in percent.
+    pub compute_allocation: Nat,
+    /// The reserved memory, in bytes.
+    pub memory_allocation: Nat,
+    /// How long, in seconds, the canister can keep running on its balance
+    /// before it gets frozen.
+    pub freezing_threshold: Nat,
+}
+
+/// The result of [canister_status].
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CanisterStatusResponse {
+    /// The running status of the canister.
+    pub status: CanisterStatusType,
+    /// The settings of the canister.
+    pub settings: DefiniteCanisterSettings,
+    /// The SHA-256 of the installed module, if any.
+    pub module_hash: Option<Vec<u8>>,
+    /// The memory used by the canister, in bytes.
+    pub memory_size: Nat,
+    /// The cycle balance of the canister.
+    pub cycles: Nat,
+}
+
+/// The argument of [canister_info].
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CanisterInfoRequest {
+    /// The canister to look up.
+    pub canister_id: CanisterId,
+    /// How many of the most recent changes to return. Defaults to none.
+    pub num_requested_changes: Option<u64>,
+}
+
+/// The result of [canister_info].
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CanisterInfoResponse {
+    /// The number of changes ever recorded for the canister.
+    pub total_num_changes: u64,
+    /// The most recent changes, oldest first.
+    pub recent_changes: Vec<CanisterChange>,
+    /// The SHA-256 of the installed module, if any.
+    pub module_hash: Option<Vec<u8>>,
+    /// The current controllers of the canister.
+    pub controllers: Vec<Principal>,
+}
+
+/// A change recorded in the history of a canister.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CanisterChange {
+    /// When the change happened, in nanoseconds since 1970-01-01.
+    pub timestamp_nanos: u64,
+    /// The version of the canister after the change.
+    pub canister_version: u64,
+    /// Who made the change.
+    pub origin: CanisterChangeOrigin,
+    /// What changed.
+    pub details: CanisterChangeDetails,
+}
+
+/// Who made a [CanisterChange].
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CanisterChangeOrigin {
+    /// A user, through an ingress message.
+    #[serde(rename = "from_user")]
+    FromUser {
+        /// The principal of the user.
+        user_id: Principal,
+    },
+    /// A canister, through a call to the management canister.
+    #[serde(rename = "from_canister")]
+    FromCanister {
+        /// The id of the canister.
+        canister_id: CanisterId,
+        /// The version of that canister when it made the call, if it
+        /// provided it.
+        canister_version: Option<u64>,
+    },
+}
+
+/// What changed in a [CanisterChange].
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum CanisterChangeDetails {
+    /// The canister was created.
+    #[serde(rename = "creation")]
+    Creation {
+        /// The controllers set on creation.
+        controllers: Vec<Principal>,
+    },
+    /// The code of the canister was uninstalled.
+    #[serde(rename = "code_uninstall")]
+    CodeUninstall,
+    /// Code was installed on the canister.
+    #[serde(rename = "code_deployment")]
+    CodeDeployment {
+        /// How the code was installed.
+        mode: CanisterInstallMode,
+        /// The SHA-256 of the installed module.
+        module_hash: Vec<u8>,
+    },
+    /// The controllers of the canister changed.
+    #[serde(rename = "controllers_change")]
+    ControllersChange {
+        /// The new controllers.
+        controllers: Vec<Principal>,
+    },
+}
+
+/// The argument of [provisional_create_canister_with_cycles].
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct ProvisionalCreateCanisterWithCyclesArgument {
+    /// The cycles the new canister starts with.
+    pub amount: Option<Nat>,
+    /// The settings of the new canister.
+    pub settings: Option<CanisterSettings>,
+}
+
+/// The argument of [provisional_top_up_canister].
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProvisionalTopUpCanisterArgument {
+    /// The canister to top up.
+    pub canister_id: CanisterId,
+    /// The cycles to add to its balance.
+    pub amount: Nat,
+}
+
+/// Creates a canister, paying for it with `cycles` from the balance of this
+/// canister. Whatever is left after the creation fee becomes the balance of
+/// the new canister.
+pub async fn create_canister(
+    arg: CreateCanisterArgument,
+    cycles: u128,
+) -> CallResult<CanisterIdRecord> {
+    let (record,) = call_with_payment128(
+        Principal::management_canister(),
+        "create_canister",
+        (arg,),
+        cycles,
+    )
+    .await?;
+    Ok(record)
+}
+
+/// Changes the settings of a canister.
+pub async fn update_settings(arg: UpdateSettingsArgument) -> CallResult<()> {
+    call(Principal::management_canister(), "update_settings", (arg,)).await
+}
+
+/// Installs, reinstalls or upgrades the code of a canister.
+pub async fn install_code(arg: InstallCodeArgument) -> CallResult<()> {
+    call(Principal::management_canister(), "install_code", (arg,)).await
+}
+
+/// Adds a chunk to the chunk store of a canister, and returns its hash.
+pub async fn upload_chunk(arg: UploadChunkArgument) -> CallResult<ChunkHash> {
+    let (hash,) = call(Principal::management_canister(), "upload_chunk", (arg,)).await?;
+    Ok(hash)
+}
+
+/// Returns the hashes of the chunks in the chunk store of a canister.
+pub async fn stored_chunks(arg: CanisterIdRecord) -> CallResult<Vec<ChunkHash>> {
+    let (hashes,) = call(Principal::management_canister(), "stored_chunks", (arg,)).await?;
+    Ok(hashes)
+}
+
+/// Removes every chunk from the chunk store of a canister.
+pub async fn clear_chunk_store(arg: CanisterIdRecord) -> CallResult<()> {
+    call(
+        Principal::management_canister(),
+        "clear_chunk_store",
+        (arg,),
+    )
+    .await
+}
+
+/// Installs a module assembled from chunks uploaded with [upload_chunk],
+/// which lifts the size limit of [install_code].
+pub async fn install_chunked_code(arg: InstallChunkedCodeArgument) -> CallResult<()> {
+    call(
+        Principal::management_canister(),
+        "install_chunked_code",
+        (arg,),
+    )
+    .await
+}
+
+/// Removes the code and the state of a canister.
+pub async fn uninstall_code(arg: CanisterIdRecord) -> CallResult<()> {
+    call(Principal::management_canister(), "uninstall_code", (arg,)).await
+}
+
+/// Starts a canister.
+pub async fn start_canister(arg: CanisterIdRecord) -> CallResult<()> {
+    call(Principal::management_canister(), "start_canister", (arg,)).await
+}
+
+/// Stops a canister, once its outstanding responses came back.
+pub async fn stop_canister(arg: CanisterIdRecord) -> CallResult<()> {
+    call(Principal::management_canister(), "stop_canister", (arg,)).await
+}
+
+/// Returns the status of a canister. Only controllers may call this.
+pub async fn canister_status(arg: CanisterIdRecord) -> CallResult<CanisterStatusResponse> {
+    let (status,) = call(Principal::management_canister(), "canister_status", (arg,)).await?;
+    Ok(status)
+}
+
+/// Returns the module hash, the controllers and the recent changes of any
+/// canister. Unlike [canister_status], this can only be called by canisters,
+/// but for any canister.
+pub async fn canister_info(arg: CanisterInfoRequest) -> CallResult<CanisterInfoResponse> {
+    let (info,) = call(Principal::management_canister(), "canister_info", (arg,)).await?;
+    Ok(info)
+}
+
+/// Deletes a stopped canister.
+pub async fn delete_canister(arg: CanisterIdRecord) -> CallResult<()> {
+    call(Principal::management_canister(), "delete_canister", (arg,)).await
+}
+
+/// Moves `cycles` from the balance of this canister to the balance of
+/// another one.
+pub async fn deposit_cycles(arg: CanisterIdRecord, cycles: u128) -> CallResult<()> {
+    call_with_payment128(
+        Principal::management_canister(),
+        "deposit_cycles",
+        (arg,),
+        cycles,
+    )
+    .await
+}
+
+/// Returns 32 bytes of randomness that no node can predict or bias.
+pub async fn raw_rand() -> CallResult<Vec<u8>> {
+    let (bytes,) = call(Principal::management_canister(), "raw_rand", ()).await?;
+    Ok(bytes)
+}
+
+/// Creates a canister with cycles out of thin air. Only available on local
+/// replicas and test networks.
+pub async fn provisional_create_canister_with_cycles(
+    arg: ProvisionalCreateCanisterWithCyclesArgument,
+) -> CallResult<CanisterIdRecord> {
+    let (record,) = call(
+        Principal::management_canister(),
+        "provisional_create_canister_with_cycles",
+        (arg,),
+    )
+    .await?;
+    Ok(record)
+}
+
+/// Adds cycles out of thin air to the balance of a canister. Only available
+/// on local replicas and test networks.
+pub async fn provisional_top_up_canister(arg: ProvisionalTopUpCanisterArgument) -> CallResult<()> {
+    call(
+        Principal::management_canister(),
+        "provisional_top_up_canister",
+        (arg,),
+    )
+    .await
+}
@@ -0,0 +1,200 @@
+//! The Bitcoin API of the management canister.
+//!
+//! Every function attaches the cycles the call costs on the given network.
+use crate::api::call::{call_with_payment128, CallResult};
+use crate::export::Principal;
+use candid::{CandidType, Deserialize};
+
+/// An amount of satoshis.
+pub type Satoshi = u64;
+
+/// A fee rate, in millisatoshis per byte.
+pub type MillisatoshiPerByte = u64;
+
+/// A Bitcoin address.
+pub type BitcoinAddress = String;
+
+/// The hash of a block.
+pub type BlockHash = Vec<u8>;
+
+/// The Bitcoin network to use.
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitcoinNetwork {
+    /// Bitcoin mainnet.
+    #[serde(rename = "mainnet")]
+    Mainnet,
+    /// Bitcoin testnet.
+    #[serde(rename = "testnet")]
+    Testnet,
+    /// A local regtest network.
+    #[serde(rename = "regtest")]
+    Regtest,
+}
+
+/// Cycle costs, per network.
+struct Fees {
+    get_balance: u128,
+    get_utxos: u128,
+    get_current_fee_percentiles: u128,
+    send_transaction_base: u128,
+    send_transaction_per_byte: u128,
+}
+
+impl BitcoinNetwork {
+    fn fees(self) -> Fees {
+        match self {
+            BitcoinNetwork::Mainnet => Fees {
+                get_balance: 100_000_000,
+                get_utxos: 10_000_000_000,
+                get_current_fee_percentiles: 100_000_000,
+                send_transaction_base: 5_000_000_000,
+                send_transaction_per_byte: 20_000_000,
+            },
+            BitcoinNetwork::Testnet | BitcoinNetwork::Regtest => Fees {
+                get_balance: 40_000_000,
+                get_utxos: 4_000_000_000,
+                get_current_fee_percentiles: 40_000_000,
+                send_transaction_base: 2_000_000_000,
+                send_transaction_per_byte: 8_000_000,
+            },
+        }
+    }
+}
+
+/// The argument of [bitcoin_get_balance].
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GetBalanceRequest {
+    /// The address to look up.
+    pub address: BitcoinAddress,
+    /// The network of the address.
+    pub network: BitcoinNetwork,
+    /// Only counts outputs with at least that many confirmations.
+    pub min_confirmations: Option<u32>,
+}
+
+/// Selects the outputs returned by [bitcoin_get_utxos].
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum UtxoFilter {
+    /// Only returns outputs with at least that many confirmations.
+    #[serde(rename = "min_confirmations")]
+    MinConfirmations(u32),
+    /// Returns the page after a previous response's `next_page`.
+    #[serde(rename = "page")]
+    Page(Vec<u8>),
+}
+
+/// The argument of [bitcoin_get_utxos].
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GetUtxosRequest {
+    /// The address to look up.
+    pub address: BitcoinAddress,
+    /// The network of the address.
+    pub network: BitcoinNetwork,
+    /// Selects the outputs to return.
+    pub filter: Option<UtxoFilter>,
+}
+
+/// Identifies a transaction output.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Outpoint {
+    /// The id of the transaction.
+    pub txid: Vec<u8>,
+    /// The index of the output in the transaction.
+    pub vout: u32,
+}
+
+/// An unspent transaction output.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Utxo {
+    /// The output.
+    pub outpoint: Outpoint,
+    /// The value of the output.
+    pub value: Satoshi,
+    /// The height of the block containing the output.
+    pub height: u32,
+}
+
+/// The result of [bitcoin_get_utxos].
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GetUtxosResponse {
+    /// The unspent outputs of the address.
+    pub utxos: Vec<Utxo>,
+    /// The hash of the tip of the chain.
+    pub tip_block_hash: BlockHash,
+    /// The height of the tip of the chain.
+    pub tip_height: u32,
+    /// Set when more outputs are available, to pass as a [UtxoFilter::Page].
+    pub next_page: Option<Vec<u8>>,
+}
+
+/// The argument of [bitcoin_send_transaction].
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SendTransactionRequest {
+    /// The serialized transaction.
+    pub transaction: Vec<u8>,
+    /// The network to send the transaction to.
+    pub network: BitcoinNetwork,
+}
+
+/// The argument of [bitcoin_get_current_fee_percentiles].
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GetCurrentFeePercentilesRequest {
+    /// The network to look up.
+    pub network: BitcoinNetwork,
+}
+
+/// Returns the balance of an address.
+pub async fn bitcoin_get_balance(arg: GetBalanceRequest) -> CallResult<Satoshi> {
+    let cycles = arg.network.fees().get_balance;
+    let (balance,) = call_with_payment128(
+        Principal::management_canister(),
+        "bitcoin_get_balance",
+        (arg,),
+        cycles,
+    )
+    .await?;
+    Ok(balance)
+}
+
+/// Returns the unspent outputs of an address.
+pub async fn bitcoin_get_utxos(arg: GetUtxosRequest) -> CallResult<GetUtxosResponse> {
+    let cycles = arg.network.fees().get_utxos;
+    let (response,) = call_with_payment128(
+        Principal::management_canister(),
+        "bitcoin_get_utxos",
+        (arg,),
+        cycles,
+    )
+    .await?;
+    Ok(response)
+}
+
+/// Sends a transaction to the Bitcoin network.
+pub async fn bitcoin_send_transaction(arg: SendTransactionRequest) -> CallResult<()> {
+    let fees = arg.network.fees();
+    let cycles =
+        fees.send_transaction_base + fees.send_transaction_per_byte * arg.transaction.len() as u128;
+    call_with_payment128(
+        Principal::management_canister(),
+        "bitcoin_send_transaction",
+        (arg,),
+        cycles,
+    )
+    .await
+}
+
+/// Returns the percentiles of the fees paid by the latest transactions, from
+/// the 1st to the 100th.
+pub async fn bitcoin_get_current_fee_percentiles(
+    arg: GetCurrentFeePercentilesRequest,
+) -> CallResult<Vec<MillisatoshiPerByte>> {
+    let cycles = arg.network.fees().get_current_fee_percentiles;
+    let (percentiles,) = call_with_payment128(
+        Principal::management_canister(),
+        "bitcoin_get_current_fee_percentiles",
+        (arg,),
+        cycles,
+    )
+    .await?;
+    Ok(percentiles)
+}
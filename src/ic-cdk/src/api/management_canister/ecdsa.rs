@@ -0,0 +1,118 @@
+//! Threshold ECDSA through the management canister.
+use super::CanisterId;
+use crate::api::call::{call, call_with_payment128, CallResult};
+use crate::export::Principal;
+use candid::{CandidType, Deserialize};
+
+/// The cycles charged by `sign_with_ecdsa` with the production key, which
+/// lives on a 34-node subnet.
+const SIGN_WITH_ECDSA_CYCLES_KEY_1: u128 = 26_153_846_153;
+
+/// The cycles charged by `sign_with_ecdsa` with the other keys.
+const SIGN_WITH_ECDSA_CYCLES: u128 = 10_000_000_000;
+
+/// Derives a key from the master key, one derivation index per element.
+pub type DerivationPath = Vec<Vec<u8>>;
+
+/// The elliptic curve of a key.
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EcdsaCurve {
+    /// secp256k1
+    #[serde(rename = "secp256k1")]
+    Secp256k1,
+}
+
+/// Identifies a master key.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EcdsaKeyId {
+    /// The curve of the key.
+    pub curve: EcdsaCurve,
+    /// The name of the key.
+    pub name: String,
+}
+
+impl EcdsaKeyId {
+    /// The key of a local replica.
+    pub fn dfx_test_key() -> Self {
+        Self::secp256k1("dfx_test_key")
+    }
+
+    /// The test key on the IC, for development.
+    pub fn test_key_1() -> Self {
+        Self::secp256k1("test_key_1")
+    }
+
+    /// The production key on the IC.
+    pub fn key_1() -> Self {
+        Self::secp256k1("key_1")
+    }
+
+    fn secp256k1(name: &str) -> Self {
+        EcdsaKeyId {
+            curve: EcdsaCurve::Secp256k1,
+            name: name.to_string(),
+        }
+    }
+}
+
+/// The argument of [ecdsa_public_key].
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EcdsaPublicKeyArgument {
+    /// The canister whose key is returned. Defaults to the caller.
+    pub canister_id: Option<CanisterId>,
+    /// The derivation path of the key.
+    pub derivation_path: DerivationPath,
+    /// The master key.
+    pub key_id: EcdsaKeyId,
+}
+
+/// The result of [ecdsa_public_key].
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EcdsaPublicKeyResponse {
+    /// The SEC1-encoded compressed public key.
+    pub public_key: Vec<u8>,
+    /// The chain code, to derive further keys off-chain.
+    pub chain_code: Vec<u8>,
+}
+
+/// The argument of [sign_with_ecdsa].
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SignWithEcdsaArgument {
+    /// The 32-byte hash of the message to sign.
+    pub message_hash: Vec<u8>,
+    /// The derivation path of the key.
+    pub derivation_path: DerivationPath,
+    /// The master key.
+    pub key_id: EcdsaKeyId,
+}
+
+/// The result of [sign_with_ecdsa].
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SignWithEcdsaResponse {
+    /// The signature, as the concatenation of `r` and `s`.
+    pub signature: Vec<u8>,
+}
+
+/// Returns a public key derived for a canister.
+pub async fn ecdsa_public_key(arg: EcdsaPublicKeyArgument) -> CallResult<EcdsaPublicKeyResponse> {
+    let (response,) = call(Principal::management_canister(), "ecdsa_public_key", (arg,)).await?;
+    Ok(response)
+}
+
+/// Signs a message hash with a key derived for this canister, attaching the
+/// cycles the signature costs.
+pub async fn sign_with_ecdsa(arg: SignWithEcdsaArgument) -> CallResult<SignWithEcdsaResponse> {
+    let cycles = if arg.key_id.name == "key_1" {
+        SIGN_WITH_ECDSA_CYCLES_KEY_1
+    } else {
+        SIGN_WITH_ECDSA_CYCLES
+    };
+    let (response,) = call_with_payment128(
+        Principal::management_canister(),
+        "sign_with_ecdsa",
+        (arg,),
+        cycles,
+    )
+    .await?;
+    Ok(response)
+}
@@ -0,0 +1,144 @@
+//! HTTPS outcalls through the management canister.
+//!
+//! The transform function named in a [TransformContext] must be a query of
+//! this canister taking [TransformArgs] and returning [HttpResponse], which
+//! `#[transform]` exports and checks:
+//!
+//! ```rust,ignore
+//! #[transform]
+//! fn transform(args: TransformArgs) -> HttpResponse {
+//!     HttpResponse {
+//!         headers: vec![],
+//!         ..args.response
+//!     }
+//! }
+//! ```
+use crate::api::call::{call_with_payment128, CallResult};
+use crate::api::id;
+use crate::export::Principal;
+use candid::{CandidType, Deserialize, Func, Nat};
+
+/// The response size assumed when `max_response_bytes` is not set.
+const DEFAULT_MAX_RESPONSE_BYTES: u64 = 2_000_000;
+
+/// A header of an HTTP request or response.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HttpHeader {
+    /// The name of the header.
+    pub name: String,
+    /// The value of the header.
+    pub value: String,
+}
+
+/// The method of an HTTP request.
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpMethod {
+    /// GET
+    #[serde(rename = "get")]
+    GET,
+    /// POST
+    #[serde(rename = "post")]
+    POST,
+    /// HEAD
+    #[serde(rename = "head")]
+    HEAD,
+}
+
+/// The query that transforms the response before the replicas agree on it.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TransformContext {
+    /// The transform query.
+    pub function: Func,
+    /// Passed as is to the transform query.
+    pub context: Vec<u8>,
+}
+
+impl TransformContext {
+    /// Names the query `method` of this canister as the transform function.
+    pub fn from_name(method: &str, context: Vec<u8>) -> Self {
+        TransformContext {
+            function: Func {
+                principal: id(),
+                method: method.to_string(),
+            },
+            context,
+        }
+    }
+}
+
+/// The argument of [http_request].
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CanisterHttpRequestArgument {
+    /// The requested URL.
+    pub url: String,
+    /// The maximum size of the response, headers included. Defaults to, and
+    /// can't exceed, 2MB. The cycle cost grows with it.
+    pub max_response_bytes: Option<u64>,
+    /// The method of the request.
+    pub method: HttpMethod,
+    /// The headers of the request.
+    pub headers: Vec<HttpHeader>,
+    /// The body of the request.
+    pub body: Option<Vec<u8>>,
+    /// Transforms the response, so that replicas can agree on it.
+    pub transform: Option<TransformContext>,
+}
+
+/// The response of an HTTPS outcall.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+    /// The status code.
+    pub status: Nat,
+    /// The headers of the response.
+    pub headers: Vec<HttpHeader>,
+    /// The body of the response.
+    pub body: Vec<u8>,
+}
+
+/// The argument of a transform query.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TransformArgs {
+    /// The raw response.
+    pub response: HttpResponse,
+    /// The context given in the [TransformContext].
+    pub context: Vec<u8>,
+}
+
+/// Makes an HTTPS outcall, attaching the cycles it costs.
+pub async fn http_request(arg: CanisterHttpRequestArgument) -> CallResult<HttpResponse> {
+    let cycles = http_request_cycles(&arg);
+    http_request_with_cycles(arg, cycles).await
+}
+
+/// Makes an HTTPS outcall, attaching `cycles`. Unused cycles are refunded.
+pub async fn http_request_with_cycles(
+    arg: CanisterHttpRequestArgument,
+    cycles: u128,
+) -> CallResult<HttpResponse> {
+    let (response,) = call_with_payment128(
+        Principal::management_canister(),
+        "http_request",
+        (arg,),
+        cycles,
+    )
+    .await?;
+    Ok(response)
+}
+
+/// Returns the cycles that [http_request] attaches to `arg`, as charged on a
+/// 13-node subnet.
+pub fn http_request_cycles(arg: &CanisterHttpRequestArgument) -> u128 {
+    let max_response_bytes = arg.max_response_bytes.unwrap_or(DEFAULT_MAX_RESPONSE_BYTES);
+    let request_bytes = arg.url.len()
+        + arg
+            .headers
+            .iter()
+            .map(|h| h.name.len() + h.value.len())
+            .sum::<usize>()
+        + arg.body.as_ref().map_or(0, |b| b.len())
+        + arg
+            .transform
+            .as_ref()
+            .map_or(0, |t| t.function.method.len() + t.context.len());
+    49_140_000 + 5_200 * request_bytes as u128 + 10_400 * max_response_bytes as u128
+}
@@ -0,0 +1,270 @@
+//! A native stand-in for the system API, to unit test canister code with
+//! `cargo test`.
+//!
+//! With the `mock` feature, outside of wasm, [caller](super::caller),
+//! [time](super::time), [data_certificate](super::data_certificate),
+//! [set_certified_data](super::set_certified_data),
+//! [in_replicated_execution](super::in_replicated_execution),
+//! [performance_counter](super::performance_counter),
+//! [set_global_timer](super::set_global_timer), [trap](super::trap) and the
+//! [stable memory](super::stable) functions work on the state kept here
+//! instead of panicking. Canisters enable it for their tests only:
+//!
+//! ```toml
+//! [dev-dependencies]
+//! ic-cdk = { version = "0.4", features = ["mock"] }
+//! ```
+//!
+//! The state starts with the anonymous caller, a time of 0, no certificate,
+//! a non-replicated execution, counters and timer at 0 and an empty stable
+//! memory. It is local to the thread, but the test
+//! harness runs the tests on a single thread when there is a single CPU, so
+//! tests should start with [reset]. Traps panic with their message, so they
+//! can be expected with `#[should_panic(expected = "...")]`.
+use crate::api::stable::StableMemoryError;
+use crate::export::Principal;
+use std::cell::RefCell;
+
+const WASM_PAGE_SIZE: usize = 65536;
+
+/// The most pages the 32-bit stable memory API can address.
+const MAX_STABLE32_PAGES: usize = 65536;
+
+struct State {
+    caller: Principal,
+    time: u64,
+    data_certificate: Option<Vec<u8>>,
+    certified_data: Vec<u8>,
+    replicated_execution: bool,
+    performance_counters: [u64; 2],
+    global_timer: u64,
+    stable_memory: Vec<u8>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            caller: Principal::anonymous(),
+            time: 0,
+            data_certificate: None,
+            certified_data: vec![],
+            replicated_execution: false,
+            performance_counters: [0; 2],
+            global_timer: 0,
+            stable_memory: vec![],
+        }
+    }
+}
+
+thread_local! {
+    static STATE: RefCell<State> = RefCell::new(State::default());
+}
+
+fn with_state<R>(f: impl FnOnce(&mut State) -> R) -> R {
+    STATE.with(|state| f(&mut state.borrow_mut()))
+}
+
+/// Sets the principal returned by [caller](super::caller).
+pub fn set_caller(caller: Principal) {
+    with_state(|state| state.caller = caller)
+}
+
+/// Sets the timestamp returned by [time](super::time), in nanoseconds since
+/// 1970-01-01.
+pub fn set_time(time: u64) {
+    with_state(|state| state.time = time)
+}
+
+/// Moves the time forward by `nanos` nanoseconds.
+pub fn advance_time(nanos: u64) {
+    with_state(|state| state.time += nanos)
+}
+
+/// Sets the certificate returned by [data_certificate](super::data_certificate),
+/// `None` standing for an update call.
+pub fn set_data_certificate(certificate: Option<Vec<u8>>) {
+    with_state(|state| state.data_certificate = certificate)
+}
+
+/// Returns the data last given to [set_certified_data](super::set_certified_data).
+pub fn certified_data() -> Vec<u8> {
+    with_state(|state| state.certified_data.clone())
+}
+
+/// Sets whether [in_replicated_execution](super::in_replicated_execution)
+/// reports the execution of an update rather than of a query.
+pub fn set_replicated_execution(replicated: bool) {
+    with_state(|state| state.replicated_execution = replicated)
+}
+
+/// Sets the value of the performance counter `counter_type`, `0` for the
+/// instructions of the message and `1` for those of the call context, as
+/// returned by [performance_counter](super::performance_counter).
+pub fn set_performance_counter(counter_type: u32, value: u64) {
+    with_state(|state| state.performance_counters[counter_index(counter_type)] = value)
+}
+
+/// Returns the timestamp last given to [set_global_timer](super::set_global_timer),
+/// `0` if the timer is inactive.
+pub fn global_timer() -> u64 {
+    with_state(|state| state.global_timer)
+}
+
+/// Goes back to the initial state, stable memory included.
+pub fn reset() {
+    with_state(|state| *state = State::default())
+}
+
+pub(crate) fn trap(message: &str) -> ! {
+    panic!("{}", message)
+}
+
+pub(crate) fn caller() -> Principal {
+    with_state(|state| state.caller)
+}
+
+pub(crate) fn time() -> u64 {
+    with_state(|state| state.time)
+}
+
+pub(crate) fn data_certificate() -> Option<Vec<u8>> {
+    with_state(|state| state.data_certificate.clone())
+}
+
+pub(crate) fn set_certified_data(data: &[u8]) {
+    if data.len() > 32 {
+        trap("certified data can't be longer than 32 bytes");
+    }
+    with_state(|state| state.certified_data = data.to_vec())
+}
+
+pub(crate) fn in_replicated_execution() -> bool {
+    with_state(|state| state.replicated_execution)
+}
+
+pub(crate) fn performance_counter(counter_type: u32) -> u64 {
+    with_state(|state| state.performance_counters[counter_index(counter_type)])
+}
+
+pub(crate) fn set_global_timer(timestamp: u64) -> u64 {
+    with_state(|state| std::mem::replace(&mut state.global_timer, timestamp))
+}
+
+fn counter_index(counter_type: u32) -> usize {
+    match counter_type {
+        0 | 1 => counter_type as usize,
+        _ => trap("unknown performance counter type"),
+    }
+}
+
+pub(crate) fn stable_size() -> u64 {
+    with_state(|state| (state.stable_memory.len() / WASM_PAGE_SIZE) as u64)
+}
+
+pub(crate) fn stable_grow(
+    new_pages: u64,
+    max_pages: Option<usize>,
+) -> Result<u64, StableMemoryError> {
+    with_state(|state| {
+        let old_pages = state.stable_memory.len() / WASM_PAGE_SIZE;
+        let pages = old_pages + new_pages as usize;
+        if max_pages.map_or(false, |max_pages| pages > max_pages) {
+            return Err(StableMemoryError::OutOfMemory);
+        }
+        state.stable_memory.resize(pages * WASM_PAGE_SIZE, 0);
+        Ok(old_pages as u64)
+    })
+}
+
+pub(crate) fn stable32_grow(new_pages: u32) -> Result<u32, StableMemoryError> {
+    stable_grow(new_pages as u64, Some(MAX_STABLE32_PAGES)).map(|pages| pages as u32)
+}
+
+pub(crate) fn stable_write(offset: u64, buf: &[u8]) {
+    with_state(|state| {
+        let range = stable_range(&state.stable_memory, offset, buf.len());
+        state.stable_memory[range].copy_from_slice(buf);
+    })
+}
+
+pub(crate) fn stable_read(offset: u64, buf: &mut [u8]) {
+    with_state(|state| {
+        let range = stable_range(&state.stable_memory, offset, buf.len());
+        buf.copy_from_slice(&state.stable_memory[range]);
+    })
+}
+
+fn stable_range(memory: &[u8], offset: u64, len: usize) -> std::ops::Range<usize> {
+    let start = offset as usize;
+    match start.checked_add(len) {
+        Some(end) if end <= memory.len() => start..end,
+        _ => trap("stable memory access out of bounds"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::{self, stable};
+
+    #[test]
+    fn state_is_mocked() {
+        super::reset();
+        assert_eq!(api::caller(), super::Principal::anonymous());
+        let caller = super::Principal::from_slice(&[1, 2, 3]);
+        super::set_caller(caller);
+        assert_eq!(api::caller(), caller);
+
+        super::set_time(10);
+        super::advance_time(5);
+        assert_eq!(api::time(), 15);
+
+        assert_eq!(api::data_certificate(), None);
+        super::set_data_certificate(Some(vec![1]));
+        assert_eq!(api::data_certificate(), Some(vec![1]));
+
+        api::set_certified_data(&[7; 32]);
+        assert_eq!(super::certified_data(), vec![7; 32]);
+
+        assert!(!api::in_replicated_execution());
+        super::set_replicated_execution(true);
+        assert!(api::in_replicated_execution());
+
+        super::set_performance_counter(0, 100);
+        super::set_performance_counter(1, 300);
+        assert_eq!(api::instruction_counter(), 100);
+        assert_eq!(api::call_context_instruction_counter(), 300);
+
+        assert_eq!(api::set_global_timer(20), 0);
+        assert_eq!(api::set_global_timer(30), 20);
+        assert_eq!(super::global_timer(), 30);
+
+        super::reset();
+        assert_eq!(api::time(), 0);
+        assert!(!api::in_replicated_execution());
+    }
+
+    #[test]
+    fn stable_memory_grows() {
+        super::reset();
+        assert_eq!(stable::stable64_size(), 0);
+        assert_eq!(stable::stable64_grow(2).unwrap(), 0);
+        stable::stable64_write(65530, b"hello world");
+        let mut buf = [0; 11];
+        stable::stable_read(65530, &mut buf);
+        assert_eq!(&buf, b"hello world");
+        assert!(stable::stable_grow(65535).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn stable_memory_is_bounded() {
+        super::reset();
+        stable::stable64_write(0, &[0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "not allowed")]
+    fn traps_panic() {
+        api::trap("not allowed");
+    }
+}
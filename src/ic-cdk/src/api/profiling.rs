@@ -0,0 +1,69 @@
+//! Instruction counts of labelled code paths.
+//!
+//! [with_instruction_budget] measures the instructions a closure executes and
+//! adds them to the statistics of its label, which [instruction_stats] returns,
+//! e.g. from a query exposed for profiling:
+//!
+//! ```rust,ignore
+//! #[query]
+//! fn profile() -> Vec<(String, InstructionStats)> {
+//!     ic_cdk::api::profiling::instruction_stats()
+//! }
+//! ```
+//!
+//! Only measurements taken in updates, heartbeats and timers are kept: the
+//! changes made by queries are discarded with the rest of their state.
+use crate::api::instruction_counter;
+use candid::{CandidType, Deserialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+/// The instructions measured under a label.
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InstructionStats {
+    /// The number of measurements.
+    pub calls: u64,
+    /// The sum of the measured instructions.
+    pub total: u64,
+    /// The largest measurement.
+    pub max: u64,
+}
+
+thread_local! {
+    static STATS: RefCell<BTreeMap<String, InstructionStats>> = RefCell::new(BTreeMap::new());
+}
+
+/// Runs `f` and records the instructions it executed under `label`.
+pub fn with_instruction_budget<R>(label: &str, f: impl FnOnce() -> R) -> R {
+    let start = instruction_counter();
+    let result = f();
+    record(label, instruction_counter().saturating_sub(start));
+    result
+}
+
+/// Adds a measurement of `spent` instructions to the statistics of `label`.
+pub(crate) fn record(label: &str, spent: u64) {
+    STATS.with(|stats| {
+        let mut stats = stats.borrow_mut();
+        let entry = stats.entry(label.to_string()).or_default();
+        entry.calls += 1;
+        entry.total = entry.total.saturating_add(spent);
+        entry.max = entry.max.max(spent);
+    });
+}
+
+/// Returns the statistics of every label, sorted by label.
+pub fn instruction_stats() -> Vec<(String, InstructionStats)> {
+    STATS.with(|stats| {
+        stats
+            .borrow()
+            .iter()
+            .map(|(label, stats)| (label.clone(), *stats))
+            .collect()
+    })
+}
+
+/// Forgets the statistics of every label.
+pub fn reset_instruction_stats() {
+    STATS.with(|stats| stats.borrow_mut().clear())
+}
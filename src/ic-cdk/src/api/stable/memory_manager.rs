@@ -0,0 +1,395 @@
+//! Independent virtual memories sharing the stable memory.
+//!
+//! The stable memory is split into buckets of a fixed number of pages. Every
+//! virtual memory, identified by a [MemoryId], grows by taking over the next
+//! free bucket, so the memories can grow independently of one another.
+//!
+//! The first page holds the header, which records the size of every memory
+//! and the owner of every bucket, so the layout survives upgrades:
+//!
+//! | offset | content                                         |
+//! |--------|-------------------------------------------------|
+//! | 0      | `MGR` and the layout version                    |
+//! | 4      | bucket size in pages (u16)                      |
+//! | 6      | number of allocated buckets (u16)               |
+//! | 8      | size in pages of each memory (255 x u64)        |
+//! | 2048   | owning memory of each bucket (32768 x u8)       |
+//!
+//! Buckets start at the second page.
+use super::{stable64_grow, stable64_read, stable64_size, stable64_write, StableMemoryError};
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::rc::Rc;
+
+const MAGIC: &[u8; 3] = b"MGR";
+const LAYOUT_VERSION: u8 = 1;
+
+const WASM_PAGE_SIZE: u64 = 65536;
+const MAX_MEMORIES: usize = 255;
+const MAX_BUCKETS: usize = 32768;
+const UNALLOCATED_BUCKET: u8 = 255;
+
+/// The default bucket size, 8MiB.
+pub const DEFAULT_BUCKET_SIZE_IN_PAGES: u16 = 128;
+
+const BUCKET_SIZE_OFFSET: u64 = 4;
+const NUM_BUCKETS_OFFSET: u64 = 6;
+const MEMORY_SIZES_OFFSET: u64 = 8;
+const BUCKET_TABLE_OFFSET: u64 = 2048;
+const BUCKETS_OFFSET: u64 = WASM_PAGE_SIZE;
+
+/// Identifies a virtual memory. Ids go from 0 to 254.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MemoryId(u8);
+
+impl MemoryId {
+    /// Panics if `id` is 255.
+    pub fn new(id: u8) -> Self {
+        assert!((id as usize) < MAX_MEMORIES, "memory id 255 is reserved");
+        MemoryId(id)
+    }
+}
+
+/// The memory the manager lays its buckets out in.
+trait Memory {
+    fn size(&self) -> u64;
+    fn grow(&mut self, pages: u64) -> Result<u64, StableMemoryError>;
+    fn read(&self, offset: u64, buf: &mut [u8]);
+    fn write(&mut self, offset: u64, buf: &[u8]);
+}
+
+struct SystemMemory;
+
+impl Memory for SystemMemory {
+    fn size(&self) -> u64 {
+        stable64_size()
+    }
+
+    fn grow(&mut self, pages: u64) -> Result<u64, StableMemoryError> {
+        stable64_grow(pages)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        stable64_read(offset, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) {
+        stable64_write(offset, buf)
+    }
+}
+
+/// Splits the stable memory into virtual memories.
+///
+/// Only one manager should exist in a canister, and nothing else should use
+/// the stable memory directly.
+#[derive(Clone)]
+pub struct MemoryManager {
+    inner: Rc<RefCell<Inner>>,
+}
+
+impl MemoryManager {
+    /// Loads the layout from the stable memory, or sets it up with the default
+    /// bucket size if the stable memory is empty.
+    ///
+    /// Panics if the stable memory is in use but wasn't set up by a manager.
+    pub fn init() -> Self {
+        Self::init_with_bucket_size(DEFAULT_BUCKET_SIZE_IN_PAGES)
+    }
+
+    /// Like [MemoryManager::init], with buckets of `bucket_size_in_pages`
+    /// pages. The bucket size recorded in the stable memory wins over this
+    /// one, if any.
+    pub fn init_with_bucket_size(bucket_size_in_pages: u16) -> Self {
+        Self::init_with_memory(Box::new(SystemMemory), bucket_size_in_pages)
+    }
+
+    fn init_with_memory(memory: Box<dyn Memory>, bucket_size_in_pages: u16) -> Self {
+        MemoryManager {
+            inner: Rc::new(RefCell::new(Inner::init(memory, bucket_size_in_pages))),
+        }
+    }
+
+    /// Returns the virtual memory with the given id.
+    pub fn get(&self, id: MemoryId) -> VirtualMemory {
+        VirtualMemory {
+            id,
+            inner: Rc::clone(&self.inner),
+        }
+    }
+}
+
+/// A virtual memory handed out by a [MemoryManager].
+#[derive(Clone)]
+pub struct VirtualMemory {
+    id: MemoryId,
+    inner: Rc<RefCell<Inner>>,
+}
+
+impl VirtualMemory {
+    /// Returns the size of the memory, in pages.
+    pub fn size(&self) -> u64 {
+        self.inner.borrow().memory_sizes[self.id.0 as usize]
+    }
+
+    /// Grows the memory by `pages` pages and returns its previous size.
+    pub fn grow(&self, pages: u64) -> Result<u64, StableMemoryError> {
+        self.inner.borrow_mut().grow(self.id, pages)
+    }
+
+    /// Reads `buf.len()` bytes at `offset`.
+    ///
+    /// Panics if the range exceeds the size of the memory.
+    pub fn read(&self, offset: u64, buf: &mut [u8]) {
+        self.inner.borrow().read(self.id, offset, buf)
+    }
+
+    /// Writes `buf` at `offset`.
+    ///
+    /// Panics if the range exceeds the size of the memory.
+    pub fn write(&self, offset: u64, buf: &[u8]) {
+        self.inner.borrow_mut().write(self.id, offset, buf)
+    }
+}
+
+struct Inner {
+    memory: Box<dyn Memory>,
+    bucket_size_in_pages: u16,
+    allocated_buckets: u16,
+    memory_sizes: Vec<u64>,
+    /// The buckets of each memory, in address order.
+    buckets: Vec<Vec<u16>>,
+}
+
+impl Inner {
+    fn init(mut memory: Box<dyn Memory>, bucket_size_in_pages: u16) -> Self {
+        assert!(bucket_size_in_pages > 0, "buckets can't be empty");
+        if memory.size() == 0 {
+            memory
+                .grow(1)
+                .expect("failed to allocate the memory manager header");
+            let mut header = [0u8; BUCKET_TABLE_OFFSET as usize];
+            header[..3].copy_from_slice(MAGIC);
+            header[3] = LAYOUT_VERSION;
+            let offset = BUCKET_SIZE_OFFSET as usize;
+            header[offset..offset + 2].copy_from_slice(&bucket_size_in_pages.to_le_bytes());
+            memory.write(0, &header);
+            memory.write(BUCKET_TABLE_OFFSET, &[UNALLOCATED_BUCKET; MAX_BUCKETS]);
+            return Inner {
+                memory,
+                bucket_size_in_pages,
+                allocated_buckets: 0,
+                memory_sizes: vec![0; MAX_MEMORIES],
+                buckets: vec![vec![]; MAX_MEMORIES],
+            };
+        }
+
+        let mut header = [0u8; BUCKET_TABLE_OFFSET as usize];
+        memory.read(0, &mut header);
+        if &header[..3] != MAGIC {
+            panic!("stable memory is in use without a memory manager");
+        }
+        if header[3] != LAYOUT_VERSION {
+            panic!("unsupported memory manager layout {}", header[3]);
+        }
+        let read_u16 = |offset: u64| {
+            let offset = offset as usize;
+            u16::from_le_bytes(header[offset..offset + 2].try_into().unwrap())
+        };
+        let bucket_size_in_pages = read_u16(BUCKET_SIZE_OFFSET);
+        let allocated_buckets = read_u16(NUM_BUCKETS_OFFSET);
+        let memory_sizes = header[MEMORY_SIZES_OFFSET as usize..]
+            .chunks_exact(8)
+            .take(MAX_MEMORIES)
+            .map(|size| u64::from_le_bytes(size.try_into().unwrap()))
+            .collect();
+
+        let mut table = vec![0u8; allocated_buckets as usize];
+        memory.read(BUCKET_TABLE_OFFSET, &mut table);
+        let mut buckets = vec![vec![]; MAX_MEMORIES];
+        for (bucket, owner) in table.into_iter().enumerate() {
+            buckets[owner as usize].push(bucket as u16);
+        }
+
+        Inner {
+            memory,
+            bucket_size_in_pages,
+            allocated_buckets,
+            memory_sizes,
+            buckets,
+        }
+    }
+
+    fn bucket_size_in_bytes(&self) -> u64 {
+        self.bucket_size_in_pages as u64 * WASM_PAGE_SIZE
+    }
+
+    fn grow(&mut self, id: MemoryId, pages: u64) -> Result<u64, StableMemoryError> {
+        let id = id.0 as usize;
+        let old_size = self.memory_sizes[id];
+        let new_size = old_size
+            .checked_add(pages)
+            .ok_or(StableMemoryError::OutOfMemory)?;
+        let bucket_size = self.bucket_size_in_pages as u64;
+        let required_buckets = (new_size + bucket_size - 1) / bucket_size;
+        let new_buckets = required_buckets.saturating_sub(self.buckets[id].len() as u64);
+        let total_buckets = self.allocated_buckets as u64 + new_buckets;
+        if total_buckets > MAX_BUCKETS as u64 {
+            return Err(StableMemoryError::OutOfMemory);
+        }
+
+        let required_pages = 1 + total_buckets * bucket_size;
+        let current_pages = self.memory.size();
+        if required_pages > current_pages {
+            self.memory.grow(required_pages - current_pages)?;
+        }
+        for _ in 0..new_buckets {
+            let bucket = self.allocated_buckets;
+            self.memory
+                .write(BUCKET_TABLE_OFFSET + bucket as u64, &[id as u8]);
+            self.buckets[id].push(bucket);
+            self.allocated_buckets += 1;
+        }
+        self.memory
+            .write(NUM_BUCKETS_OFFSET, &self.allocated_buckets.to_le_bytes());
+        self.memory_sizes[id] = new_size;
+        self.memory
+            .write(MEMORY_SIZES_OFFSET + 8 * id as u64, &new_size.to_le_bytes());
+        Ok(old_size)
+    }
+
+    /// Splits `len` bytes at `offset` of memory `id` into ranges that don't
+    /// cross buckets, as `(address, start, end)` with `start..end` the
+    /// matching range of the buffer.
+    fn ranges(&self, id: MemoryId, offset: u64, len: usize) -> Vec<(u64, usize, usize)> {
+        let id = id.0 as usize;
+        let size_in_bytes = self.memory_sizes[id] * WASM_PAGE_SIZE;
+        let end = offset.checked_add(len as u64);
+        if end.map_or(true, |end| end > size_in_bytes) {
+            panic!("virtual memory {} accessed out of bounds", id);
+        }
+
+        let bucket_size = self.bucket_size_in_bytes();
+        let mut ranges = vec![];
+        let mut start = 0;
+        while start < len {
+            let address = offset + start as u64;
+            let bucket = self.buckets[id][(address / bucket_size) as usize];
+            let within = address % bucket_size;
+            let n = std::cmp::min((len - start) as u64, bucket_size - within) as usize;
+            ranges.push((
+                BUCKETS_OFFSET + bucket as u64 * bucket_size + within,
+                start,
+                start + n,
+            ));
+            start += n;
+        }
+        ranges
+    }
+
+    fn read(&self, id: MemoryId, offset: u64, buf: &mut [u8]) {
+        for (address, start, end) in self.ranges(id, offset, buf.len()) {
+            self.memory.read(address, &mut buf[start..end]);
+        }
+    }
+
+    fn write(&mut self, id: MemoryId, offset: u64, buf: &[u8]) {
+        for (address, start, end) in self.ranges(id, offset, buf.len()) {
+            self.memory.write(address, &buf[start..end]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A memory backed by a vector that outlives the manager.
+    struct VecMemory(Rc<RefCell<Vec<u8>>>);
+
+    impl Memory for VecMemory {
+        fn size(&self) -> u64 {
+            self.0.borrow().len() as u64 / WASM_PAGE_SIZE
+        }
+
+        fn grow(&mut self, pages: u64) -> Result<u64, StableMemoryError> {
+            let old_size = self.size();
+            let new_len = (old_size + pages) * WASM_PAGE_SIZE;
+            self.0.borrow_mut().resize(new_len as usize, 0);
+            Ok(old_size)
+        }
+
+        fn read(&self, offset: u64, buf: &mut [u8]) {
+            let offset = offset as usize;
+            buf.copy_from_slice(&self.0.borrow()[offset..offset + buf.len()]);
+        }
+
+        fn write(&mut self, offset: u64, buf: &[u8]) {
+            let offset = offset as usize;
+            self.0.borrow_mut()[offset..offset + buf.len()].copy_from_slice(buf);
+        }
+    }
+
+    fn manager(bytes: &Rc<RefCell<Vec<u8>>>) -> MemoryManager {
+        MemoryManager::init_with_memory(Box::new(VecMemory(Rc::clone(bytes))), 1)
+    }
+
+    #[test]
+    fn memories_grow_independently() {
+        let bytes = Rc::new(RefCell::new(vec![]));
+        let manager = manager(&bytes);
+        let a = manager.get(MemoryId::new(0));
+        let b = manager.get(MemoryId::new(1));
+
+        assert_eq!(a.grow(1).unwrap(), 0);
+        assert_eq!(b.grow(1).unwrap(), 0);
+        assert_eq!(a.grow(1).unwrap(), 1);
+        assert_eq!((a.size(), b.size()), (2, 1));
+
+        // Spans the two buckets of `a`, which are not adjacent.
+        let data: Vec<u8> = (0..100).collect();
+        a.write(WASM_PAGE_SIZE - 50, &data);
+        b.write(0, &[7; 10]);
+
+        let mut read = vec![0; 100];
+        a.read(WASM_PAGE_SIZE - 50, &mut read);
+        assert_eq!(read, data);
+        let mut read = vec![0; 10];
+        b.read(0, &mut read);
+        assert_eq!(read, vec![7; 10]);
+    }
+
+    #[test]
+    fn layout_survives_reloading() {
+        let bytes = Rc::new(RefCell::new(vec![]));
+        {
+            let manager = manager(&bytes);
+            let a = manager.get(MemoryId::new(3));
+            a.grow(2).unwrap();
+            a.write(WASM_PAGE_SIZE + 1, b"hello");
+        }
+
+        let manager = MemoryManager::init_with_memory(Box::new(VecMemory(bytes)), 64);
+        let a = manager.get(MemoryId::new(3));
+        assert_eq!(a.size(), 2);
+        let mut read = [0; 5];
+        a.read(WASM_PAGE_SIZE + 1, &mut read);
+        assert_eq!(&read, b"hello");
+        assert_eq!(manager.inner.borrow().bucket_size_in_pages, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn access_past_the_size_panics() {
+        let bytes = Rc::new(RefCell::new(vec![]));
+        let a = manager(&bytes).get(MemoryId::new(0));
+        a.grow(1).unwrap();
+        a.write(WASM_PAGE_SIZE - 1, &[0, 0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "without a memory manager")]
+    fn foreign_stable_memory_is_rejected() {
+        let bytes = Rc::new(RefCell::new(vec![1; WASM_PAGE_SIZE as usize]));
+        manager(&bytes);
+    }
+}
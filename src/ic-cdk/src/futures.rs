@@ -1,87 +1,332 @@
+//! The executor driving the futures of canister methods.
+//!
+//! Every spawned future is a task, stored in a table under its id. Wakers only
+//! carry that id, never a pointer to the future, so waking a task that already
+//! finished or was dropped does nothing instead of touching freed memory.
+//!
+//! A task is taken out of the table while it is polled and put back if it is
+//! still pending. If the poll traps, the system rolls the whole message back,
+//! table included, so the task is left as it was before the message. The
+//! system then calls the cleanup callback of the call the task was waiting
+//! for, which drops the task: its pending call is over and it would never be
+//! woken again.
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
 use std::future::Future;
 use std::pin::Pin;
-use std::task::Context;
+use std::task::{Context, Waker};
+
+type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+enum Slot {
+    /// Waits to be woken.
+    Idle(Task),
+    /// Being polled, and whether it was woken during the poll.
+    Running { woken: bool },
+}
+
+#[derive(Default)]
+struct Executor {
+    next_id: usize,
+    tasks: BTreeMap<usize, Slot>,
+}
+
+thread_local! {
+    static EXECUTOR: RefCell<Executor> = RefCell::new(Executor::default());
+    static RECOVERING: Cell<bool> = Cell::new(false);
+}
+
+/// A handle to a spawned task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskHandle {
+    id: usize,
+}
+
+impl TaskHandle {
+    /// Returns whether the task completed or was cancelled.
+    pub fn is_finished(&self) -> bool {
+        EXECUTOR.with(|e| !e.borrow().tasks.contains_key(&self.id))
+    }
+
+    /// Drops the task, so that it is never polled again. Does nothing if the
+    /// task already finished.
+    ///
+    /// A task can cancel itself: it is then dropped once its current poll
+    /// returns.
+    pub fn cancel(&self) {
+        let task = EXECUTOR.with(|e| e.borrow_mut().tasks.remove(&self.id));
+        drop(task);
+    }
+}
 
 /// Must be called on every top-level future corresponding to a method call of a
 /// canister by the IC.
 ///
-/// Saves the pointer to the future on the heap and kickstarts the future by
-/// polling it once. During the polling we also need to provide the waker
-/// callback which is triggered after the future made progress.
-/// The waker would then poll the future one last time to advance it to
-/// the final state. For that, we pass the future pointer to the waker, so that
-/// it can be restored into a box from a raw pointer and then dropped if not
-/// needed anymore.
-///
-/// Technically, we store 2 pointers on the heap: the pointer to the future
-/// itself, and a pointer to that pointer. The reason for this is that the waker
-/// API requires us to pass one thin pointer, while a a pointer to a `dyn Trait`
-/// can only be fat. So we create one additional thin pointer, pointing to the
-/// fat pointer and pass it instead.
-pub fn spawn<F: 'static + Future<Output = ()>>(future: F) {
-    let future_ptr = Box::into_raw(Box::new(future));
-    let future_ptr_ptr: *mut *mut dyn Future<Output = ()> = Box::into_raw(Box::new(future_ptr));
-    let mut pinned_future = unsafe { Pin::new_unchecked(&mut *future_ptr) };
-    if pinned_future
-        .as_mut()
-        .poll(&mut Context::from_waker(&waker::waker(
-            future_ptr_ptr as *const (),
-        )))
-        .is_ready()
-    {
-        unsafe {
-            let _ = Box::from_raw(future_ptr);
-            let _ = Box::from_raw(future_ptr_ptr);
+/// Stores the future as a new task and polls it once. It is polled again
+/// whenever its waker is woken, which happens when a reply or reject callback
+/// of a call it awaits runs.
+pub fn spawn<F: 'static + Future<Output = ()>>(future: F) -> TaskHandle {
+    let id = insert(Slot::Running { woken: false });
+    run(id, Box::pin(future));
+    TaskHandle { id }
+}
+
+/// Stores the future as a new task without polling it. It is first polled
+/// when [wake_task] is called.
+#[cfg(feature = "timers")]
+pub fn spawn_unpolled<F: 'static + Future<Output = ()>>(future: F) -> TaskHandle {
+    TaskHandle {
+        id: insert(Slot::Idle(Box::pin(future))),
+    }
+}
+
+/// Polls the task of `handle`, if it is still pending.
+#[cfg(feature = "timers")]
+pub fn wake_task(handle: TaskHandle) {
+    wake(handle.id)
+}
+
+fn insert(slot: Slot) -> usize {
+    EXECUTOR.with(|e| {
+        let mut e = e.borrow_mut();
+        let id = e.next_id;
+        e.next_id = e.next_id.wrapping_add(1);
+        e.tasks.insert(id, slot);
+        id
+    })
+}
+
+/// Polls the task until it is done or waits without having been woken.
+fn run(id: usize, mut task: Task) {
+    loop {
+        let waker = waker::waker(id);
+        let ready = task
+            .as_mut()
+            .poll(&mut Context::from_waker(&waker))
+            .is_ready();
+        if ready {
+            EXECUTOR.with(|e| e.borrow_mut().tasks.remove(&id));
+            return;
+        }
+        let woken = EXECUTOR.with(|e| match e.borrow_mut().tasks.get_mut(&id) {
+            Some(Slot::Running { woken }) => std::mem::replace(woken, false),
+            _ => false,
+        });
+        if !woken {
+            EXECUTOR.with(|e| {
+                if let Some(slot) = e.borrow_mut().tasks.get_mut(&id) {
+                    *slot = Slot::Idle(task);
+                }
+            });
+            return;
         }
     }
 }
 
-// This module contains the implementation of a waker we're using for waking
-// top-level futures (the ones returned by canister methods). The waker polls
-// the future once and re-pins it on the heap, if it's pending. If the future is
-// done, we do nothing. Hence, it will be unallocated once we exit the scope and
-// we're not interested in the result, as it can only be a unit `()` if the
-// waker was used as intended.
+fn wake(id: usize) {
+    if RECOVERING.with(|r| r.get()) {
+        // The task is dropped outside of the borrow, in case its destructors
+        // touch the executor.
+        let task = EXECUTOR.with(|e| e.borrow_mut().tasks.remove(&id));
+        drop(task);
+        return;
+    }
+    let task = EXECUTOR.with(|e| {
+        let mut e = e.borrow_mut();
+        match e.tasks.get_mut(&id) {
+            Some(Slot::Running { woken }) => {
+                *woken = true;
+                None
+            }
+            Some(slot) => match std::mem::replace(slot, Slot::Running { woken: false }) {
+                Slot::Idle(task) => Some(task),
+                Slot::Running { .. } => unreachable!(),
+            },
+            None => None,
+        }
+    });
+    if let Some(task) = task {
+        run(id, task);
+    }
+}
+
+/// Drops the task that `waker` belongs to, after one of its callbacks trapped.
+pub(crate) fn cleanup(waker: Waker) {
+    RECOVERING.with(|r| r.set(true));
+    waker.wake();
+    RECOVERING.with(|r| r.set(false));
+}
+
+/// Returns whether tasks are being dropped after a trap.
+pub fn is_recovering_from_trap() -> bool {
+    RECOVERING.with(|r| r.get())
+}
+
+// This module contains the implementation of the waker of the tasks. The data
+// pointer of the waker is the id of the task, not an actual pointer. Ids are
+// reused only after the counter wraps around, and a stale waker then at worst
+// polls another task spuriously, which futures have to tolerate anyway.
 mod waker {
-    use super::*;
     use std::task::{RawWaker, RawWakerVTable, Waker};
-    type FuturePtr = *mut dyn Future<Output = ()>;
 
     static MY_VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop);
 
-    fn raw_waker(ptr: *const ()) -> RawWaker {
-        RawWaker::new(ptr, &MY_VTABLE)
+    fn raw_waker(id: usize) -> RawWaker {
+        RawWaker::new(id as *const (), &MY_VTABLE)
     }
 
     fn clone(ptr: *const ()) -> RawWaker {
-        raw_waker(ptr)
-    }
-
-    // Our waker will be called only if one of the response callbacks is triggered.
-    // Then, the waker will restore the future from the pointer we passed into the
-    // waker inside the `kickstart` method and poll the future again. If the future
-    // is pending, we leave it on the heap. If it's ready, we deallocate the
-    // pointer.
-    unsafe fn wake(ptr: *const ()) {
-        let boxed_future_ptr_ptr = Box::from_raw(ptr as *mut FuturePtr);
-        let future_ptr: FuturePtr = *boxed_future_ptr_ptr;
-        let boxed_future = Box::from_raw(future_ptr);
-        let mut pinned_future = Pin::new_unchecked(&mut *future_ptr);
-        if pinned_future
-            .as_mut()
-            .poll(&mut Context::from_waker(&waker::waker(ptr)))
-            .is_pending()
-        {
-            Box::into_raw(boxed_future_ptr_ptr);
-            Box::into_raw(boxed_future);
-        }
+        raw_waker(ptr as usize)
     }
 
-    fn wake_by_ref(_: *const ()) {}
+    fn wake(ptr: *const ()) {
+        super::wake(ptr as usize)
+    }
+
+    fn wake_by_ref(ptr: *const ()) {
+        super::wake(ptr as usize)
+    }
 
     fn drop(_: *const ()) {}
 
-    pub fn waker(ptr: *const ()) -> Waker {
-        unsafe { Waker::from_raw(raw_waker(ptr)) }
+    pub fn waker(id: usize) -> Waker {
+        unsafe { Waker::from_raw(raw_waker(id)) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::task::Poll;
+
+    /// A future that is pending until the waker it was last polled with is woken.
+    struct Pending {
+        waker: Rc<RefCell<Option<Waker>>>,
+        woken: bool,
+    }
+
+    impl Future for Pending {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<()> {
+            if self.woken {
+                return Poll::Ready(());
+            }
+            self.woken = true;
+            *self.waker.borrow_mut() = Some(context.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    fn task_count() -> usize {
+        EXECUTOR.with(|e| e.borrow().tasks.len())
+    }
+
+    #[test]
+    fn ready_future_is_dropped_right_away() {
+        let done = Rc::new(Cell::new(false));
+        let d = done.clone();
+        spawn(async move { d.set(true) });
+        assert!(done.get());
+        assert_eq!(task_count(), 0);
+    }
+
+    #[test]
+    fn pending_future_runs_when_woken() {
+        let waker = Rc::new(RefCell::new(None));
+        let done = Rc::new(Cell::new(false));
+        let (w, d) = (waker.clone(), done.clone());
+        spawn(async move {
+            Pending {
+                waker: w,
+                woken: false,
+            }
+            .await;
+            d.set(true);
+        });
+        assert!(!done.get());
+        assert_eq!(task_count(), 1);
+
+        let waker = waker.borrow_mut().take().unwrap();
+        waker.wake_by_ref();
+        assert!(done.get());
+        assert_eq!(task_count(), 0);
+
+        // Waking a finished task does nothing.
+        waker.wake();
+        assert_eq!(task_count(), 0);
+    }
+
+    #[test]
+    fn cleanup_drops_the_task_without_polling_it() {
+        let waker = Rc::new(RefCell::new(None));
+        let done = Rc::new(Cell::new(false));
+        let (w, d) = (waker.clone(), done.clone());
+        spawn(async move {
+            Pending {
+                waker: w,
+                woken: false,
+            }
+            .await;
+            d.set(true);
+        });
+
+        let waker = waker.borrow_mut().take().unwrap();
+        cleanup(waker.clone());
+        assert!(!done.get());
+        assert!(!is_recovering_from_trap());
+        assert_eq!(task_count(), 0);
+
+        waker.wake();
+        assert!(!done.get());
+    }
+
+    #[test]
+    fn cancelled_task_is_never_polled_again() {
+        let waker = Rc::new(RefCell::new(None));
+        let done = Rc::new(Cell::new(false));
+        let (w, d) = (waker.clone(), done.clone());
+        let handle = spawn(async move {
+            Pending {
+                waker: w,
+                woken: false,
+            }
+            .await;
+            d.set(true);
+        });
+        assert!(!handle.is_finished());
+
+        handle.cancel();
+        assert!(handle.is_finished());
+        waker.borrow_mut().take().unwrap().wake();
+        assert!(!done.get());
+    }
+
+    #[test]
+    fn task_can_cancel_itself() {
+        let handle = Rc::new(Cell::new(None));
+        let h = handle.clone();
+        let waker = Rc::new(RefCell::new(None));
+        let w = waker.clone();
+        let spawned = spawn(async move {
+            Pending {
+                waker: w,
+                woken: false,
+            }
+            .await;
+            let handle: TaskHandle = h.get().unwrap();
+            handle.cancel();
+            Pending {
+                waker: Rc::new(RefCell::new(None)),
+                woken: false,
+            }
+            .await;
+        });
+        handle.set(Some(spawned));
+
+        waker.borrow_mut().take().unwrap().wake();
+        assert!(spawned.is_finished());
+        assert_eq!(task_count(), 0);
     }
 }
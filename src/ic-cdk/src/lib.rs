@@ -8,11 +8,17 @@
 
 pub mod api;
 mod futures;
+pub mod log;
 mod printer;
+#[cfg(feature = "random")]
+pub mod random;
 pub mod storage;
+#[cfg(feature = "timers")]
+pub mod timer;
 
 pub use api::call::call;
 pub use api::{caller, id, print, trap};
+pub use futures::TaskHandle;
 
 static mut DONE: bool = false;
 
@@ -45,16 +51,53 @@ pub fn block_on<F: 'static + std::future::Future<Output = ()>>(future: F) {
 
 /// Spawn an asynchronous task that drives the provided future to
 /// completion.
-pub fn spawn<F: 'static + std::future::Future<Output = ()>>(future: F) {
-    futures::spawn(future);
+///
+/// Every `.await` on an inter-canister call ends the current message: the
+/// state changes made before it are committed, and the rest of the future
+/// runs in the callback of the call. A trap after an `.await` only rolls back
+/// the changes made since that `.await`. The future is then dropped, and its
+/// destructors can check [is_recovering_from_trap] to tell this case apart.
+///
+/// The future is polled for the first time right away, in the current
+/// message. The returned handle can cancel the task. Before it was added,
+/// `spawn` returned `()`, so callers relying on that need to discard it.
+pub fn spawn<F: 'static + std::future::Future<Output = ()>>(future: F) -> TaskHandle {
+    futures::spawn(future)
+}
+
+/// Spawn an asynchronous task that is first polled by the global timer, once
+/// the current message completed.
+///
+/// A trap in the task then doesn't roll back the current message, and the
+/// task doesn't use up its instructions. The task isn't alone in the global
+/// timer message though: it runs there with every other timer and detached
+/// task that is due, one after the other, so they share an instruction limit
+/// and a trap in any of them rolls back those that ran before it. Once the
+/// task awaits a call, the rest of it runs in the callbacks as with [spawn].
+/// This needs the `timers` feature.
+#[cfg(feature = "timers")]
+pub fn spawn_detached<F: 'static + std::future::Future<Output = ()>>(future: F) -> TaskHandle {
+    let handle = futures::spawn_unpolled(future);
+    timer::set_timer(std::time::Duration::from_secs(0), move || {
+        futures::wake_task(handle)
+    });
+    handle
+}
+
+/// Returns whether the futures of a trapped callback are being dropped.
+///
+/// Destructors that release locks or similar state run in a separate
+/// message in that case, and can't make inter-canister calls.
+pub fn is_recovering_from_trap() -> bool {
+    futures::is_recovering_from_trap()
 }
 
 /// Format and then print the formatted message
 #[cfg(target_arch = "wasm32")]
 #[macro_export]
 macro_rules! println {
-    ($fmt:expr) => (ic_cdk::print(format!($fmt)));
-    ($fmt:expr, $($arg:tt)*) => (ic_cdk::print(format!($fmt, $($arg)*)));
+    ($fmt:expr) => ($crate::print(format!($fmt)));
+    ($fmt:expr, $($arg:tt)*) => ($crate::print(format!($fmt, $($arg)*)));
 }
 
 /// Format and then print the formatted message
@@ -69,8 +112,8 @@ macro_rules! println {
 #[cfg(target_arch = "wasm32")]
 #[macro_export]
 macro_rules! eprintln {
-    ($fmt:expr) => (ic_cdk::print(format!($fmt)));
-    ($fmt:expr, $($arg:tt)*) => (ic_cdk::print(format!($fmt, $($arg)*)));
+    ($fmt:expr) => ($crate::print(format!($fmt)));
+    ($fmt:expr, $($arg:tt)*) => ($crate::print(format!($fmt, $($arg)*)));
 }
 
 /// Format and then print the formatted message
@@ -0,0 +1,131 @@
+//! Leveled logging to the debug output of the replica.
+//!
+//! Messages are written with the [log!](crate::log!) macro, and only the ones
+//! at or above the level set with [set_max_level] are formatted and printed.
+//!
+//! ```rust,ignore
+//! use ic_cdk::log::{set_max_level, Level};
+//!
+//! set_max_level(Level::Debug);
+//! ic_cdk::log!(Level::Debug, "request for {}", path);
+//! ```
+use std::cell::Cell;
+use std::fmt;
+
+/// The severity of a message, from the most to the least severe.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    /// Something failed.
+    Error,
+    /// Something looks wrong but the canister carries on.
+    Warn,
+    /// Notable events.
+    Info,
+    /// Details useful when debugging.
+    Debug,
+    /// Very verbose details.
+    Trace,
+}
+
+impl Level {
+    /// Returns the name of the level, as printed in front of messages.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+thread_local! {
+    static MAX_LEVEL: Cell<Level> = Cell::new(Level::Info);
+}
+
+/// Sets the least severe level that is printed. Defaults to [Level::Info].
+///
+/// The level lives on the heap, so it is reset by upgrades.
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.with(|l| l.set(level))
+}
+
+/// Returns the least severe level that is printed.
+pub fn max_level() -> Level {
+    MAX_LEVEL.with(|l| l.get())
+}
+
+/// Returns whether messages at `level` are printed.
+pub fn enabled(level: Level) -> bool {
+    level <= max_level()
+}
+
+#[doc(hidden)]
+pub fn __log(level: Level, args: fmt::Arguments<'_>) {
+    if !enabled(level) {
+        return;
+    }
+    let message = format!("[{}] {}", level, args);
+    #[cfg(target_arch = "wasm32")]
+    crate::api::print(message);
+    #[cfg(not(target_arch = "wasm32"))]
+    std::eprintln!("{}", message);
+}
+
+/// Format and then print the formatted message at the given [Level], if it
+/// is enabled.
+///
+/// ```rust,ignore
+/// ic_cdk::log!(ic_cdk::log::Level::Warn, "{} retries left", retries);
+/// ```
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => ($crate::log::__log($level, format_args!($($arg)+)));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn prints_info_by_default() {
+        // A new thread, as other tests may have set the level of this one.
+        std::thread::spawn(|| {
+            assert_eq!(max_level(), Level::Info);
+            assert!(enabled(Level::Warn));
+            assert!(enabled(Level::Info));
+            assert!(!enabled(Level::Debug));
+            crate::log!(Level::Warn, "printed");
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn trace_enables_every_level() {
+        set_max_level(Level::Trace);
+        assert!(enabled(Level::Error));
+        assert!(enabled(Level::Info));
+        assert!(enabled(Level::Debug));
+        assert!(enabled(Level::Trace));
+        set_max_level(Level::Info);
+    }
+
+    #[test]
+    fn error_only_enables_errors() {
+        set_max_level(Level::Error);
+        assert!(enabled(Level::Error));
+        for level in [Level::Warn, Level::Info, Level::Debug, Level::Trace].iter() {
+            assert!(!enabled(*level), "{}", level);
+        }
+        crate::log!(Level::Warn, "filtered out");
+        set_max_level(Level::Info);
+    }
+}
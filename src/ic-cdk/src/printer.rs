@@ -1,22 +1,32 @@
-use crate::api;
+use crate::api::{self, CodedError};
 use std::panic;
 
 /// Sets a custom panic hook, uses debug.trace
+///
+/// Panics with a [CodedError] payload trap with the code in front of the
+/// message, like [api::trap_with].
 pub fn set_panic_hook() {
     panic::set_hook(Box::new(|info| {
         let file = info.location().unwrap().file();
         let line = info.location().unwrap().line();
         let col = info.location().unwrap().column();
 
-        let msg = match info.payload().downcast_ref::<&'static str>() {
-            Some(s) => *s,
-            None => match info.payload().downcast_ref::<String>() {
-                Some(s) => &s[..],
-                None => "Box<Any>",
+        let (code, msg) = match info.payload().downcast_ref::<CodedError>() {
+            Some(err) => (Some(&err.code[..]), &err.message[..]),
+            None => match info.payload().downcast_ref::<&'static str>() {
+                Some(s) => (None, *s),
+                None => match info.payload().downcast_ref::<String>() {
+                    Some(s) => (None, &s[..]),
+                    None => (None, "Box<Any>"),
+                },
             },
         };
 
         let err_info = format!("Panicked at '{}', {}:{}:{}", msg, file, line, col);
+        let err_info = match code {
+            Some(code) => format!("[{}] {}", code, err_info),
+            None => err_info,
+        };
         api::print(&err_info);
         api::trap(&err_info);
     }));
@@ -0,0 +1,49 @@
+//! A `getrandom` backend for canisters.
+//!
+//! Enabling the `random` feature registers a custom `getrandom`
+//! implementation, so that crates such as `rand` or `uuid` work on
+//! `wasm32-unknown-unknown`. It draws from a ChaCha20 generator seeded with
+//! [raw_rand].
+//!
+//! Canisters can't make calls from `canister_init` or
+//! `canister_post_upgrade`, so the generator starts unseeded and `getrandom`
+//! fails until [reseed] completes. Call it from the first update or
+//! heartbeat, and again after every upgrade.
+use crate::api::call::CallResult;
+use crate::api::management_canister::raw_rand;
+use getrandom::{register_custom_getrandom, Error};
+use rand_chacha::rand_core::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::num::NonZeroU32;
+
+thread_local! {
+    static RNG: RefCell<Option<ChaCha20Rng>> = RefCell::new(None);
+}
+
+/// Seeds the generator with fresh bytes from [raw_rand].
+pub async fn reseed() -> CallResult<()> {
+    let bytes = raw_rand().await?;
+    let seed: [u8; 32] = bytes[..32].try_into().unwrap();
+    RNG.with(|rng| *rng.borrow_mut() = Some(ChaCha20Rng::from_seed(seed)));
+    Ok(())
+}
+
+/// Returns whether [reseed] completed since the canister was installed or
+/// upgraded.
+pub fn is_seeded() -> bool {
+    RNG.with(|rng| rng.borrow().is_some())
+}
+
+fn custom_getrandom(buf: &mut [u8]) -> Result<(), Error> {
+    RNG.with(|rng| match rng.borrow_mut().as_mut() {
+        Some(rng) => {
+            rng.fill_bytes(buf);
+            Ok(())
+        }
+        None => Err(Error::from(NonZeroU32::new(Error::CUSTOM_START).unwrap())),
+    })
+}
+
+register_custom_getrandom!(custom_getrandom);
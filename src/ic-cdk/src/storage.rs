@@ -1,5 +1,9 @@
 //! Tools for managing stable storage of data in a canister.
 use crate::api::stable;
+use candid::CandidType;
+use serde::de::DeserializeOwned;
+use std::convert::TryInto;
+use std::io::{Read, Seek, SeekFrom, Write};
 
 /// Saves the storage into the stable memory.
 ///
@@ -28,3 +32,171 @@ where
     let _ = de.done();
     Ok(res)
 }
+
+/// The header written in front of a [StableStorage] value: a magic number,
+/// the version of the type and the length of the encoded value.
+const STABLE_STORAGE_MAGIC: &[u8; 4] = b"CDKS";
+const STABLE_STORAGE_HEADER_SIZE: u64 = 16;
+
+/// State that is saved to the stable memory before an upgrade and restored
+/// after it.
+///
+/// Implement it with `#[derive(StableStorage)]` from `ic-cdk-macros`. The
+/// value is encoded with Candid behind a header that records its length and
+/// the version of the type, set with `#[stable_storage(version = 2)]` (1 by
+/// default). Bump the version when a change of the type can't be decoded
+/// from the old encoding, so that restoring old state fails instead of
+/// decoding garbage.
+///
+/// ```rust,ignore
+/// use ic_cdk::storage::StableStorage;
+/// use ic_cdk_macros::StableStorage;
+///
+/// #[derive(Default, CandidType, Deserialize, StableStorage)]
+/// #[stable_storage(version = 2)]
+/// struct State {
+///     owners: Vec<Principal>,
+/// }
+///
+/// #[pre_upgrade]
+/// fn pre_upgrade() {
+///     STATE.with(|s| ic_cdk::storage::save_for_upgrade(&*s.borrow()));
+/// }
+///
+/// #[post_upgrade]
+/// fn post_upgrade() {
+///     let state: State = ic_cdk::storage::restore_after_upgrade();
+///     STATE.with(|s| *s.borrow_mut() = state);
+/// }
+/// ```
+pub trait StableStorage: CandidType + DeserializeOwned {
+    /// The version of the type, stored along with the value.
+    const VERSION: u32;
+
+    /// Saves the value at the start of the stable memory, growing it as needed.
+    ///
+    /// This will override any value previously stored in stable memory.
+    fn save_to_stable(&self) -> Result<(), String> {
+        let mut writer = stable::BufferedStableWriter::default();
+        writer
+            .seek(SeekFrom::Start(STABLE_STORAGE_HEADER_SIZE))
+            .map_err(|e| e.to_string())?;
+        candid::write_args(&mut writer, (self,)).map_err(|e| format!("{:?}", e))?;
+        let end = writer
+            .seek(SeekFrom::Current(0))
+            .map_err(|e| e.to_string())?;
+
+        let mut header = [0u8; STABLE_STORAGE_HEADER_SIZE as usize];
+        header[..4].copy_from_slice(STABLE_STORAGE_MAGIC);
+        header[4..8].copy_from_slice(&Self::VERSION.to_le_bytes());
+        header[8..].copy_from_slice(&(end - STABLE_STORAGE_HEADER_SIZE).to_le_bytes());
+        writer
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| writer.write_all(&header))
+            .and_then(|_| writer.flush())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Restores a value saved with [StableStorage::save_to_stable].
+    ///
+    /// Fails if the stable memory doesn't hold a value of this version.
+    fn restore_from_stable() -> Result<Self, String> {
+        let mut reader = stable::BufferedStableReader::default();
+        let mut header = [0u8; STABLE_STORAGE_HEADER_SIZE as usize];
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| "the stable memory holds no saved state".to_string())?;
+        if &header[..4] != STABLE_STORAGE_MAGIC {
+            return Err("the stable memory holds no saved state".to_string());
+        }
+        let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        if version != Self::VERSION {
+            return Err(format!(
+                "the saved state has version {}, expected {}",
+                version,
+                Self::VERSION
+            ));
+        }
+        let len = u64::from_le_bytes(header[8..].try_into().unwrap());
+        // The length is read from the stable memory, so it can be anything.
+        match STABLE_STORAGE_HEADER_SIZE.checked_add(len) {
+            Some(end) if end <= stable::stable64_size() << 16 => {}
+            _ => return Err("the saved state exceeds the stable memory".to_string()),
+        }
+
+        let mut bytes = Vec::with_capacity(len as usize);
+        reader
+            .take(len)
+            .read_to_end(&mut bytes)
+            .map_err(|e| e.to_string())?;
+        candid::decode_one(&bytes).map_err(|e| format!("{:?}", e))
+    }
+}
+
+/// Saves `state` for an upgrade, to be called from `pre_upgrade`.
+///
+/// Traps if the state can't be saved, which aborts the upgrade.
+pub fn save_for_upgrade<T: StableStorage>(state: &T) {
+    if let Err(e) = state.save_to_stable() {
+        crate::trap(&format!("failed to save the state: {}", e));
+    }
+}
+
+/// Restores the state saved by [save_for_upgrade], to be called from
+/// `post_upgrade`.
+///
+/// Traps if the state can't be restored, which rolls the upgrade back.
+pub fn restore_after_upgrade<T: StableStorage>() -> T {
+    T::restore_from_stable()
+        .unwrap_or_else(|e| crate::trap(&format!("failed to restore the state: {}", e)))
+}
+
+#[cfg(all(test, feature = "mock", not(target_arch = "wasm32")))]
+mod test {
+    use super::*;
+    use crate::api::mock;
+    use candid::Deserialize;
+
+    #[derive(Debug, CandidType, Deserialize)]
+    struct State {
+        owners: Vec<String>,
+    }
+
+    impl StableStorage for State {
+        const VERSION: u32 = 2;
+    }
+
+    fn write_header(version: u32, len: u64) {
+        let mut header = [0u8; STABLE_STORAGE_HEADER_SIZE as usize];
+        header[..4].copy_from_slice(STABLE_STORAGE_MAGIC);
+        header[4..8].copy_from_slice(&version.to_le_bytes());
+        header[8..].copy_from_slice(&len.to_le_bytes());
+        stable::stable64_write(0, &header);
+    }
+
+    #[test]
+    fn rejects_empty_memory_and_other_versions() {
+        mock::reset();
+        assert!(State::restore_from_stable().is_err());
+        stable::stable64_grow(1).unwrap();
+        assert!(State::restore_from_stable().is_err());
+        write_header(1, 0);
+        assert_eq!(
+            State::restore_from_stable().unwrap_err(),
+            "the saved state has version 1, expected 2"
+        );
+    }
+
+    #[test]
+    fn rejects_lengths_past_the_memory() {
+        mock::reset();
+        stable::stable64_grow(1).unwrap();
+        for len in [65536 - STABLE_STORAGE_HEADER_SIZE + 1, u64::MAX].iter() {
+            write_header(State::VERSION, *len);
+            assert_eq!(
+                State::restore_from_stable().unwrap_err(),
+                "the saved state exceeds the stable memory"
+            );
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Timers multiplexed over the global timer of the canister.
+//!
+//! Enabling the `timers` feature exports `canister_global_timer`, which runs
+//! every timer that is due and then sets the global timer to the next
+//! deadline. Timers run one after the other in that same message, so a timer
+//! that traps rolls back the others that ran before it.
+//!
+//! Timers live on the heap: they are lost on upgrade and have to be set again
+//! from `post_upgrade`.
+use crate::api::{set_global_timer, time};
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::time::Duration;
+
+/// Identifies a timer, to clear it with [clear_timer].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+enum Timer {
+    Once(Box<dyn FnOnce()>),
+    Repeated {
+        func: Box<dyn FnMut()>,
+        interval: Duration,
+    },
+}
+
+#[derive(Default)]
+struct Timers {
+    next_id: u64,
+    timers: HashMap<TimerId, Timer>,
+    /// Deadlines in nanoseconds, earliest first. Entries of cleared timers are
+    /// skipped when they come up.
+    queue: BinaryHeap<Reverse<(u64, TimerId)>>,
+    /// The timer currently running, and whether it was cleared meanwhile.
+    running: Option<(TimerId, bool)>,
+}
+
+thread_local! {
+    static TIMERS: RefCell<Timers> = RefCell::new(Timers::default());
+}
+
+/// Runs `func` once, after `delay`.
+pub fn set_timer(delay: Duration, func: impl FnOnce() + 'static) -> TimerId {
+    add_timer(delay, Timer::Once(Box::new(func)))
+}
+
+/// Runs `func` every `interval`, starting `interval` from now.
+pub fn set_timer_interval(interval: Duration, func: impl FnMut() + 'static) -> TimerId {
+    add_timer(
+        interval,
+        Timer::Repeated {
+            func: Box::new(func),
+            interval,
+        },
+    )
+}
+
+/// Cancels a timer. Does nothing if the timer already ran or was cleared.
+pub fn clear_timer(id: TimerId) {
+    TIMERS.with(|t| {
+        let mut t = t.borrow_mut();
+        t.timers.remove(&id);
+        if let Some((running, cleared)) = t.running.as_mut() {
+            if *running == id {
+                *cleared = true;
+            }
+        }
+    })
+}
+
+fn add_timer(delay: Duration, timer: Timer) -> TimerId {
+    let deadline = time().saturating_add(delay.as_nanos() as u64);
+    let id = TIMERS.with(|t| {
+        let mut t = t.borrow_mut();
+        let id = TimerId(t.next_id);
+        t.next_id += 1;
+        t.timers.insert(id, timer);
+        t.queue.push(Reverse((deadline, id)));
+        id
+    });
+    arm_global_timer();
+    id
+}
+
+/// Sets the global timer to the earliest deadline.
+fn arm_global_timer() {
+    let next = TIMERS.with(|t| {
+        let mut t = t.borrow_mut();
+        while let Some(Reverse((deadline, id))) = t.queue.peek().copied() {
+            if t.timers.contains_key(&id) {
+                return Some(deadline);
+            }
+            t.queue.pop();
+        }
+        None
+    });
+    // Zero would deactivate the timer, so a deadline that is already past is
+    // set to the smallest valid time instead.
+    set_global_timer(next.map_or(0, |deadline| deadline.max(1)));
+}
+
+#[export_name = "canister_global_timer"]
+fn global_timer() {
+    crate::setup();
+    let now = time();
+    loop {
+        // `None` when nothing else is due, `Some(None)` for a cleared timer.
+        let due = TIMERS.with(|t| {
+            let mut t = t.borrow_mut();
+            match t.queue.peek() {
+                Some(Reverse((deadline, _))) if *deadline <= now => {
+                    let Reverse((_, id)) = t.queue.pop().unwrap();
+                    let timer = t.timers.remove(&id);
+                    if timer.is_some() {
+                        t.running = Some((id, false));
+                    }
+                    Some(timer.map(|timer| (id, timer)))
+                }
+                _ => None,
+            }
+        });
+        let (id, timer) = match due {
+            Some(Some(due)) => due,
+            Some(None) => continue,
+            None => break,
+        };
+
+        // The timer is out of the map while it runs, so it can set or clear
+        // timers, including itself.
+        let repeat = match timer {
+            Timer::Once(func) => {
+                func();
+                None
+            }
+            Timer::Repeated { mut func, interval } => {
+                func();
+                Some(Timer::Repeated { func, interval })
+            }
+        };
+        TIMERS.with(|t| {
+            let mut t = t.borrow_mut();
+            let cleared = matches!(t.running.take(), Some((_, true)));
+            if let Some(timer) = repeat.filter(|_| !cleared) {
+                if let Timer::Repeated { interval, .. } = &timer {
+                    let deadline = now.saturating_add((interval.as_nanos() as u64).max(1));
+                    t.queue.push(Reverse((deadline, id)));
+                }
+                t.timers.insert(id, timer);
+            }
+        });
+    }
+    arm_global_timer();
+}
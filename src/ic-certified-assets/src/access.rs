@@ -0,0 +1,301 @@
+//! Expiring access tokens for assets marked as protected.
+//!
+//! `http_request` only serves a protected asset when the URL carries a
+//! `?token=` minted for its key by `create_access_token`, and before the
+//! token expires. A token is the expiry followed by an HMAC-SHA256 of the key
+//! and the expiry under a secret of the canister, so checking it needs no
+//! state besides that secret. The secret is drawn from `raw_rand` when the
+//! first token is minted, and kept across upgrades.
+//!
+//! The other read methods only return protected assets to authorized
+//! principals.
+
+#[cfg(any(feature = "serve", feature = "admin"))]
+use crate::is_authorized;
+#[cfg(any(feature = "upload", feature = "admin"))]
+use crate::replication::format_reject;
+#[cfg(feature = "serve")]
+use crate::{authenticator, staging, Asset, CodedTrap};
+use crate::{url_decode, Hash, Key, STATE};
+#[cfg(any(feature = "upload", feature = "admin"))]
+use ic_cdk::api::management_canister::raw_rand;
+use ic_cdk::api::time;
+#[cfg(any(feature = "upload", feature = "admin"))]
+use ic_cdk::api::trap;
+use ic_cdk::export::candid::{CandidType, Deserialize};
+#[cfg(feature = "admin")]
+use ic_cdk_macros::update;
+use sha2::Digest;
+#[cfg(any(feature = "upload", feature = "admin"))]
+use std::convert::TryInto;
+
+#[cfg(feature = "serve")]
+pub(crate) const ACCESS_DENIED: CodedTrap = ("access_denied", "the asset is protected");
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct CreateAccessTokenArguments {
+    key: Key,
+    /// The time the token expires, in nanoseconds since 1970-01-01.
+    expires_at: u64,
+}
+
+/// Mints a token granting access to the protected asset `key` until
+/// `expires_at`, to be passed as `?token=` in the URL.
+#[cfg(feature = "admin")]
+#[update(guard = "is_authorized")]
+async fn create_access_token(arg: CreateAccessTokenArguments) -> String {
+    let secret = init_secret().await;
+    mint(&secret, &arg.key, arg.expires_at)
+}
+
+/// Returns the secret of the canister, drawing it from `raw_rand` if it has
+/// none yet.
+#[cfg(any(feature = "upload", feature = "admin"))]
+pub(crate) async fn init_secret() -> Hash {
+    if STATE.with(|s| s.access_secret.borrow().is_none()) {
+        let bytes = raw_rand()
+            .await
+            .unwrap_or_else(|err| trap(&format!("raw_rand failed: {}", format_reject(err))));
+        let secret: Hash = bytes[..32].try_into().unwrap();
+        // Another call may have set the secret while this one was waiting.
+        STATE.with(|s| {
+            s.access_secret.borrow_mut().get_or_insert(secret);
+        });
+    }
+    STATE.with(|s| s.access_secret.borrow().unwrap())
+}
+
+/// Derives the secret of another kind of token from the secret of the
+/// canister, so that tokens of one kind never pass for the other.
+#[cfg(feature = "upload")]
+pub(crate) fn derive_secret(secret: &Hash, purpose: &str) -> Hash {
+    hmac_sha256(secret, purpose.as_bytes())
+}
+
+/// Mints a token for `key`, valid until `expires_at`.
+#[cfg(any(feature = "upload", feature = "admin"))]
+pub(crate) fn mint(secret: &Hash, key: &str, expires_at: u64) -> String {
+    format!(
+        "{}-{}",
+        expires_at,
+        hex::encode(mac(secret, key, expires_at))
+    )
+}
+
+/// Returns whether `query`, the query string of a request, carries an
+/// unexpired token for `key`.
+pub(crate) fn has_valid_token(key: &str, query: Option<&str>) -> bool {
+    match query.and_then(token_param) {
+        Some(token) => is_valid(key, &token),
+        None => false,
+    }
+}
+
+/// Returns whether `token`, as found in a streaming callback token, is an
+/// unexpired token for `key`.
+pub(crate) fn is_valid(key: &str, token: &str) -> bool {
+    match STATE.with(|s| *s.access_secret.borrow()) {
+        Some(secret) => verify(&secret, key, token),
+        None => false,
+    }
+}
+
+/// Returns whether `token` was minted for `key` under `secret` and hasn't
+/// expired.
+pub(crate) fn verify(secret: &Hash, key: &str, token: &str) -> bool {
+    let (expires_at, tag) = match token.find('-') {
+        Some(i) => (&token[..i], &token[i + 1..]),
+        None => return false,
+    };
+    let (expires_at, tag) = match (expires_at.parse::<u64>(), hex::decode(tag)) {
+        (Ok(expires_at), Ok(tag)) => (expires_at, tag),
+        _ => return false,
+    };
+    time() < expires_at && constant_time_eq(&tag, &mac(secret, key, expires_at))
+}
+
+/// Returns whether the caller may read `asset` through the methods other
+/// than `http_request`: it must be neither protected, staged nor under a
+/// prefix requiring authentication, or the caller must be authorized.
+#[cfg(feature = "serve")]
+pub(crate) fn is_readable(key: &str, asset: &Asset) -> bool {
+    (asset.protected != Some(true) && !staging::is_staged(key) && !authenticator::is_protected(key))
+        || is_authorized().is_ok()
+}
+
+pub(crate) fn token_param(query: &str) -> Option<String> {
+    query.split('&').find_map(|pair| {
+        let value = pair.strip_prefix("token=")?;
+        url_decode(value).ok()
+    })
+}
+
+fn mac(secret: &Hash, key: &str, expires_at: u64) -> Hash {
+    let mut message = key.as_bytes().to_vec();
+    message.push(0);
+    message.extend_from_slice(&expires_at.to_be_bytes());
+    hmac_sha256(secret, &message)
+}
+
+/// HMAC-SHA256 (RFC 2104).
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Hash {
+    const BLOCK_SIZE: usize = 64;
+    let mut padded = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        padded[..32].copy_from_slice(&sha2::Sha256::digest(key));
+    } else {
+        padded[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = sha2::Sha256::new();
+    inner.update(padded.iter().map(|b| b ^ 0x36).collect::<Vec<u8>>());
+    inner.update(message);
+    let inner = inner.finalize();
+
+    let mut outer = sha2::Sha256::new();
+    outer.update(padded.iter().map(|b| b ^ 0x5c).collect::<Vec<u8>>());
+    outer.update(inner);
+    outer.finalize().into()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ic_cdk::api::mock;
+
+    /// The test cases of RFC 4231, section 4, as (key, data, HMAC-SHA256).
+    /// Test case 5, which truncates the output, only checks the first 16
+    /// bytes.
+    #[test]
+    fn hmac_sha256_matches_rfc_4231() {
+        let long_key = vec![0xaa; 131];
+        let cases: Vec<(Vec<u8>, Vec<u8>, &str)> = vec![
+            (
+                vec![0x0b; 20],
+                b"Hi There".to_vec(),
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ),
+            (
+                b"Jefe".to_vec(),
+                b"what do ya want for nothing?".to_vec(),
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            (
+                vec![0xaa; 20],
+                vec![0xdd; 50],
+                "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe",
+            ),
+            (
+                (1..=25).collect(),
+                vec![0xcd; 50],
+                "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b",
+            ),
+            (
+                vec![0x0c; 20],
+                b"Test With Truncation".to_vec(),
+                "a3b6167473100ee06e0c796c2955552b",
+            ),
+            (
+                long_key.clone(),
+                b"Test Using Larger Than Block-Size Key - Hash Key First".to_vec(),
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            ),
+            (
+                long_key,
+                b"This is a test using a larger than block-size key and a larger than \
+                  block-size data. The key needs to be hashed before being used by the \
+                  HMAC algorithm."
+                    .to_vec(),
+                "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2",
+            ),
+        ];
+        for (key, data, expected) in cases {
+            let mac = hex::encode(hmac_sha256(&key, &data));
+            assert_eq!(&mac[..expected.len()], expected);
+        }
+    }
+
+    const SECRET: Hash = [7; 32];
+
+    #[test]
+    fn verifies_minted_tokens_until_they_expire() {
+        mock::reset();
+        mock::set_time(1_000);
+        let token = mint(&SECRET, "/a.pdf", 2_000);
+        assert!(token.starts_with("2000-"));
+        assert!(verify(&SECRET, "/a.pdf", &token));
+        mock::set_time(1_999);
+        assert!(verify(&SECRET, "/a.pdf", &token));
+        mock::set_time(2_000);
+        assert!(!verify(&SECRET, "/a.pdf", &token));
+    }
+
+    #[test]
+    fn rejects_tokens_minted_for_something_else() {
+        mock::reset();
+        let token = mint(&SECRET, "/a.pdf", 2_000);
+        assert!(!verify(&SECRET, "/b.pdf", &token));
+        assert!(!verify(&[8; 32], "/a.pdf", &token));
+        // Moving the expiry breaks the tag.
+        let tag = &token[token.find('-').unwrap()..];
+        assert!(!verify(&SECRET, "/a.pdf", &format!("3000{}", tag)));
+        // So does flipping a digit of the tag.
+        let mut flipped = token.clone().into_bytes();
+        let last = flipped.last_mut().unwrap();
+        *last = if *last == b'0' { b'1' } else { b'0' };
+        assert!(!verify(
+            &SECRET,
+            "/a.pdf",
+            &String::from_utf8(flipped).unwrap()
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_tokens() {
+        mock::reset();
+        let token = mint(&SECRET, "/a.pdf", 2_000);
+        let (_, tag) = token.split_at(token.find('-').unwrap() + 1);
+        for malformed in [
+            "",
+            "-",
+            "2000",
+            "2000-",
+            "x-00",
+            "-2000-00",
+            &format!("2000-{}", &tag[1..]),
+            &format!("2000-{}zz", tag),
+            &format!("2000-{}00", tag),
+        ]
+        .iter()
+        {
+            assert!(!verify(&SECRET, "/a.pdf", malformed), "{}", malformed);
+        }
+    }
+
+    #[test]
+    fn reads_the_token_parameter() {
+        assert_eq!(
+            token_param("a=1&token=12-ab%2Bcd&b=2"),
+            Some("12-ab+cd".to_string())
+        );
+        assert_eq!(token_param("a=1&tokens=12"), None);
+    }
+
+    #[test]
+    fn staged_assets_are_only_readable_by_authorized_principals() {
+        mock::reset();
+        let member = ic_cdk::export::Principal::from_slice(&[1]);
+        STATE.with(|s| s.authorized.replace(vec![member]));
+        let asset = Asset::default();
+
+        assert!(is_readable("/index.html", &asset));
+        assert!(!is_readable("/.staged/index.html", &asset));
+        mock::set_caller(member);
+        assert!(is_readable("/.staged/index.html", &asset));
+        STATE.with(|s| s.authorized.replace(vec![]));
+    }
+}
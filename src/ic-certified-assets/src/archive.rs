@@ -0,0 +1,458 @@
+//! Tar archives of the assets, to back them up and restore them, or to move
+//! them to another canister.
+//!
+//! `import_archive` unpacks a tar uploaded as the chunks of a batch, one
+//! asset per regular file. `export_archive` returns a tar of the assets under
+//! a prefix, one page per call since it may not fit in a single response.
+//! Both handle the long names of GNU and pax archives.
+
+use crate::{
+    hash_bytes, heap, is_authorized, is_writable, mime, set_encoding, trap, trap_error, Asset,
+    AssetEncoding, ChunkId, HashAlgorithm, Key, RcBytes, CHUNK_NOT_FOUND, STATE,
+};
+use ic_cdk::export::candid::{CandidType, Deserialize, Nat};
+use ic_cdk_macros::{query, update};
+use num_traits::ToPrimitive;
+use serde_bytes::ByteBuf;
+
+const BLOCK_SIZE: usize = 512;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct ImportArchiveArguments {
+    /// The chunks holding the archive, in order.
+    chunk_ids: Vec<ChunkId>,
+    /// Prepended to the name of each entry to make its key, `/` by default.
+    prefix: Option<Key>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct ExportArchiveArguments {
+    /// Only the assets whose key starts with this prefix are exported.
+    prefix: Key,
+    /// The offset of the page in the archive, 0 for the first one.
+    start: Nat,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct ExportArchivePage {
+    content: ByteBuf,
+    /// The `start` of the next page, if there is one.
+    next_start: Option<Nat>,
+}
+
+/// Stores each regular file of the archive as the `identity` encoding of an
+/// asset, with the content type of its extension for new assets. Returns the
+/// keys of the assets.
+#[update(guard = "is_writable")]
+fn import_archive(arg: ImportArchiveArguments) -> Vec<Key> {
+    let archive = STATE.with(|s| {
+        let mut chunks = s.chunks.borrow_mut();
+        let mut archive = vec![];
+        for chunk_id in arg.chunk_ids.iter() {
+            let chunk = chunks
+                .remove(chunk_id)
+                .unwrap_or_else(|| trap_error(CHUNK_NOT_FOUND));
+            heap::chunk_dropped(chunk.content.len());
+            archive.extend_from_slice(&chunk.content);
+        }
+        archive
+    });
+    let prefix = arg.prefix.unwrap_or_default();
+    let prefix = prefix.trim_matches('/');
+
+    let mut keys = vec![];
+    for (name, content) in read_entries(&archive) {
+        let name = name.trim_start_matches("./").trim_start_matches('/');
+        if name.is_empty() || name.ends_with('/') {
+            continue;
+        }
+        let key = if prefix.is_empty() {
+            format!("/{}", name)
+        } else {
+            format!("/{}/{}", prefix, name)
+        };
+        store_entry(&key, content);
+        keys.push(key);
+    }
+    keys
+}
+
+/// Returns a page of the tar archive of the assets under `prefix`, in the
+/// order of their keys. Only assets with an `identity` encoding are archived.
+///
+/// Each page is built from the assets at the time of the call, so the assets
+/// shouldn't change while the pages are fetched.
+#[query(guard = "is_authorized")]
+fn export_archive(arg: ExportArchiveArguments) -> ExportArchivePage {
+    let start = arg.start.0.to_usize().unwrap_or(usize::MAX);
+    archive_page(&arg.prefix, start)
+}
+
+/// The page of the archive of the assets under `prefix` that starts at
+/// `start` and holds up to `max_chunk_size` bytes. Only the chunks it
+/// overlaps are read.
+fn archive_page(prefix: &str, start: usize) -> ExportArchivePage {
+    STATE.with(|s| {
+        let page_size = s.config.borrow().max_chunk_size() as usize;
+        let assets = s.assets.borrow();
+        let mut entries: Vec<(&Key, &AssetEncoding)> = assets
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .filter_map(|(key, asset)| Some((key, asset.encodings.get("identity")?)))
+            .collect();
+        entries.sort_by(|l, r| l.0.cmp(r.0));
+
+        let mut page = Page {
+            start,
+            end: start.saturating_add(page_size),
+            offset: 0,
+            content: vec![],
+        };
+        for (key, enc) in entries {
+            let name = key.trim_start_matches('/');
+            let entry_len = header_len(name) + padded(enc.total_length);
+            if page.offset + entry_len <= page.start {
+                page.offset += entry_len;
+                continue;
+            }
+            if page.offset >= page.end {
+                break;
+            }
+            for block in headers(name, enc) {
+                page.push(&block);
+            }
+            for i in 0..enc.chunk_count() {
+                let len = enc.chunk_len(i);
+                if page.overlaps(len) {
+                    page.push(&enc.chunk(i));
+                } else {
+                    page.skip(len);
+                }
+            }
+            page.push(&vec![0; padded(enc.total_length) - enc.total_length]);
+        }
+        // The archive ends with two zero blocks.
+        page.push(&[0; 2 * BLOCK_SIZE]);
+
+        ExportArchivePage {
+            content: ByteBuf::from(page.content),
+            next_start: if page.offset > page.end {
+                Some(Nat::from(page.end))
+            } else {
+                None
+            },
+        }
+    })
+}
+
+/// The bytes of the archive between `start` and `end`, out of the bytes
+/// pushed so far, which end at `offset`.
+struct Page {
+    start: usize,
+    end: usize,
+    offset: usize,
+    content: Vec<u8>,
+}
+
+impl Page {
+    /// Whether the next `len` bytes are part of the page.
+    fn overlaps(&self, len: usize) -> bool {
+        self.offset < self.end && self.offset + len > self.start
+    }
+
+    fn skip(&mut self, len: usize) {
+        self.offset += len;
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let from = self.start.max(self.offset).min(self.offset + bytes.len());
+        let to = self.end.min(self.offset + bytes.len()).max(from);
+        self.content
+            .extend_from_slice(&bytes[from - self.offset..to - self.offset]);
+        self.offset += bytes.len();
+    }
+}
+
+fn store_entry(key: &str, content: &[u8]) {
+    heap::reserve(content.len());
+    STATE.with(|s| {
+        let max_chunk_size = s.config.borrow().max_chunk_size() as usize;
+        let mut assets = s.assets.borrow_mut();
+        let asset = assets.entry(key.to_string()).or_insert_with(|| Asset {
+            content_type: mime::resolve(key, String::new()),
+            ..Asset::default()
+        });
+        let content_chunks: Vec<RcBytes> = if content.is_empty() {
+            vec![RcBytes::from(ByteBuf::new())]
+        } else {
+            content
+                .chunks(max_chunk_size)
+                .map(|chunk| RcBytes::from(ByteBuf::from(chunk.to_vec())))
+                .collect()
+        };
+        let chunk_hashes = content_chunks
+            .iter()
+            .map(|chunk| hash_bytes(chunk))
+            .collect();
+        set_encoding(
+            key,
+            asset,
+            "identity".to_string(),
+            content_chunks,
+            (HashAlgorithm::Sha256, chunk_hashes),
+            None,
+        );
+    })
+}
+
+/// Returns the name and content of each regular file of `archive`.
+fn read_entries(archive: &[u8]) -> Vec<(String, &[u8])> {
+    let mut entries = vec![];
+    let mut long_name = None;
+    let mut offset = 0;
+    while offset + BLOCK_SIZE <= archive.len() {
+        let header = &archive[offset..offset + BLOCK_SIZE];
+        if header.iter().all(|b| *b == 0) {
+            break;
+        }
+        let size = parse_octal(&header[124..136]);
+        let content_start = offset + BLOCK_SIZE;
+        let content = match archive.get(content_start..content_start.saturating_add(size)) {
+            Some(content) => content,
+            None => trap("truncated archive"),
+        };
+        offset = content_start + padded(size);
+
+        match header[156] {
+            // A GNU long name for the next entry.
+            b'L' => long_name = Some(field(content)),
+            // pax extended attributes of the next entry.
+            b'x' => long_name = pax_path(content).or(long_name),
+            b'0' | 0 => {
+                let name = long_name.take().unwrap_or_else(|| {
+                    let name = field(&header[..100]);
+                    let prefix = field(&header[345..500]);
+                    if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                        format!("{}/{}", prefix, name)
+                    } else {
+                        name
+                    }
+                });
+                entries.push((name, content));
+            }
+            _ => long_name = None,
+        }
+    }
+    entries
+}
+
+/// The `path` record of pax extended attributes, made of `<length>
+/// <key>=<value>\n` records.
+fn pax_path(attributes: &[u8]) -> Option<String> {
+    let attributes = String::from_utf8_lossy(attributes);
+    let mut rest = &attributes[..];
+    while let Some(space) = rest.find(' ') {
+        let len: usize = rest[..space].parse().ok()?;
+        let record = rest.get(space + 1..len)?.trim_end_matches('\n');
+        if let Some(path) = record.strip_prefix("path=") {
+            return Some(path.to_string());
+        }
+        rest = &rest[len..];
+    }
+    None
+}
+
+/// The header blocks of the entry `name`, preceded by a GNU long name entry
+/// if the name doesn't fit in the header.
+fn headers(name: &str, enc: &AssetEncoding) -> Vec<Vec<u8>> {
+    let mtime = enc.modified.0.to_u64().unwrap_or(0) / 1_000_000_000;
+    let mut blocks = vec![];
+    if name.len() > 100 {
+        let mut long_name = name.as_bytes().to_vec();
+        long_name.push(0);
+        blocks.push(header("././@LongLink", long_name.len(), mtime, b'L'));
+        long_name.resize(padded(long_name.len()), 0);
+        blocks.push(long_name);
+    }
+    blocks.push(header(name, enc.total_length, mtime, b'0'));
+    blocks
+}
+
+fn header_len(name: &str) -> usize {
+    if name.len() > 100 {
+        2 * BLOCK_SIZE + padded(name.len() + 1)
+    } else {
+        BLOCK_SIZE
+    }
+}
+
+/// A ustar header. Names longer than the field are cut, the long name entry
+/// before holds them.
+fn header(name: &str, size: usize, mtime: u64, typeflag: u8) -> Vec<u8> {
+    let mut header = vec![0; BLOCK_SIZE];
+    let name = &name.as_bytes()[..name.len().min(100)];
+    header[..name.len()].copy_from_slice(name);
+    header[100..108].copy_from_slice(b"0000644\0");
+    header[108..116].copy_from_slice(b"0000000\0");
+    header[116..124].copy_from_slice(b"0000000\0");
+    header[124..136].copy_from_slice(format!("{:011o}\0", size).as_bytes());
+    header[136..148].copy_from_slice(format!("{:011o}\0", mtime).as_bytes());
+    header[156] = typeflag;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is computed with its own field filled with spaces.
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|b| *b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    header
+}
+
+/// `len` rounded up to a whole number of blocks.
+fn padded(len: usize) -> usize {
+    (len + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE
+}
+
+/// A NUL-terminated string field.
+fn field(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+fn parse_octal(bytes: &[u8]) -> usize {
+    let digits = field(bytes);
+    let digits = digits.trim();
+    if digits.is_empty() {
+        return 0;
+    }
+    usize::from_str_radix(digits, 8).unwrap_or_else(|_| trap("invalid archive header"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{AssetCanisterArgs, Config};
+
+    /// Stores `files` with chunks and pages of `page_size` bytes, and returns
+    /// the archive of the assets under `prefix`, fetched page by page.
+    fn export(files: &[(&str, Vec<u8>)], page_size: u64, prefix: &str) -> Vec<u8> {
+        ic_cdk::api::mock::reset();
+        STATE.with(|s| {
+            s.assets.borrow_mut().clear();
+            let mut config = Config::default();
+            config.apply(AssetCanisterArgs {
+                max_chunk_size: Some(page_size),
+                ..AssetCanisterArgs::default()
+            });
+            s.config.replace(config);
+        });
+        for (key, content) in files {
+            store_entry(key, content);
+        }
+        let mut archive = vec![];
+        let mut start = 0;
+        loop {
+            let page = archive_page(prefix, start);
+            assert!(page.content.len() <= page_size as usize);
+            archive.extend_from_slice(&page.content);
+            match page.next_start {
+                Some(next_start) => start = next_start.0.to_usize().unwrap(),
+                None => break,
+            }
+        }
+        archive
+    }
+
+    fn entries(archive: &[u8]) -> Vec<(String, Vec<u8>)> {
+        read_entries(archive)
+            .into_iter()
+            .map(|(name, content)| (name, content.to_vec()))
+            .collect()
+    }
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn exports_and_reads_back_the_assets() {
+        let long_name = format!("/{}.txt", "d/".repeat(60));
+        let files = vec![
+            ("/a.txt", content(0)),
+            ("/b.bin", content(1500)),
+            (long_name.as_str(), content(700)),
+            ("/other/c.txt", content(10)),
+        ];
+        for page_size in [300, 512, 1000, 1 << 20].iter() {
+            let archive = export(&files, *page_size, "/");
+            assert_eq!(archive.len() % BLOCK_SIZE, 0);
+            let mut expected: Vec<(String, Vec<u8>)> = files
+                .iter()
+                .map(|(key, content)| (key[1..].to_string(), content.clone()))
+                .collect();
+            expected.sort();
+            assert_eq!(entries(&archive), expected, "page size {}", page_size);
+        }
+    }
+
+    #[test]
+    fn exports_the_assets_under_the_prefix() {
+        let files = vec![("/a.txt", content(3)), ("/other/c.txt", content(10))];
+        let archive = export(&files, 1 << 20, "/other/");
+        assert_eq!(
+            entries(&archive),
+            vec![("other/c.txt".to_string(), content(10))]
+        );
+    }
+
+    #[test]
+    fn reads_gnu_long_names() {
+        let name = "n".repeat(150);
+        let enc = AssetEncoding {
+            total_length: 5,
+            ..AssetEncoding::default()
+        };
+        let mut archive = headers(&name, &enc).concat();
+        assert_eq!(archive.len(), header_len(&name));
+        archive.extend_from_slice(b"hello");
+        archive.resize(padded(archive.len()) + 2 * BLOCK_SIZE, 0);
+        assert_eq!(entries(&archive), vec![(name, b"hello".to_vec())]);
+    }
+
+    #[test]
+    fn reads_pax_paths() {
+        let name = "p/".repeat(80) + "file";
+        let record = format!(" path={}\n", name);
+        let len = record.len() + 3;
+        let attributes = format!("{}{}", len, record);
+        assert_eq!(attributes.len(), len);
+
+        let mut archive = header("PaxHeaders/file", attributes.len(), 0, b'x');
+        archive.extend_from_slice(attributes.as_bytes());
+        archive.resize(padded(archive.len()), 0);
+        archive.extend(header("file", 2, 0, b'0'));
+        archive.extend_from_slice(b"hi");
+        archive.resize(padded(archive.len()) + 2 * BLOCK_SIZE, 0);
+        assert_eq!(entries(&archive), vec![(name, b"hi".to_vec())]);
+    }
+
+    #[test]
+    fn reads_ustar_prefixes() {
+        let mut archive = header("file", 2, 0, b'0');
+        archive[345..348].copy_from_slice(b"dir");
+        archive.extend_from_slice(b"hi");
+        archive.resize(padded(archive.len()) + 2 * BLOCK_SIZE, 0);
+        assert_eq!(
+            entries(&archive),
+            vec![("dir/file".to_string(), b"hi".to_vec())]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "truncated archive")]
+    fn rejects_truncated_archives() {
+        let mut archive = header("file", 600, 0, b'0');
+        archive.extend_from_slice(&[0; 100]);
+        read_entries(&archive);
+    }
+}
@@ -0,0 +1,210 @@
+//! Path prefixes whose assets are only served to authenticated requests.
+//!
+//! The host canister registers an [Authenticator] per prefix, e.g. one that
+//! validates a JWT or a delegation against a public key it is configured
+//! with. As with routes, the longest matching prefix wins.
+//!
+//! Rejected requests get a 401 response, certified along with the assets
+//! under the prefix so that queries answer it. Its body is the same for every
+//! rejection, since the reasons the authenticator gives can't be certified in
+//! advance; only streaming callbacks trap with them. Streaming callbacks only
+//! carry the `Authorization` header of the original request, so
+//! authenticators should read the credentials from there for assets served in
+//! several chunks.
+
+use crate::{recertify_prefix, HttpRequest};
+#[cfg(feature = "serve")]
+use serde_bytes::ByteBuf;
+use std::cell::RefCell;
+
+/// The error code of the streaming callbacks the authenticator rejects, whose
+/// message is the reason it gives.
+#[cfg(feature = "serve")]
+pub(crate) const UNAUTHENTICATED: &str = "unauthenticated";
+
+/// Decides whether a request may read the assets under a prefix.
+pub trait Authenticator {
+    /// Returns why the request is rejected, if it is.
+    fn authenticate(&self, req: &HttpRequest) -> Result<(), String>;
+}
+
+impl<F> Authenticator for F
+where
+    F: Fn(&HttpRequest) -> Result<(), String>,
+{
+    fn authenticate(&self, req: &HttpRequest) -> Result<(), String> {
+        self(req)
+    }
+}
+
+struct ProtectedPrefix {
+    prefix: String,
+    authenticator: Box<dyn Authenticator>,
+}
+
+thread_local! {
+    static PREFIXES: RefCell<Vec<ProtectedPrefix>> = RefCell::new(vec![]);
+}
+
+/// Only serves the assets whose key starts with `prefix` to the requests
+/// `authenticator` accepts.
+///
+/// Authenticators aren't kept across upgrades, so they should be registered
+/// from both `init` and `post_upgrade`.
+pub fn require_authentication<A: Authenticator + 'static>(prefix: &str, authenticator: A) {
+    PREFIXES.with(|prefixes| {
+        let mut prefixes = prefixes.borrow_mut();
+        prefixes.retain(|p| p.prefix != prefix);
+        prefixes.push(ProtectedPrefix {
+            prefix: prefix.to_string(),
+            authenticator: Box::new(authenticator),
+        });
+    });
+    // The assets under the prefix may now get a 401 response.
+    recertify_prefix(prefix);
+}
+
+/// Returns the token of an `Authorization: Bearer <token>` header, if `req`
+/// has one.
+pub fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Authorization"))
+        .and_then(|(_, value)| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// Returns whether `path` is under a prefix requiring authentication.
+pub(crate) fn is_protected(path: &str) -> bool {
+    PREFIXES.with(|prefixes| {
+        prefixes
+            .borrow()
+            .iter()
+            .any(|p| path.starts_with(&p.prefix))
+    })
+}
+
+/// Returns why `req`, a request for `path`, is rejected, if it is.
+pub(crate) fn check(path: &str, req: &HttpRequest) -> Result<(), String> {
+    PREFIXES.with(|prefixes| {
+        let prefixes = prefixes.borrow();
+        match prefixes
+            .iter()
+            .filter(|p| path.starts_with(&p.prefix))
+            .max_by_key(|p| p.prefix.len())
+        {
+            Some(p) => p.authenticator.authenticate(req),
+            None => Ok(()),
+        }
+    })
+}
+
+/// Authenticates a streaming callback for `key`, which carries the
+/// `Authorization` header of the request it continues.
+#[cfg(feature = "serve")]
+pub(crate) fn check_callback(key: &str, authorization: Option<String>) -> Result<(), String> {
+    let req = HttpRequest {
+        method: "GET".to_string(),
+        url: key.to_string(),
+        headers: authorization
+            .map(|value| ("Authorization".to_string(), value))
+            .into_iter()
+            .collect(),
+        body: ByteBuf::new(),
+    };
+    check(key, &req)
+}
+
+/// Returns the `Authorization` header of `req`, to be carried by the
+/// streaming callback tokens of the response.
+pub(crate) fn authorization(req: &HttpRequest) -> Option<String> {
+    req.headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Authorization"))
+        .map(|(_, value)| value.clone())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(headers: Vec<(&str, &str)>) -> HttpRequest {
+        HttpRequest {
+            method: "GET".to_string(),
+            url: "/".to_string(),
+            headers: headers
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: ByteBuf::new(),
+        }
+    }
+
+    fn reject(reason: &'static str) -> impl Fn(&HttpRequest) -> Result<(), String> {
+        move |_: &HttpRequest| Err(reason.to_string())
+    }
+
+    #[test]
+    fn longest_prefix_decides() {
+        PREFIXES.with(|prefixes| prefixes.borrow_mut().clear());
+        require_authentication("/private/", reject("private"));
+        require_authentication("/private/shared/", |_: &HttpRequest| Ok(()));
+        require_authentication("/private/shared/secret/", reject("secret"));
+        let req = request(vec![]);
+
+        assert_eq!(check("/public/a.txt", &req), Ok(()));
+        assert_eq!(check("/private/a.txt", &req), Err("private".to_string()));
+        assert_eq!(check("/private/shared/a.txt", &req), Ok(()));
+        assert_eq!(
+            check("/private/shared/secret/a.txt", &req),
+            Err("secret".to_string())
+        );
+        assert!(!is_protected("/public/a.txt"));
+        assert!(is_protected("/private/shared/a.txt"));
+    }
+
+    #[test]
+    fn registering_a_prefix_again_replaces_it() {
+        PREFIXES.with(|prefixes| prefixes.borrow_mut().clear());
+        require_authentication("/private/", reject("first"));
+        require_authentication("/private/", reject("second"));
+
+        assert_eq!(
+            check("/private/a.txt", &request(vec![])),
+            Err("second".to_string())
+        );
+        PREFIXES.with(|prefixes| assert_eq!(prefixes.borrow().len(), 1));
+    }
+
+    #[test]
+    fn callbacks_carry_the_authorization_header() {
+        PREFIXES.with(|prefixes| prefixes.borrow_mut().clear());
+        require_authentication("/private/", |req: &HttpRequest| match bearer_token(req) {
+            Some("secret") => Ok(()),
+            _ => Err("bad token".to_string()),
+        });
+
+        assert_eq!(
+            check_callback("/private/a.txt", Some("Bearer secret".to_string())),
+            Ok(())
+        );
+        assert_eq!(
+            check_callback("/private/a.txt", None),
+            Err("bad token".to_string())
+        );
+    }
+
+    #[test]
+    fn bearer_token_is_read_from_the_authorization_header() {
+        assert_eq!(
+            bearer_token(&request(vec![("authorization", "Bearer abc.def ")])),
+            Some("abc.def")
+        );
+        assert_eq!(
+            bearer_token(&request(vec![("Authorization", "Basic dXNlcg==")])),
+            None
+        );
+        assert_eq!(bearer_token(&request(vec![("Accept", "*/*")])), None);
+        assert_eq!(bearer_token(&request(vec![])), None);
+    }
+}
@@ -0,0 +1,141 @@
+//! Instruction counts of representative workloads, to catch performance
+//! regressions of the certification path before a release.
+//!
+//! Only built with the `benchmarks` feature. The workloads run on assets of
+//! their own under [BENCHMARK_PREFIX], which are deleted again afterwards, so
+//! they refuse to run while other assets exist under it.
+
+use crate::rc_bytes::{encode_around_blob, leb128};
+use crate::{
+    do_delete_asset, do_store, is_writable, on_asset_change, serve_asset, DeleteAssetArguments,
+    GetChunkResponse, HttpRequest, RcBytes, StoreArg, STATE,
+};
+use ic_cdk::api::instruction_counter;
+use ic_cdk::export::candid::{encode_one, CandidType, Deserialize};
+use ic_cdk_macros::update;
+use serde_bytes::ByteBuf;
+
+/// The keys of the benchmark assets start with this prefix.
+const BENCHMARK_PREFIX: &str = "/__benchmark/";
+
+const ASSET_COUNT: usize = 100;
+const ASSET_SIZE: usize = 1024;
+const REQUEST_COUNT: usize = 1000;
+const REPLY_SIZE: usize = 1024 * 1024;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct BenchmarkResult {
+    name: String,
+    instructions: u64,
+}
+
+/// Runs every workload and returns the instructions each of them executed.
+///
+/// The counts are only comparable between builds of the same canister with
+/// the same assets, since serving and certifying depend on the size of the
+/// asset tree.
+#[update(guard = "is_writable")]
+fn run_benchmarks() -> Vec<BenchmarkResult> {
+    // The workloads overwrite and then delete whatever is under the prefix.
+    let in_use = STATE.with(|s| {
+        s.assets
+            .borrow()
+            .keys()
+            .any(|key| key.starts_with(BENCHMARK_PREFIX))
+    });
+    if in_use {
+        ic_cdk::trap(&format!(
+            "assets already exist under {}, delete them to run the benchmarks",
+            BENCHMARK_PREFIX
+        ));
+    }
+    let keys: Vec<String> = (0..ASSET_COUNT)
+        .map(|i| format!("{}{}", BENCHMARK_PREFIX, i))
+        .collect();
+    let results = vec![
+        measure("store_100_assets", || store_assets(&keys)),
+        // Range requests aren't served yet, so these request whole assets.
+        measure("serve_1000_requests", || serve_requests(&keys)),
+        measure("recertify_100_assets", || recertify_assets(&keys)),
+        measure("encode_1mb_reply_copied", encode_reply_copied),
+        measure("encode_1mb_reply_spliced", encode_reply_spliced),
+    ];
+    for key in keys {
+        do_delete_asset(DeleteAssetArguments { key });
+    }
+    results
+}
+
+fn measure(name: &str, workload: impl FnOnce()) -> BenchmarkResult {
+    let start = instruction_counter();
+    workload();
+    BenchmarkResult {
+        name: name.to_string(),
+        instructions: instruction_counter().saturating_sub(start),
+    }
+}
+
+fn store_assets(keys: &[String]) {
+    for (i, key) in keys.iter().enumerate() {
+        do_store(StoreArg {
+            key: key.clone(),
+            content_type: "application/octet-stream".to_string(),
+            content_encoding: "identity".to_string(),
+            content: RcBytes::from(ByteBuf::from(vec![i as u8; ASSET_SIZE])),
+            sha256: None,
+            expected_sha256: None,
+        });
+    }
+}
+
+fn serve_requests(keys: &[String]) {
+    for i in 0..REQUEST_COUNT {
+        let response = serve_asset(&HttpRequest {
+            method: "GET".to_string(),
+            url: keys[i % keys.len()].clone(),
+            headers: vec![],
+            body: ByteBuf::new(),
+        });
+        if response.status_code != 200 {
+            ic_cdk::trap("benchmark asset not served");
+        }
+    }
+}
+
+/// Certifies the assets again from scratch, as [post_upgrade](crate::post_upgrade)
+/// does for every asset.
+fn recertify_assets(keys: &[String]) {
+    STATE.with(|s| {
+        let mut assets = s.assets.borrow_mut();
+        for key in keys {
+            if let Some(asset) = assets.get_mut(key) {
+                for enc in asset.encodings.values_mut() {
+                    enc.certified = false;
+                }
+                on_asset_change(key, asset);
+            }
+        }
+    })
+}
+
+/// Encodes a `get_chunk` reply of 1 MiB the way `#[query]` would, copying the
+/// content into the Candid serialization buffer.
+fn encode_reply_copied() {
+    let content = RcBytes::from(ByteBuf::from(vec![0; REPLY_SIZE]));
+    let reply = encode_one(GetChunkResponse { content }).unwrap();
+    if reply.len() < REPLY_SIZE {
+        ic_cdk::trap("benchmark reply too short");
+    }
+}
+
+/// Encodes the same reply the way `get_chunk` does, leaving the content to be
+/// appended to the reply straight from the asset.
+fn encode_reply_spliced() {
+    let content = RcBytes::from(ByteBuf::from(vec![0; REPLY_SIZE]));
+    let (empty, _) = encode_around_blob(|content| GetChunkResponse { content });
+    // The empty blob's length byte is replaced by the length of the content.
+    let reply_len = empty.len() - 1 + leb128(content.len()).len() + content.len();
+    if reply_len < REPLY_SIZE {
+        ic_cdk::trap("benchmark reply too short");
+    }
+}
@@ -0,0 +1,214 @@
+//! BLAKE3 in its default hashing mode, for the chunk hashes of uploads.
+//!
+//! This is the portable reference design, without SIMD, which is all a
+//! canister can use anyway. The `blake3` crate isn't a dependency because its
+//! releases need a newer compiler than this crate builds with.
+
+use crate::Hash;
+use std::convert::TryInto;
+
+const IV: [u32; 8] = [
+    0x6A09E667, 0xBB67AE85, 0x3C6EF372, 0xA54FF53A, 0x510E527F, 0x9B05688C, 0x1F83D9AB, 0x5BE0CD19,
+];
+
+const MSG_PERMUTATION: [usize; 16] = [2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8];
+
+const CHUNK_LEN: usize = 1024;
+const BLOCK_LEN: usize = 64;
+
+const CHUNK_START: u32 = 1 << 0;
+const CHUNK_END: u32 = 1 << 1;
+const PARENT: u32 = 1 << 2;
+const ROOT: u32 = 1 << 3;
+
+/// The 32-byte BLAKE3 hash of `bytes`.
+pub(crate) fn hash(bytes: &[u8]) -> Hash {
+    let chunks: Vec<&[u8]> = if bytes.is_empty() {
+        vec![bytes]
+    } else {
+        bytes.chunks(CHUNK_LEN).collect()
+    };
+    let cv = subtree_cv(&chunks, 0, true);
+    let mut hash = [0; 32];
+    for (bytes, word) in hash.chunks_mut(4).zip(cv.iter()) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    hash
+}
+
+/// The chaining value of the subtree made of `chunks`, the first of which is
+/// the chunk number `counter` of the input. The left subtree holds the
+/// largest power of two of chunks that leaves some for the right one.
+fn subtree_cv(chunks: &[&[u8]], counter: u64, is_root: bool) -> [u32; 8] {
+    if chunks.len() == 1 {
+        return chunk_cv(chunks[0], counter, is_root);
+    }
+    let mut left_len = 1;
+    while left_len * 2 < chunks.len() {
+        left_len *= 2;
+    }
+    let left = subtree_cv(&chunks[..left_len], counter, false);
+    let right = subtree_cv(&chunks[left_len..], counter + left_len as u64, false);
+
+    let mut block = [0; 16];
+    block[..8].copy_from_slice(&left);
+    block[8..].copy_from_slice(&right);
+    let flags = if is_root { PARENT | ROOT } else { PARENT };
+    first_8_words(compress(&IV, &block, 0, BLOCK_LEN as u32, flags))
+}
+
+fn chunk_cv(chunk: &[u8], counter: u64, is_root: bool) -> [u32; 8] {
+    let blocks: Vec<&[u8]> = if chunk.is_empty() {
+        vec![chunk]
+    } else {
+        chunk.chunks(BLOCK_LEN).collect()
+    };
+    let mut cv = IV;
+    for (i, block) in blocks.iter().enumerate() {
+        let mut flags = 0;
+        if i == 0 {
+            flags |= CHUNK_START;
+        }
+        if i == blocks.len() - 1 {
+            flags |= CHUNK_END;
+            if is_root {
+                flags |= ROOT;
+            }
+        }
+        // The last block is padded with zeros.
+        let mut padded = [0; BLOCK_LEN];
+        padded[..block.len()].copy_from_slice(block);
+        let mut words = [0; 16];
+        for (word, bytes) in words.iter_mut().zip(padded.chunks(4)) {
+            *word = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+        cv = first_8_words(compress(&cv, &words, counter, block.len() as u32, flags));
+    }
+    cv
+}
+
+fn compress(
+    cv: &[u32; 8],
+    block: &[u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+) -> [u32; 16] {
+    let mut state = [
+        cv[0],
+        cv[1],
+        cv[2],
+        cv[3],
+        cv[4],
+        cv[5],
+        cv[6],
+        cv[7],
+        IV[0],
+        IV[1],
+        IV[2],
+        IV[3],
+        counter as u32,
+        (counter >> 32) as u32,
+        block_len,
+        flags,
+    ];
+    let mut block = *block;
+    for i in 0..7 {
+        round(&mut state, &block);
+        if i < 6 {
+            let mut permuted = [0; 16];
+            for (word, &from) in permuted.iter_mut().zip(MSG_PERMUTATION.iter()) {
+                *word = block[from];
+            }
+            block = permuted;
+        }
+    }
+    for i in 0..8 {
+        state[i] ^= state[i + 8];
+        state[i + 8] ^= cv[i];
+    }
+    state
+}
+
+fn round(state: &mut [u32; 16], m: &[u32; 16]) {
+    // The columns, then the diagonals.
+    g(state, 0, 4, 8, 12, m[0], m[1]);
+    g(state, 1, 5, 9, 13, m[2], m[3]);
+    g(state, 2, 6, 10, 14, m[4], m[5]);
+    g(state, 3, 7, 11, 15, m[6], m[7]);
+    g(state, 0, 5, 10, 15, m[8], m[9]);
+    g(state, 1, 6, 11, 12, m[10], m[11]);
+    g(state, 2, 7, 8, 13, m[12], m[13]);
+    g(state, 3, 4, 9, 14, m[14], m[15]);
+}
+
+fn g(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize, mx: u32, my: u32) {
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(mx);
+    state[d] = (state[d] ^ state[a]).rotate_right(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(12);
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(my);
+    state[d] = (state[d] ^ state[a]).rotate_right(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(7);
+}
+
+fn first_8_words(words: [u32; 16]) -> [u32; 8] {
+    let mut first = [0; 8];
+    first.copy_from_slice(&words[..8]);
+    first
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// The hashes of the official test vectors, whose input byte `i` is
+    /// `i % 251`.
+    const TEST_VECTORS: &[(usize, &str)] = &[
+        (
+            0,
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+        ),
+        (
+            1,
+            "2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213",
+        ),
+        (
+            1023,
+            "10108970eeda3eb932baac1428c7a2163b0e924c9a9e25b35bba72b28f70bd11",
+        ),
+        (
+            1024,
+            "42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7",
+        ),
+        (
+            1025,
+            "d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444",
+        ),
+        (
+            2048,
+            "e776b6028c7cd22a4d0ba182a8bf62205d2ef576467e838ed6f2529b85fba24a",
+        ),
+        (
+            2049,
+            "5f4d72f40d7a5f82b15ca2b2e44b1de3c2ef86c426c95c1af0b6879522563030",
+        ),
+        (
+            3072,
+            "b98cb0ff3623be03326b373de6b9095218513e64f1ee2edd2525c7ad1e5cffd2",
+        ),
+        (
+            31744,
+            "62b6960e1a44bcc1eb1a611a8d6235b6b4b78f32e7abc4fb4c6cdcce94895c47",
+        ),
+    ];
+
+    #[test]
+    fn matches_the_test_vectors() {
+        for (len, expected) in TEST_VECTORS.iter() {
+            let input: Vec<u8> = (0..*len).map(|i| (i % 251) as u8).collect();
+            assert_eq!(hex::encode(hash(&input)), *expected, "length {}", len);
+        }
+    }
+}
@@ -0,0 +1,890 @@
+//! Response verification v2, which certifies whole responses (status code,
+//! headers and body) rather than only the body served at each path.
+//!
+//! The responses are certified under the `http_expr` label, at
+//! `<path segments>/<$>/<expression hash>/<request hash>/<response hash>` for
+//! every encoding of every asset, and at `<*>/...` for the fallback file.
+//! Serving the fallback file also proves that no more specific path is
+//! certified.
+//!
+//! The error responses that may be served in place of an asset, such as the
+//! 403 response to a request without a valid access token, are certified at
+//! the same paths under [ERROR_EXPRESSION], which certifies no part of the
+//! request, so queries answer them as well. The 404 response is certified at
+//! `<*>` when there is no fallback file.
+//!
+//! The request hash is empty unless the asset has certified query
+//! parameters. Requests without any of them are certified along with the
+//! asset, whatever other parameters they carry, and so are the query strings
+//! declared with it, up to [MAX_CERTIFIED_QUERIES]. Requests for other values
+//! get a certified 404 response.
+//!
+//! [CertificationVersion] selects the certificate responses carry while
+//! gateways move from v1 to v2. With [CertificationVersion::Both], the `tree`
+//! field proves both the `http_assets` and the `http_expr` subtrees: gateways
+//! that only know v1 ignore the `expr_path` and `version` fields, the others
+//! verify the whole response.
+//!
+//! Unlike `http_assets`, which holds a single hash per path, the `http_expr`
+//! subtree certifies every encoding of an asset, so it is kept whatever the
+//! version. The encodings without a v1 proof are served with both
+//! certificates even with [CertificationVersion::V1].
+
+use crate::staging::is_staged;
+use crate::{
+    asset_headers, assets_label, build_404, data_certificate, error_responses, hash_bytes,
+    merge_hash_trees, serialize_tree, set_subtree_root, subtree_witness, trap, with_asset_witness,
+    Asset, HeaderField, HttpRequest, HttpResponse, ResolvedAsset, CERTIFIED_TREES, STATE,
+};
+use ic_cdk::export::candid::{CandidType, Deserialize};
+use ic_certified_map::{leaf_hash, AsHashTree, Hash, HashTree, RbTree};
+use sha2::Digest;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+/// The label of the certified responses in the certified data.
+pub(crate) const EXPR_LABEL: &[u8] = b"http_expr";
+
+/// The most query strings an asset may declare certified.
+#[cfg(feature = "upload")]
+const MAX_CERTIFIED_QUERIES: usize = 64;
+
+/// The expression of the error responses, which certifies all their headers
+/// and no part of the request.
+const ERROR_EXPRESSION: &str = "default_certification(ValidationArgs{certification:Certification{no_request_certification:Empty{},response_certification:ResponseCertification{response_header_exclusions:ResponseHeaderList{headers:[]}}}})";
+
+const EXACT_MATCH: &str = "<$>";
+const WILDCARD_MATCH: &str = "<*>";
+
+/// The certificate carried by asset responses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum CertificationVersion {
+    /// Only the body is certified, as gateways before v2 expect. The
+    /// encodings it can't certify carry both certificates.
+    V1,
+    /// The status code, headers and body are certified.
+    V2,
+    /// Both certificates, for gateways of either kind.
+    Both,
+}
+
+impl Default for CertificationVersion {
+    fn default() -> Self {
+        Self::V1
+    }
+}
+
+/// The response headers certified for an asset, besides
+/// `IC-CertificateExpression` which always is. `IC-Certificate` never is.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub(crate) enum CertifiedHeaders {
+    /// Every header but these, which may then vary between responses.
+    AllExcept(Vec<String>),
+    /// Only these headers.
+    Only(Vec<String>),
+}
+
+impl Default for CertifiedHeaders {
+    fn default() -> Self {
+        Self::AllExcept(vec![])
+    }
+}
+
+impl CertifiedHeaders {
+    fn is_certified(&self, name: &str) -> bool {
+        if name.eq_ignore_ascii_case("IC-Certificate") {
+            return false;
+        }
+        if name.eq_ignore_ascii_case("IC-CertificateExpression") {
+            return true;
+        }
+        match self {
+            CertifiedHeaders::AllExcept(names) => {
+                !names.iter().any(|n| n.eq_ignore_ascii_case(name))
+            }
+            CertifiedHeaders::Only(names) => names.iter().any(|n| n.eq_ignore_ascii_case(name)),
+        }
+    }
+}
+
+/// A node of the `http_expr` subtree. The leaves are the empty values under
+/// the response hashes.
+enum ExprNode {
+    Leaf,
+    Branch(RbTree<Vec<u8>, ExprNode>),
+}
+
+impl AsHashTree for ExprNode {
+    fn root_hash(&self) -> Hash {
+        match self {
+            ExprNode::Leaf => leaf_hash(&[]),
+            ExprNode::Branch(children) => children.root_hash(),
+        }
+    }
+
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        match self {
+            ExprNode::Leaf => HashTree::Leaf(Cow::Borrowed(&[])),
+            ExprNode::Branch(children) => children.as_hash_tree(),
+        }
+    }
+}
+
+type ExprTree = RbTree<Vec<u8>, ExprNode>;
+
+thread_local! {
+    static EXPR_TREE: RefCell<ExprTree> = RefCell::new(RbTree::new());
+}
+
+pub(crate) fn version() -> CertificationVersion {
+    STATE.with(|s| s.config.borrow().certification_version.unwrap_or_default())
+}
+
+/// The `IC-CertificateExpression` header of `asset`, which is certified along
+/// with the other headers.
+pub(crate) fn expression_header(asset: &Asset) -> HeaderField {
+    ("IC-CertificateExpression".to_string(), expression(asset))
+}
+
+/// The `IC-CertificateExpression` header of the error responses.
+pub(crate) fn error_expression_header() -> HeaderField {
+    (
+        "IC-CertificateExpression".to_string(),
+        ERROR_EXPRESSION.to_string(),
+    )
+}
+
+/// Certifies the responses serving each encoding of `asset`, and the error
+/// responses that may be served in its place, instead of the ones certified
+/// for `key` so far.
+pub(crate) fn certify(key: &str, asset: &Asset) {
+    let is_fallback_file = STATE.with(|s| s.config.borrow().fallback_file == key);
+    let mut expr_paths = vec![exact_path(key)];
+    if is_fallback_file {
+        expr_paths.push(wildcard_path());
+    }
+    // Staged assets aren't served.
+    let response_hashes = if is_staged(key) {
+        vec![]
+    } else {
+        response_hashes(asset)
+    };
+    let error_hashes: Vec<Hash> = error_responses(key, asset)
+        .iter()
+        .map(error_response_hash)
+        .collect();
+    // The requests without certified parameters, with and without a query
+    // string, and the declared query strings.
+    let request_labels = match certified_query_params(asset) {
+        Some(params) => {
+            let mut labels = vec![
+                request_hash("GET", None, &[], params).to_vec(),
+                request_hash("GET", Some(""), &[], params).to_vec(),
+            ];
+            for query in asset.certified_queries.iter().flatten() {
+                labels.push(request_hash("GET", Some(query), &[], params).to_vec());
+            }
+            labels
+        }
+        None => vec![vec![]],
+    };
+    let expression = expression(asset);
+    update_tree(|tree| {
+        for expr_path in expr_paths {
+            remove(tree, &expr_path);
+            for request_label in request_labels.iter() {
+                for hash in response_hashes.iter() {
+                    insert(
+                        tree,
+                        &leaf_path(&expr_path, &expression, request_label, hash),
+                    );
+                }
+            }
+            for hash in error_hashes.iter() {
+                insert(tree, &leaf_path(&expr_path, ERROR_EXPRESSION, &[], hash));
+            }
+        }
+    });
+}
+
+/// Removes the responses certified for `key`.
+#[cfg(feature = "upload")]
+pub(crate) fn remove_key(key: &str) {
+    let is_fallback_file = STATE.with(|s| s.config.borrow().fallback_file == key);
+    update_tree(|tree| {
+        remove(tree, &exact_path(key));
+        if is_fallback_file {
+            remove(tree, &wildcard_path());
+        }
+    });
+    if is_fallback_file {
+        certify_not_found();
+    }
+}
+
+/// Removes every certified response but the 404 one.
+pub(crate) fn clear() {
+    update_tree(|tree| *tree = RbTree::new());
+    certify_not_found();
+}
+
+/// Certifies the 404 response at `<*>`, in place of the fallback file if it
+/// isn't certified, e.g. after the configuration changed.
+pub(crate) fn certify_not_found() {
+    let fallback_file = STATE.with(|s| s.config.borrow().fallback_file.clone());
+    let not_found_hash = error_response_hash(&build_404());
+    update_tree(|tree| {
+        if children(tree, &exact_path(&fallback_file)).is_some() {
+            return;
+        }
+        let wildcard = wildcard_path();
+        remove(tree, &wildcard);
+        insert(
+            tree,
+            &leaf_path(&wildcard, ERROR_EXPRESSION, &[], &not_found_hash),
+        );
+    });
+}
+
+/// Returns whether the response serving `resolved` to `req` is certified,
+/// which only fails for the requests with certified query parameters whose
+/// values weren't declared with the asset.
+pub(crate) fn is_request_certified(
+    path: &str,
+    resolved: &ResolvedAsset,
+    req: &HttpRequest,
+) -> bool {
+    let leaf_path = resolved_leaf_path(path, resolved, req);
+    let (leaf, parent) = leaf_path.split_last().unwrap();
+    EXPR_TREE.with(|t| {
+        children(&t.borrow(), parent).map_or(false, |children| children.get(leaf).is_some())
+    })
+}
+
+/// Traps if more than [MAX_CERTIFIED_QUERIES] query strings are declared.
+#[cfg(feature = "upload")]
+pub(crate) fn validate_certified_queries(queries: Option<&[String]>) {
+    if queries.map_or(0, |queries| queries.len()) > MAX_CERTIFIED_QUERIES {
+        trap(&format!(
+            "at most {} certified queries can be declared",
+            MAX_CERTIFIED_QUERIES
+        ));
+    }
+}
+
+pub(crate) fn has_certified_query_params(asset: &Asset) -> bool {
+    certified_query_params(asset).is_some()
+}
+
+/// Builds the `IC-Certificate` header proving `resolved`, the asset served
+/// for `path` in response to `req`. With `with_v1`, the header also proves
+/// the body the v1 way.
+pub(crate) fn certificate_header(
+    path: &str,
+    resolved: &ResolvedAsset,
+    req: &HttpRequest,
+    with_v1: bool,
+) -> HeaderField {
+    let expr_path = resolved_expr_path(path, resolved);
+    let leaf_path = resolved_leaf_path(path, resolved, req);
+    build_header(path, Some(resolved), expr_path, leaf_path, with_v1)
+}
+
+/// Builds the `IC-Certificate` header proving `response`, an error response
+/// served for `path` in place of `resolved`, or because no asset answers it.
+/// With `with_v1`, the header also carries the v1 proof of `path`.
+pub(crate) fn error_certificate_header(
+    path: &str,
+    resolved: Option<&ResolvedAsset>,
+    response: &HttpResponse,
+    with_v1: bool,
+) -> HeaderField {
+    let expr_path = match resolved {
+        Some(r) => resolved_expr_path(path, r),
+        // An asset without the requested encoding still has its own path.
+        None if EXPR_TREE.with(|t| children(&t.borrow(), &exact_path(path)).is_some()) => {
+            exact_path(path)
+        }
+        None => wildcard_path(),
+    };
+    let leaf_path = leaf_path(
+        &expr_path,
+        ERROR_EXPRESSION,
+        &[],
+        &error_response_hash(response),
+    );
+    build_header(path, resolved, expr_path, leaf_path, with_v1)
+}
+
+fn build_header(
+    path: &str,
+    resolved: Option<&ResolvedAsset>,
+    expr_path: Vec<Vec<u8>>,
+    leaf_path: Vec<Vec<u8>>,
+    with_v1: bool,
+) -> HeaderField {
+    let tree = EXPR_TREE.with(|t| {
+        let tree = t.borrow();
+        let mut witness = witness(&tree, &leaf_path);
+        if expr_path == wildcard_path() {
+            for more_specific in more_specific_paths(path) {
+                witness = merge_hash_trees(witness, self::witness(&tree, &more_specific));
+            }
+        }
+        if with_v1 {
+            let assets_label = assets_label();
+            with_asset_witness(path, resolved, |v1_witness| {
+                CERTIFIED_TREES.with(|t| {
+                    let trees = t.borrow();
+                    serialize_tree(merge_hash_trees(
+                        trees.witness(&assets_label, v1_witness),
+                        trees.witness(EXPR_LABEL, witness),
+                    ))
+                })
+            })
+        } else {
+            subtree_witness(EXPR_LABEL, witness)
+        }
+    });
+    let mut labels = vec!["http_expr".to_string()];
+    labels.extend(
+        expr_path
+            .iter()
+            .map(|label| String::from_utf8_lossy(label).into_owned()),
+    );
+    let expr_path = serde_cbor::to_vec(&labels).unwrap();
+
+    let certificate = data_certificate().unwrap_or_else(|| trap("no data certificate available"));
+    (
+        "IC-Certificate".to_string(),
+        format!(
+            "certificate=:{}:, tree=:{}:, expr_path=:{}:, version=2",
+            base64::encode(&certificate),
+            base64::encode(&tree),
+            base64::encode(&expr_path)
+        ),
+    )
+}
+
+fn update_tree(f: impl FnOnce(&mut ExprTree)) {
+    EXPR_TREE.with(|t| {
+        let mut tree = t.borrow_mut();
+        f(&mut tree);
+        let root_hash = if tree.is_empty() {
+            None
+        } else {
+            Some(tree.root_hash())
+        };
+        set_subtree_root(EXPR_LABEL, root_hash);
+    })
+}
+
+/// The segments of `path`, e.g. `["img", "logo.png"]` for `/img/logo.png`.
+fn segments(path: &str) -> Vec<Vec<u8>> {
+    path.strip_prefix('/')
+        .unwrap_or(path)
+        .split('/')
+        .map(|segment| segment.as_bytes().to_vec())
+        .collect()
+}
+
+fn wildcard_path() -> Vec<Vec<u8>> {
+    vec![WILDCARD_MATCH.as_bytes().to_vec()]
+}
+
+fn exact_path(path: &str) -> Vec<Vec<u8>> {
+    let mut expr_path = segments(path);
+    expr_path.push(EXACT_MATCH.as_bytes().to_vec());
+    expr_path
+}
+
+/// The expression paths that would take precedence over the root wildcard
+/// for `path`: the exact path and the wildcards of its ancestors.
+fn more_specific_paths(path: &str) -> Vec<Vec<Vec<u8>>> {
+    let segments = segments(path);
+    let mut paths = vec![exact_path(path)];
+    for len in 1..=segments.len() {
+        let mut wildcard = segments[..len].to_vec();
+        wildcard.push(WILDCARD_MATCH.as_bytes().to_vec());
+        paths.push(wildcard);
+    }
+    paths
+}
+
+fn resolved_expr_path(path: &str, resolved: &ResolvedAsset) -> Vec<Vec<u8>> {
+    if resolved.is_fallback {
+        wildcard_path()
+    } else {
+        exact_path(path)
+    }
+}
+
+fn resolved_leaf_path(path: &str, resolved: &ResolvedAsset, req: &HttpRequest) -> Vec<Vec<u8>> {
+    let response_hash = response_hash(
+        200,
+        &asset_headers(resolved.asset, resolved.enc_name, resolved.enc),
+        &resolved.asset.certified_headers.clone().unwrap_or_default(),
+        &resolved.enc.sha256,
+    );
+    leaf_path(
+        &resolved_expr_path(path, resolved),
+        &expression(resolved.asset),
+        &request_label(resolved.asset, req),
+        &response_hash,
+    )
+}
+
+fn leaf_path(
+    expr_path: &[Vec<u8>],
+    expression: &str,
+    request_label: &[u8],
+    response_hash: &Hash,
+) -> Vec<Vec<u8>> {
+    let mut path = expr_path.to_vec();
+    path.push(hash_bytes(expression.as_bytes()).to_vec());
+    path.push(request_label.to_vec());
+    path.push(response_hash.to_vec());
+    path
+}
+
+fn certified_query_params(asset: &Asset) -> Option<&[String]> {
+    asset
+        .certified_query_params
+        .as_deref()
+        .filter(|params| !params.is_empty())
+}
+
+/// The expression of the responses serving `asset`, which certifies the
+/// certified query parameters of the asset, if it has any, and no other part
+/// of the request, and its certified headers.
+fn expression(asset: &Asset) -> String {
+    let request_certification = match certified_query_params(asset) {
+        Some(params) => format!(
+            "request_certification:RequestCertification{{certified_request_headers:[],certified_query_parameters:[{}]}}",
+            string_list(params)
+        ),
+        None => "no_request_certification:Empty{}".to_string(),
+    };
+    let response_certification = match asset.certified_headers.clone().unwrap_or_default() {
+        CertifiedHeaders::AllExcept(names) => format!(
+            "response_header_exclusions:ResponseHeaderList{{headers:[{}]}}",
+            string_list(&names)
+        ),
+        CertifiedHeaders::Only(names) => format!(
+            "certified_response_headers:ResponseHeaderList{{headers:[{}]}}",
+            string_list(&names)
+        ),
+    };
+    format!(
+        "default_certification(ValidationArgs{{certification:Certification{{{},response_certification:ResponseCertification{{{}}}}}}})",
+        request_certification, response_certification
+    )
+}
+
+/// `items` as a list of strings of the expression language.
+fn string_list(items: &[String]) -> String {
+    items
+        .iter()
+        .map(|item| format!("\"{}\"", item))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// The label of `req` under the expression of `asset`: the request hash if
+/// the asset has certified query parameters, empty otherwise.
+fn request_label(asset: &Asset, req: &HttpRequest) -> Vec<u8> {
+    match certified_query_params(asset) {
+        Some(params) => {
+            let query = req.url.find('?').map(|i| &req.url[i + 1..]);
+            request_hash(&req.method, query, &req.body, params).to_vec()
+        }
+        None => vec![],
+    }
+}
+
+fn request_hash(method: &str, query: Option<&str>, body: &[u8], params: &[String]) -> Hash {
+    let mut headers = vec![(":ic-cert-method".to_string(), method.as_bytes().to_vec())];
+    // Only the certified parameters are hashed, so others such as cache
+    // busters don't change the hash.
+    if let Some(query) = query {
+        let filtered_query = query
+            .split('&')
+            .filter(|pair| {
+                let name = pair.split('=').next().unwrap_or("");
+                params.iter().any(|param| param == name)
+            })
+            .collect::<Vec<_>>()
+            .join("&");
+        headers.push((
+            ":ic-cert-query".to_string(),
+            hash_bytes(filtered_query.as_bytes()).to_vec(),
+        ));
+    }
+    let mut hash = sha2::Sha256::new();
+    hash.update(map_hash(headers));
+    hash.update(hash_bytes(body));
+    hash.finalize().into()
+}
+
+fn response_hashes(asset: &Asset) -> Vec<Hash> {
+    asset
+        .encodings
+        .iter()
+        .map(|(enc_name, enc)| {
+            response_hash(
+                200,
+                &asset_headers(asset, enc_name, enc),
+                &asset.certified_headers.clone().unwrap_or_default(),
+                &enc.sha256,
+            )
+        })
+        .collect()
+}
+
+/// The hash of `response`, an error response whose headers are all
+/// certified.
+fn error_response_hash(response: &HttpResponse) -> Hash {
+    response_hash(
+        response.status_code,
+        &response.headers,
+        &CertifiedHeaders::default(),
+        &hash_bytes(&response.body),
+    )
+}
+
+fn insert(tree: &mut ExprTree, path: &[Vec<u8>]) {
+    let (label, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    if rest.is_empty() {
+        tree.insert(label.clone(), ExprNode::Leaf);
+    } else if tree.get(label).is_some() {
+        tree.modify(label, |child| {
+            if let ExprNode::Branch(children) = child {
+                insert(children, rest);
+            }
+        });
+    } else {
+        let mut children = RbTree::new();
+        insert(&mut children, rest);
+        tree.insert(label.clone(), ExprNode::Branch(children));
+    }
+}
+
+/// Removes the node at `path` along with the branches it leaves empty.
+fn remove(tree: &mut ExprTree, path: &[Vec<u8>]) {
+    let (label, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    if rest.is_empty() {
+        tree.delete(label);
+        return;
+    }
+    let mut is_empty = false;
+    tree.modify(label, |child| {
+        if let ExprNode::Branch(children) = child {
+            remove(children, rest);
+            is_empty = children.is_empty();
+        }
+    });
+    if is_empty {
+        tree.delete(label);
+    }
+}
+
+/// The children of the branch at `path`, if there is one.
+fn children<'a>(tree: &'a ExprTree, path: &[Vec<u8>]) -> Option<&'a ExprTree> {
+    match path.split_first() {
+        Some((label, rest)) => match tree.get(label)? {
+            ExprNode::Branch(children) => self::children(children, rest),
+            ExprNode::Leaf => None,
+        },
+        None => Some(tree),
+    }
+}
+
+/// Proves the node at `path`, or its absence.
+fn witness<'a>(tree: &'a ExprTree, path: &[Vec<u8>]) -> HashTree<'a> {
+    match path.split_first() {
+        Some((label, rest)) => tree.nested_witness(label, |child| match child {
+            ExprNode::Branch(children) => witness(children, rest),
+            ExprNode::Leaf => child.as_hash_tree(),
+        }),
+        None => tree.as_hash_tree(),
+    }
+}
+
+/// The hash of a response with `status_code`, `headers`, of which only the
+/// `certified_headers` count, and a body hashing to `body_sha256`.
+fn response_hash(
+    status_code: u16,
+    headers: &[HeaderField],
+    certified_headers: &CertifiedHeaders,
+    body_sha256: &Hash,
+) -> Hash {
+    // The status code is hashed as the `:ic-cert-status` pseudo-header.
+    let mut entries: Vec<(String, Vec<u8>)> = headers
+        .iter()
+        .filter(|(name, _)| certified_headers.is_certified(name))
+        .map(|(name, value)| (name.to_ascii_lowercase(), value.as_bytes().to_vec()))
+        .collect();
+    entries.push((":ic-cert-status".to_string(), leb128(status_code as u64)));
+
+    let mut hash = sha2::Sha256::new();
+    hash.update(map_hash(entries));
+    hash.update(body_sha256);
+    hash.finalize().into()
+}
+
+/// The representation-independent hash of a map of headers, each value being
+/// the encoding of a string, a blob or a number.
+fn map_hash(entries: Vec<(String, Vec<u8>)>) -> Hash {
+    let mut entries: Vec<Vec<u8>> = entries
+        .into_iter()
+        .map(|(name, value)| {
+            let mut entry = hash_bytes(name.as_bytes()).to_vec();
+            entry.extend_from_slice(&hash_bytes(&value));
+            entry
+        })
+        .collect();
+    entries.sort();
+    hash_bytes(&entries.concat())
+}
+
+fn leb128(mut n: u64) -> Vec<u8> {
+    let mut bytes = vec![];
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::AssetEncoding;
+
+    fn has_leaf(path: &[Vec<u8>]) -> bool {
+        let (leaf, parent) = path.split_last().unwrap();
+        EXPR_TREE.with(|t| {
+            children(&t.borrow(), parent).map_or(false, |children| children.get(leaf).is_some())
+        })
+    }
+
+    fn error_leaf(expr_path: &[Vec<u8>], response: &HttpResponse) -> Vec<Vec<u8>> {
+        leaf_path(
+            expr_path,
+            ERROR_EXPRESSION,
+            &[],
+            &error_response_hash(response),
+        )
+    }
+
+    fn asset(protected: bool) -> Asset {
+        let mut asset = Asset {
+            content_type: "text/plain".to_string(),
+            protected: Some(protected),
+            ..Asset::default()
+        };
+        asset
+            .encodings
+            .insert("identity".to_string(), AssetEncoding::default());
+        asset
+    }
+
+    fn reset() {
+        ic_cdk::api::mock::reset();
+        STATE.with(|s| s.config.replace(Default::default()));
+        clear();
+    }
+
+    #[test]
+    fn leb128_matches_the_reference_encoding() {
+        assert_eq!(leb128(0), vec![0x00]);
+        assert_eq!(leb128(127), vec![0x7f]);
+        assert_eq!(leb128(128), vec![0x80, 0x01]);
+        assert_eq!(leb128(624485), vec![0xe5, 0x8e, 0x26]);
+    }
+
+    #[test]
+    fn map_hash_matches_the_request_id_example() {
+        // The example of the representation-independent hash in the
+        // interface specification.
+        let hash = map_hash(vec![
+            ("request_type".to_string(), b"call".to_vec()),
+            ("sender".to_string(), vec![0x04]),
+            ("ingress_expiry".to_string(), leb128(1685570400000000000)),
+            (
+                "canister_id".to_string(),
+                vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0xd2],
+            ),
+            ("method_name".to_string(), b"hello".to_vec()),
+            ("arg".to_string(), b"DIDL\x00\xfd*".to_vec()),
+        ]);
+        assert_eq!(
+            hex::encode(hash),
+            "1d1091364d6bb8a6c16b203ee75467d59ead468f523eb058880ae8ec80e2b101"
+        );
+    }
+
+    #[test]
+    fn response_hash_covers_the_status_and_certified_headers() {
+        let headers = vec![
+            ("Content-Type".to_string(), "text/plain".to_string()),
+            (
+                "IC-CertificateExpression".to_string(),
+                "default_certification(ValidationArgs{no_certification:Empty{}})".to_string(),
+            ),
+            ("IC-Certificate".to_string(), "never certified".to_string()),
+        ];
+        let hash = response_hash(
+            404,
+            &headers,
+            &CertifiedHeaders::default(),
+            &hash_bytes(b"not found"),
+        );
+        assert_eq!(
+            hex::encode(hash),
+            "0f4e94349de0b1edc0b11e186e31147ff1b91023c96fb8222744969c9c360fde"
+        );
+        // `IC-CertificateExpression` is always certified, `IC-Certificate`
+        // never is.
+        let only_content_type = CertifiedHeaders::Only(vec!["content-type".to_string()]);
+        let body_sha256 = hash_bytes(b"not found");
+        assert_eq!(
+            response_hash(404, &headers, &only_content_type, &body_sha256),
+            hash
+        );
+        let without_content_type = CertifiedHeaders::AllExcept(vec!["content-type".to_string()]);
+        assert_ne!(
+            response_hash(404, &headers, &without_content_type, &body_sha256),
+            hash
+        );
+        assert_ne!(
+            response_hash(200, &headers, &CertifiedHeaders::default(), &body_sha256),
+            hash
+        );
+    }
+
+    #[test]
+    fn not_found_is_certified_without_a_fallback_file() {
+        reset();
+        let not_found = error_leaf(&wildcard_path(), &build_404());
+        assert!(has_leaf(&not_found));
+
+        certify("/index.html", &asset(false));
+        assert!(!has_leaf(&not_found));
+
+        remove_key("/index.html");
+        assert!(has_leaf(&not_found));
+    }
+
+    #[test]
+    fn refusals_are_certified_with_the_asset() {
+        reset();
+        let forbidden = error_leaf(&exact_path("/a.txt"), &crate::build_403());
+        certify("/a.txt", &asset(true));
+        assert!(has_leaf(&forbidden));
+
+        certify("/a.txt", &asset(false));
+        assert!(!has_leaf(&forbidden));
+    }
+
+    #[test]
+    fn authentication_failures_are_certified_once_required() {
+        reset();
+        STATE.with(|s| {
+            s.assets
+                .borrow_mut()
+                .insert("/members/a.txt".to_string(), asset(false))
+        });
+        let unauthorized = error_leaf(
+            &exact_path("/members/a.txt"),
+            &crate::build_401(crate::AUTHENTICATION_REQUIRED),
+        );
+        certify("/members/a.txt", &asset(false));
+        assert!(!has_leaf(&unauthorized));
+
+        crate::require_authentication("/members/", |_: &HttpRequest| Err("no".to_string()));
+        assert!(has_leaf(&unauthorized));
+        STATE.with(|s| s.assets.borrow_mut().clear());
+    }
+
+    #[test]
+    fn staged_assets_are_certified_as_missing() {
+        reset();
+        let key = "/.staged/a.txt";
+        certify(key, &asset(false));
+        let not_found = error_leaf(&exact_path(key), &build_404());
+        assert!(has_leaf(&not_found));
+        let requests_path = &not_found[..not_found.len() - 2];
+        EXPR_TREE.with(|t| {
+            let tree = t.borrow();
+            let expressions = children(&tree, &requests_path[..requests_path.len() - 1]).unwrap();
+            assert_eq!(expressions.iter().count(), 1);
+        });
+    }
+
+    fn request(url: &str) -> HttpRequest {
+        HttpRequest {
+            method: "GET".to_string(),
+            url: url.to_string(),
+            headers: vec![],
+            body: serde_bytes::ByteBuf::new(),
+        }
+    }
+
+    #[test]
+    fn only_declared_queries_are_certified() {
+        reset();
+        let mut lang = asset(false);
+        lang.certified_query_params = Some(vec!["lang".to_string()]);
+        lang.certified_queries = Some(vec!["lang=fr".to_string()]);
+        certify("/a.txt", &lang);
+        let enc = &lang.encodings["identity"];
+        let resolved = ResolvedAsset {
+            key: "/a.txt",
+            asset: &lang,
+            enc_name: "identity",
+            enc,
+            is_fallback: false,
+        };
+        let is_certified = |url: &str| is_request_certified("/a.txt", &resolved, &request(url));
+
+        assert!(is_certified("/a.txt"));
+        assert!(is_certified("/a.txt?"));
+        assert!(is_certified("/a.txt?v=123"));
+        assert!(is_certified("/a.txt?lang=fr"));
+        assert!(is_certified("/a.txt?v=123&lang=fr"));
+        assert!(!is_certified("/a.txt?lang=de"));
+        assert!(has_leaf(&error_leaf(&exact_path("/a.txt"), &build_404())));
+    }
+
+    #[test]
+    #[should_panic(expected = "at most 64 certified queries")]
+    fn rejects_too_many_declared_queries() {
+        let queries: Vec<String> = (0..65).map(|i| format!("page={}", i)).collect();
+        validate_certified_queries(Some(&queries));
+    }
+
+    #[test]
+    fn error_certificates_prove_absence_at_the_wildcard() {
+        reset();
+        ic_cdk::api::mock::set_data_certificate(Some(vec![]));
+        certify("/a.txt", &asset(false));
+        let (name, value) = error_certificate_header("/b.txt", None, &build_404(), false);
+        assert_eq!(name, "IC-Certificate");
+        let expr_path = serde_cbor::to_vec(&["http_expr", "<*>"]).unwrap();
+        assert!(value.ends_with(&format!(
+            "expr_path=:{}:, version=2",
+            base64::encode(&expr_path)
+        )));
+    }
+}
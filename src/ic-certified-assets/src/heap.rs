@@ -0,0 +1,216 @@
+//! A guard turning uploads down once the heap grows past a watermark, so the
+//! canister doesn't run out of wasm memory in the middle of a commit, which
+//! would trap whatever the canister does.
+//!
+//! The wasm memory never shrinks, but the allocator reuses what was freed in
+//! it. So once the memory crosses the watermark, the content still held on
+//! the heap, in chunks and assets, estimates the usage instead. Everything
+//! else on the heap is small next to it. That content is counted as it is
+//! stored and released, so checking it doesn't scan the assets.
+
+use crate::{Asset, Key, STATE};
+use ic_cdk::api::trap_with;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+/// The code of the errors of uploads turned down.
+const HEAP_FULL: &str = "heap_full";
+
+#[cfg(target_arch = "wasm32")]
+const WASM_PAGE_SIZE: u64 = 65536;
+
+thread_local! {
+    /// The bytes of the uploaded chunks not committed yet.
+    static CHUNK_BYTES: Cell<u64> = Cell::new(0);
+    /// The bytes of content each asset keeps on the heap, as of its last
+    /// change, and their sum.
+    static ASSET_BYTES: RefCell<HashMap<Key, u64>> = RefCell::new(HashMap::new());
+    static TOTAL_ASSET_BYTES: Cell<u64> = Cell::new(0);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+thread_local! {
+    /// Stands for the size of the wasm memory outside of a canister.
+    static MEMORY_BYTES: Cell<u64> = Cell::new(0);
+}
+
+/// Traps unless `bytes` more of content keep the heap under the watermark.
+pub(crate) fn reserve(bytes: usize) {
+    if let Err(err) = check(bytes) {
+        trap_with(HEAP_FULL, &err);
+    }
+}
+
+/// Returns why `bytes` more of content would take the heap over the
+/// watermark, if they would.
+pub(crate) fn check(bytes: usize) -> Result<(), String> {
+    let watermark = STATE.with(|s| s.config.borrow().heap_watermark());
+    let bytes = bytes as u64;
+    if memory_bytes().saturating_add(bytes) <= watermark {
+        return Ok(());
+    }
+    let usage = content_bytes().saturating_add(bytes);
+    if usage <= watermark {
+        return Ok(());
+    }
+    Err(format!(
+        "the heap would hold about {} bytes of content, over the watermark of {} bytes; \
+         commit or delete assets first, or keep the content in stable memory",
+        usage, watermark
+    ))
+}
+
+/// Counts an uploaded chunk of `len` bytes.
+pub(crate) fn chunk_stored(len: usize) {
+    CHUNK_BYTES.with(|b| b.set(b.get() + len as u64));
+}
+
+/// Stops counting an uploaded chunk of `len` bytes, once it is dropped.
+pub(crate) fn chunk_dropped(len: usize) {
+    CHUNK_BYTES.with(|b| b.set(b.get().saturating_sub(len as u64)));
+}
+
+/// Counts the content `asset` keeps on the heap in place of what it kept
+/// before. Called after every change to the content of an asset.
+pub(crate) fn asset_changed(key: &str, asset: &Asset) {
+    let bytes: usize = asset
+        .encodings
+        .values()
+        .map(|enc| {
+            let content: usize = enc.content_chunks.iter().map(|c| c.len()).sum();
+            content + enc.template_source.as_ref().map_or(0, |t| t.len())
+        })
+        .sum();
+    let old = ASSET_BYTES.with(|a| a.borrow_mut().insert(key.to_string(), bytes as u64));
+    TOTAL_ASSET_BYTES.with(|t| t.set(t.get() - old.unwrap_or(0) + bytes as u64));
+}
+
+/// Stops counting the content of the asset at `key`, once it is removed.
+pub(crate) fn asset_removed(key: &str) {
+    if let Some(old) = ASSET_BYTES.with(|a| a.borrow_mut().remove(key)) {
+        TOTAL_ASSET_BYTES.with(|t| t.set(t.get() - old));
+    }
+}
+
+/// Stops counting any content, once the chunks and assets are cleared.
+pub(crate) fn clear() {
+    CHUNK_BYTES.with(|b| b.set(0));
+    ASSET_BYTES.with(|a| a.borrow_mut().clear());
+    TOTAL_ASSET_BYTES.with(|t| t.set(0));
+}
+
+#[cfg(target_arch = "wasm32")]
+fn memory_bytes() -> u64 {
+    core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn memory_bytes() -> u64 {
+    MEMORY_BYTES.with(|m| m.get())
+}
+
+/// The bytes of the uploaded chunks and of the content of the assets kept on
+/// the heap.
+fn content_bytes() -> u64 {
+    CHUNK_BYTES.with(|b| b.get()) + TOTAL_ASSET_BYTES.with(|t| t.get())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{do_delete_asset, do_store, AssetCanisterArgs, Config, DeleteAssetArguments};
+    use crate::{ChunksInfoReponse, RcBytes, StoreArg};
+    use ic_cdk::export::candid::Nat;
+    use serde_bytes::ByteBuf;
+
+    /// Sets the wasm memory over a watermark of 1000 bytes.
+    fn fill_memory() {
+        ic_cdk::api::mock::reset();
+        clear();
+        MEMORY_BYTES.with(|m| m.set(2000));
+        STATE.with(|s| {
+            s.assets.borrow_mut().clear();
+            let mut config = Config::default();
+            config.apply(AssetCanisterArgs {
+                heap_watermark: Some(1000),
+                ..AssetCanisterArgs::default()
+            });
+            s.config.replace(config);
+        });
+    }
+
+    fn store(key: &str, len: usize) {
+        do_store(StoreArg {
+            key: key.to_string(),
+            content_type: "text/plain".to_string(),
+            content_encoding: "identity".to_string(),
+            content: RcBytes::from(ByteBuf::from(vec![0; len])),
+            sha256: None,
+            expected_sha256: None,
+        });
+    }
+
+    #[test]
+    fn checks_the_memory_under_the_watermark() {
+        fill_memory();
+        MEMORY_BYTES.with(|m| m.set(400));
+        chunk_stored(900);
+        assert!(check(600).is_ok());
+        assert!(check(601).is_err());
+        clear();
+    }
+
+    #[test]
+    fn counts_the_chunks() {
+        fill_memory();
+        chunk_stored(600);
+        assert!(check(400).is_ok());
+        assert!(check(401).is_err());
+        chunk_dropped(600);
+        assert!(check(1000).is_ok());
+        clear();
+    }
+
+    #[test]
+    fn counts_the_assets() {
+        fill_memory();
+        store("/a", 300);
+        store("/b", 300);
+        assert!(check(400).is_ok());
+        assert!(check(401).is_err());
+
+        // Replacing the content counts the new one only.
+        store("/a", 100);
+        assert!(check(600).is_ok());
+        assert!(check(601).is_err());
+
+        do_delete_asset(DeleteAssetArguments {
+            key: "/b".to_string(),
+        });
+        assert!(check(900).is_ok());
+        assert!(check(901).is_err());
+        STATE.with(|s| s.assets.borrow_mut().clear());
+        clear();
+    }
+
+    #[test]
+    #[should_panic(expected = "[heap_full]")]
+    fn turns_pulls_down() {
+        fill_memory();
+        store("/a", 600);
+        crate::sync::reserve_pulled(&ChunksInfoReponse {
+            content_encoding: "identity".to_string(),
+            total_length: Nat::from(600),
+            sha256: ByteBuf::new(),
+            chunks: vec![],
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "[heap_full]")]
+    fn turns_uploads_down() {
+        fill_memory();
+        store("/a", 600);
+        store("/b", 600);
+    }
+}
@@ -0,0 +1,229 @@
+//! Hotlink protection: keeping other sites from embedding assets.
+//!
+//! A [HotlinkRule] lists the origins allowed to request the assets under a
+//! path prefix, as told by the `Origin` header or else the `Referer` one.
+//! Pages served by this canister through a boundary node gateway, e.g.
+//! `https://<canister id>.icp0.io`, are always allowed. Other origins are
+//! redirected to the placeholder asset of the rule, or get a 403 response.
+//!
+//! Both responses are certified along with the assets under the prefix, so
+//! queries answer the denied requests as well.
+
+use crate::{trap, Key, STATE};
+use ic_cdk::api::id;
+use ic_cdk::export::candid::{CandidType, Deserialize};
+
+/// The domains under which the gateways serve canisters as
+/// `<canister id>.<domain>`.
+const GATEWAY_DOMAINS: &[&str] = &[
+    "ic0.app",
+    "raw.ic0.app",
+    "icp0.io",
+    "raw.icp0.io",
+    "localhost",
+];
+
+/// Restricts which sites may embed the assets under a path prefix.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct HotlinkRule {
+    /// The paths the rule applies to, e.g. `/media/`. The rule with the
+    /// longest matching prefix applies.
+    pub prefix: String,
+    /// The origins allowed besides this canister's, e.g.
+    /// `https://example.com`.
+    pub allowed_origins: Vec<String>,
+    /// Whether requests with neither an `Origin` nor a `Referer` header are
+    /// allowed, e.g. when the URL is typed in or the referrer policy hides it.
+    pub allow_missing: bool,
+    /// The asset other origins are redirected to instead of getting a 403
+    /// response. It must be outside of `prefix`.
+    pub placeholder: Option<Key>,
+}
+
+/// What to do with a request denied by a rule.
+pub(crate) enum Denial {
+    Placeholder(Key),
+    Forbidden,
+}
+
+/// Returns how to answer a request for `path` with `headers`, if a rule
+/// denies it.
+pub(crate) fn check(path: &str, headers: &[(String, String)]) -> Option<Denial> {
+    with_rule(path, |rule| {
+        let allowed = match request_origin(headers) {
+            Some(origin) => is_own_origin(&origin) || is_listed(rule, &origin),
+            None => rule.allow_missing,
+        };
+        if allowed {
+            None
+        } else {
+            Some(denial(rule))
+        }
+    })
+}
+
+/// Returns how the rule applying to `path`, if there is one, answers the
+/// requests it denies.
+pub(crate) fn denial_for(path: &str) -> Option<Denial> {
+    with_rule(path, |rule| Some(denial(rule)))
+}
+
+/// Traps if the placeholder of a rule is under its own prefix, which would
+/// redirect the denied requests to themselves.
+pub(crate) fn validate(rules: &[HotlinkRule]) {
+    for rule in rules {
+        if let Some(placeholder) = &rule.placeholder {
+            if placeholder.starts_with(&rule.prefix) {
+                trap(&format!(
+                    "the placeholder {} is under the prefix {} of its hotlink rule",
+                    placeholder, rule.prefix
+                ));
+            }
+        }
+    }
+}
+
+fn with_rule<R>(path: &str, f: impl FnOnce(&HotlinkRule) -> Option<R>) -> Option<R> {
+    STATE.with(|s| {
+        let config = s.config.borrow();
+        let rule = config
+            .hotlink_rules
+            .iter()
+            .flatten()
+            .filter(|rule| path.starts_with(&rule.prefix))
+            .max_by_key(|rule| rule.prefix.len())?;
+        f(rule)
+    })
+}
+
+fn denial(rule: &HotlinkRule) -> Denial {
+    match &rule.placeholder {
+        Some(key) => Denial::Placeholder(key.clone()),
+        None => Denial::Forbidden,
+    }
+}
+
+/// The origin of the request, from the `Origin` header or else the `Referer`
+/// one, as `scheme://host[:port]`.
+fn request_origin(headers: &[(String, String)]) -> Option<String> {
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim())
+            .filter(|value| !value.is_empty() && *value != "null")
+    };
+    if let Some(origin) = header("Origin") {
+        return Some(origin.to_ascii_lowercase());
+    }
+    let referer = header("Referer")?;
+    let host_start = referer.find("://")? + 3;
+    let origin = match referer[host_start..].find('/') {
+        Some(i) => &referer[..host_start + i],
+        None => referer,
+    };
+    Some(origin.to_ascii_lowercase())
+}
+
+fn is_own_origin(origin: &str) -> bool {
+    is_canister_origin(origin, &id().to_text())
+}
+
+/// Returns whether `origin` is a gateway domain serving `canister_id`. Any
+/// other domain starting with the id, such as `<canister id>.example.com`,
+/// may be anyone's.
+fn is_canister_origin(origin: &str, canister_id: &str) -> bool {
+    let host = match origin.find("://") {
+        Some(i) => &origin[i + 3..],
+        None => origin,
+    };
+    let host = match host.rfind(':') {
+        Some(i) if host[i + 1..].bytes().all(|b| b.is_ascii_digit()) => &host[..i],
+        _ => host,
+    };
+    match host.strip_prefix(canister_id) {
+        Some(rest) => rest
+            .strip_prefix('.')
+            .map_or(false, |domain| GATEWAY_DOMAINS.contains(&domain)),
+        None => false,
+    }
+}
+
+fn is_listed(rule: &HotlinkRule, origin: &str) -> bool {
+    rule.allowed_origins
+        .iter()
+        .any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CANISTER_ID: &str = "rrkah-fqaaa-aaaaa-aaaaq-cai";
+
+    #[test]
+    fn gateway_origins_are_own() {
+        for origin in [
+            "https://rrkah-fqaaa-aaaaa-aaaaq-cai.ic0.app",
+            "https://rrkah-fqaaa-aaaaa-aaaaq-cai.raw.ic0.app",
+            "https://rrkah-fqaaa-aaaaa-aaaaq-cai.icp0.io",
+            "https://rrkah-fqaaa-aaaaa-aaaaq-cai.raw.icp0.io",
+            "http://rrkah-fqaaa-aaaaa-aaaaq-cai.localhost:8000",
+        ]
+        .iter()
+        {
+            assert!(is_canister_origin(origin, CANISTER_ID), "{}", origin);
+        }
+    }
+
+    #[test]
+    fn other_origins_are_not_own() {
+        for origin in [
+            "https://rrkah-fqaaa-aaaaa-aaaaq-cai.evil.com",
+            "https://rrkah-fqaaa-aaaaa-aaaaq-cai.ic0.app.evil.com",
+            "https://rrkah-fqaaa-aaaaa-aaaaq-cai.evil.icp0.io",
+            "https://rrkah-fqaaa-aaaaa-aaaaq-caix.icp0.io",
+            "https://ryjl3-tyaaa-aaaaa-aaaba-cai.icp0.io",
+            "https://icp0.io",
+        ]
+        .iter()
+        {
+            assert!(!is_canister_origin(origin, CANISTER_ID), "{}", origin);
+        }
+    }
+
+    fn rule(prefix: &str, placeholder: &str) -> HotlinkRule {
+        HotlinkRule {
+            prefix: prefix.to_string(),
+            allowed_origins: vec![],
+            allow_missing: false,
+            placeholder: Some(placeholder.to_string()),
+        }
+    }
+
+    #[test]
+    fn accepts_placeholders_outside_of_the_prefix() {
+        validate(&[rule("/media/", "/hotlink.png")]);
+    }
+
+    #[test]
+    #[should_panic(expected = "under the prefix /media/")]
+    fn rejects_placeholders_under_the_prefix() {
+        validate(&[rule("/media/", "/media/hotlink.png")]);
+    }
+
+    #[test]
+    fn origin_comes_from_origin_or_referer() {
+        let header = |name: &str, value: &str| vec![(name.to_string(), value.to_string())];
+        assert_eq!(
+            request_origin(&header("Origin", "https://Example.com")),
+            Some("https://example.com".to_string())
+        );
+        assert_eq!(
+            request_origin(&header("referer", "https://example.com/page?q=1")),
+            Some("https://example.com".to_string())
+        );
+        assert_eq!(request_origin(&header("Origin", "null")), None);
+        assert_eq!(request_origin(&[]), None);
+    }
+}
@@ -0,0 +1,134 @@
+//! Uploads over plain HTTP, for scripts that deploy assets with `curl` rather
+//! than Candid tooling: `PUT /key` stores the body of the request as the
+//! asset `/key`, and `DELETE /key` deletes it.
+//!
+//! `http_request` upgrades these requests, which are handled by
+//! `http_request_update`. HTTP gateways make that call anonymously, so a
+//! request has to carry a token minted by `create_upload_token`, in an
+//! `Authorization: Bearer` header or as the password of basic authentication,
+//! unless the caller itself is authorized, as when calling through an agent.
+
+use crate::authenticator::bearer_token;
+use crate::heap;
+use crate::{
+    access, build_401, build_upgrade, do_delete_asset, do_store, is_authorized, is_readonly,
+    security_headers, url_decode, DeleteAssetArguments, HttpRequest, HttpResponse, RcBytes,
+    StoreArg, READONLY, STATE,
+};
+use ic_cdk::api::in_replicated_execution;
+use ic_cdk::export::candid::{CandidType, Deserialize};
+use ic_cdk_macros::update;
+use serde_bytes::ByteBuf;
+
+/// Upload tokens are minted for this key, under a secret of their own.
+const UPLOAD_KEY: &str = "";
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct CreateUploadTokenArguments {
+    /// The time the token expires, in nanoseconds since 1970-01-01.
+    expires_at: u64,
+}
+
+/// Mints a token allowing `PUT` and `DELETE` requests for any key until
+/// `expires_at`, to be sent as `Authorization: Bearer <token>`.
+#[update(guard = "is_authorized")]
+async fn create_upload_token(arg: CreateUploadTokenArguments) -> String {
+    let secret = access::derive_secret(&access::init_secret().await, "upload");
+    access::mint(&secret, UPLOAD_KEY, arg.expires_at)
+}
+
+/// Returns whether `req` is an upload.
+pub(crate) fn is_upload(req: &HttpRequest) -> bool {
+    req.method.eq_ignore_ascii_case("PUT") || req.method.eq_ignore_ascii_case("DELETE")
+}
+
+/// Answers `req`, an upload, from `http_request_update`.
+pub(crate) fn handle(req: &HttpRequest) -> HttpResponse {
+    if !in_replicated_execution() {
+        return build_upgrade();
+    }
+    if !is_permitted(req) {
+        return build_401("an upload token is required");
+    }
+    if is_readonly() {
+        return response(503, READONLY);
+    }
+    let path = match req.url.find('?') {
+        Some(i) => &req.url[..i],
+        None => &req.url[..],
+    };
+    let key = match url_decode(path) {
+        Ok(key) => key,
+        Err(err) => return response(400, &format!("failed to decode path '{}': {}", path, err)),
+    };
+
+    if req.method.eq_ignore_ascii_case("PUT") {
+        if let Err(err) = heap::check(req.body.len()) {
+            return response(507, &err);
+        }
+        do_store(StoreArg {
+            key,
+            content_type: header(req, "Content-Type").unwrap_or_default(),
+            content_encoding: header(req, "Content-Encoding")
+                .unwrap_or_else(|| "identity".to_string()),
+            content: RcBytes::from(req.body.clone()),
+            sha256: None,
+            expected_sha256: None,
+        });
+        response(204, "")
+    } else if STATE.with(|s| s.assets.borrow().contains_key(&key)) {
+        do_delete_asset(DeleteAssetArguments { key });
+        response(204, "")
+    } else {
+        response(404, "not found")
+    }
+}
+
+/// Returns whether the caller is authorized or `req` carries a valid upload
+/// token.
+pub(crate) fn is_permitted(req: &HttpRequest) -> bool {
+    is_authorized().is_ok() || has_valid_token(req)
+}
+
+/// Accepts the token as a bearer token, or as the password of basic
+/// authentication for clients that only support that.
+fn has_valid_token(req: &HttpRequest) -> bool {
+    let secret = match STATE.with(|s| *s.access_secret.borrow()) {
+        Some(secret) => access::derive_secret(&secret, "upload"),
+        None => return false,
+    };
+    let token = match bearer_token(req) {
+        Some(token) => token.to_string(),
+        None => match basic_password(req) {
+            Some(password) => password,
+            None => return false,
+        },
+    };
+    access::verify(&secret, UPLOAD_KEY, &token)
+}
+
+fn basic_password(req: &HttpRequest) -> Option<String> {
+    let credentials = header(req, "Authorization")?
+        .strip_prefix("Basic ")
+        .and_then(|encoded| base64::decode(encoded.trim()).ok())?;
+    let credentials = String::from_utf8(credentials).ok()?;
+    let (_, password) = credentials.split_at(credentials.find(':')? + 1);
+    Some(password.to_string())
+}
+
+pub(crate) fn header(req: &HttpRequest, name: &str) -> Option<String> {
+    req.headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().to_string())
+}
+
+pub(crate) fn response(status_code: u16, body: &str) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: security_headers(),
+        body: RcBytes::from(ByteBuf::from(body)),
+        streaming_strategy: None,
+        upgrade: None,
+    }
+}
@@ -0,0 +1,98 @@
+//! Importing assets from external URLs through HTTPS outcalls.
+
+use crate::replication::format_reject;
+use crate::{
+    hash_bytes, heap, is_writable, mime, set_encoding, trap_if_readonly, HashAlgorithm, Key,
+    RcBytes, STATE,
+};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
+use ic_cdk::api::trap;
+use ic_cdk::export::candid::{CandidType, Deserialize};
+use ic_cdk_macros::{transform, update};
+use serde_bytes::ByteBuf;
+
+/// The largest response the management canister is able to return.
+const MAX_IMPORT_BYTES: u64 = 2_000_000;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct ImportFromUrlArguments {
+    url: String,
+    key: Key,
+    content_type: String,
+    /// Rejects the import if the fetched content doesn't hash to this value.
+    sha256: Option<ByteBuf>,
+    /// Defaults to, and can't exceed, the 2MB outcall response limit. Content
+    /// larger than `max_chunk_size` is stored in several chunks.
+    max_response_bytes: Option<u64>,
+}
+
+#[update(guard = "is_writable")]
+async fn import_from_url(arg: ImportFromUrlArguments) {
+    let max_response_bytes = arg.max_response_bytes.unwrap_or(MAX_IMPORT_BYTES);
+    if max_response_bytes > MAX_IMPORT_BYTES {
+        trap("max_response_bytes exceeds the outcall limit");
+    }
+
+    let request = CanisterHttpRequestArgument {
+        url: arg.url,
+        max_response_bytes: Some(max_response_bytes),
+        method: HttpMethod::GET,
+        headers: vec![],
+        body: None,
+        transform: Some(TransformContext::from_name("import_transform", vec![])),
+    };
+    let response = http_request(request)
+        .await
+        .unwrap_or_else(|err| trap(&format!("http_request failed: {}", format_reject(err))));
+
+    if response.status != 200u64 {
+        trap(&format!("unexpected status {}", response.status));
+    }
+    trap_if_readonly();
+
+    store_body(arg.key, arg.content_type, &response.body, arg.sha256);
+}
+
+/// Stores `body` as the identity encoding of `key`, in chunks of at most
+/// `max_chunk_size` bytes, since a response can be larger than a chunk.
+fn store_body(key: Key, content_type: String, body: &[u8], sha256: Option<ByteBuf>) {
+    heap::reserve(body.len());
+    STATE.with(|s| {
+        let max_chunk_size = s.config.borrow().max_chunk_size() as usize;
+        let content_chunks: Vec<RcBytes> = if body.is_empty() {
+            vec![RcBytes::from(ByteBuf::new())]
+        } else {
+            body.chunks(max_chunk_size)
+                .map(|chunk| RcBytes::from(ByteBuf::from(chunk.to_vec())))
+                .collect()
+        };
+        let chunk_hashes = content_chunks
+            .iter()
+            .map(|chunk| hash_bytes(chunk))
+            .collect();
+        let mut assets = s.assets.borrow_mut();
+        let asset = assets.entry(key.clone()).or_default();
+        asset.content_type = mime::resolve(&key, content_type);
+        set_encoding(
+            &key,
+            asset,
+            "identity".to_string(),
+            content_chunks,
+            (HashAlgorithm::Sha256, chunk_hashes),
+            sha256,
+        );
+    })
+}
+
+/// Drops the response headers, which often differ between replicas and would
+/// prevent them from agreeing on the response.
+#[transform]
+fn import_transform(arg: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        headers: vec![],
+        ..arg.response
+    }
+}
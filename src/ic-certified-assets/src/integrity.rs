@@ -0,0 +1,130 @@
+//! Self-check of the stored content against the certified hashes.
+
+use crate::{is_authorized, Asset, Key, ASSET_HASHES, STATE};
+use ic_cdk::export::candid::{CandidType, Deserialize, Nat};
+use ic_cdk_macros::query;
+use sha2::Digest;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct IntegrityIssue {
+    key: Key,
+    content_encoding: Option<String>,
+    problem: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct IntegrityReport {
+    checked_encodings: Nat,
+    issues: Vec<IntegrityIssue>,
+}
+
+/// Recomputes the hashes of the stored content and compares them with the
+/// recorded and certified ones. Only the given keys are checked if `keys` is
+/// set, which keeps the instruction count within limits on large canisters.
+#[query(guard = "is_authorized")]
+fn verify_integrity(keys: Option<Vec<Key>>) -> IntegrityReport {
+    let mut report = Report::default();
+    STATE.with(|s| {
+        let assets = s.assets.borrow();
+        match keys {
+            Some(keys) => {
+                for key in keys {
+                    match assets.get(&key) {
+                        Some(asset) => report.check_asset(&key, asset),
+                        None => report.check_uncertified(&key),
+                    }
+                }
+            }
+            None => {
+                for (key, asset) in assets.iter() {
+                    report.check_asset(key, asset);
+                }
+                ASSET_HASHES.with(|t| {
+                    for (key, _) in t.borrow().iter() {
+                        if !assets.contains_key(key) {
+                            report.issue(key, None, "certified but not stored");
+                        }
+                    }
+                });
+            }
+        }
+    });
+    IntegrityReport {
+        checked_encodings: Nat::from(report.checked_encodings),
+        issues: report.issues,
+    }
+}
+
+#[derive(Default)]
+struct Report {
+    checked_encodings: usize,
+    issues: Vec<IntegrityIssue>,
+}
+
+impl Report {
+    fn issue(&mut self, key: &str, content_encoding: Option<&str>, problem: &str) {
+        self.issues.push(IntegrityIssue {
+            key: key.to_string(),
+            content_encoding: content_encoding.map(|e| e.to_string()),
+            problem: problem.to_string(),
+        });
+    }
+
+    fn check_uncertified(&mut self, key: &str) {
+        if ASSET_HASHES.with(|t| t.borrow().get(key.as_bytes()).is_some()) {
+            self.issue(key, None, "certified but not stored");
+        }
+    }
+
+    fn check_asset(&mut self, key: &str, asset: &Asset) {
+        let certified_hash = ASSET_HASHES.with(|t| t.borrow().get(key.as_bytes()).cloned());
+        let mut certified_encodings = 0;
+
+        for (name, enc) in asset.encodings.iter() {
+            self.checked_encodings += 1;
+            let name = Some(name.as_str());
+
+            // Chunks are read one at a time, since content kept in stable
+            // memory is copied to the heap when read.
+            let mut hasher = sha2::Sha256::new();
+            let mut total_length = 0;
+            let mut chunks_match = enc
+                .chunk_hashes
+                .as_ref()
+                .map_or(true, |hashes| hashes.len() == enc.chunk_count());
+            for i in 0..enc.chunk_count() {
+                let chunk = enc.chunk(i);
+                hasher.update(&chunk);
+                total_length += chunk.len();
+                if let Some(chunk_hashes) = enc.chunk_hashes.as_ref() {
+                    chunks_match = chunks_match
+                        && chunk_hashes.get(i) == Some(&enc.chunk_hash_algorithm().hash(&chunk));
+                }
+            }
+            let sha256: [u8; 32] = hasher.finalize().into();
+            if sha256 != enc.sha256 {
+                self.issue(key, name, "content does not match its sha256");
+            }
+            if total_length != enc.total_length {
+                self.issue(key, name, "content does not match its total length");
+            }
+            if !chunks_match {
+                self.issue(key, name, "chunks do not match their hashes");
+            }
+            if enc.certified {
+                certified_encodings += 1;
+                if certified_hash != Some(enc.sha256) {
+                    self.issue(key, name, "certified hash does not match the content");
+                }
+            }
+        }
+
+        if asset.encodings.is_empty() {
+            if certified_hash.is_some() {
+                self.issue(key, None, "certified but has no content");
+            }
+        } else if certified_encodings != 1 {
+            self.issue(key, None, "not exactly one encoding is certified");
+        }
+    }
+}
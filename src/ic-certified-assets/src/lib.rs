@@ -1,53 +1,387 @@
+mod access;
+#[cfg(feature = "upload")]
+mod archive;
+mod authenticator;
+#[cfg(feature = "benchmarks")]
+mod benchmarks;
+#[cfg(any(feature = "upload", feature = "metrics"))]
+mod blake3;
+mod certification_v2;
+#[cfg(feature = "upload")]
+mod heap;
+mod hotlink;
+#[cfg(feature = "upload")]
+mod http_upload;
+#[cfg(feature = "upload")]
+mod import;
+#[cfg(feature = "metrics")]
+mod integrity;
+#[cfg(feature = "upload")]
+mod mime;
+mod notifications;
+#[cfg(any(feature = "upload", feature = "admin"))]
+mod proposals;
 mod rc_bytes;
-
-use crate::rc_bytes::RcBytes;
-use ic_cdk::api::{caller, data_certificate, set_certified_data, time, trap};
+mod replication;
+mod rope;
+mod router;
+mod stable_bytes;
+mod staging;
+#[cfg(feature = "upload")]
+mod sync;
+mod templates;
+#[cfg(feature = "webdav")]
+mod webdav;
+mod witness_cache;
+
+pub use crate::authenticator::{bearer_token, require_authentication, Authenticator};
+pub use crate::certification_v2::CertificationVersion;
+use crate::certification_v2::CertifiedHeaders;
+pub use crate::hotlink::HotlinkRule;
+use crate::notifications::Subscriber;
+pub use crate::rc_bytes::RcBytes;
+use crate::replication::Mirror;
+pub use crate::rope::RcBytesRope;
+pub use crate::router::{register_route, register_update_route, RouteHandler};
+use crate::stable_bytes::StableBytes;
+#[cfg(feature = "serve")]
+use ic_cdk::api::call::ManualReply;
+use ic_cdk::api::call::{accept_message, method_name};
+use ic_cdk::api::profiling::with_instruction_budget;
+#[cfg(feature = "upload")]
+use ic_cdk::api::time;
+#[cfg(any(feature = "serve", feature = "upload"))]
+use ic_cdk::api::trap_with;
+use ic_cdk::api::{caller, data_certificate, in_replicated_execution, set_certified_data, trap};
 use ic_cdk::export::candid::{CandidType, Deserialize, Func, Int, Nat, Principal};
-use ic_cdk_macros::{query, update};
-use ic_certified_map::{AsHashTree, Hash, HashTree, RbTree};
+use ic_cdk_macros::query;
+#[cfg(any(feature = "serve", feature = "upload", feature = "admin"))]
+use ic_cdk_macros::update;
+use ic_certified_map::{AsHashTree, Hash, HashTree, LabeledTrees, RbTree};
+#[cfg(any(feature = "serve", feature = "upload"))]
 use num_traits::ToPrimitive;
 use serde::Serialize;
 use serde_bytes::ByteBuf;
 use sha2::Digest;
 use std::cell::RefCell;
-use std::collections::HashMap;
-use std::convert::TryInto;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt;
 
 /// The amount of time a batch is kept alive. Modifying the batch
 /// delays the expiry further.
+#[cfg(feature = "upload")]
 const BATCH_EXPIRY_NANOS: u64 = 300_000_000_000;
 
-/// The order in which we pick encodings for certification.
+/// The amount of time a proposed batch is kept, 30 days, which leaves room
+/// for the voting period of an SNS proposal and its wait-for-quiet
+/// extensions. Proposing the batch again delays the expiry further.
+#[cfg(feature = "upload")]
+const PROPOSAL_EXPIRY_NANOS: u64 = 30 * 24 * 3600 * 1_000_000_000;
+
+/// The order in which we pick the encoding certified under `http_assets`.
 const ENCODING_CERTIFICATION_ORDER: &[&str] = &["identity", "gzip", "compress", "deflate", "br"];
 
-/// The file to serve if the requested file wasn't found.
+/// An error clients can tell apart by its code, as `(code, message)`.
+#[cfg(any(feature = "serve", feature = "upload"))]
+type CodedTrap = (&'static str, &'static str);
+
+#[cfg(any(feature = "serve", feature = "upload"))]
+const ASSET_NOT_FOUND: CodedTrap = ("asset_not_found", "asset not found");
+#[cfg(any(feature = "serve", feature = "upload"))]
+const ENCODING_NOT_FOUND: CodedTrap = ("encoding_not_found", "no such encoding");
+#[cfg(feature = "upload")]
+const CHUNK_NOT_FOUND: CodedTrap = ("chunk_not_found", "chunk not found");
+#[cfg(feature = "upload")]
+const CONTENT_CHANGED: CodedTrap = (
+    "content_changed",
+    "the stored content doesn't have the expected sha256",
+);
+
+/// The body of the 401 response to the requests an [Authenticator] rejects.
+const AUTHENTICATION_REQUIRED: &str = "authentication required";
+
+/// The error of the calls and uploads that would change the assets while the
+/// canister is read-only.
+#[cfg(any(feature = "upload", feature = "admin"))]
+const READONLY: &str = "the canister is read-only";
+
+/// The default file to serve if the requested file wasn't found.
 const INDEX_FILE: &str = "/index.html";
 
+/// The default maximum size of a chunk, which keeps the largest chunk
+/// comfortably within the size limit of a query response.
+#[cfg(any(feature = "upload", feature = "admin"))]
+const DEFAULT_MAX_CHUNK_SIZE: u64 = 1_900_000;
+
+/// The default maximum size of the content a patch builds. `Copy`
+/// instructions can repeat the current content, so the patch size alone
+/// doesn't bound it.
+#[cfg(any(feature = "upload", feature = "admin"))]
+const DEFAULT_MAX_PATCHED_SIZE: u64 = 64 << 20;
+
+/// The most keys `certification_stats` builds a witness for to measure their
+/// average size.
+#[cfg(feature = "metrics")]
+const WITNESS_SAMPLE_SIZE: usize = 1000;
+
 thread_local! {
     static STATE: State = State::default();
     static ASSET_HASHES: RefCell<AssetHashes> = RefCell::new(RbTree::new());
+    static CERTIFIED_TREES: RefCell<LabeledTrees> = RefCell::new(LabeledTrees::new());
+    /// The root hashes left to certify by [with_deferred_certification], by
+    /// label, while it runs.
+    static DEFERRED_ROOTS: RefCell<Option<BTreeMap<Vec<u8>, Option<Hash>>>> = RefCell::new(None);
 }
 
+/// The default label of the asset hashes in the certified data, which is
+/// where HTTP gateways look them up.
+const DEFAULT_ASSETS_LABEL: &str = "http_assets";
+
+/// The default heap watermark, leaving a quarter of the 4GiB of wasm memory
+/// to commits and certification.
+#[cfg(any(feature = "upload", feature = "admin"))]
+const DEFAULT_HEAP_WATERMARK: u64 = 3 << 30;
+
+/// The certified paths, stored flat under the assets label because that is
+/// where HTTP gateways look them up. Witnesses still grow only with the
+/// logarithm of the number of keys since the tree is balanced; see
+/// `certification_stats` for their actual size.
 type AssetHashes = RbTree<Key, Hash>;
 
 #[derive(Default)]
 struct State {
     assets: RefCell<HashMap<Key, Asset>>,
 
+    #[cfg(feature = "upload")]
     chunks: RefCell<HashMap<ChunkId, Chunk>>,
+    #[cfg(feature = "upload")]
     next_chunk_id: RefCell<ChunkId>,
 
+    #[cfg(feature = "upload")]
     batches: RefCell<HashMap<BatchId, Batch>>,
+    #[cfg(feature = "upload")]
     next_batch_id: RefCell<BatchId>,
 
     authorized: RefCell<Vec<Principal>>,
+
+    config: RefCell<Config>,
+
+    mirrors: RefCell<BTreeMap<Principal, Mirror>>,
+
+    subscribers: RefCell<Vec<Subscriber>>,
+
+    fallback_handler: RefCell<Option<FallbackHandler>>,
+
+    template_variables: RefCell<BTreeMap<String, String>>,
+
+    access_secret: RefCell<Option<Hash>>,
+
+    /// The empty collections created over WebDAV, as prefixes ending in `/`.
+    collections: RefCell<BTreeSet<Key>>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct StableState {
     authorized: Vec<Principal>,
     stable_assets: HashMap<String, Asset>,
+    config: Option<Config>,
+    mirrors: Option<BTreeMap<Principal, Mirror>>,
+    subscribers: Option<Vec<Subscriber>>,
+    template_variables: Option<BTreeMap<String, String>>,
+    access_secret: Option<Hash>,
+    collections: Option<BTreeSet<Key>>,
+}
+
+/// Arguments accepted by [init] and [post_upgrade] to configure the asset
+/// canister.
+///
+/// On install, fields left as `None` take their default value. On upgrade,
+/// fields left as `None` keep the value they had before the upgrade.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct AssetCanisterArgs {
+    /// Principals allowed to modify assets, in addition to the installer.
+    pub authorized: Option<Vec<Principal>>,
+    /// The file to serve when the requested one doesn't exist.
+    pub fallback_file: Option<String>,
+    /// Headers added to every HTTP response, e.g. `Content-Security-Policy`.
+    pub security_headers: Option<Vec<(String, String)>>,
+    /// Maximum number of batches that can be open at the same time.
+    pub max_batches: Option<u64>,
+    /// Maximum number of uncommitted chunks that can be stored at the same time.
+    pub max_chunks: Option<u64>,
+    /// Maximum size of a chunk in bytes, 1.9MB by default.
+    pub max_chunk_size: Option<u64>,
+    /// How the `ETag` header of asset responses is rendered.
+    pub etag_format: Option<EtagFormat>,
+    /// Restricts the sites that may embed the assets, see [HotlinkRule].
+    pub hotlink_rules: Option<Vec<HotlinkRule>>,
+    /// Content types by file extension, e.g. `("md", "text/markdown")`, used
+    /// in place of the built-in ones for uploads that don't give a specific
+    /// content type.
+    pub mime_types: Option<Vec<(String, String)>>,
+    /// The certificate asset responses carry, v1 by default. Changing it
+    /// certifies every asset again.
+    pub certification_version: Option<CertificationVersion>,
+    /// The label the asset hashes are certified under, `http_assets` by
+    /// default. HTTP gateways only verify v1 certificates under that label,
+    /// so another one suits canisters whose clients verify the assets
+    /// themselves, e.g. because `http_assets` is taken by their own data.
+    pub assets_label: Option<String>,
+    /// Rejects the calls and HTTP requests that change the assets, while they
+    /// are still served, e.g. during an audit or a migration. Also set by
+    /// `set_readonly`.
+    pub readonly: Option<bool>,
+    /// The bytes of heap beyond which `create_chunk`, `store` and the other
+    /// uploads are turned down, 3GiB by default.
+    pub heap_watermark: Option<u64>,
+    /// Proves the fallback file without proving that the requested path is
+    /// absent, which keeps the `IC-Certificate` header small when the keys
+    /// are long. A replica could then serve the fallback file in place of any
+    /// asset, so this is off by default. Only the v1 proof is pruned: v2
+    /// gateways require the proof of absence.
+    pub prune_fallback_witness: Option<bool>,
+    /// Maximum size in bytes of the content `patch_asset_content` builds,
+    /// 64MiB by default.
+    pub max_patched_size: Option<u64>,
+}
+
+/// How the `ETag` header of asset responses is rendered from the sha256 of
+/// the served encoding, e.g. to keep the tags a CDN or service worker
+/// already cached. By default, the tag is strong and holds the full hash.
+#[derive(Clone, Debug, Default, PartialEq, Eq, CandidType, Deserialize)]
+pub struct EtagFormat {
+    /// Marks the tag as weak, as in `W/"<hash>"`.
+    pub weak: bool,
+    /// Keeps only the first hex digits of the hash, from 1 to 64.
+    pub hex_digits: Option<u8>,
+}
+
+impl EtagFormat {
+    fn render(&self, sha256: &Hash) -> String {
+        let mut hash = hex::encode(sha256);
+        if let Some(digits) = self.hex_digits {
+            hash.truncate(digits as usize);
+        }
+        if self.weak {
+            format!("W/\"{}\"", hash)
+        } else {
+            format!("\"{}\"", hash)
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct Config {
+    fallback_file: String,
+    security_headers: Vec<HeaderField>,
+    max_batches: Option<u64>,
+    max_chunks: Option<u64>,
+    max_chunk_size: Option<u64>,
+    etag_format: Option<EtagFormat>,
+    hotlink_rules: Option<Vec<HotlinkRule>>,
+    mime_types: Option<Vec<(String, String)>>,
+    certification_version: Option<CertificationVersion>,
+    assets_label: Option<String>,
+    readonly: Option<bool>,
+    heap_watermark: Option<u64>,
+    prune_fallback_witness: Option<bool>,
+    max_patched_size: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            fallback_file: INDEX_FILE.to_string(),
+            security_headers: vec![],
+            max_batches: None,
+            max_chunks: None,
+            max_chunk_size: None,
+            etag_format: None,
+            hotlink_rules: None,
+            mime_types: None,
+            certification_version: None,
+            assets_label: None,
+            readonly: None,
+            heap_watermark: None,
+            prune_fallback_witness: None,
+            max_patched_size: None,
+        }
+    }
+}
+
+impl Config {
+    fn apply(&mut self, args: AssetCanisterArgs) {
+        if let Some(fallback_file) = args.fallback_file {
+            self.fallback_file = fallback_file;
+        }
+        if let Some(security_headers) = args.security_headers {
+            self.security_headers = security_headers;
+        }
+        if args.max_batches.is_some() {
+            self.max_batches = args.max_batches;
+        }
+        if args.max_chunks.is_some() {
+            self.max_chunks = args.max_chunks;
+        }
+        if args.max_chunk_size.is_some() {
+            self.max_chunk_size = args.max_chunk_size;
+        }
+        if let Some(etag_format) = args.etag_format {
+            if let Some(digits) = etag_format.hex_digits {
+                if digits == 0 || digits > 64 {
+                    trap("etag_format.hex_digits must be between 1 and 64");
+                }
+            }
+            self.etag_format = Some(etag_format);
+        }
+        if let Some(hotlink_rules) = args.hotlink_rules {
+            hotlink::validate(&hotlink_rules);
+            self.hotlink_rules = Some(hotlink_rules);
+        }
+        if args.mime_types.is_some() {
+            self.mime_types = args.mime_types;
+        }
+        if args.certification_version.is_some() {
+            self.certification_version = args.certification_version;
+        }
+        if let Some(assets_label) = args.assets_label {
+            if assets_label.as_bytes() == certification_v2::EXPR_LABEL {
+                trap("http_expr is reserved for the certified responses");
+            }
+            self.assets_label = Some(assets_label);
+        }
+        if args.readonly.is_some() {
+            self.readonly = args.readonly;
+        }
+        if args.heap_watermark.is_some() {
+            self.heap_watermark = args.heap_watermark;
+        }
+        if args.prune_fallback_witness.is_some() {
+            self.prune_fallback_witness = args.prune_fallback_witness;
+        }
+        if args.max_patched_size.is_some() {
+            self.max_patched_size = args.max_patched_size;
+        }
+    }
+
+    fn assets_label(&self) -> &str {
+        self.assets_label.as_deref().unwrap_or(DEFAULT_ASSETS_LABEL)
+    }
+
+    #[cfg(any(feature = "upload", feature = "admin"))]
+    fn max_chunk_size(&self) -> u64 {
+        self.max_chunk_size.unwrap_or(DEFAULT_MAX_CHUNK_SIZE)
+    }
+
+    #[cfg(any(feature = "upload", feature = "admin"))]
+    fn heap_watermark(&self) -> u64 {
+        self.heap_watermark.unwrap_or(DEFAULT_HEAP_WATERMARK)
+    }
+
+    #[cfg(any(feature = "upload", feature = "admin"))]
+    fn max_patched_size(&self) -> u64 {
+        self.max_patched_size.unwrap_or(DEFAULT_MAX_PATCHED_SIZE)
+    }
 }
 
 #[derive(Default, Clone, Debug, CandidType, Deserialize)]
@@ -55,14 +389,182 @@ struct AssetEncoding {
     modified: Timestamp,
     content_chunks: Vec<RcBytes>,
     total_length: usize,
+    /// Whether this is the encoding whose hash is certified under
+    /// `http_assets`. Every encoding is certified under `http_expr`.
     certified: bool,
     sha256: [u8; 32],
+    chunk_hashes: Option<Vec<Hash>>,
+    /// The algorithm of `chunk_hashes`, SHA-256 if `None`.
+    chunk_hash_algorithm: Option<HashAlgorithm>,
+    /// The content before rendering, if the asset is a template.
+    template_source: Option<RcBytes>,
+    /// The chunks, when they are kept in the content memory. `content_chunks`
+    /// is then empty.
+    stable_chunks: Option<Vec<StableBytes>>,
+}
+
+impl AssetEncoding {
+    #[cfg(any(feature = "serve", feature = "metrics"))]
+    fn chunk_hash_algorithm(&self) -> HashAlgorithm {
+        self.chunk_hash_algorithm.unwrap_or_default()
+    }
+
+    fn chunk_count(&self) -> usize {
+        match self.stable_chunks.as_ref() {
+            Some(chunks) => chunks.len(),
+            None => self.content_chunks.len(),
+        }
+    }
+
+    #[cfg(any(feature = "serve", feature = "upload"))]
+    fn chunk_len(&self, index: usize) -> usize {
+        match self.stable_chunks.as_ref() {
+            Some(chunks) => chunks[index].len(),
+            None => self.content_chunks[index].len(),
+        }
+    }
+
+    /// Returns the chunk at `index`, read from the content memory if needed.
+    fn chunk(&self, index: usize) -> RcBytes {
+        match self.stable_chunks.as_ref() {
+            Some(chunks) => chunks[index].read(),
+            None => self.content_chunks[index].clone(),
+        }
+    }
+
+    #[cfg(any(feature = "upload", feature = "admin"))]
+    fn set_chunks(&mut self, chunks: Vec<RcBytes>) {
+        self.release();
+        self.content_chunks = chunks;
+    }
+
+    /// Moves the chunks to the content memory, if there is one.
+    fn offload(&mut self) {
+        if self.stable_chunks.is_none() && stable_bytes::is_enabled() {
+            let chunks = std::mem::take(&mut self.content_chunks);
+            self.stable_chunks = Some(chunks.iter().map(|c| StableBytes::write(c)).collect());
+        }
+    }
+
+    /// Frees the chunks kept in the content memory.
+    #[cfg(any(feature = "upload", feature = "admin"))]
+    fn release(&mut self) {
+        for chunk in self.stable_chunks.take().into_iter().flatten() {
+            chunk.free();
+        }
+    }
 }
 
 #[derive(Default, Clone, Debug, CandidType, Deserialize)]
 struct Asset {
     content_type: String,
     encodings: HashMap<String, AssetEncoding>,
+    tags: Option<Vec<String>>,
+    template: Option<bool>,
+    /// Only served over HTTP with an access token, see [access].
+    protected: Option<bool>,
+    content_disposition: Option<ContentDisposition>,
+    /// Whether the asset is served on raw domains, which skip certificate
+    /// verification. Requests there are redirected to the certified domain
+    /// when this is `Some(false)`.
+    allow_raw_access: Option<bool>,
+    /// The query parameters v2 certification covers, the others being
+    /// ignored, see [certification_v2].
+    certified_query_params: Option<Vec<String>>,
+    /// The query strings whose responses are certified, besides the requests
+    /// without any certified parameter, e.g. `lang=fr`. Other values get a
+    /// 404 response.
+    certified_queries: Option<Vec<String>>,
+    /// The response headers v2 certification covers, all of them if `None`.
+    certified_headers: Option<CertifiedHeaders>,
+}
+
+/// Whether browsers display an asset or download it, sent as the
+/// `Content-Disposition` header.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+enum ContentDisposition {
+    Inline,
+    /// Downloads the asset, saved as `filename` if given.
+    Attachment {
+        filename: Option<String>,
+    },
+}
+
+impl ContentDisposition {
+    fn header_value(&self) -> String {
+        match self {
+            ContentDisposition::Inline => "inline".to_string(),
+            ContentDisposition::Attachment { filename: None } => "attachment".to_string(),
+            ContentDisposition::Attachment {
+                filename: Some(filename),
+            } => {
+                // Older clients read the quoted name, where anything but
+                // printable ASCII is replaced. `filename*` carries the
+                // exact name, as of RFC 6266.
+                let ascii: String = filename
+                    .chars()
+                    .map(|c| match c {
+                        '"' | '\\' => '_',
+                        ' '..='~' => c,
+                        _ => '_',
+                    })
+                    .collect();
+                let mut encoded = String::new();
+                for byte in filename.bytes() {
+                    if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+                        encoded.push(byte as char);
+                    } else {
+                        encoded.push_str(&format!("%{:02X}", byte));
+                    }
+                }
+                format!(
+                    "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+                    ascii, encoded
+                )
+            }
+        }
+    }
+}
+
+impl Asset {
+    #[cfg(any(feature = "serve", feature = "upload"))]
+    fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().flatten().any(|t| t == tag)
+    }
+
+    #[cfg(any(feature = "serve", feature = "upload"))]
+    fn properties(&self) -> AssetProperties {
+        AssetProperties {
+            protected: self.protected,
+            content_disposition: self.content_disposition.clone(),
+            allow_raw_access: self.allow_raw_access,
+            certified_query_params: self.certified_query_params.clone(),
+            certified_queries: self.certified_queries.clone(),
+            certified_headers: self.certified_headers.clone(),
+        }
+    }
+
+    #[cfg(feature = "upload")]
+    fn set_properties(&mut self, properties: AssetProperties) {
+        self.protected = properties.protected;
+        self.content_disposition = properties.content_disposition;
+        self.allow_raw_access = properties.allow_raw_access;
+        self.certified_query_params = properties.certified_query_params;
+        self.certified_queries = properties.certified_queries;
+        self.certified_headers = properties.certified_headers;
+    }
+}
+
+/// The properties set with `create_asset` that change how an asset is
+/// served, as listed for `sync_from`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, CandidType, Deserialize)]
+struct AssetProperties {
+    protected: Option<bool>,
+    content_disposition: Option<ContentDisposition>,
+    allow_raw_access: Option<bool>,
+    certified_query_params: Option<Vec<String>>,
+    certified_queries: Option<Vec<String>>,
+    certified_headers: Option<CertifiedHeaders>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -79,6 +581,9 @@ struct AssetDetails {
     key: String,
     content_type: String,
     encodings: Vec<AssetEncodingDetails>,
+    tags: Vec<String>,
+    /// `None` from the canisters that don't list them.
+    properties: Option<AssetProperties>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -89,13 +594,51 @@ struct AssetEncodingDetails {
     modified: Timestamp,
 }
 
+#[cfg(feature = "upload")]
 struct Chunk {
     batch_id: BatchId,
     content: RcBytes,
+    hash: Hash,
+    hash_algorithm: HashAlgorithm,
+}
+
+/// The algorithm chunks are hashed with to check their integrity. Whatever
+/// the algorithm, the content of each encoding is certified by its SHA-256.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+enum HashAlgorithm {
+    Sha256,
+    /// Much cheaper than SHA-256 in instructions, for large uploads.
+    Blake3,
+}
+
+impl Default for HashAlgorithm {
+    fn default() -> Self {
+        Self::Sha256
+    }
 }
 
+impl HashAlgorithm {
+    #[cfg(any(feature = "upload", feature = "metrics"))]
+    fn hash(self, bytes: &[u8]) -> Hash {
+        match self {
+            HashAlgorithm::Sha256 => hash_bytes(bytes),
+            HashAlgorithm::Blake3 => blake3::hash(bytes),
+        }
+    }
+}
+
+#[cfg(feature = "upload")]
 struct Batch {
     expires_at: Timestamp,
+    /// The operations `propose_commit_batch` stored for a proposal to commit.
+    proposal: Option<CommitBatchArguments>,
+}
+
+#[cfg(feature = "upload")]
+impl Batch {
+    fn is_live(&self, now: u64) -> bool {
+        self.expires_at > now
+    }
 }
 
 type Timestamp = Int;
@@ -109,6 +652,14 @@ type Key = String;
 struct CreateAssetArguments {
     key: Key,
     content_type: String,
+    tags: Option<Vec<String>>,
+    template: Option<bool>,
+    protected: Option<bool>,
+    content_disposition: Option<ContentDisposition>,
+    allow_raw_access: Option<bool>,
+    certified_query_params: Option<Vec<String>>,
+    certified_queries: Option<Vec<String>>,
+    certified_headers: Option<CertifiedHeaders>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -117,6 +668,32 @@ struct SetAssetContentArguments {
     content_encoding: String,
     chunk_ids: Vec<ChunkId>,
     sha256: Option<ByteBuf>,
+    /// Fails the operation unless the encoding currently holds content with
+    /// this hash, so concurrent deployers don't overwrite each other.
+    expected_sha256: Option<ByteBuf>,
+}
+
+/// Changes an encoding by a patch against its current content, so a small
+/// change to a large file doesn't take uploading it again.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct PatchAssetContentArguments {
+    key: Key,
+    content_encoding: String,
+    /// Builds the new content, instruction after instruction.
+    patch: Vec<PatchInstruction>,
+    /// The sha256 of the new content.
+    sha256: Option<ByteBuf>,
+    /// Fails the operation unless the encoding currently holds content with
+    /// this hash, i.e. the content the patch was computed against.
+    expected_sha256: Option<ByteBuf>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum PatchInstruction {
+    /// Appends `length` bytes of the current content, from `offset` on.
+    Copy { offset: Nat, length: Nat },
+    /// Appends new bytes.
+    Insert(ByteBuf),
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -130,6 +707,17 @@ struct DeleteAssetArguments {
     key: Key,
 }
 
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct SetAssetTagsArguments {
+    key: Key,
+    tags: Vec<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct DeleteByTagArguments {
+    tag: String,
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 struct ClearArguments {}
 
@@ -137,8 +725,11 @@ struct ClearArguments {}
 enum BatchOperation {
     CreateAsset(CreateAssetArguments),
     SetAssetContent(SetAssetContentArguments),
+    PatchAssetContent(PatchAssetContentArguments),
     UnsetAssetContent(UnsetAssetContentArguments),
     DeleteAsset(DeleteAssetArguments),
+    SetAssetTags(SetAssetTagsArguments),
+    DeleteByTag(DeleteByTagArguments),
     Clear(ClearArguments),
 }
 
@@ -153,8 +744,11 @@ struct StoreArg {
     key: Key,
     content_type: String,
     content_encoding: String,
-    content: ByteBuf,
+    content: RcBytes,
     sha256: Option<ByteBuf>,
+    /// Fails the operation unless the encoding currently holds content with
+    /// this hash.
+    expected_sha256: Option<ByteBuf>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -163,6 +757,12 @@ struct GetArg {
     accept_encodings: Vec<String>,
 }
 
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum GetManyResult {
+    Found(EncodedAsset),
+    Missing(Key),
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 struct GetChunkArg {
     key: Key,
@@ -184,7 +784,9 @@ struct CreateBatchResponse {
 #[derive(Clone, Debug, CandidType, Deserialize)]
 struct CreateChunkArg {
     batch_id: BatchId,
-    content: ByteBuf,
+    content: RcBytes,
+    /// The algorithm the chunk is hashed with, SHA-256 by default.
+    hash_algorithm: Option<HashAlgorithm>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -193,47 +795,117 @@ struct CreateChunkResponse {
 }
 // HTTP interface
 
-type HeaderField = (String, String);
+pub type HeaderField = (String, String);
+
+/// A fallback handler registered with [set_fallback_handler].
+pub type FallbackHandler = fn(&HttpRequest) -> Option<HttpResponse>;
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
-struct HttpRequest {
-    method: String,
-    url: String,
-    headers: Vec<(String, String)>,
-    body: ByteBuf,
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: ByteBuf,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
 struct ChunkInfo {
     chunk_id: ChunkId,
     total_length: Nat,
+    /// The offset of the first byte of this chunk within the encoded content.
+    offset: Nat,
+    sha256: ByteBuf,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
 struct ChunksInfoReponse {
+    content_encoding: String,
     total_length: Nat,
+    sha256: ByteBuf,
     chunks: Vec<ChunkInfo>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
-struct HttpResponse {
-    status_code: u16,
-    headers: Vec<HeaderField>,
-    body: RcBytes,
-    streaming_strategy: Option<StreamingStrategy>,
+struct CertificationStats {
+    certified_keys: Nat,
+    /// The size of the `tree` field of the `IC-Certificate` header, before
+    /// base64 encoding, for each of the requested keys.
+    witness_bytes: Vec<(Key, Nat)>,
+    /// The average and largest size of the `tree` field over the certified
+    /// keys, or a sample of them spread over the whole tree.
+    average_witness_bytes: Nat,
+    max_witness_bytes: Nat,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
-struct StreamingCallbackToken {
+struct CertificationStatus {
+    /// The root hash of the subtree of the asset hashes, `http_assets` unless
+    /// configured otherwise.
+    assets_root_hash: ByteBuf,
+    /// The certified data of the canister, which covers every subtree.
+    certified_data: ByteBuf,
+    certified_keys: Nat,
+    /// The assets in the order of their keys.
+    assets: Vec<AssetCertificationStatus>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct AssetCertificationStatus {
+    key: Key,
+    encodings: Vec<EncodingCertificationStatus>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct EncodingCertificationStatus {
+    content_encoding: String,
+    sha256: ByteBuf,
+    /// Whether this encoding is the one certified under `http_assets`. Every
+    /// encoding is certified under `http_expr`.
+    certified: bool,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct ConfigResponse {
+    fallback_file: String,
+    max_batches: Option<u64>,
+    max_chunks: Option<u64>,
+    max_chunk_size: u64,
+    etag_format: EtagFormat,
+    hotlink_rules: Vec<HotlinkRule>,
+    certification_version: CertificationVersion,
+    assets_label: String,
+    readonly: bool,
+    heap_watermark: u64,
+    prune_fallback_witness: bool,
+    max_patched_size: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<HeaderField>,
+    pub body: RcBytes,
+    pub streaming_strategy: Option<StreamingStrategy>,
+    /// Asks the HTTP gateway to repeat the request as an update call.
+    pub upgrade: Option<bool>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StreamingCallbackToken {
     key: String,
     content_encoding: String,
     index: Nat,
     // We don't care about the sha, we just want to be backward compatible.
     sha256: Option<ByteBuf>,
+    /// The access token of the request, for protected assets.
+    access_token: Option<String>,
+    /// The `Authorization` header of the request, for the assets requiring
+    /// authentication.
+    authorization: Option<String>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
-enum StreamingStrategy {
+pub enum StreamingStrategy {
     Callback {
         callback: Func,
         token: StreamingCallbackToken,
@@ -246,6 +918,7 @@ struct StreamingCallbackHttpResponse {
     token: Option<StreamingCallbackToken>,
 }
 
+#[cfg(feature = "admin")]
 #[update]
 fn authorize(other: Principal) {
     let caller = caller();
@@ -257,28 +930,70 @@ fn authorize(other: Principal) {
     })
 }
 
+/// Revokes the authorization of `other`, e.g. once a DAO took over the
+/// deployments.
+#[cfg(feature = "admin")]
+#[update(guard = "is_authorized")]
+fn deauthorize(other: Principal) {
+    STATE.with(|s| s.authorized.borrow_mut().retain(|p| *p != other))
+}
+
+#[cfg(feature = "serve")]
 #[query]
 fn retrieve(key: Key) -> RcBytes {
     STATE.with(|s| {
         let assets = s.assets.borrow();
-        let asset = assets.get(&key).unwrap_or_else(|| trap("asset not found"));
+        let asset = assets
+            .get(&key)
+            .unwrap_or_else(|| trap_error(ASSET_NOT_FOUND));
+        if !access::is_readable(&key, asset) {
+            trap_error(access::ACCESS_DENIED);
+        }
         let id_enc = asset
             .encodings
             .get("identity")
             .unwrap_or_else(|| trap("no identity encoding"));
-        if id_enc.content_chunks.len() > 1 {
+        if id_enc.chunk_count() > 1 {
             trap("Asset too large. Use get() and get_chunk() instead.");
         }
-        id_enc.content_chunks[0].clone()
+        id_enc.chunk(0)
     })
 }
 
-#[update(guard = "is_authorized")]
+#[cfg(feature = "upload")]
+#[update(guard = "is_writable")]
 fn store(arg: StoreArg) {
+    do_store(arg)
+}
+
+/// Stores several small assets at once, certifying the new root hash only
+/// once at the end.
+#[cfg(feature = "upload")]
+#[update(guard = "is_writable")]
+fn store_many(args: Vec<StoreArg>) {
+    with_deferred_certification(|| {
+        for arg in args {
+            do_store(arg);
+        }
+    })
+}
+
+#[cfg(feature = "upload")]
+fn do_store(arg: StoreArg) {
     STATE.with(move |s| {
+        if arg.content.len() as u64 > s.config.borrow().max_chunk_size() {
+            trap("content too large");
+        }
+        heap::reserve(arg.content.len());
+
         let mut assets = s.assets.borrow_mut();
+        check_expected_sha256(
+            assets.get(&arg.key),
+            &arg.content_encoding,
+            arg.expected_sha256,
+        );
         let asset = assets.entry(arg.key.clone()).or_default();
-        asset.content_type = arg.content_type;
+        asset.content_type = mime::resolve(&arg.key, arg.content_type);
 
         let hash = hash_bytes(&arg.content);
         if let Some(provided_hash) = arg.sha256 {
@@ -289,15 +1004,18 @@ fn store(arg: StoreArg) {
 
         let encoding = asset.encodings.entry(arg.content_encoding).or_default();
         encoding.total_length = arg.content.len();
-        encoding.content_chunks = vec![RcBytes::from(arg.content)];
+        encoding.set_chunks(vec![arg.content]);
         encoding.modified = Int::from(time() as u64);
         encoding.sha256 = hash;
+        encoding.chunk_hashes = Some(vec![hash]);
+        encoding.chunk_hash_algorithm = None;
 
         on_asset_change(&arg.key, asset);
     });
 }
 
-#[update(guard = "is_authorized")]
+#[cfg(feature = "upload")]
+#[update(guard = "is_writable")]
 fn create_batch() -> CreateBatchResponse {
     STATE.with(|s| {
         let batch_id = s.next_batch_id.borrow().clone();
@@ -306,25 +1024,33 @@ fn create_batch() -> CreateBatchResponse {
         let now = time() as u64;
 
         let mut batches = s.batches.borrow_mut();
+        if let Some(max_batches) = s.config.borrow().max_batches {
+            let open_batches = batches.values().filter(|b| b.is_live(now)).count();
+            if open_batches as u64 >= max_batches {
+                trap("batch limit exceeded");
+            }
+        }
         batches.insert(
             batch_id.clone(),
             Batch {
                 expires_at: Int::from(now + BATCH_EXPIRY_NANOS),
+                proposal: None,
             },
         );
-        s.chunks.borrow_mut().retain(|_, c| {
+        retain_chunks(s, |c| {
             batches
                 .get(&c.batch_id)
-                .map(|b| b.expires_at > now)
+                .map(|b| b.is_live(now))
                 .unwrap_or(false)
         });
-        batches.retain(|_, b| b.expires_at > now);
+        batches.retain(|_, b| b.is_live(now));
 
         CreateBatchResponse { batch_id }
     })
 }
 
-#[update(guard = "is_authorized")]
+#[cfg(feature = "upload")]
+#[update(guard = "is_writable")]
 fn create_chunk(arg: CreateChunkArg) -> CreateChunkResponse {
     STATE.with(|s| {
         let mut batches = s.batches.borrow_mut();
@@ -332,16 +1058,34 @@ fn create_chunk(arg: CreateChunkArg) -> CreateChunkResponse {
         let mut batch = batches
             .get_mut(&arg.batch_id)
             .unwrap_or_else(|| trap("batch not found"));
+        if batch.proposal.is_some() {
+            trap("batch already proposed");
+        }
         batch.expires_at = Int::from(now + BATCH_EXPIRY_NANOS);
 
+        if let Some(max_chunks) = s.config.borrow().max_chunks {
+            if s.chunks.borrow().len() as u64 >= max_chunks {
+                trap("chunk limit exceeded");
+            }
+        }
+        if arg.content.len() as u64 > s.config.borrow().max_chunk_size() {
+            trap("chunk too large");
+        }
+        heap::reserve(arg.content.len());
+
         let chunk_id = s.next_chunk_id.borrow().clone();
         *s.next_chunk_id.borrow_mut() += 1;
 
+        let hash_algorithm = arg.hash_algorithm.unwrap_or_default();
+        let hash = hash_algorithm.hash(&arg.content);
+        heap::chunk_stored(arg.content.len());
         s.chunks.borrow_mut().insert(
             chunk_id.clone(),
             Chunk {
                 batch_id: arg.batch_id,
-                content: RcBytes::from(arg.content),
+                content: arg.content,
+                hash,
+                hash_algorithm,
             },
         );
 
@@ -349,132 +1093,313 @@ fn create_chunk(arg: CreateChunkArg) -> CreateChunkResponse {
     })
 }
 
-#[update(guard = "is_authorized")]
+#[cfg(feature = "upload")]
+#[update(guard = "is_writable")]
 fn create_asset(arg: CreateAssetArguments) {
     do_create_asset(arg);
 }
 
-#[update(guard = "is_authorized")]
+#[cfg(feature = "upload")]
+#[update(guard = "is_writable")]
 fn set_asset_content(arg: SetAssetContentArguments) {
+    let chunk_ids = arg.chunk_ids.clone();
     do_set_asset_content(arg);
+    STATE.with(|s| {
+        let mut chunks = s.chunks.borrow_mut();
+        for chunk_id in chunk_ids.iter() {
+            if let Some(chunk) = chunks.remove(chunk_id) {
+                heap::chunk_dropped(chunk.content.len());
+            }
+        }
+    });
 }
 
-#[update(guard = "is_authorized")]
+#[cfg(feature = "upload")]
+#[update(guard = "is_writable")]
+fn patch_asset_content(arg: PatchAssetContentArguments) {
+    do_patch_asset_content(arg);
+}
+
+#[cfg(feature = "upload")]
+#[update(guard = "is_writable")]
 fn unset_asset_content(arg: UnsetAssetContentArguments) {
     do_unset_asset_content(arg);
 }
 
-#[update(guard = "is_authorized")]
+#[cfg(feature = "upload")]
+#[update(guard = "is_writable")]
 fn delete_content(arg: DeleteAssetArguments) {
     do_delete_asset(arg);
 }
 
-#[update(guard = "is_authorized")]
+#[cfg(feature = "upload")]
+#[update(guard = "is_writable")]
+fn set_asset_tags(arg: SetAssetTagsArguments) {
+    do_set_asset_tags(arg);
+}
+
+#[cfg(feature = "upload")]
+#[update(guard = "is_writable")]
+fn delete_by_tag(arg: DeleteByTagArguments) {
+    do_delete_by_tag(arg);
+}
+
+#[cfg(feature = "upload")]
+#[update(guard = "is_writable")]
 fn clear() {
     do_clear();
 }
 
-#[update(guard = "is_authorized")]
+#[cfg(feature = "upload")]
+#[update(guard = "is_writable")]
 fn commit_batch(arg: CommitBatchArguments) {
+    do_commit_batch(arg);
+}
+
+#[cfg(feature = "upload")]
+fn do_commit_batch(arg: CommitBatchArguments) {
     let batch_id = arg.batch_id;
+    let mut changed_keys = BTreeSet::new();
     for op in arg.operations {
+        changed_keys.extend(notifications::affected_keys(&op));
         match op {
             BatchOperation::CreateAsset(arg) => do_create_asset(arg),
             BatchOperation::SetAssetContent(arg) => do_set_asset_content(arg),
+            BatchOperation::PatchAssetContent(arg) => do_patch_asset_content(arg),
             BatchOperation::UnsetAssetContent(arg) => do_unset_asset_content(arg),
             BatchOperation::DeleteAsset(arg) => do_delete_asset(arg),
+            BatchOperation::SetAssetTags(arg) => do_set_asset_tags(arg),
+            BatchOperation::DeleteByTag(arg) => do_delete_by_tag(arg),
             BatchOperation::Clear(_) => do_clear(),
         }
     }
     STATE.with(|s| {
         s.batches.borrow_mut().remove(&batch_id);
-    })
+        retain_chunks(s, |chunk| chunk.batch_id != batch_id);
+    });
+    notifications::notify_commit(batch_id, changed_keys);
 }
 
+#[cfg(feature = "serve")]
+#[query(manual_reply = true)]
+fn get(arg: GetArg) -> ManualReply<EncodedAsset> {
+    let asset = STATE
+        .with(|s| lookup_encoded_asset(&s.assets.borrow(), &arg).unwrap_or_else(|e| trap_error(e)));
+    rc_bytes::reply_with_blob(&asset.content.clone().into(), |content| EncodedAsset {
+        content,
+        ..asset.clone()
+    });
+    ManualReply::empty()
+}
+
+#[cfg(feature = "serve")]
 #[query]
-fn get(arg: GetArg) -> EncodedAsset {
+fn get_many(args: Vec<GetArg>) -> Vec<GetManyResult> {
     STATE.with(|s| {
         let assets = s.assets.borrow();
-        let asset = assets.get(&arg.key).unwrap_or_else(|| {
-            trap("asset not found");
-        });
+        args.into_iter()
+            .map(|arg| match lookup_encoded_asset(&assets, &arg) {
+                Ok(asset) => GetManyResult::Found(asset),
+                Err(_) => GetManyResult::Missing(arg.key),
+            })
+            .collect()
+    })
+}
 
-        for enc in arg.accept_encodings.iter() {
-            if let Some(asset_enc) = asset.encodings.get(enc) {
-                return EncodedAsset {
-                    content: asset_enc.content_chunks[0].clone(),
-                    content_type: asset.content_type.clone(),
-                    content_encoding: enc.clone(),
-                    total_length: Nat::from(asset_enc.total_length as u64),
-                    sha256: Some(ByteBuf::from(asset_enc.sha256)),
-                };
-            }
+#[cfg(feature = "serve")]
+fn lookup_encoded_asset(
+    assets: &HashMap<Key, Asset>,
+    arg: &GetArg,
+) -> Result<EncodedAsset, CodedTrap> {
+    let asset = assets.get(&arg.key).ok_or(ASSET_NOT_FOUND)?;
+    if !access::is_readable(&arg.key, asset) {
+        return Err(access::ACCESS_DENIED);
+    }
+
+    for enc in arg.accept_encodings.iter() {
+        if let Some(asset_enc) = asset.encodings.get(enc) {
+            return Ok(EncodedAsset {
+                content: asset_enc.chunk(0),
+                content_type: asset.content_type.clone(),
+                content_encoding: enc.clone(),
+                total_length: Nat::from(asset_enc.total_length as u64),
+                sha256: Some(ByteBuf::from(asset_enc.sha256)),
+            });
         }
-        trap("no such encoding");
-    })
+    }
+    Err(ENCODING_NOT_FOUND)
 }
 
+#[cfg(feature = "serve")]
 #[query]
 fn get_chunks_info(arg: GetArg) -> ChunksInfoReponse {
     STATE.with(|s| {
         let assets = s.assets.borrow();
         let asset = assets.get(&arg.key).unwrap_or_else(|| {
-            trap("asset not found");
+            trap_error(ASSET_NOT_FOUND);
         });
+        if !access::is_readable(&arg.key, asset) {
+            trap_error(access::ACCESS_DENIED);
+        }
 
-        let mut result = ChunksInfoReponse {
-            total_length: Nat::from(0),
-            chunks: vec![],
-        };
-
+        let (enc_name, enc) = arg
+            .accept_encodings
+            .iter()
+            .find_map(|enc_name| asset.encodings.get(enc_name).map(|enc| (enc_name, enc)))
+            .unwrap_or_else(|| trap_error(ENCODING_NOT_FOUND));
+
+        let mut offset = 0;
+        let mut chunks = Vec::with_capacity(enc.chunk_count());
+        for i in 0..enc.chunk_count() {
+            let sha256 = match enc.chunk_hashes.as_ref() {
+                Some(hashes) if enc.chunk_hash_algorithm() == HashAlgorithm::Sha256 => hashes[i],
+                _ => hash_bytes(&enc.chunk(i)),
+            };
+            let len = enc.chunk_len(i);
+            chunks.push(ChunkInfo {
+                chunk_id: Nat::from(i),
+                total_length: Nat::from(len),
+                offset: Nat::from(offset),
+                sha256: ByteBuf::from(sha256),
+            });
+            offset += len;
+        }
 
-        for enc in arg.accept_encodings.iter() {
-            if let Some(asset_enc) = asset.encodings.get(enc) {
-                for (i, chunk) in asset_enc.content_chunks.iter().enumerate() {
-                    result.total_length = result.total_length + asset_enc.total_length;
-                    result.chunks.push(ChunkInfo {
-                        chunk_id: Nat::from(i),
-                        total_length: Nat::from(chunk.len()),
-                    });
-                }
-            }
+        ChunksInfoReponse {
+            content_encoding: enc_name.clone(),
+            total_length: Nat::from(enc.total_length),
+            sha256: ByteBuf::from(enc.sha256),
+            chunks,
         }
-        result
     })
 }
 
-#[query]
-fn get_chunk(arg: GetChunkArg) -> GetChunkResponse {
+#[cfg(feature = "serve")]
+#[query(manual_reply = true)]
+fn get_chunk(arg: GetChunkArg) -> ManualReply<GetChunkResponse> {
     STATE.with(|s| {
         let assets = s.assets.borrow();
         let asset = assets
             .get(&arg.key)
-            .unwrap_or_else(|| trap("asset not found"));
+            .unwrap_or_else(|| trap_error(ASSET_NOT_FOUND));
+        if !access::is_readable(&arg.key, asset) {
+            trap_error(access::ACCESS_DENIED);
+        }
 
         let enc = asset
             .encodings
             .get(&arg.content_encoding)
-            .unwrap_or_else(|| trap("no such encoding"));
+            .unwrap_or_else(|| trap_error(ENCODING_NOT_FOUND));
 
         if let Some(expected_hash) = arg.sha256 {
             if expected_hash != enc.sha256 {
                 trap("sha256 mismatch")
             }
         }
-        if arg.index >= enc.content_chunks.len() {
+        if arg.index >= enc.chunk_count() {
             trap("chunk index out of bounds");
         }
         let index: usize = arg.index.0.to_usize().unwrap();
 
-        GetChunkResponse {
-            content: enc.content_chunks[index].clone(),
-        }
+        rc_bytes::reply_with_blob(&enc.chunk(index).into(), |content| GetChunkResponse {
+            content,
+        });
+        ManualReply::empty()
     })
 }
 
+#[cfg(feature = "serve")]
 #[query]
 fn list() -> Vec<AssetDetails> {
     STATE.with(|s| {
+        s.assets
+            .borrow()
+            .iter()
+            .map(|(key, asset)| asset_details(key, asset))
+            .collect::<Vec<_>>()
+    })
+}
+
+#[cfg(feature = "serve")]
+#[query]
+fn list_by_tag(tag: String) -> Vec<AssetDetails> {
+    STATE.with(|s| {
+        s.assets
+            .borrow()
+            .iter()
+            .filter(|(_, asset)| asset.has_tag(&tag))
+            .map(|(key, asset)| asset_details(key, asset))
+            .collect::<Vec<_>>()
+    })
+}
+
+/// Rejects, or accepts again, the changes to the assets. See
+/// [AssetCanisterArgs::readonly].
+#[cfg(feature = "admin")]
+#[update(guard = "is_authorized")]
+fn set_readonly(readonly: bool) {
+    STATE.with(|s| s.config.borrow_mut().readonly = Some(readonly));
+}
+
+#[cfg(feature = "admin")]
+#[query]
+fn config() -> ConfigResponse {
+    STATE.with(|s| {
+        let config = s.config.borrow();
+        ConfigResponse {
+            fallback_file: config.fallback_file.clone(),
+            max_batches: config.max_batches,
+            max_chunks: config.max_chunks,
+            max_chunk_size: config.max_chunk_size(),
+            etag_format: config.etag_format.clone().unwrap_or_default(),
+            hotlink_rules: config.hotlink_rules.clone().unwrap_or_default(),
+            certification_version: config.certification_version.unwrap_or_default(),
+            assets_label: config.assets_label().to_string(),
+            readonly: config.readonly.unwrap_or(false),
+            heap_watermark: config.heap_watermark(),
+            prune_fallback_witness: config.prune_fallback_witness.unwrap_or(false),
+            max_patched_size: config.max_patched_size(),
+        }
+    })
+}
+
+#[cfg(feature = "metrics")]
+#[query]
+fn certification_stats(keys: Vec<Key>) -> CertificationStats {
+    ASSET_HASHES.with(|t| {
+        let tree = t.borrow();
+        let certified_keys = tree.iter().count();
+        let step = ((certified_keys + WITNESS_SAMPLE_SIZE - 1) / WITNESS_SAMPLE_SIZE).max(1);
+        let sizes: Vec<usize> = tree
+            .iter()
+            .step_by(step)
+            .map(|(key, _)| witness_bytes(tree.witness(key.as_bytes())).len())
+            .collect();
+        CertificationStats {
+            certified_keys: Nat::from(certified_keys),
+            average_witness_bytes: Nat::from(sizes.iter().sum::<usize>() / sizes.len().max(1)),
+            max_witness_bytes: Nat::from(sizes.iter().copied().max().unwrap_or(0)),
+            witness_bytes: keys
+                .into_iter()
+                .map(|key| {
+                    let size = witness_bytes(tree.witness(key.as_bytes())).len();
+                    (key, Nat::from(size))
+                })
+                .collect(),
+        }
+    })
+}
+
+/// Returns what is certified, for deploy tooling to check that it matches
+/// what was uploaded before sending traffic to the canister.
+#[cfg(feature = "metrics")]
+#[query]
+fn certification_status() -> CertificationStatus {
+    let (assets_root_hash, certified_keys) = ASSET_HASHES.with(|t| {
+        let tree = t.borrow();
+        (tree.root_hash(), tree.iter().count())
+    });
+    let mut assets: Vec<AssetCertificationStatus> = STATE.with(|s| {
         s.assets
             .borrow()
             .iter()
@@ -482,23 +1407,50 @@ fn list() -> Vec<AssetDetails> {
                 let mut encodings: Vec<_> = asset
                     .encodings
                     .iter()
-                    .map(|(enc_name, enc)| AssetEncodingDetails {
+                    .map(|(enc_name, enc)| EncodingCertificationStatus {
                         content_encoding: enc_name.clone(),
-                        sha256: Some(ByteBuf::from(enc.sha256)),
-                        length: Nat::from(enc.total_length),
-                        modified: enc.modified.clone(),
+                        sha256: ByteBuf::from(enc.sha256),
+                        certified: enc.certified,
                     })
                     .collect();
                 encodings.sort_by(|l, r| l.content_encoding.cmp(&r.content_encoding));
-
-                AssetDetails {
+                AssetCertificationStatus {
                     key: key.clone(),
-                    content_type: asset.content_type.clone(),
                     encodings,
                 }
             })
-            .collect::<Vec<_>>()
-    })
+            .collect()
+    });
+    assets.sort_by(|l, r| l.key.cmp(&r.key));
+    CertificationStatus {
+        assets_root_hash: ByteBuf::from(assets_root_hash),
+        certified_data: ByteBuf::from(root_hash()),
+        certified_keys: Nat::from(certified_keys),
+        assets,
+    }
+}
+
+#[cfg(feature = "serve")]
+fn asset_details(key: &str, asset: &Asset) -> AssetDetails {
+    let mut encodings: Vec<_> = asset
+        .encodings
+        .iter()
+        .map(|(enc_name, enc)| AssetEncodingDetails {
+            content_encoding: enc_name.clone(),
+            sha256: Some(ByteBuf::from(enc.sha256)),
+            length: Nat::from(enc.total_length),
+            modified: enc.modified.clone(),
+        })
+        .collect();
+    encodings.sort_by(|l, r| l.content_encoding.cmp(&r.content_encoding));
+
+    AssetDetails {
+        key: key.to_string(),
+        content_type: asset.content_type.clone(),
+        encodings,
+        tags: asset.tags.clone().unwrap_or_default(),
+        properties: Some(asset.properties()),
+    }
 }
 
 fn create_token(
@@ -508,7 +1460,7 @@ fn create_token(
     key: &str,
     chunk_index: usize,
 ) -> Option<StreamingCallbackToken> {
-    if chunk_index + 1 >= enc.content_chunks.len() {
+    if chunk_index + 1 >= enc.chunk_count() {
         None
     } else {
         Some(StreamingCallbackToken {
@@ -516,6 +1468,8 @@ fn create_token(
             content_encoding: enc_name.to_string(),
             index: Nat::from(chunk_index + 1),
             sha256: Some(ByteBuf::from(enc.sha256)),
+            access_token: None,
+            authorization: None,
         })
     }
 }
@@ -544,10 +1498,7 @@ fn build_200(
     chunk_index: usize,
     certificate_header: Option<HeaderField>,
 ) -> HttpResponse {
-    let mut headers = vec![("Content-Type".to_string(), asset.content_type.to_string())];
-    if enc_name != "identity" {
-        headers.push(("Content-Encoding".to_string(), enc_name.to_string()));
-    }
+    let mut headers = asset_headers(asset, enc_name, enc);
     if let Some(head) = certificate_header {
         headers.push(head);
     }
@@ -557,93 +1508,349 @@ fn build_200(
     HttpResponse {
         status_code: 200,
         headers,
-        body: enc.content_chunks[chunk_index].clone(),
+        body: enc.chunk(chunk_index),
         streaming_strategy,
+        upgrade: None,
     }
 }
 
-fn build_404(certificate_header: HeaderField) -> HttpResponse {
+/// The headers of a response serving `enc`, but for `IC-Certificate`. They
+/// are certified along with the body by v2 certification.
+fn asset_headers(asset: &Asset, enc_name: &str, enc: &AssetEncoding) -> Vec<HeaderField> {
+    let mut headers = vec![("Content-Type".to_string(), asset.content_type.to_string())];
+    if enc_name != "identity" {
+        headers.push(("Content-Encoding".to_string(), enc_name.to_string()));
+    }
+    headers.push(("ETag".to_string(), etag(&enc.sha256)));
+    if let Some(disposition) = &asset.content_disposition {
+        headers.push((
+            "Content-Disposition".to_string(),
+            disposition.header_value(),
+        ));
+    }
+    headers.extend(security_headers());
+    headers.push(certification_v2::expression_header(asset));
+    headers
+}
+
+fn build_404() -> HttpResponse {
+    build_error(404, "not found", vec![])
+}
+
+/// The response to a request that is refused, e.g. for a protected asset
+/// without a valid access token.
+fn build_403() -> HttpResponse {
+    build_error(403, "forbidden", vec![])
+}
+
+/// Redirects a request denied by a [HotlinkRule] to its placeholder.
+fn build_307(placeholder: &str) -> HttpResponse {
+    build_error(
+        307,
+        "",
+        vec![("Location".to_string(), placeholder.to_string())],
+    )
+}
+
+/// An error response served in place of an asset, whose headers are all
+/// certified by v2 certification, as the body.
+fn build_error(status_code: u16, body: &str, mut headers: Vec<HeaderField>) -> HttpResponse {
+    headers.extend(security_headers());
+    headers.push(certification_v2::error_expression_header());
+
     HttpResponse {
-        status_code: 404,
-        headers: vec![certificate_header],
-        body: RcBytes::from(ByteBuf::from("not found")),
+        status_code,
+        headers,
+        body: RcBytes::from(ByteBuf::from(body)),
         streaming_strategy: None,
+        upgrade: None,
     }
 }
 
-fn build_http_response(path: &str, encodings: Vec<String>, index: usize) -> HttpResponse {
+/// The error responses that may be served in place of `asset`, the asset at
+/// `key`. They are certified along with it.
+fn error_responses(key: &str, asset: &Asset) -> Vec<HttpResponse> {
+    // Staged assets are only certified to be missing.
+    if staging::is_staged(key) {
+        return vec![build_404()];
+    }
+    let mut responses = vec![];
+    // The query strings that weren't declared.
+    if certification_v2::has_certified_query_params(asset) {
+        responses.push(build_404());
+    }
+    if asset.protected == Some(true) {
+        responses.push(build_403());
+    }
+    if authenticator::is_protected(key) {
+        responses.push(build_401(AUTHENTICATION_REQUIRED));
+    }
+    match hotlink::denial_for(key) {
+        Some(hotlink::Denial::Placeholder(placeholder)) => responses.push(build_307(&placeholder)),
+        Some(hotlink::Denial::Forbidden) if asset.protected != Some(true) => {
+            responses.push(build_403())
+        }
+        _ => {}
+    }
+    // Only the requests accepting another encoding get the asset.
+    if !asset.encodings.contains_key("identity") {
+        responses.push(build_404());
+    }
+    responses
+}
+
+/// The response to a request rejected by an [Authenticator], or to an upload
+/// without a valid token.
+fn build_401(reason: &str) -> HttpResponse {
+    build_error(
+        401,
+        reason,
+        vec![("WWW-Authenticate".to_string(), "Bearer".to_string())],
+    )
+}
+
+/// The response to a request sent to a raw domain for an asset that doesn't
+/// allow raw access, to be certified by the subnet.
+fn build_308(location: &str) -> HttpResponse {
+    let mut headers = vec![("Location".to_string(), location.to_string())];
+    headers.extend(security_headers());
+
+    HttpResponse {
+        status_code: 308,
+        headers,
+        body: RcBytes::from(ByteBuf::new()),
+        streaming_strategy: None,
+        upgrade: None,
+    }
+}
+
+fn security_headers() -> Vec<HeaderField> {
+    STATE.with(|s| s.config.borrow().security_headers.clone())
+}
+
+fn etag(sha256: &Hash) -> String {
+    STATE.with(|s| {
+        let config = s.config.borrow();
+        match &config.etag_format {
+            Some(format) => format.render(sha256),
+            None => EtagFormat::default().render(sha256),
+        }
+    })
+}
+
+/// Builds the response to `path` for `req`, `query` being the query string
+/// the asset is served for if it has one.
+fn build_http_response(
+    path: &str,
+    query: Option<&str>,
+    encodings: Vec<String>,
+    index: usize,
+    req: &HttpRequest,
+) -> HttpResponse {
+    let certified_url = certified_url(req);
+    let certified_url = certified_url.as_deref();
     STATE.with(|s| {
         let assets = s.assets.borrow();
+        let fallback_file = s.config.borrow().fallback_file.clone();
 
-        let index_redirect_certificate = ASSET_HASHES.with(|t| {
-            let tree = t.borrow();
-            if tree.get(path.as_bytes()).is_none() && tree.get(INDEX_FILE.as_bytes()).is_some() {
-                let absence_proof = tree.witness(path.as_bytes());
-                let index_proof = tree.witness(INDEX_FILE.as_bytes());
-                let combined_proof = merge_hash_trees(absence_proof, index_proof);
-                Some(witness_to_header(combined_proof))
-            } else {
-                None
+        let resolved = resolve_asset(&assets, path, &fallback_file, &encodings);
+        let r = match resolved {
+            Some(r) => r,
+            None => return certify_error(path, None, build_404()),
+        };
+        // Only the query strings declared with the asset are certified.
+        if certification_v2::has_certified_query_params(r.asset)
+            && !certification_v2::is_request_certified(path, &r, req)
+        {
+            return certify_error(path, Some(&r), build_404());
+        }
+        if let Some(certified_url) = certified_url {
+            if r.asset.allow_raw_access == Some(false) {
+                // The redirect differs from the certified asset, so it can
+                // only be certified by the subnet.
+                return if in_replicated_execution() {
+                    build_308(certified_url)
+                } else {
+                    build_upgrade()
+                };
             }
-        });
-
-        if let Some(certificate_header) = index_redirect_certificate {
-            if let Some(asset) = assets.get(INDEX_FILE) {
-                for enc_name in encodings.iter() {
-                    if let Some(enc) = asset.encodings.get(enc_name) {
-                        if enc.certified {
-                            return build_200(
-                                asset,
-                                enc_name,
-                                enc,
-                                INDEX_FILE,
-                                index,
-                                Some(certificate_header),
-                            );
-                        }
-                    }
-                }
+        }
+        if staging::is_staged(r.key) {
+            return certify_error(path, Some(&r), build_404());
+        }
+        if r.asset.protected == Some(true) && !access::has_valid_token(r.key, query) {
+            return certify_error(path, Some(&r), build_403());
+        }
+        if authenticator::check(r.key, req).is_err() {
+            // The reason isn't certified, so it isn't served either.
+            return certify_error(path, Some(&r), build_401(AUTHENTICATION_REQUIRED));
+        }
+        match hotlink::check(r.key, &req.headers) {
+            Some(hotlink::Denial::Placeholder(placeholder)) => {
+                return certify_error(path, Some(&r), build_307(&placeholder))
+            }
+            Some(hotlink::Denial::Forbidden) => return certify_error(path, Some(&r), build_403()),
+            None => {}
+        }
+        let certificate_header = build_certificate(path, &r, req);
+        let mut response = build_200(r.asset, r.enc_name, r.enc, r.key, index, certificate_header);
+        if r.asset.protected == Some(true) {
+            if let Some(StreamingStrategy::Callback { token, .. }) =
+                response.streaming_strategy.as_mut()
+            {
+                token.access_token = query.and_then(access::token_param);
             }
         }
+        response
+    })
+}
 
-        let certificate_header =
-            ASSET_HASHES.with(|t| witness_to_header(t.borrow().witness(path.as_bytes())));
-
-        if let Some(asset) = assets.get(path) {
-            for enc_name in encodings.iter() {
-                if let Some(enc) = asset.encodings.get(enc_name) {
-                    if enc.certified {
-                        return build_200(
-                            asset,
-                            enc_name,
-                            enc,
-                            path,
-                            index,
-                            Some(certificate_header),
-                        );
-                    } else {
-                        // Find if identity is certified, if it's not.
-                        if let Some(id_enc) = asset.encodings.get("identity") {
-                            if id_enc.certified {
-                                return build_200(
-                                    asset,
-                                    enc_name,
-                                    enc,
-                                    path,
-                                    index,
-                                    Some(certificate_header),
-                                );
-                            }
-                        }
-                    }
-                }
+/// Adds the certificate of `response`, an error response served for `path`
+/// in place of `resolved`, to it.
+fn certify_error(
+    path: &str,
+    resolved: Option<&ResolvedAsset>,
+    mut response: HttpResponse,
+) -> HttpResponse {
+    // In replicated execution, the subnet certifies the response itself.
+    if !in_replicated_execution() {
+        let with_v1 = certification_v2::version() != CertificationVersion::V2;
+        response
+            .headers
+            .push(certification_v2::error_certificate_header(
+                path, resolved, &response, with_v1,
+            ));
+    }
+    response
+}
+
+/// The asset and encoding chosen to answer a request.
+struct ResolvedAsset<'a> {
+    key: &'a str,
+    asset: &'a Asset,
+    enc_name: &'a str,
+    enc: &'a AssetEncoding,
+    /// Whether `key` is the fallback file, served in place of a missing path.
+    is_fallback: bool,
+}
+
+/// Finds the asset answering `path`: the asset itself if it exists, or the
+/// fallback file if `path` isn't certified.
+fn resolve_asset<'a>(
+    assets: &'a HashMap<Key, Asset>,
+    path: &'a str,
+    fallback_file: &'a str,
+    encodings: &'a [String],
+) -> Option<ResolvedAsset<'a>> {
+    let is_certified = |key: &str| ASSET_HASHES.with(|t| t.borrow().get(key.as_bytes()).is_some());
+
+    if !is_certified(path) && is_certified(fallback_file) {
+        if let Some(asset) = assets.get(fallback_file) {
+            if let Some((enc_name, enc)) = pick_encoding(asset, encodings) {
+                return Some(ResolvedAsset {
+                    key: fallback_file,
+                    asset,
+                    enc_name,
+                    enc,
+                    is_fallback: true,
+                });
             }
         }
+    }
+
+    let asset = assets.get(path)?;
+    let (enc_name, enc) = pick_encoding(asset, encodings)?;
+    Some(ResolvedAsset {
+        key: path,
+        asset,
+        enc_name,
+        enc,
+        is_fallback: false,
+    })
+}
+
+/// Picks the first acceptable encoding. Every encoding has a certified
+/// response under `http_expr`.
+fn pick_encoding<'a>(
+    asset: &'a Asset,
+    encodings: &'a [String],
+) -> Option<(&'a str, &'a AssetEncoding)> {
+    encodings.iter().find_map(|enc_name| {
+        let enc = asset.encodings.get(enc_name)?;
+        Some((enc_name.as_str(), enc))
+    })
+}
 
-        build_404(certificate_header)
+/// Builds the `IC-Certificate` header proving the response serving
+/// `resolved` for `path`, in the configured [CertificationVersion]. The
+/// encodings not certified under `http_assets` are proven by both versions.
+///
+/// Returns `None` in replicated execution, e.g. from `http_request_update`:
+/// the subnet certifies the response itself and no data certificate is
+/// available.
+fn build_certificate(
+    path: &str,
+    resolved: &ResolvedAsset,
+    req: &HttpRequest,
+) -> Option<HeaderField> {
+    if in_replicated_execution() {
+        return None;
+    }
+    let with_v1 = certification_v2::version() != CertificationVersion::V2;
+    let header = if is_v2_certified(resolved) {
+        certification_v2::certificate_header(path, resolved, req, with_v1)
+    } else {
+        // The witness of the fallback file only depends on the requested
+        // path when it proves its absence.
+        let cached = if resolved.is_fallback && !prunes_fallback_witness() {
+            None
+        } else {
+            witness_cache::get(resolved.key)
+        };
+        let tree =
+            cached.unwrap_or_else(|| with_asset_witness(path, Some(resolved), serialize_witness));
+        certificate_header(tree)
+    };
+    Some(header)
+}
+
+/// Returns whether the response serving `resolved` carries a v2 certificate.
+fn is_v2_certified(resolved: &ResolvedAsset) -> bool {
+    certification_v2::version() != CertificationVersion::V1 || !resolved.enc.certified
+}
+
+/// Calls `f` with the witness of the asset hashes proving the response to
+/// `path`: the absence of `path` and the fallback file if it is served, the
+/// witness of `path` otherwise.
+///
+/// The proof of absence adds the labels of the keys around `path`, which are
+/// as long as the paths themselves, and oversized `IC-Certificate` headers
+/// break some proxies. With `prune_fallback_witness`, it is left out, as
+/// gateways check the body against the fallback file whenever they don't
+/// find `path` in the tree.
+fn with_asset_witness<R>(
+    path: &str,
+    resolved: Option<&ResolvedAsset>,
+    f: impl FnOnce(HashTree) -> R,
+) -> R {
+    ASSET_HASHES.with(|t| {
+        let tree = t.borrow();
+        let witness = match resolved {
+            Some(r) if r.is_fallback && prunes_fallback_witness() => tree.witness(r.key.as_bytes()),
+            Some(r) if r.is_fallback => merge_hash_trees(
+                tree.witness(path.as_bytes()),
+                tree.witness(r.key.as_bytes()),
+            ),
+            _ => tree.witness(path.as_bytes()),
+        };
+        f(witness)
     })
 }
 
+fn prunes_fallback_witness() -> bool {
+    STATE.with(|s| s.config.borrow().prune_fallback_witness == Some(true))
+}
+
 /// An iterator-like structure that decode a URL.
 struct UrlDecode<'a> {
     bytes: std::slice::Iter<'a, u8>,
@@ -725,8 +1932,60 @@ fn check_url_decode() {
     assert_eq!(url_decode("/%e6"), Ok("/æ".to_string()));
 }
 
+#[cfg(feature = "serve")]
 #[query]
 fn http_request(req: HttpRequest) -> HttpResponse {
+    let handler = STATE.with(|s| *s.fallback_handler.borrow());
+    http_request_with_fallback(req, |req| handler.and_then(|handler| handler(req)))
+}
+
+#[cfg(feature = "serve")]
+#[update]
+fn http_request_update(req: HttpRequest) -> HttpResponse {
+    if let Some(response) = router::route(&req, true) {
+        return response;
+    }
+    http_request(req)
+}
+
+/// Serves `req` from the registered routes or the assets, and lets `fallback`
+/// answer the requests that would otherwise get a 404 response.
+///
+/// Responses returned by routes and by `fallback` are not certified by the
+/// asset canister.
+pub fn http_request_with_fallback<F>(req: HttpRequest, fallback: F) -> HttpResponse
+where
+    F: FnOnce(&HttpRequest) -> Option<HttpResponse>,
+{
+    if let Some(response) = router::route(&req, false) {
+        return response;
+    }
+    #[cfg(feature = "webdav")]
+    if webdav::is_webdav(&req) {
+        return webdav::handle(&req);
+    }
+    #[cfg(feature = "upload")]
+    if http_upload::is_upload(&req) {
+        return http_upload::handle(&req);
+    }
+    let response = serve_asset(&req);
+    if response.status_code == 404 {
+        if let Some(response) = fallback(&req) {
+            return response;
+        }
+    }
+    response
+}
+
+/// Registers a handler answering the requests that don't match any asset.
+///
+/// The handler isn't kept across upgrades, so it should be registered from
+/// both `init` and `post_upgrade`.
+pub fn set_fallback_handler(handler: FallbackHandler) {
+    STATE.with(|s| s.fallback_handler.replace(Some(handler)));
+}
+
+fn serve_asset(req: &HttpRequest) -> HttpResponse {
     let mut encodings = vec![];
     for (name, value) in req.headers.iter() {
         if name.eq_ignore_ascii_case("Accept-Encoding") {
@@ -737,24 +1996,64 @@ fn http_request(req: HttpRequest) -> HttpResponse {
     }
     encodings.push("identity".to_string());
 
-    let path = match req.url.find('?') {
-        Some(i) => &req.url[..i],
-        None => &req.url[..],
+    let (path, query) = match req.url.find('?') {
+        Some(i) => (&req.url[..i], Some(&req.url[i + 1..])),
+        None => (&req.url[..], None),
     };
-    match url_decode(path) {
-        Ok(path) => build_http_response(&path, encodings, 0),
-        Err(err) => HttpResponse {
-            status_code: 400,
-            headers: vec![],
-            body: RcBytes::from(ByteBuf::from(format!(
-                "failed to decode path '{}': {}",
-                path, err
-            ))),
-            streaming_strategy: None,
-        },
+    let path = match url_decode(path) {
+        Ok(path) => path,
+        Err(err) => {
+            return HttpResponse {
+                status_code: 400,
+                headers: vec![],
+                body: RcBytes::from(ByteBuf::from(format!(
+                    "failed to decode path '{}': {}",
+                    path, err
+                ))),
+                streaming_strategy: None,
+                upgrade: None,
+            }
+        }
+    };
+
+    let mut response = build_http_response(&path, query, encodings, 0, req);
+    if let Some(StreamingStrategy::Callback { token, .. }) = response.streaming_strategy.as_mut() {
+        if authenticator::is_protected(&token.key) {
+            token.authorization = authenticator::authorization(req);
+        }
+    }
+    response
+}
+
+/// Returns the URL of `req` on the certified domain, if it was sent to a raw
+/// one such as `<canister id>.raw.icp0.io`.
+fn certified_url(req: &HttpRequest) -> Option<String> {
+    let host = req
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Host"))
+        .map(|(_, value)| value.trim())?;
+    let i = host.find(".raw.")?;
+    Some(format!(
+        "https://{}{}{}",
+        &host[..i],
+        &host[i + ".raw".len()..],
+        req.url
+    ))
+}
+
+/// Asks the gateway to send the request again to `http_request_update`.
+fn build_upgrade() -> HttpResponse {
+    HttpResponse {
+        status_code: 200,
+        headers: vec![],
+        body: RcBytes::from(ByteBuf::new()),
+        streaming_strategy: None,
+        upgrade: Some(true),
     }
 }
 
+#[cfg(feature = "serve")]
 #[query]
 fn http_request_streaming_callback(
     StreamingCallbackToken {
@@ -762,10 +2061,16 @@ fn http_request_streaming_callback(
         content_encoding,
         index,
         sha256,
+        access_token,
+        authorization,
     }: StreamingCallbackToken,
 ) -> StreamingCallbackHttpResponse {
     STATE.with(|s| {
         let assets = s.assets.borrow();
+        // Staged assets are never served, so no response streams them.
+        if staging::is_staged(&key) {
+            trap_error(ASSET_NOT_FOUND);
+        }
         let asset = assets
             .get(&key)
             .expect("Invalid token on streaming: key not found.");
@@ -779,36 +2084,90 @@ fn http_request_streaming_callback(
                 trap("sha256 mismatch");
             }
         }
+        if asset.protected == Some(true)
+            && !access_token
+                .as_deref()
+                .map_or(false, |token| access::is_valid(&key, token))
+        {
+            trap_error(access::ACCESS_DENIED);
+        }
+        if let Err(reason) = authenticator::check_callback(&key, authorization.clone()) {
+            trap_with(authenticator::UNAUTHENTICATED, &reason);
+        }
 
         // MAX is good enough. This means a chunk would be above 64-bits, which is impossible...
         let chunk_index = index.0.to_usize().unwrap_or(usize::MAX);
 
+        let mut token = create_token(asset, &content_encoding, enc, &key, chunk_index);
+        if let Some(token) = token.as_mut() {
+            token.access_token = access_token;
+            token.authorization = authorization;
+        }
         StreamingCallbackHttpResponse {
-            body: enc.content_chunks[chunk_index].clone(),
-            token: create_token(asset, &content_encoding, enc, &key, chunk_index),
+            body: enc.chunk(chunk_index),
+            token,
         }
     })
 }
 
+#[cfg(feature = "upload")]
 fn do_create_asset(arg: CreateAssetArguments) {
+    certification_v2::validate_certified_queries(arg.certified_queries.as_deref());
+    replication::mark_changed(&arg.key);
+    let content_type = mime::resolve(&arg.key, arg.content_type.clone());
     STATE.with(|s| {
         let mut assets = s.assets.borrow_mut();
-        if let Some(asset) = assets.get(&arg.key) {
-            if asset.content_type != arg.content_type {
+        if let Some(asset) = assets.get_mut(&arg.key) {
+            if asset.content_type != content_type {
                 trap("create_asset: content type mismatch");
             }
+            if let Some(tags) = arg.tags {
+                asset.tags = Some(normalize_tags(tags));
+            }
+            if arg.template.is_some() {
+                asset.template = arg.template;
+            }
+            if arg.protected.is_some() {
+                asset.protected = arg.protected;
+            }
+            if arg.content_disposition.is_some() {
+                asset.content_disposition = arg.content_disposition;
+            }
+            if arg.allow_raw_access.is_some() {
+                asset.allow_raw_access = arg.allow_raw_access;
+            }
+            if arg.certified_query_params.is_some() {
+                asset.certified_query_params = arg.certified_query_params;
+            }
+            if arg.certified_queries.is_some() {
+                asset.certified_queries = arg.certified_queries;
+            }
+            if arg.certified_headers.is_some() {
+                asset.certified_headers = arg.certified_headers;
+            }
+            // The properties may change the headers v2 certifies.
+            certification_v2::certify(&arg.key, asset);
         } else {
             assets.insert(
                 arg.key,
                 Asset {
-                    content_type: arg.content_type,
+                    content_type,
                     encodings: HashMap::new(),
+                    tags: arg.tags.map(normalize_tags),
+                    template: arg.template,
+                    protected: arg.protected,
+                    content_disposition: arg.content_disposition,
+                    allow_raw_access: arg.allow_raw_access,
+                    certified_query_params: arg.certified_query_params,
+                    certified_queries: arg.certified_queries,
+                    certified_headers: arg.certified_headers,
                 },
             );
         }
     })
 }
 
+#[cfg(feature = "upload")]
 fn do_set_asset_content(arg: SetAssetContentArguments) {
     STATE.with(|s| {
         if arg.chunk_ids.is_empty() {
@@ -818,74 +2177,292 @@ fn do_set_asset_content(arg: SetAssetContentArguments) {
         let mut assets = s.assets.borrow_mut();
         let asset = assets
             .get_mut(&arg.key)
-            .unwrap_or_else(|| trap("asset not found"));
-        let now = Int::from(time() as u64);
+            .unwrap_or_else(|| trap_error(ASSET_NOT_FOUND));
+        check_expected_sha256(Some(asset), &arg.content_encoding, arg.expected_sha256);
 
-        let mut chunks = s.chunks.borrow_mut();
+        // A chunk may be used several times, by one asset or several, so it
+        // is only consumed at the end of the call or batch.
+        let chunks = s.chunks.borrow();
 
+        // The encoding keeps the hashes of the algorithm of its first chunk,
+        // the chunks hashed otherwise are hashed again.
         let mut content_chunks = vec![];
+        let mut chunk_hashes = vec![];
+        let mut chunk_hash_algorithm = None;
         for chunk_id in arg.chunk_ids.iter() {
-            let chunk = chunks.remove(chunk_id).expect("chunk not found");
-            content_chunks.push(chunk.content);
+            let chunk = chunks.get(chunk_id).expect("chunk not found");
+            let algorithm = *chunk_hash_algorithm.get_or_insert(chunk.hash_algorithm);
+            content_chunks.push(chunk.content.clone());
+            chunk_hashes.push(if chunk.hash_algorithm == algorithm {
+                chunk.hash
+            } else {
+                algorithm.hash(&chunk.content)
+            });
         }
 
-        let sha256: [u8; 32] = match arg.sha256 {
-            Some(bytes) => bytes
-                .into_vec()
-                .try_into()
-                .unwrap_or_else(|_| trap("invalid SHA-256")),
+        set_encoding(
+            &arg.key,
+            asset,
+            arg.content_encoding,
+            content_chunks,
+            (chunk_hash_algorithm.unwrap_or_default(), chunk_hashes),
+            arg.sha256,
+        );
+    })
+}
+
+#[cfg(feature = "upload")]
+fn do_patch_asset_content(arg: PatchAssetContentArguments) {
+    STATE.with(|s| {
+        let max_chunk_size = s.config.borrow().max_chunk_size() as usize;
+        let max_patched_size = s.config.borrow().max_patched_size() as usize;
+        let mut assets = s.assets.borrow_mut();
+        let asset = assets
+            .get_mut(&arg.key)
+            .unwrap_or_else(|| trap_error(ASSET_NOT_FOUND));
+        check_expected_sha256(Some(asset), &arg.content_encoding, arg.expected_sha256);
+        let enc = asset
+            .encodings
+            .get(&arg.content_encoding)
+            .unwrap_or_else(|| trap_error(ENCODING_NOT_FOUND));
+
+        // Templates are patched before rendering, like they are uploaded.
+        let base = match enc.template_source.as_ref() {
+            Some(source) => source.as_ref().to_vec(),
             None => {
-                let mut hasher = sha2::Sha256::new();
-                for chunk in content_chunks.iter() {
-                    hasher.update(chunk);
+                let mut base = Vec::with_capacity(enc.total_length);
+                for i in 0..enc.chunk_count() {
+                    base.extend_from_slice(&enc.chunk(i));
                 }
-                hasher.finalize().into()
+                base
             }
         };
+        let content = apply_patch(&base, arg.patch, max_patched_size);
+        if content.is_empty() {
+            trap("encoding must have at least one chunk");
+        }
+        heap::reserve(content.len());
 
-        let total_length: usize = content_chunks.iter().map(|c| c.len()).sum();
-        let enc = AssetEncoding {
-            modified: now,
+        let content_chunks: Vec<RcBytes> = content
+            .chunks(max_chunk_size)
+            .map(|chunk| RcBytes::from(ByteBuf::from(chunk.to_vec())))
+            .collect();
+        let chunk_hashes = content_chunks
+            .iter()
+            .map(|chunk| hash_bytes(chunk))
+            .collect();
+        set_encoding(
+            &arg.key,
+            asset,
+            arg.content_encoding,
             content_chunks,
-            certified: false,
-            total_length,
-            sha256,
+            (HashAlgorithm::Sha256, chunk_hashes),
+            arg.sha256,
+        );
+    })
+}
+
+/// Builds the content `patch` describes against `base`, trapping if it
+/// reads past the end of `base` or builds more than `max_size` bytes.
+#[cfg(feature = "upload")]
+fn apply_patch(base: &[u8], patch: Vec<PatchInstruction>, max_size: usize) -> Vec<u8> {
+    let mut content = vec![];
+    for instruction in patch {
+        let bytes = match &instruction {
+            PatchInstruction::Copy { offset, length } => {
+                let range = match (offset.0.to_usize(), length.0.to_usize()) {
+                    (Some(offset), Some(length)) => offset..offset.saturating_add(length),
+                    _ => trap("patch out of bounds"),
+                };
+                base.get(range)
+                    .unwrap_or_else(|| trap("patch out of bounds"))
+            }
+            PatchInstruction::Insert(bytes) => bytes.as_slice(),
         };
-        asset.encodings.insert(arg.content_encoding, enc);
+        if bytes.len() > max_size - content.len() {
+            trap(&format!("the patched content exceeds {} bytes", max_size));
+        }
+        content.extend_from_slice(bytes);
+    }
+    content
+}
 
-        on_asset_change(&arg.key, asset);
-    })
+/// Replaces the `content_encoding` of `asset` with `content_chunks`, hashed
+/// as `chunk_hashes`, after checking the sha256 the caller provided, if any.
+#[cfg(feature = "upload")]
+fn set_encoding(
+    key: &str,
+    asset: &mut Asset,
+    content_encoding: String,
+    content_chunks: Vec<RcBytes>,
+    (chunk_hash_algorithm, chunk_hashes): (HashAlgorithm, Vec<Hash>),
+    provided_sha256: Option<ByteBuf>,
+) {
+    let now = Int::from(time() as u64);
+
+    // The certified hash must be the digest of the full body, so it is
+    // always computed here and the caller's one is only checked against it.
+    let mut hasher = sha2::Sha256::new();
+    for chunk in content_chunks.iter() {
+        hasher.update(chunk);
+    }
+    let sha256: [u8; 32] = hasher.finalize().into();
+    if let Some(provided_hash) = provided_sha256 {
+        if sha256 != provided_hash.as_ref() {
+            trap("sha256 mismatch");
+        }
+    }
+
+    let total_length: usize = content_chunks.iter().map(|c| c.len()).sum();
+    let mut enc = AssetEncoding {
+        modified: now,
+        content_chunks,
+        certified: false,
+        total_length,
+        sha256,
+        chunk_hashes: Some(chunk_hashes),
+        chunk_hash_algorithm: Some(chunk_hash_algorithm),
+        template_source: None,
+        stable_chunks: None,
+    };
+    if asset.template == Some(true) {
+        templates::render_encoding(&content_encoding, &mut enc);
+    }
+    if let Some(mut old) = asset.encodings.insert(content_encoding, enc) {
+        old.release();
+    }
+
+    on_asset_change(key, asset);
+}
+
+/// Traps with [CONTENT_CHANGED] if `expected` is set and the encoding of
+/// `asset` doesn't hold content with that hash, or doesn't exist.
+#[cfg(feature = "upload")]
+fn check_expected_sha256(asset: Option<&Asset>, content_encoding: &str, expected: Option<ByteBuf>) {
+    let expected = match expected {
+        Some(expected) => expected,
+        None => return,
+    };
+    let current = asset.and_then(|asset| asset.encodings.get(content_encoding));
+    if current.map_or(true, |enc| enc.sha256 != expected.as_ref()) {
+        trap_error(CONTENT_CHANGED);
+    }
 }
 
+#[cfg(feature = "upload")]
 fn do_unset_asset_content(arg: UnsetAssetContentArguments) {
     STATE.with(|s| {
         let mut assets = s.assets.borrow_mut();
         let asset = assets
             .get_mut(&arg.key)
-            .unwrap_or_else(|| trap("asset not found"));
+            .unwrap_or_else(|| trap_error(ASSET_NOT_FOUND));
 
-        if asset.encodings.remove(&arg.content_encoding).is_some() {
+        if let Some(mut enc) = asset.encodings.remove(&arg.content_encoding) {
+            enc.release();
             on_asset_change(&arg.key, asset);
         }
     })
 }
 
+#[cfg(feature = "upload")]
 fn do_delete_asset(arg: DeleteAssetArguments) {
+    replication::mark_changed(&arg.key);
     STATE.with(|s| {
         let mut assets = s.assets.borrow_mut();
-        assets.remove(&arg.key);
+        if let Some(mut asset) = assets.remove(&arg.key) {
+            for enc in asset.encodings.values_mut() {
+                enc.release();
+            }
+        }
     });
+    heap::asset_removed(&arg.key);
     delete_asset_hash(&arg.key);
+    certification_v2::remove_key(&arg.key);
+}
+
+#[cfg(feature = "upload")]
+fn do_set_asset_tags(arg: SetAssetTagsArguments) {
+    replication::mark_changed(&arg.key);
+    STATE.with(|s| {
+        let mut assets = s.assets.borrow_mut();
+        let asset = assets
+            .get_mut(&arg.key)
+            .unwrap_or_else(|| trap_error(ASSET_NOT_FOUND));
+        asset.tags = Some(normalize_tags(arg.tags));
+    })
+}
+
+#[cfg(feature = "upload")]
+fn do_delete_by_tag(arg: DeleteByTagArguments) {
+    let keys: Vec<Key> = STATE.with(|s| {
+        s.assets
+            .borrow()
+            .iter()
+            .filter(|(_, asset)| asset.has_tag(&arg.tag))
+            .map(|(key, _)| key.clone())
+            .collect()
+    });
+    for key in keys {
+        do_delete_asset(DeleteAssetArguments { key });
+    }
+}
+
+#[cfg(feature = "upload")]
+fn normalize_tags(mut tags: Vec<String>) -> Vec<String> {
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+/// Renames the asset `from` to `to`, replacing the asset `to` if there is
+/// one.
+#[cfg(feature = "upload")]
+fn do_move_asset(from: &str, to: &str) {
+    let asset = STATE.with(|s| s.assets.borrow_mut().remove(from));
+    replication::mark_changed(from);
+    heap::asset_removed(from);
+    delete_asset_hash(from);
+    certification_v2::remove_key(from);
+    if let Some(mut asset) = asset {
+        do_delete_asset(DeleteAssetArguments {
+            key: to.to_string(),
+        });
+        for enc in asset.encodings.values_mut() {
+            enc.certified = false;
+        }
+        on_asset_change(to, &mut asset);
+        STATE.with(|s| s.assets.borrow_mut().insert(to.to_string(), asset));
+    }
+}
+
+/// Drops the uploaded chunks `keep` returns false for.
+#[cfg(feature = "upload")]
+fn retain_chunks(s: &State, mut keep: impl FnMut(&Chunk) -> bool) {
+    s.chunks.borrow_mut().retain(|_, chunk| {
+        let kept = keep(chunk);
+        if !kept {
+            heap::chunk_dropped(chunk.content.len());
+        }
+        kept
+    });
 }
 
 fn do_clear() {
+    replication::mark_cleared();
     STATE.with(|s| {
         s.assets.borrow_mut().clear();
-        s.batches.borrow_mut().clear();
-        s.chunks.borrow_mut().clear();
-        *s.next_batch_id.borrow_mut() = Nat::from(1);
-        *s.next_chunk_id.borrow_mut() = Nat::from(1);
-    })
+        stable_bytes::clear();
+        #[cfg(feature = "upload")]
+        {
+            s.batches.borrow_mut().clear();
+            s.chunks.borrow_mut().clear();
+            *s.next_batch_id.borrow_mut() = Nat::from(1);
+            *s.next_chunk_id.borrow_mut() = Nat::from(1);
+        }
+    });
+    #[cfg(feature = "upload")]
+    heap::clear();
+    certification_v2::clear();
 }
 
 pub fn is_authorized() -> Result<(), String> {
@@ -898,7 +2475,89 @@ pub fn is_authorized() -> Result<(), String> {
     })
 }
 
+/// Like [is_authorized], but also fails while the canister is read-only.
+#[cfg(any(feature = "upload", feature = "admin"))]
+fn is_writable() -> Result<(), String> {
+    is_authorized()?;
+    if is_readonly() {
+        return Err(READONLY.to_string());
+    }
+    Ok(())
+}
+
+#[cfg(any(feature = "upload", feature = "admin"))]
+fn is_readonly() -> bool {
+    STATE.with(|s| s.config.borrow().readonly.unwrap_or(false))
+}
+
+/// Traps if the canister became read-only while an async call awaited, since
+/// its guard only ran before the first await.
+#[cfg(feature = "upload")]
+fn trap_if_readonly() {
+    if is_readonly() {
+        trap(READONLY);
+    }
+}
+
+/// The methods that only authorized principals may call.
+const AUTHORIZED_METHODS: &[&str] = &[
+    "authorize",
+    "clear",
+    "commit_batch",
+    "commit_proposed_batch",
+    "create_access_token",
+    "create_asset",
+    "create_batch",
+    "create_chunk",
+    "create_upload_token",
+    "deauthorize",
+    "delete_by_tag",
+    "delete_content",
+    "export_archive",
+    "import_archive",
+    "import_from_url",
+    "patch_asset_content",
+    "propose_commit_batch",
+    "publish",
+    "replicate_to",
+    "run_benchmarks",
+    "set_asset_content",
+    "set_asset_tags",
+    "set_readonly",
+    "set_template_variable",
+    "stop_replication",
+    "store",
+    "store_many",
+    "subscribe",
+    "sync_from",
+    "unset_asset_content",
+    "unsubscribe",
+    "verify_integrity",
+];
+
+/// Accepts the ingress message, unless it calls an upload or admin method
+/// and the caller is not authorized.
+///
+/// Call this from the canister's `#[inspect_message]` function, so that
+/// unauthorized uploads are turned down before the canister pays for them.
+/// The methods stay guarded either way.
+pub fn inspect_message() {
+    let method = method_name();
+    if !AUTHORIZED_METHODS.contains(&method.as_str()) || is_authorized().is_ok() {
+        accept_message();
+    }
+}
+
 fn on_asset_change(key: &str, asset: &mut Asset) {
+    replication::mark_changed(key);
+
+    for enc in asset.encodings.values_mut() {
+        enc.offload();
+    }
+    #[cfg(feature = "upload")]
+    heap::asset_changed(key, asset);
+    certification_v2::certify(key, asset);
+
     // If the most preferred encoding is present and certified,
     // there is nothing to do.
     for enc_name in ENCODING_CERTIFICATION_ORDER.iter() {
@@ -957,28 +2616,118 @@ fn delete_asset_hash(key: &str) {
 }
 
 fn set_root_hash(tree: &AssetHashes) {
-    use ic_certified_map::labeled_hash;
-    let full_tree_hash = labeled_hash(b"http_assets", &tree.root_hash());
-    set_certified_data(&full_tree_hash);
+    set_subtree_root(&assets_label(), Some(tree.root_hash()));
 }
 
-fn witness_to_header(witness: HashTree) -> HeaderField {
-    use ic_certified_map::labeled;
+/// The label the asset hashes are certified under.
+fn assets_label() -> Vec<u8> {
+    STATE.with(|s| s.config.borrow().assets_label().as_bytes().to_vec())
+}
 
-    let hash_tree = labeled(b"http_assets", witness);
+/// Updates the subtree under `label` and certifies the new root hash.
+fn set_subtree_root(label: &[u8], root_hash: Option<Hash>) {
+    let deferred = DEFERRED_ROOTS.with(|d| match d.borrow_mut().as_mut() {
+        Some(roots) => {
+            roots.insert(label.to_vec(), root_hash);
+            true
+        }
+        None => false,
+    });
+    if deferred {
+        return;
+    }
+    // Cached witnesses prune the other subtrees, so they change too.
+    witness_cache::invalidate();
+    CERTIFIED_TREES.with(|t| {
+        let mut trees = t.borrow_mut();
+        match root_hash {
+            Some(hash) => trees.insert(label, hash),
+            None => trees.remove(label),
+        }
+        set_certified_data(&trees.root_hash());
+    });
+}
+
+/// Certifies again the responses of the assets whose key starts with
+/// `prefix`, e.g. after the error responses they may get changed.
+fn recertify_prefix(prefix: &str) {
+    STATE.with(|s| {
+        let assets = s.assets.borrow();
+        with_deferred_certification(|| {
+            for (key, asset) in assets.iter().filter(|(key, _)| key.starts_with(prefix)) {
+                certification_v2::certify(key, asset);
+            }
+        })
+    })
+}
+
+/// Runs `f`, then certifies the subtrees it changed at once rather than after
+/// every change.
+fn with_deferred_certification<R>(f: impl FnOnce() -> R) -> R {
+    DEFERRED_ROOTS.with(|d| d.replace(Some(BTreeMap::new())));
+    let result = f();
+    let roots = DEFERRED_ROOTS.with(|d| d.take()).unwrap_or_default();
+    for (label, root_hash) in roots {
+        set_subtree_root(&label, root_hash);
+    }
+    result
+}
+
+/// Returns the certified data of the canister.
+#[cfg(any(feature = "upload", feature = "metrics"))]
+fn root_hash() -> Hash {
+    CERTIFIED_TREES.with(|t| t.borrow().root_hash())
+}
+
+/// Certifies application data next to the assets: `root_hash` becomes the
+/// root hash of the subtree under `label`, or the subtree is removed if it is
+/// `None`.
+///
+/// The subtrees only live on the heap, so they have to be certified again
+/// after an upgrade. Traps if `label` is the label of the asset hashes,
+/// `http_assets` unless configured otherwise.
+pub fn certify_subtree(label: &[u8], root_hash: Option<Hash>) {
+    if label == assets_label().as_slice() {
+        trap("the label is reserved for the asset hashes");
+    }
+    if label == certification_v2::EXPR_LABEL {
+        trap("http_expr is reserved for the certified responses");
+    }
+    set_subtree_root(label, root_hash);
+}
+
+/// Serializes `witness`, a witness of the subtree under `label`, into a
+/// witness of the certified data, in the CBOR format of certificate trees.
+///
+/// Sent along with [data_certificate], it lets clients check the witnessed
+/// values.
+pub fn subtree_witness(label: &[u8], witness: HashTree) -> Vec<u8> {
+    CERTIFIED_TREES.with(|t| serialize_tree(t.borrow().witness(label, witness)))
+}
+
+/// Serializes `witness` the way it appears in the `tree` field of the
+/// `IC-Certificate` header.
+fn serialize_witness(witness: HashTree) -> String {
+    base64::encode(&witness_bytes(witness))
+}
+
+fn witness_bytes(witness: HashTree) -> Vec<u8> {
+    subtree_witness(&assets_label(), witness)
+}
+
+fn serialize_tree(hash_tree: HashTree) -> Vec<u8> {
     let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
     serializer.self_describe().unwrap();
     hash_tree.serialize(&mut serializer).unwrap();
+    serializer.into_inner()
+}
 
+fn certificate_header(tree: String) -> HeaderField {
     let certificate = data_certificate().unwrap_or_else(|| trap("no data certificate available"));
 
     (
         "IC-Certificate".to_string(),
-        String::from("certificate=:")
-            + &base64::encode(&certificate)
-            + ":, tree=:"
-            + &base64::encode(&serializer.into_inner())
-            + ":",
+        String::from("certificate=:") + &base64::encode(&certificate) + ":, tree=:" + &tree + ":",
     )
 }
 
@@ -1017,30 +2766,88 @@ fn merge_hash_trees<'a>(lhs: HashTree<'a>, rhs: HashTree<'a>) -> HashTree<'a> {
     }
 }
 
+#[cfg(any(feature = "serve", feature = "upload"))]
+fn trap_error((code, message): CodedTrap) -> ! {
+    trap_with(code, message)
+}
+
 fn hash_bytes(bytes: &[u8]) -> Hash {
     let mut hash = sha2::Sha256::new();
     hash.update(bytes);
     hash.finalize().into()
 }
 
-pub fn init() {
+pub fn init(args: Option<AssetCanisterArgs>) {
     do_clear();
-    STATE.with(|s| s.authorized.borrow_mut().push(caller()));
+    STATE.with(|s| {
+        let args = args.unwrap_or_default();
+        let mut authorized = s.authorized.borrow_mut();
+        authorized.push(caller());
+        authorize_all(&mut authorized, args.authorized.clone());
+
+        let mut config = Config::default();
+        config.apply(args);
+        s.config.replace(config);
+    });
+    // The 404 response carries the configured security headers.
+    certification_v2::certify_not_found();
+}
+
+/// Keeps the content of the assets in `memory` rather than on the heap, so
+/// the canister can hold more content than fits in its heap. Chunks are then
+/// read from the stable memory when they are served.
+///
+/// Call this before [init] and [post_upgrade], with the same memory across
+/// upgrades. The content already on the heap moves to the memory after the
+/// next upgrade. Nothing else may use the memory, and the state returned by
+/// [pre_upgrade] has to be saved in another memory of the same manager rather
+/// than with `ic_cdk::storage`, which writes from the start of the stable
+/// memory.
+pub fn set_content_memory(memory: ic_cdk::api::stable::memory_manager::VirtualMemory) {
+    stable_bytes::set_memory(memory)
 }
 
 pub fn pre_upgrade() -> StableState {
     STATE.with(|s| StableState {
         authorized: s.authorized.take(),
         stable_assets: s.assets.take(),
+        config: Some(s.config.take()),
+        mirrors: Some(s.mirrors.take()),
+        subscribers: Some(s.subscribers.take()),
+        template_variables: Some(s.template_variables.take()),
+        access_secret: s.access_secret.take(),
+        collections: Some(s.collections.take()),
     })
 }
 
-pub fn post_upgrade(stable_state: StableState) {
+pub fn post_upgrade(mut stable_state: StableState, args: Option<AssetCanisterArgs>) {
     do_clear();
+    let mirrors = stable_state.mirrors.take();
     STATE.with(|s| {
         s.authorized.replace(stable_state.authorized);
         s.assets.replace(stable_state.stable_assets);
-
+        s.subscribers
+            .replace(stable_state.subscribers.unwrap_or_default());
+        s.template_variables
+            .replace(stable_state.template_variables.unwrap_or_default());
+        s.access_secret.replace(stable_state.access_secret);
+        s.collections
+            .replace(stable_state.collections.unwrap_or_default());
+
+        let mut config = stable_state.config.unwrap_or_default();
+        if let Some(args) = args {
+            authorize_all(&mut s.authorized.borrow_mut(), args.authorized.clone());
+            config.apply(args);
+        }
+        s.config.replace(config);
+
+        stable_bytes::restore(
+            s.assets
+                .borrow()
+                .values()
+                .flat_map(|asset| asset.encodings.values())
+                .flat_map(|enc| enc.stable_chunks.iter().flatten().copied()),
+        );
         for (asset_name, asset) in s.assets.borrow_mut().iter_mut() {
             for enc in asset.encodings.values_mut() {
                 enc.certified = false;
@@ -1048,4 +2855,260 @@ pub fn post_upgrade(stable_state: StableState) {
             on_asset_change(asset_name, asset);
         }
     });
+    certification_v2::certify_not_found();
+    replication::restore(mirrors.unwrap_or_default());
+}
+
+/// Drives background work such as replication to mirror canisters.
+///
+/// Call this from the canister's `#[heartbeat]` function.
+///
+/// The instructions spent refreshing the witness cache are recorded under
+/// `witness_cache_refresh` in `ic_cdk::api::profiling`.
+pub fn heartbeat() {
+    replication::start_pending();
+    with_instruction_budget("witness_cache_refresh", witness_cache::refresh);
+}
+
+fn authorize_all(authorized: &mut Vec<Principal>, principals: Option<Vec<Principal>>) {
+    for principal in principals.into_iter().flatten() {
+        if !authorized.contains(&principal) {
+            authorized.push(principal);
+        }
+    }
+}
+
+ic_cdk_macros::export_candid!();
+
+/// Returns the Candid service description of the asset canister interface.
+///
+/// Canisters that add methods of their own can use this as a starting point
+/// for their `.did` file or `candid:service` metadata.
+pub fn candid_interface() -> String {
+    __export_service()
+}
+
+#[query(hidden = true)]
+fn __get_candid_interface_tmp_hack() -> String {
+    candid_interface()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn etag_args(weak: bool, hex_digits: Option<u8>) -> AssetCanisterArgs {
+        AssetCanisterArgs {
+            etag_format: Some(EtagFormat { weak, hex_digits }),
+            ..AssetCanisterArgs::default()
+        }
+    }
+
+    #[test]
+    fn renders_etags() {
+        let sha256 = [0xab; 32];
+        assert_eq!(
+            EtagFormat::default().render(&sha256),
+            format!("\"{}\"", "ab".repeat(32))
+        );
+        let format = EtagFormat {
+            weak: true,
+            hex_digits: Some(7),
+        };
+        assert_eq!(format.render(&sha256), "W/\"abababa\"");
+    }
+
+    #[test]
+    fn accepts_etag_digits_up_to_the_hash_length() {
+        let mut config = Config::default();
+        for digits in [1, 64].iter() {
+            config.apply(etag_args(false, Some(*digits)));
+            assert_eq!(
+                config.etag_format.as_ref().unwrap().hex_digits,
+                Some(*digits)
+            );
+        }
+        config.apply(etag_args(true, None));
+        assert_eq!(
+            config.etag_format,
+            Some(EtagFormat {
+                weak: true,
+                hex_digits: None
+            })
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "between 1 and 64")]
+    fn rejects_empty_etags() {
+        Config::default().apply(etag_args(false, Some(0)));
+    }
+
+    #[test]
+    #[should_panic(expected = "between 1 and 64")]
+    fn rejects_etags_longer_than_the_hash() {
+        Config::default().apply(etag_args(false, Some(65)));
+    }
+
+    /// The labels of `tree`, outside of its pruned branches.
+    fn witness_labels(tree: &HashTree) -> Vec<Vec<u8>> {
+        match tree {
+            HashTree::Fork(forks) => {
+                let mut labels = witness_labels(&forks.0);
+                labels.extend(witness_labels(&forks.1));
+                labels
+            }
+            HashTree::Labeled(label, subtree) => {
+                let mut labels = vec![label.to_vec()];
+                labels.extend(witness_labels(subtree));
+                labels
+            }
+            _ => vec![],
+        }
+    }
+
+    fn fallback_witness_labels(prune: bool) -> Vec<Vec<u8>> {
+        STATE.with(|s| {
+            s.config.replace(Config {
+                prune_fallback_witness: Some(prune),
+                ..Config::default()
+            });
+        });
+        ASSET_HASHES.with(|t| {
+            let mut tree = RbTree::new();
+            for key in ["/a", "/c", "/index.html"].iter() {
+                tree.insert(key.to_string(), hash_bytes(key.as_bytes()));
+            }
+            t.replace(tree);
+        });
+        let asset = Asset::default();
+        let enc = AssetEncoding::default();
+        let resolved = ResolvedAsset {
+            key: "/index.html",
+            asset: &asset,
+            enc_name: "identity",
+            enc: &enc,
+            is_fallback: true,
+        };
+        with_asset_witness("/b", Some(&resolved), |witness| {
+            let root_hash = ASSET_HASHES.with(|t| t.borrow().root_hash());
+            assert_eq!(witness.reconstruct(), root_hash);
+            witness_labels(&witness)
+        })
+    }
+
+    #[test]
+    fn fallback_witness_proves_the_path_absent() {
+        let labels = fallback_witness_labels(false);
+        for key in ["/a", "/c", "/index.html"].iter() {
+            assert!(labels.contains(&key.as_bytes().to_vec()), "{}", key);
+        }
+    }
+
+    #[test]
+    fn pruned_fallback_witness_only_proves_the_fallback_file() {
+        assert_eq!(fallback_witness_labels(true), vec![b"/index.html".to_vec()]);
+    }
+
+    fn get(url: &str) -> HttpResponse {
+        http_request(HttpRequest {
+            method: "GET".to_string(),
+            url: url.to_string(),
+            headers: vec![],
+            body: ByteBuf::new(),
+        })
+    }
+
+    fn certificate(response: &HttpResponse) -> Option<&str> {
+        response
+            .headers
+            .iter()
+            .find(|(name, _)| name == "IC-Certificate")
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn serves_certified_assets() {
+        ic_cdk::api::mock::reset();
+        STATE.with(|s| s.config.replace(Config::default()));
+        do_clear();
+        do_store(StoreArg {
+            key: "/a.txt".to_string(),
+            content_type: "text/plain".to_string(),
+            content_encoding: "identity".to_string(),
+            content: RcBytes::from(ByteBuf::from(b"hello".to_vec())),
+            sha256: None,
+            expected_sha256: None,
+        });
+        ic_cdk::api::mock::set_data_certificate(Some(vec![1, 2, 3]));
+
+        let response = get("/a.txt");
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.body.as_ref(), b"hello");
+        assert!(certificate(&response)
+            .unwrap()
+            .starts_with("certificate=:AQID:"));
+        let response = get("/missing.txt");
+        assert_eq!(response.status_code, 404);
+        assert!(certificate(&response).is_some());
+
+        // The subnet certifies the responses of updates itself.
+        ic_cdk::api::mock::set_replicated_execution(true);
+        let response = get("/a.txt");
+        assert_eq!(response.status_code, 200);
+        assert_eq!(certificate(&response), None);
+        do_clear();
+    }
+
+    fn copy(offset: u64, length: u64) -> PatchInstruction {
+        PatchInstruction::Copy {
+            offset: Nat::from(offset),
+            length: Nat::from(length),
+        }
+    }
+
+    fn insert(bytes: &[u8]) -> PatchInstruction {
+        PatchInstruction::Insert(ByteBuf::from(bytes.to_vec()))
+    }
+
+    #[test]
+    fn applies_patches() {
+        let patch = vec![copy(0, 6), insert(b"patched"), copy(11, 7)];
+        assert_eq!(
+            apply_patch(b"hello world, again", patch, 100),
+            b"hello patched, again"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "patch out of bounds")]
+    fn rejects_copies_past_the_content() {
+        apply_patch(b"hello", vec![copy(3, 3)], 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "patch out of bounds")]
+    fn rejects_copies_overflowing_the_offset() {
+        apply_patch(b"hello", vec![copy(u64::MAX, 2)], 100);
+    }
+
+    #[test]
+    fn accepts_patched_content_up_to_the_maximum() {
+        assert_eq!(
+            apply_patch(b"abcd", vec![copy(0, 4), copy(0, 4)], 8).len(),
+            8
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "the patched content exceeds 8 bytes")]
+    fn rejects_copies_beyond_the_maximum() {
+        apply_patch(b"abcd", vec![copy(0, 4); 3], 8);
+    }
+
+    #[test]
+    #[should_panic(expected = "the patched content exceeds 8 bytes")]
+    fn rejects_inserts_beyond_the_maximum() {
+        apply_patch(b"abcd", vec![copy(0, 4), insert(b"efghi")], 8);
+    }
 }
@@ -0,0 +1,74 @@
+//! Content types inferred from the extension of the key, for uploads that
+//! don't give a specific one.
+
+use crate::STATE;
+
+/// The content types of common web file extensions.
+const MIME_TYPES: &[(&str, &str)] = &[
+    ("avif", "image/avif"),
+    ("css", "text/css"),
+    ("csv", "text/csv"),
+    ("gif", "image/gif"),
+    ("htm", "text/html"),
+    ("html", "text/html"),
+    ("ico", "image/x-icon"),
+    ("jpeg", "image/jpeg"),
+    ("jpg", "image/jpeg"),
+    ("js", "text/javascript"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("md", "text/markdown"),
+    ("mjs", "text/javascript"),
+    ("mp3", "audio/mpeg"),
+    ("mp4", "video/mp4"),
+    ("ogg", "audio/ogg"),
+    ("otf", "font/otf"),
+    ("pdf", "application/pdf"),
+    ("png", "image/png"),
+    ("svg", "image/svg+xml"),
+    ("ttf", "font/ttf"),
+    ("txt", "text/plain"),
+    ("wasm", "application/wasm"),
+    ("wav", "audio/wav"),
+    ("webm", "video/webm"),
+    ("webmanifest", "application/manifest+json"),
+    ("webp", "image/webp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("xml", "application/xml"),
+    ("zip", "application/zip"),
+];
+
+const GENERIC_TYPE: &str = "application/octet-stream";
+
+/// Returns `content_type`, or the type inferred from the extension of `key`
+/// if it is empty or `application/octet-stream`.
+///
+/// The extensions set in the configuration take precedence over the built-in
+/// ones. Unknown extensions keep `application/octet-stream`.
+pub(crate) fn resolve(key: &str, content_type: String) -> String {
+    if !content_type.is_empty() && content_type != GENERIC_TYPE {
+        return content_type;
+    }
+    infer(key).unwrap_or_else(|| GENERIC_TYPE.to_string())
+}
+
+fn infer(key: &str) -> Option<String> {
+    let file_name = &key[key.rfind('/').map_or(0, |i| i + 1)..];
+    let extension = file_name[file_name.rfind('.')? + 1..].to_ascii_lowercase();
+    let configured = STATE.with(|s| {
+        s.config
+            .borrow()
+            .mime_types
+            .iter()
+            .flatten()
+            .find(|(ext, _)| ext.eq_ignore_ascii_case(&extension))
+            .map(|(_, content_type)| content_type.clone())
+    });
+    configured.or_else(|| {
+        MIME_TYPES
+            .iter()
+            .find(|(ext, _)| *ext == extension)
+            .map(|(_, content_type)| content_type.to_string())
+    })
+}
@@ -0,0 +1,143 @@
+//! One-way notifications sent to subscriber canisters after every
+//! `commit_batch`, so caches and indexers can react to deploys.
+
+#[cfg(feature = "admin")]
+use crate::is_authorized;
+#[cfg(any(feature = "upload", feature = "admin"))]
+use crate::STATE;
+#[cfg(feature = "upload")]
+use crate::{root_hash, BatchOperation};
+use crate::{BatchId, Key};
+#[cfg(feature = "upload")]
+use ic_cdk::api::call::notify;
+use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+#[cfg(feature = "admin")]
+use ic_cdk_macros::{query, update};
+use serde_bytes::ByteBuf;
+#[cfg(feature = "upload")]
+use std::collections::BTreeSet;
+
+/// The method called on subscribers that don't specify one.
+#[cfg(feature = "admin")]
+const DEFAULT_METHOD: &str = "on_assets_committed";
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub(crate) struct Subscriber {
+    canister_id: Principal,
+    method: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct SubscribeArguments {
+    canister_id: Principal,
+    method: Option<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct CommitNotification {
+    batch_id: BatchId,
+    changed_keys: Vec<Key>,
+    root_hash: ByteBuf,
+}
+
+#[cfg(feature = "admin")]
+#[update(guard = "is_authorized")]
+fn subscribe(arg: SubscribeArguments) {
+    let subscriber = Subscriber {
+        canister_id: arg.canister_id,
+        method: arg.method.unwrap_or_else(|| DEFAULT_METHOD.to_string()),
+    };
+    STATE.with(|s| {
+        let mut subscribers = s.subscribers.borrow_mut();
+        subscribers.retain(|other| other.canister_id != subscriber.canister_id);
+        subscribers.push(subscriber);
+    })
+}
+
+#[cfg(feature = "admin")]
+#[update(guard = "is_authorized")]
+fn unsubscribe(canister_id: Principal) {
+    STATE.with(|s| {
+        s.subscribers
+            .borrow_mut()
+            .retain(|subscriber| subscriber.canister_id != canister_id);
+    })
+}
+
+#[cfg(feature = "admin")]
+#[query]
+fn validate_subscribe(arg: SubscribeArguments) -> Result<String, String> {
+    let method = arg.method.as_deref().unwrap_or(DEFAULT_METHOD);
+    Ok(format!(
+        "notify {} of the commits through {}",
+        arg.canister_id, method
+    ))
+}
+
+#[cfg(feature = "admin")]
+#[query]
+fn validate_unsubscribe(canister_id: Principal) -> Result<String, String> {
+    STATE.with(|s| {
+        if s.subscribers
+            .borrow()
+            .iter()
+            .any(|subscriber| subscriber.canister_id == canister_id)
+        {
+            Ok(format!("stop notifying {} of the commits", canister_id))
+        } else {
+            Err(format!("{} is not subscribed", canister_id))
+        }
+    })
+}
+
+#[cfg(feature = "admin")]
+#[query]
+fn list_subscribers() -> Vec<Subscriber> {
+    STATE.with(|s| s.subscribers.borrow().clone())
+}
+
+/// Returns the keys that applying `op` may change. Must be called before the
+/// operation is applied.
+#[cfg(feature = "upload")]
+pub(crate) fn affected_keys(op: &BatchOperation) -> Vec<Key> {
+    match op {
+        BatchOperation::CreateAsset(arg) => vec![arg.key.clone()],
+        BatchOperation::SetAssetContent(arg) => vec![arg.key.clone()],
+        BatchOperation::PatchAssetContent(arg) => vec![arg.key.clone()],
+        BatchOperation::UnsetAssetContent(arg) => vec![arg.key.clone()],
+        BatchOperation::DeleteAsset(arg) => vec![arg.key.clone()],
+        BatchOperation::SetAssetTags(arg) => vec![arg.key.clone()],
+        BatchOperation::DeleteByTag(arg) => STATE.with(|s| {
+            s.assets
+                .borrow()
+                .iter()
+                .filter(|(_, asset)| asset.has_tag(&arg.tag))
+                .map(|(key, _)| key.clone())
+                .collect()
+        }),
+        BatchOperation::Clear(_) => STATE.with(|s| s.assets.borrow().keys().cloned().collect()),
+    }
+}
+
+/// Notifies every subscriber that `batch_id` was committed.
+#[cfg(feature = "upload")]
+pub(crate) fn notify_commit(batch_id: BatchId, changed_keys: BTreeSet<Key>) {
+    let subscribers = STATE.with(|s| s.subscribers.borrow().clone());
+    if subscribers.is_empty() {
+        return;
+    }
+    let notification = CommitNotification {
+        batch_id,
+        changed_keys: changed_keys.into_iter().collect(),
+        root_hash: ByteBuf::from(root_hash()),
+    };
+    for subscriber in subscribers {
+        // Delivery is best effort: a subscriber that can't be reached must
+        // not make the commit fail.
+        let _ = notify(
+            subscriber.canister_id,
+            &subscriber.method,
+            (notification.clone(),),
+        );
+    }
+}
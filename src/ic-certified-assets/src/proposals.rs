@@ -0,0 +1,268 @@
+//! Endpoints for SNS generic-function proposals, so a DAO can manage the
+//! assets of its canister.
+//!
+//! A batch is too large to fit in a proposal, so it is uploaded as usual and
+//! `propose_commit_batch` stores its operations instead of executing them. It
+//! returns their evidence, the SHA-256 of the operations and of the hashes of
+//! the chunks they use. The proposal then calls `commit_proposed_batch` with
+//! the evidence, which commits the batch only if it didn't change since.
+//!
+//! SNS governance validates the payload of a proposal by calling the
+//! `validate_` method of the proposed one, which returns a summary of what the
+//! call would do, or why it would fail, without changing anything. The
+//! validators of the calls defined in other modules, such as
+//! `set_template_variable`, are next to them.
+
+use crate::STATE;
+#[cfg(feature = "upload")]
+use crate::{
+    do_commit_batch, hash_bytes, is_readonly, is_writable, trap, BatchId, BatchOperation,
+    CommitBatchArguments, Hash, PROPOSAL_EXPIRY_NANOS, READONLY,
+};
+#[cfg(feature = "upload")]
+use ic_cdk::api::time;
+#[cfg(feature = "admin")]
+use ic_cdk::export::candid::Principal;
+#[cfg(feature = "upload")]
+use ic_cdk::export::candid::{encode_one, CandidType, Deserialize, Int};
+use ic_cdk_macros::query;
+#[cfg(feature = "upload")]
+use ic_cdk_macros::update;
+#[cfg(feature = "upload")]
+use serde_bytes::ByteBuf;
+
+#[cfg(feature = "upload")]
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct CommitProposedBatchArguments {
+    batch_id: BatchId,
+    /// The evidence `propose_commit_batch` returned.
+    evidence: ByteBuf,
+}
+
+/// Stores the operations of a batch to be committed by a proposal, replacing
+/// those stored before, and returns their evidence. The batch no longer
+/// accepts chunks, and expires after 30 days instead of 5 minutes unless it is
+/// proposed again. Committing it with `commit_batch` instead, with no
+/// operations, discards it.
+#[cfg(feature = "upload")]
+#[update(guard = "is_writable")]
+fn propose_commit_batch(arg: CommitBatchArguments) -> ByteBuf {
+    let evidence = evidence(&arg).unwrap_or_else(|e| trap(&e));
+    STATE.with(|s| {
+        let mut batches = s.batches.borrow_mut();
+        let batch = batches
+            .get_mut(&arg.batch_id)
+            .unwrap_or_else(|| trap("batch not found"));
+        batch.expires_at = Int::from(time() as u64 + PROPOSAL_EXPIRY_NANOS);
+        batch.proposal = Some(arg);
+    });
+    ByteBuf::from(evidence.to_vec())
+}
+
+/// Commits the operations stored by `propose_commit_batch`, if `evidence`
+/// still matches them.
+#[cfg(feature = "upload")]
+#[update(guard = "is_writable")]
+fn commit_proposed_batch(arg: CommitProposedBatchArguments) {
+    let proposal = check_proposal(&arg).unwrap_or_else(|e| trap(&e));
+    do_commit_batch(proposal);
+}
+
+#[cfg(feature = "upload")]
+#[query]
+fn validate_commit_proposed_batch(arg: CommitProposedBatchArguments) -> Result<String, String> {
+    if is_readonly() {
+        return Err(READONLY.to_string());
+    }
+    let proposal = check_proposal(&arg)?;
+    let mut summary = format!(
+        "commit batch {} with evidence {}",
+        arg.batch_id,
+        hex::encode(&arg.evidence)
+    );
+    for op in proposal.operations.iter() {
+        summary.push_str("\n- ");
+        summary.push_str(&describe(op));
+    }
+    Ok(summary)
+}
+
+#[cfg(feature = "admin")]
+#[query]
+fn validate_authorize(other: Principal) -> Result<String, String> {
+    STATE.with(|s| {
+        if s.authorized.borrow().contains(&other) {
+            Err(format!("{} is already authorized", other))
+        } else {
+            Ok(format!("authorize {}", other))
+        }
+    })
+}
+
+#[cfg(feature = "admin")]
+#[query]
+fn validate_deauthorize(other: Principal) -> Result<String, String> {
+    STATE.with(|s| {
+        let authorized = s.authorized.borrow();
+        if !authorized.contains(&other) {
+            Err(format!("{} is not authorized", other))
+        } else if authorized.len() == 1 {
+            Err(format!("{} is the only authorized principal", other))
+        } else {
+            Ok(format!("deauthorize {}", other))
+        }
+    })
+}
+
+#[cfg(feature = "upload")]
+#[query]
+fn validate_clear() -> Result<String, String> {
+    if is_readonly() {
+        return Err(READONLY.to_string());
+    }
+    let count = STATE.with(|s| s.assets.borrow().len());
+    Ok(format!("delete all {} assets", count))
+}
+
+#[cfg(feature = "admin")]
+#[query]
+fn validate_set_readonly(readonly: bool) -> Result<String, String> {
+    Ok(if readonly {
+        "make the canister read-only".to_string()
+    } else {
+        "accept changes to the assets again".to_string()
+    })
+}
+
+/// Returns the operations proposed for the batch, if `arg.evidence` matches
+/// them.
+#[cfg(feature = "upload")]
+fn check_proposal(arg: &CommitProposedBatchArguments) -> Result<CommitBatchArguments, String> {
+    let proposal = STATE.with(|s| {
+        let batches = s.batches.borrow();
+        let batch = batches
+            .get(&arg.batch_id)
+            .ok_or_else(|| format!("batch {} not found", arg.batch_id))?;
+        if !batch.is_live(time() as u64) {
+            return Err(format!("batch {} expired", arg.batch_id));
+        }
+        batch
+            .proposal
+            .clone()
+            .ok_or_else(|| format!("batch {} is not proposed", arg.batch_id))
+    })?;
+    let evidence = evidence(&proposal)?;
+    if arg.evidence.as_slice() != evidence {
+        return Err(format!(
+            "the evidence of batch {} is {}",
+            arg.batch_id,
+            hex::encode(evidence)
+        ));
+    }
+    Ok(proposal)
+}
+
+/// The SHA-256 of the Candid encoding of `arg` followed by the hashes of the
+/// chunks it uses, in order. Chunks never change, so their hashes stand for
+/// their content.
+#[cfg(feature = "upload")]
+fn evidence(arg: &CommitBatchArguments) -> Result<Hash, String> {
+    let mut bytes = encode_one(arg).map_err(|e| e.to_string())?;
+    STATE.with(|s| {
+        let chunks = s.chunks.borrow();
+        for op in arg.operations.iter() {
+            if let BatchOperation::SetAssetContent(op) = op {
+                for chunk_id in op.chunk_ids.iter() {
+                    let chunk = chunks
+                        .get(chunk_id)
+                        .ok_or_else(|| format!("chunk {} not found", chunk_id))?;
+                    bytes.extend_from_slice(&chunk.hash);
+                }
+            }
+        }
+        Ok(hash_bytes(&bytes))
+    })
+}
+
+#[cfg(feature = "upload")]
+fn describe(op: &BatchOperation) -> String {
+    match op {
+        BatchOperation::CreateAsset(arg) => {
+            format!("create {} as {}", arg.key, arg.content_type)
+        }
+        BatchOperation::SetAssetContent(arg) => format!(
+            "set the {} content of {} from {} chunks",
+            arg.content_encoding,
+            arg.key,
+            arg.chunk_ids.len()
+        ),
+        BatchOperation::PatchAssetContent(arg) => {
+            format!("patch the {} content of {}", arg.content_encoding, arg.key)
+        }
+        BatchOperation::UnsetAssetContent(arg) => {
+            format!("unset the {} content of {}", arg.content_encoding, arg.key)
+        }
+        BatchOperation::DeleteAsset(arg) => format!("delete {}", arg.key),
+        BatchOperation::SetAssetTags(arg) => {
+            format!("set the tags of {} to [{}]", arg.key, arg.tags.join(", "))
+        }
+        BatchOperation::DeleteByTag(arg) => format!("delete the assets tagged {}", arg.tag),
+        BatchOperation::Clear(_) => "delete all assets".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Batch;
+    use ic_cdk::api::mock;
+
+    #[cfg(feature = "upload")]
+    #[test]
+    fn proposals_expire() {
+        mock::reset();
+        mock::set_time(1_000);
+        let batch_id = BatchId::from(1);
+        STATE.with(|s| {
+            s.batches.borrow_mut().insert(
+                batch_id.clone(),
+                Batch {
+                    expires_at: Int::from(1_000 + PROPOSAL_EXPIRY_NANOS),
+                    proposal: Some(CommitBatchArguments {
+                        batch_id: batch_id.clone(),
+                        operations: vec![],
+                    }),
+                },
+            )
+        });
+        let arg = CommitProposedBatchArguments {
+            batch_id,
+            evidence: ByteBuf::new(),
+        };
+
+        mock::set_time(1_000 + PROPOSAL_EXPIRY_NANOS);
+        assert_eq!(check_proposal(&arg).unwrap_err(), "batch 1 expired");
+        STATE.with(|s| s.batches.borrow_mut().clear());
+    }
+
+    #[cfg(feature = "admin")]
+    #[test]
+    fn validates_deauthorizations() {
+        let first = Principal::from_slice(&[1]);
+        let second = Principal::from_slice(&[2]);
+        STATE.with(|s| s.authorized.replace(vec![first]));
+        assert!(validate_deauthorize(second)
+            .unwrap_err()
+            .ends_with("is not authorized"));
+        assert!(validate_deauthorize(first)
+            .unwrap_err()
+            .ends_with("is the only authorized principal"));
+
+        STATE.with(|s| s.authorized.borrow_mut().push(second));
+        assert_eq!(
+            validate_deauthorize(first),
+            Ok(format!("deauthorize {}", first))
+        );
+        STATE.with(|s| s.authorized.borrow_mut().clear());
+    }
+}
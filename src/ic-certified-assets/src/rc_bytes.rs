@@ -1,3 +1,9 @@
+#[cfg(feature = "serve")]
+use crate::rope::RcBytesRope;
+#[cfg(feature = "serve")]
+use ic_cdk::api::call::{reply_raw, CallReplyWriter};
+#[cfg(feature = "serve")]
+use ic_cdk::export::candid::encode_one;
 use ic_cdk::export::candid::{
     types::{internal::Type, Serializer},
     CandidType, Deserialize,
@@ -5,11 +11,32 @@ use ic_cdk::export::candid::{
 use serde::de::Deserializer;
 use serde_bytes::ByteBuf;
 use std::convert::AsRef;
+#[cfg(feature = "serve")]
+use std::io::Write;
 use std::ops::Deref;
 use std::rc::Rc;
 
+/// A reference-counted byte buffer that is cheap to clone and serializes as a
+/// Candid `blob`.
 #[derive(Clone, Debug)]
-pub(crate) struct RcBytes(Rc<ByteBuf>);
+pub struct RcBytes(Rc<ByteBuf>);
+
+impl RcBytes {
+    /// Returns the bytes for modification. They are copied first if the
+    /// buffer is shared with other clones, so the clones are unaffected.
+    pub fn make_mut(&mut self) -> &mut Vec<u8> {
+        let buf: &mut ByteBuf = Rc::make_mut(&mut self.0);
+        buf
+    }
+
+    /// Returns the bytes, only copying them if the buffer is shared with
+    /// other clones.
+    pub fn into_vec(self) -> Vec<u8> {
+        Rc::try_unwrap(self.0)
+            .map(ByteBuf::into_vec)
+            .unwrap_or_else(|shared| shared.to_vec())
+    }
+}
 
 impl CandidType for RcBytes {
     fn _ty() -> Type {
@@ -51,3 +78,58 @@ impl Deref for RcBytes {
         &*self.0
     }
 }
+
+/// Replies with `make(content)` without copying `content` into the Candid
+/// serialization buffer, or its segments into a single buffer.
+///
+/// The reply is encoded once with an empty blob and once with a one byte blob.
+/// Everything but the blob is identical, so the first byte where the two
+/// differ is the blob length, and `content` is spliced in at that position.
+#[cfg(feature = "serve")]
+pub(crate) fn reply_with_blob<T, F>(content: &RcBytesRope, make: F)
+where
+    T: CandidType,
+    F: Fn(RcBytes) -> T,
+{
+    let (empty, at) = encode_around_blob(make);
+    let mut writer = CallReplyWriter;
+    writer.write_all(&empty[..at]).unwrap();
+    writer.write_all(&leb128(content.len())).unwrap();
+    for segment in content.segments() {
+        writer.write_all(segment).unwrap();
+    }
+    reply_raw(&empty[at + 1..]);
+}
+
+/// Encodes `make` with an empty blob, and returns the encoding with the
+/// position of the blob length in it.
+#[cfg(feature = "serve")]
+pub(crate) fn encode_around_blob<T, F>(make: F) -> (Vec<u8>, usize)
+where
+    T: CandidType,
+    F: Fn(RcBytes) -> T,
+{
+    let empty = encode_one(make(RcBytes::from(ByteBuf::new()))).expect("Could not encode reply.");
+    let probe =
+        encode_one(make(RcBytes::from(ByteBuf::from(vec![0])))).expect("Could not encode reply.");
+    let at = empty
+        .iter()
+        .zip(probe.iter())
+        .position(|(l, r)| l != r)
+        .expect("blob not found in the reply");
+    (empty, at)
+}
+
+#[cfg(feature = "serve")]
+pub(crate) fn leb128(mut n: usize) -> Vec<u8> {
+    let mut bytes = vec![];
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
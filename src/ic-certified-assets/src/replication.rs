@@ -0,0 +1,309 @@
+//! Push replication of assets to mirror asset canisters.
+//!
+//! Every mirror keeps a queue of keys that changed since they were last
+//! pushed. [heartbeat](crate::heartbeat) drains these queues by uploading the
+//! changed assets with `create_batch`, `create_chunk` and `commit_batch`
+//! calls, so the mirror has to authorize this canister beforehand.
+//! Keys that fail to replicate are put back in the queue and retried later.
+
+#[cfg(feature = "admin")]
+use crate::is_authorized;
+use crate::rc_bytes::RcBytes;
+use crate::{
+    BatchId, BatchOperation, ClearArguments, CommitBatchArguments, CreateAssetArguments,
+    CreateBatchResponse, CreateChunkResponse, DeleteAssetArguments, Key, SetAssetContentArguments,
+    STATE,
+};
+use ic_cdk::api::call::try_join_all_bounded;
+#[cfg(any(feature = "upload", feature = "admin"))]
+use ic_cdk::api::call::RejectionCode;
+use ic_cdk::export::candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk_macros::canister_client;
+#[cfg(any(feature = "admin", feature = "metrics"))]
+use ic_cdk_macros::query;
+#[cfg(feature = "admin")]
+use ic_cdk_macros::update;
+use serde_bytes::ByteBuf;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The maximum number of keys pushed to a mirror in a single batch.
+const KEYS_PER_BATCH: usize = 16;
+
+/// The maximum number of chunks uploaded to a mirror at the same time.
+const PARALLEL_CHUNK_UPLOADS: usize = 4;
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub(crate) struct Mirror {
+    pending: BTreeSet<Key>,
+    needs_clear: bool,
+    last_error: Option<String>,
+    in_progress: bool,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct ReplicationStatus {
+    target: Principal,
+    pending_keys: Nat,
+    in_progress: bool,
+    last_error: Option<String>,
+}
+
+/// Same wire format as `CreateChunkArg`, but without copying the content.
+#[derive(Clone, Debug, CandidType)]
+struct ReplicaChunkArg {
+    batch_id: BatchId,
+    content: RcBytes,
+}
+
+canister_client! {
+    /// The methods of a mirror that the assets are pushed with.
+    trait MirrorCanister {
+        async fn create_batch() -> CreateBatchResponse;
+        async fn create_chunk(arg: ReplicaChunkArg) -> CreateChunkResponse;
+        async fn commit_batch(arg: CommitBatchArguments);
+    }
+}
+
+#[cfg(feature = "admin")]
+#[update(guard = "is_authorized")]
+fn replicate_to(target: Principal) {
+    STATE.with(|s| {
+        let keys = s.assets.borrow().keys().cloned().collect();
+        s.mirrors.borrow_mut().insert(
+            target,
+            Mirror {
+                pending: keys,
+                needs_clear: true,
+                ..Mirror::default()
+            },
+        );
+    })
+}
+
+#[cfg(feature = "admin")]
+#[update(guard = "is_authorized")]
+fn stop_replication(target: Principal) {
+    STATE.with(|s| {
+        s.mirrors.borrow_mut().remove(&target);
+    })
+}
+
+#[cfg(feature = "admin")]
+#[query]
+fn validate_replicate_to(target: Principal) -> Result<String, String> {
+    let count = STATE.with(|s| s.assets.borrow().len());
+    Ok(format!(
+        "replace the assets of {} with the {} assets of this canister, and keep them in sync",
+        target, count
+    ))
+}
+
+#[cfg(feature = "admin")]
+#[query]
+fn validate_stop_replication(target: Principal) -> Result<String, String> {
+    STATE.with(|s| {
+        if s.mirrors.borrow().contains_key(&target) {
+            Ok(format!("stop replicating the assets to {}", target))
+        } else {
+            Err(format!("the assets are not replicated to {}", target))
+        }
+    })
+}
+
+#[cfg(feature = "metrics")]
+#[query]
+fn replication_status() -> Vec<ReplicationStatus> {
+    STATE.with(|s| {
+        s.mirrors
+            .borrow()
+            .iter()
+            .map(|(target, mirror)| ReplicationStatus {
+                target: *target,
+                pending_keys: Nat::from(mirror.pending.len()),
+                in_progress: mirror.in_progress,
+                last_error: mirror.last_error.clone(),
+            })
+            .collect()
+    })
+}
+
+/// Restores the mirrors saved before an upgrade.
+pub(crate) fn restore(mut mirrors: BTreeMap<Principal, Mirror>) {
+    for mirror in mirrors.values_mut() {
+        mirror.in_progress = false;
+    }
+    STATE.with(|s| s.mirrors.replace(mirrors));
+}
+
+/// Queues `key` to be pushed to every mirror.
+pub(crate) fn mark_changed(key: &str) {
+    STATE.with(|s| {
+        for mirror in s.mirrors.borrow_mut().values_mut() {
+            mirror.pending.insert(key.to_string());
+        }
+    })
+}
+
+/// Makes every mirror drop all its assets before receiving new ones.
+pub(crate) fn mark_cleared() {
+    STATE.with(|s| {
+        for mirror in s.mirrors.borrow_mut().values_mut() {
+            mirror.pending.clear();
+            mirror.needs_clear = true;
+        }
+    })
+}
+
+/// Starts a replication round for every mirror that has pending work and
+/// isn't already being replicated to.
+pub(crate) fn start_pending() {
+    let targets: Vec<Principal> = STATE.with(|s| {
+        s.mirrors
+            .borrow_mut()
+            .iter_mut()
+            .filter(|(_, m)| !m.in_progress && (m.needs_clear || !m.pending.is_empty()))
+            .map(|(target, m)| {
+                m.in_progress = true;
+                *target
+            })
+            .collect()
+    });
+    for target in targets {
+        ic_cdk::spawn(replicate_round(target));
+    }
+}
+
+async fn replicate_round(target: Principal) {
+    let work = STATE.with(|s| {
+        s.mirrors.borrow_mut().get_mut(&target).map(|m| {
+            let keys: Vec<Key> = m.pending.iter().take(KEYS_PER_BATCH).cloned().collect();
+            for key in keys.iter() {
+                m.pending.remove(key);
+            }
+            (std::mem::take(&mut m.needs_clear), keys)
+        })
+    });
+    let (clear, keys) = match work {
+        Some(work) => work,
+        None => return,
+    };
+    let mut round = Round {
+        target,
+        clear,
+        keys,
+        result: None,
+    };
+    round.result = Some(push_to_mirror(target, clear, &round.keys).await);
+}
+
+/// The work a replication round took from the queue of a mirror. Dropping it
+/// ends the round, putting the work back on failure.
+struct Round {
+    target: Principal,
+    clear: bool,
+    keys: Vec<Key>,
+    result: Option<Result<(), String>>,
+}
+
+impl Drop for Round {
+    /// A round whose callback traps is dropped by the cleanup callback
+    /// without a result, which is the only state change of the message that
+    /// persists, so the mirror doesn't stay in progress forever.
+    fn drop(&mut self) {
+        let result = self
+            .result
+            .take()
+            .unwrap_or_else(|| Err("the replication round trapped".to_string()));
+        STATE.with(|s| {
+            if let Some(m) = s.mirrors.borrow_mut().get_mut(&self.target) {
+                m.in_progress = false;
+                match result {
+                    Ok(()) => m.last_error = None,
+                    Err(err) => {
+                        m.needs_clear |= self.clear;
+                        m.pending.extend(self.keys.drain(..));
+                        m.last_error = Some(err);
+                    }
+                }
+            }
+        })
+    }
+}
+
+async fn push_to_mirror(target: Principal, clear: bool, keys: &[Key]) -> Result<(), String> {
+    let mirror = MirrorCanister::new(target);
+    let CreateBatchResponse { batch_id } =
+        mirror.create_batch().await.map_err(|err| err.to_string())?;
+
+    let mut operations = vec![];
+    if clear {
+        operations.push(BatchOperation::Clear(ClearArguments {}));
+    }
+    for key in keys {
+        operations.push(BatchOperation::DeleteAsset(DeleteAssetArguments {
+            key: key.clone(),
+        }));
+        let asset = match STATE.with(|s| s.assets.borrow().get(key).cloned()) {
+            Some(asset) => asset,
+            None => continue,
+        };
+        operations.push(BatchOperation::CreateAsset(CreateAssetArguments {
+            key: key.clone(),
+            content_type: asset.content_type.clone(),
+            tags: asset.tags.clone(),
+            // The content pushed is already rendered.
+            template: None,
+            protected: asset.protected,
+            content_disposition: asset.content_disposition.clone(),
+            allow_raw_access: asset.allow_raw_access,
+            certified_query_params: asset.certified_query_params.clone(),
+            certified_queries: asset.certified_queries.clone(),
+            certified_headers: asset.certified_headers.clone(),
+        }));
+        for (content_encoding, enc) in asset.encodings {
+            // Chunks are read as they are uploaded. If the asset changes in
+            // the meantime, the mirror rejects the batch for the sha256
+            // mismatch and the key is pushed again.
+            let uploads = (0..enc.chunk_count()).map(|i| {
+                let arg = ReplicaChunkArg {
+                    batch_id: batch_id.clone(),
+                    content: enc.chunk(i),
+                };
+                async move {
+                    let response = mirror.create_chunk(arg).await;
+                    response.map(|response| response.chunk_id)
+                }
+            });
+            let chunk_ids = try_join_all_bounded(uploads, PARALLEL_CHUNK_UPLOADS)
+                .await
+                .map_err(|errors| {
+                    errors
+                        .into_iter()
+                        .map(|(_, err)| err.to_string())
+                        .collect::<Vec<_>>()
+                        .join("; ")
+                })?;
+            operations.push(BatchOperation::SetAssetContent(SetAssetContentArguments {
+                key: key.clone(),
+                content_encoding,
+                chunk_ids,
+                sha256: Some(ByteBuf::from(enc.sha256)),
+                expected_sha256: None,
+            }));
+        }
+    }
+
+    let arg = CommitBatchArguments {
+        batch_id,
+        operations,
+    };
+    mirror
+        .commit_batch(arg)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+#[cfg(any(feature = "upload", feature = "admin"))]
+pub(crate) fn format_reject((code, message): (RejectionCode, String)) -> String {
+    format!("{:?}: {}", code, message)
+}
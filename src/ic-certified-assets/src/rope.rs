@@ -0,0 +1,213 @@
+use crate::rc_bytes::RcBytes;
+use std::ops::Range;
+
+/// A byte string made of slices of shared [RcBytes] buffers, e.g. a range of
+/// an asset spanning several chunks.
+///
+/// Appending and slicing only copy segment handles, never the bytes:
+/// appending a segment is O(1) and slicing is O(log n) in the number of
+/// segments, plus the segments the slice spans.
+#[derive(Clone, Debug, Default)]
+pub struct RcBytesRope {
+    segments: Vec<Segment>,
+    /// The end of each segment in the whole string.
+    ends: Vec<usize>,
+}
+
+#[derive(Clone, Debug)]
+struct Segment {
+    bytes: RcBytes,
+    range: Range<usize>,
+}
+
+impl RcBytesRope {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the length of the string, in bytes.
+    pub fn len(&self) -> usize {
+        self.ends.last().copied().unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Appends `bytes` to the string.
+    pub fn push(&mut self, bytes: RcBytes) {
+        let range = 0..bytes.len();
+        self.push_segment(bytes, range);
+    }
+
+    /// Appends the segments of `other` to the string.
+    pub fn append(&mut self, other: RcBytesRope) {
+        for segment in other.segments {
+            self.push_segment(segment.bytes, segment.range);
+        }
+    }
+
+    fn push_segment(&mut self, bytes: RcBytes, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        self.ends.push(self.len() + range.len());
+        self.segments.push(Segment { bytes, range });
+    }
+
+    /// Returns the bytes in `range` of the string.
+    ///
+    /// Panics if the range is out of bounds.
+    pub fn slice(&self, range: Range<usize>) -> RcBytesRope {
+        assert!(
+            range.start <= range.end && range.end <= self.len(),
+            "range {:?} out of bounds for a string of {} bytes",
+            range,
+            self.len()
+        );
+        let mut slice = RcBytesRope::new();
+        if range.is_empty() {
+            return slice;
+        }
+        // The first segment that ends after the start of the range.
+        let first = match self.ends.binary_search(&range.start) {
+            Ok(i) => i + 1,
+            Err(i) => i,
+        };
+        for (segment, end) in self.segments[first..].iter().zip(&self.ends[first..]) {
+            let start = end - segment.range.len();
+            if start >= range.end {
+                break;
+            }
+            let from = segment.range.start + range.start.saturating_sub(start);
+            let to = segment.range.end - end.saturating_sub(range.end);
+            slice.push_segment(segment.bytes.clone(), from..to);
+        }
+        slice
+    }
+
+    /// Returns the segments of the string, in order.
+    pub fn segments(&self) -> impl Iterator<Item = &[u8]> {
+        self.segments
+            .iter()
+            .map(|segment| &segment.bytes[segment.range.clone()])
+    }
+
+    /// Copies the string into a single buffer.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.len());
+        for segment in self.segments() {
+            bytes.extend_from_slice(segment);
+        }
+        bytes
+    }
+}
+
+impl From<RcBytes> for RcBytesRope {
+    fn from(bytes: RcBytes) -> Self {
+        let mut rope = RcBytesRope::new();
+        rope.push(bytes);
+        rope
+    }
+}
+
+impl std::iter::FromIterator<RcBytes> for RcBytesRope {
+    fn from_iter<I: IntoIterator<Item = RcBytes>>(iter: I) -> Self {
+        let mut rope = RcBytesRope::new();
+        for bytes in iter {
+            rope.push(bytes);
+        }
+        rope
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_bytes::ByteBuf;
+
+    fn bytes(content: &[u8]) -> RcBytes {
+        RcBytes::from(ByteBuf::from(content.to_vec()))
+    }
+
+    /// "abc" + "defg" + "hi", with an empty chunk pushed in between.
+    fn rope() -> RcBytesRope {
+        vec![bytes(b"abc"), bytes(b""), bytes(b"defg"), bytes(b"hi")]
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn skips_empty_segments() {
+        let rope = rope();
+        assert_eq!(rope.len(), 9);
+        assert_eq!(rope.segments().count(), 3);
+        assert_eq!(rope.to_vec(), b"abcdefghi");
+    }
+
+    #[test]
+    fn empty_slices() {
+        let rope = rope();
+        for at in 0..=rope.len() {
+            let slice = rope.slice(at..at);
+            assert!(slice.is_empty());
+            assert_eq!(slice.segments().count(), 0);
+        }
+        assert!(RcBytesRope::new().slice(0..0).is_empty());
+    }
+
+    #[test]
+    fn slices_within_a_chunk() {
+        let rope = rope();
+        let slice = rope.slice(4..6);
+        assert_eq!(slice.segments().collect::<Vec<_>>(), vec![&b"ef"[..]]);
+    }
+
+    #[test]
+    fn slices_spanning_chunks() {
+        let rope = rope();
+        let slice = rope.slice(2..8);
+        assert_eq!(
+            slice.segments().collect::<Vec<_>>(),
+            vec![&b"c"[..], &b"defg"[..], &b"h"[..]]
+        );
+        assert_eq!(rope.slice(0..9).to_vec(), b"abcdefghi");
+    }
+
+    #[test]
+    fn slices_on_chunk_boundaries() {
+        let rope = rope();
+        // Ending exactly at the end of a chunk doesn't take any of the next.
+        assert_eq!(
+            rope.slice(0..3).segments().collect::<Vec<_>>(),
+            vec![&b"abc"[..]]
+        );
+        assert_eq!(
+            rope.slice(1..7).segments().collect::<Vec<_>>(),
+            vec![&b"bc"[..], &b"defg"[..]]
+        );
+        // Starting exactly at the end of a chunk starts with the next one.
+        assert_eq!(
+            rope.slice(3..9).segments().collect::<Vec<_>>(),
+            vec![&b"defg"[..], &b"hi"[..]]
+        );
+        assert_eq!(
+            rope.slice(7..9).segments().collect::<Vec<_>>(),
+            vec![&b"hi"[..]]
+        );
+    }
+
+    #[test]
+    fn slices_of_slices() {
+        let rope = rope();
+        let slice = rope.slice(2..8);
+        assert_eq!(slice.slice(1..5).to_vec(), b"defg");
+        assert_eq!(slice.slice(0..2).to_vec(), b"cd");
+    }
+
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn slices_past_the_end_panic() {
+        rope().slice(5..10);
+    }
+}
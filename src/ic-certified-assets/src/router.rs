@@ -0,0 +1,87 @@
+//! Dynamic routes registered by the host canister.
+//!
+//! Routes are matched by path prefix before any asset lookup, the longest
+//! prefix winning. A route registered with [register_update_route] is answered
+//! from `http_request_update`: its query response only asks the gateway to
+//! upgrade the request to an update call.
+
+use crate::{HttpRequest, HttpResponse, RcBytes};
+use serde_bytes::ByteBuf;
+use std::cell::RefCell;
+
+/// Answers the requests matching a route.
+pub trait RouteHandler {
+    fn handle(&self, req: &HttpRequest) -> HttpResponse;
+}
+
+impl<F> RouteHandler for F
+where
+    F: Fn(&HttpRequest) -> HttpResponse,
+{
+    fn handle(&self, req: &HttpRequest) -> HttpResponse {
+        self(req)
+    }
+}
+
+struct Route {
+    prefix: String,
+    handler: Box<dyn RouteHandler>,
+    update: bool,
+}
+
+thread_local! {
+    static ROUTES: RefCell<Vec<Route>> = RefCell::new(vec![]);
+}
+
+/// Serves the requests whose path starts with `prefix` with `handler`.
+///
+/// Routes aren't kept across upgrades, so they should be registered from both
+/// `init` and `post_upgrade`.
+pub fn register_route<H: RouteHandler + 'static>(prefix: &str, handler: H) {
+    add_route(prefix, Box::new(handler), false);
+}
+
+/// Like [register_route], but `handler` runs in an update call, so it can
+/// modify the canister state.
+pub fn register_update_route<H: RouteHandler + 'static>(prefix: &str, handler: H) {
+    add_route(prefix, Box::new(handler), true);
+}
+
+fn add_route(prefix: &str, handler: Box<dyn RouteHandler>, update: bool) {
+    ROUTES.with(|routes| {
+        let mut routes = routes.borrow_mut();
+        routes.retain(|route| route.prefix != prefix);
+        routes.push(Route {
+            prefix: prefix.to_string(),
+            handler,
+            update,
+        });
+    })
+}
+
+/// Answers `req` from the matching route, if any. `in_update` tells whether
+/// the request is being served by `http_request_update`.
+pub(crate) fn route(req: &HttpRequest, in_update: bool) -> Option<HttpResponse> {
+    let path = match req.url.find('?') {
+        Some(i) => &req.url[..i],
+        None => &req.url[..],
+    };
+    ROUTES.with(|routes| {
+        let routes = routes.borrow();
+        let route = routes
+            .iter()
+            .filter(|route| path.starts_with(&route.prefix))
+            .max_by_key(|route| route.prefix.len())?;
+        if route.update && !in_update {
+            Some(HttpResponse {
+                status_code: 200,
+                headers: vec![],
+                body: RcBytes::from(ByteBuf::new()),
+                streaming_strategy: None,
+                upgrade: Some(true),
+            })
+        } else {
+            Some(route.handler.handle(req))
+        }
+    })
+}
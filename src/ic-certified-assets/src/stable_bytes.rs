@@ -0,0 +1,291 @@
+//! Asset content kept in stable memory.
+//!
+//! Once a canister hands a virtual memory over with
+//! [set_content_memory](crate::set_content_memory), the chunks of new content
+//! are moved there, and only [StableBytes] handles stay on the heap. A chunk
+//! is read back into an [RcBytes] when it is served, so the heap only ever
+//! holds the chunks of the responses in flight.
+//!
+//! The handles are saved with the rest of the state on upgrade, and the free
+//! space is worked out again from them afterwards.
+use crate::rc_bytes::RcBytes;
+use ic_cdk::api::stable::memory_manager::VirtualMemory;
+use ic_cdk::export::candid::{CandidType, Deserialize};
+use serde_bytes::ByteBuf;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+const WASM_PAGE_SIZE: u64 = 65536;
+
+/// A byte buffer stored in the content memory.
+///
+/// Unlike [RcBytes], the handle doesn't own the bytes: they are freed
+/// explicitly once no asset refers to them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub(crate) struct StableBytes {
+    offset: u64,
+    len: u64,
+}
+
+impl StableBytes {
+    /// Copies `content` into the content memory.
+    ///
+    /// Traps if no content memory is set or it can't grow.
+    pub(crate) fn write(content: &[u8]) -> Self {
+        with_store(|store| {
+            let bytes = store.allocate(content.len() as u64);
+            store.memory.write(bytes.offset, content);
+            bytes
+        })
+    }
+
+    /// Returns the length in bytes.
+    #[cfg(any(feature = "serve", feature = "upload"))]
+    pub(crate) fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    /// Returns whether the buffer is empty.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Reads the bytes into the heap.
+    pub(crate) fn read(&self) -> RcBytes {
+        let mut buf = vec![0; self.len as usize];
+        with_store(|store| store.memory.read(self.offset, &mut buf));
+        RcBytes::from(ByteBuf::from(buf))
+    }
+
+    /// Gives the space back to the content memory.
+    #[cfg(any(feature = "upload", feature = "admin"))]
+    pub(crate) fn free(self) {
+        with_store(|store| store.free(self))
+    }
+}
+
+struct Store {
+    memory: VirtualMemory,
+    /// The free ranges below `end`, by offset, with their length.
+    free: BTreeMap<u64, u64>,
+    /// The end of the last allocated range.
+    end: u64,
+}
+
+thread_local! {
+    static STORE: RefCell<Option<Store>> = RefCell::new(None);
+}
+
+fn with_store<R>(f: impl FnOnce(&mut Store) -> R) -> R {
+    STORE.with(|store| match store.borrow_mut().as_mut() {
+        Some(store) => f(store),
+        None => ic_cdk::trap("no content memory is set"),
+    })
+}
+
+pub(crate) fn set_memory(memory: VirtualMemory) {
+    STORE.with(|store| {
+        *store.borrow_mut() = Some(Store {
+            memory,
+            free: BTreeMap::new(),
+            end: 0,
+        })
+    })
+}
+
+pub(crate) fn is_enabled() -> bool {
+    STORE.with(|store| store.borrow().is_some())
+}
+
+/// Frees all the content, to be called when all the assets are dropped.
+pub(crate) fn clear() {
+    STORE.with(|store| {
+        if let Some(store) = store.borrow_mut().as_mut() {
+            store.free.clear();
+            store.end = 0;
+        }
+    })
+}
+
+/// Works out the free space from `used`, every buffer in use after an
+/// upgrade.
+pub(crate) fn restore(used: impl Iterator<Item = StableBytes>) {
+    STORE.with(|store| {
+        let mut store = store.borrow_mut();
+        let store = match store.as_mut() {
+            Some(store) => store,
+            None => return,
+        };
+        let mut used: Vec<StableBytes> = used.filter(|bytes| !bytes.is_empty()).collect();
+        used.sort_by_key(|bytes| bytes.offset);
+        store.free.clear();
+        store.end = 0;
+        for bytes in used {
+            if bytes.offset > store.end {
+                store.free.insert(store.end, bytes.offset - store.end);
+            }
+            store.end = store.end.max(bytes.offset + bytes.len);
+        }
+    })
+}
+
+impl Store {
+    /// Takes the first free range that is large enough, or grows the memory.
+    fn allocate(&mut self, len: u64) -> StableBytes {
+        if len == 0 {
+            return StableBytes { offset: 0, len: 0 };
+        }
+        let found = self
+            .free
+            .iter()
+            .find(|(_, free_len)| **free_len >= len)
+            .map(|(offset, free_len)| (*offset, *free_len));
+        if let Some((offset, free_len)) = found {
+            self.free.remove(&offset);
+            if free_len > len {
+                self.free.insert(offset + len, free_len - len);
+            }
+            return StableBytes { offset, len };
+        }
+
+        let offset = self.end;
+        let end = offset + len;
+        let capacity = self.memory.size() * WASM_PAGE_SIZE;
+        if end > capacity {
+            let pages = (end - capacity + WASM_PAGE_SIZE - 1) / WASM_PAGE_SIZE;
+            if self.memory.grow(pages).is_err() {
+                ic_cdk::trap("the content memory is full");
+            }
+        }
+        self.end = end;
+        StableBytes { offset, len }
+    }
+
+    /// Returns the range to the free ones, merged with its neighbours.
+    #[cfg(any(feature = "upload", feature = "admin"))]
+    fn free(&mut self, bytes: StableBytes) {
+        if bytes.is_empty() {
+            return;
+        }
+        let mut offset = bytes.offset;
+        let mut len = bytes.len;
+        let previous = self
+            .free
+            .range(..offset)
+            .next_back()
+            .map(|(offset, len)| (*offset, *len));
+        if let Some((previous_offset, previous_len)) = previous {
+            if previous_offset + previous_len == offset {
+                self.free.remove(&previous_offset);
+                offset = previous_offset;
+                len += previous_len;
+            }
+        }
+        if let Some(next_len) = self.free.remove(&(offset + len)) {
+            len += next_len;
+        }
+
+        if offset + len == self.end {
+            self.end = offset;
+        } else {
+            self.free.insert(offset, len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ic_cdk::api::mock;
+    use ic_cdk::api::stable::memory_manager::{MemoryId, MemoryManager};
+
+    fn set_up() {
+        mock::reset();
+        set_memory(MemoryManager::init().get(MemoryId::new(0)));
+    }
+
+    /// The free ranges and the end of the allocated ones.
+    fn layout() -> (Vec<(u64, u64)>, u64) {
+        STORE.with(|store| {
+            let store = store.borrow();
+            let store = store.as_ref().unwrap();
+            (
+                store.free.iter().map(|(o, l)| (*o, *l)).collect(),
+                store.end,
+            )
+        })
+    }
+
+    #[test]
+    fn allocates_after_the_end_and_reuses_freed_ranges() {
+        set_up();
+        let first = StableBytes::write(b"aaaa");
+        let second = StableBytes::write(b"bb");
+        let third = StableBytes::write(b"cccccc");
+        assert_eq!((first.offset, second.offset, third.offset), (0, 4, 6));
+        assert_eq!(&*second.read(), b"bb");
+        assert_eq!(layout(), (vec![], 12));
+
+        second.free();
+        assert_eq!(layout(), (vec![(4, 2)], 12));
+        let fourth = StableBytes::write(b"d");
+        assert_eq!(fourth.offset, 4);
+        assert_eq!(layout(), (vec![(5, 1)], 12));
+        // Too large for the free range.
+        let fifth = StableBytes::write(b"eee");
+        assert_eq!(fifth.offset, 12);
+        assert_eq!(&*first.read(), b"aaaa");
+        assert_eq!(&*third.read(), b"cccccc");
+        assert_eq!(&*fifth.read(), b"eee");
+    }
+
+    #[test]
+    fn merges_freed_ranges_and_moves_the_end_back() {
+        set_up();
+        let first = StableBytes::write(b"aaaa");
+        let second = StableBytes::write(b"bb");
+        let third = StableBytes::write(b"cccccc");
+        first.free();
+        assert_eq!(layout(), (vec![(0, 4)], 12));
+        third.free();
+        assert_eq!(layout(), (vec![(0, 4)], 6));
+        second.free();
+        assert_eq!(layout(), (vec![], 0));
+    }
+
+    #[test]
+    fn grows_the_memory_across_pages() {
+        set_up();
+        let small = StableBytes::write(b"x");
+        let large = StableBytes::write(&vec![7; WASM_PAGE_SIZE as usize + 1]);
+        assert_eq!(large.offset, 1);
+        assert_eq!(large.len(), WASM_PAGE_SIZE as usize + 1);
+        assert!(large.read().iter().all(|byte| *byte == 7));
+        assert_eq!(&*small.read(), b"x");
+    }
+
+    #[test]
+    fn restore_works_out_the_free_ranges() {
+        set_up();
+        let first = StableBytes::write(b"aaaa");
+        StableBytes::write(b"bb");
+        let third = StableBytes::write(b"cccccc");
+        let empty = StableBytes::write(b"");
+        assert!(empty.is_empty());
+
+        restore(vec![third, empty, first].into_iter());
+        assert_eq!(layout(), (vec![(4, 2)], 12));
+        assert_eq!(StableBytes::write(b"dd").offset, 4);
+
+        clear();
+        assert_eq!(layout(), (vec![], 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "no content memory is set")]
+    fn traps_without_content_memory() {
+        mock::reset();
+        STORE.with(|store| *store.borrow_mut() = None);
+        StableBytes::write(b"a");
+    }
+}
@@ -0,0 +1,73 @@
+//! A staging area, separating "upload finished" from "users see it".
+//!
+//! Assets under [STAGED_PREFIX] are committed like the others, but
+//! `http_request` answers them with a 404 response, certified in place of
+//! their content, and only authorized principals can read them with `get`.
+//! `publish` then moves them to their live keys in a single call, so visitors
+//! see either the old content or the new one, never a mix of both:
+//! `/.staged/index.html` becomes `/index.html`.
+
+use crate::Key;
+#[cfg(feature = "upload")]
+use crate::{do_move_asset, is_writable, trap, with_deferred_certification, STATE};
+use ic_cdk::export::candid::{CandidType, Deserialize};
+#[cfg(feature = "upload")]
+use ic_cdk_macros::update;
+
+/// The prefix of the keys of staged assets.
+pub(crate) const STAGED_PREFIX: &str = "/.staged";
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum PublishArguments {
+    /// The live keys to publish the staged content of.
+    Keys(Vec<Key>),
+    /// Every staged asset.
+    All,
+}
+
+/// Returns whether `path` is in the staging area, and so not served.
+pub(crate) fn is_staged(path: &str) -> bool {
+    path.strip_prefix(STAGED_PREFIX)
+        .map_or(false, |rest| rest.starts_with('/'))
+}
+
+/// Replaces the assets of the given keys, or of every staged asset, with their
+/// staged content. Traps without publishing anything if a key has no staged
+/// content. Returns the published keys.
+#[cfg(feature = "upload")]
+#[update(guard = "is_writable")]
+fn publish(arg: PublishArguments) -> Vec<Key> {
+    let keys: Vec<Key> = STATE.with(|s| {
+        let assets = s.assets.borrow();
+        match arg {
+            PublishArguments::Keys(keys) => {
+                for key in keys.iter() {
+                    if !assets.contains_key(&staged_key(key)) {
+                        trap(&format!("{} is not staged", key));
+                    }
+                }
+                keys
+            }
+            PublishArguments::All => {
+                let mut keys: Vec<Key> = assets
+                    .keys()
+                    .filter(|key| is_staged(key))
+                    .map(|key| key[STAGED_PREFIX.len()..].to_string())
+                    .collect();
+                keys.sort();
+                keys
+            }
+        }
+    });
+    with_deferred_certification(|| {
+        for key in keys.iter() {
+            do_move_asset(&staged_key(key), key);
+        }
+    });
+    keys
+}
+
+#[cfg(feature = "upload")]
+fn staged_key(key: &str) -> Key {
+    format!("{}{}", STAGED_PREFIX, key)
+}
@@ -0,0 +1,272 @@
+//! Pull synchronization from a source asset canister.
+//!
+//! `sync_from` compares the source's `list` with the local assets and only
+//! downloads the encodings whose sha256 differs, chunk by chunk. Assets and
+//! encodings that no longer exist on the source are removed locally, and the
+//! properties of the others, such as `protected`, follow the source's.
+
+use crate::rc_bytes::RcBytes;
+use crate::replication::{self, format_reject};
+use crate::{
+    certification_v2, do_create_asset, do_delete_asset, do_set_asset_tags, do_unset_asset_content,
+    hash_bytes, heap, is_writable, on_asset_change, trap_if_readonly, AssetDetails, AssetEncoding,
+    AssetProperties, ChunksInfoReponse, CreateAssetArguments, DeleteAssetArguments, GetArg,
+    GetChunkArg, GetChunkResponse, Key, SetAssetTagsArguments, UnsetAssetContentArguments, STATE,
+};
+use ic_cdk::api::call::call;
+use ic_cdk::api::{time, trap};
+use ic_cdk::export::candid::{CandidType, Deserialize, Int, Nat, Principal};
+use ic_cdk_macros::update;
+use num_traits::ToPrimitive;
+use sha2::Digest;
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct SyncResponse {
+    pulled_encodings: Nat,
+    pulled_bytes: Nat,
+    deleted_assets: Nat,
+}
+
+/// What the local canister has for a key, as far as syncing is concerned.
+struct LocalAsset {
+    content_type: String,
+    tags: Vec<String>,
+    properties: AssetProperties,
+    encodings: HashMap<String, [u8; 32]>,
+}
+
+#[update(guard = "is_writable")]
+async fn sync_from(source: Principal) -> SyncResponse {
+    let (source_assets,): (Vec<AssetDetails>,) = call(source, "list", ())
+        .await
+        .unwrap_or_else(|err| trap(&format!("list failed: {}", format_reject(err))));
+    trap_if_readonly();
+
+    let mut local = local_assets();
+    let mut pulled_encodings: usize = 0;
+    let mut pulled_bytes: usize = 0;
+    let mut deleted_assets: usize = 0;
+
+    for key in local.keys() {
+        if !source_assets.iter().any(|details| &details.key == key) {
+            do_delete_asset(DeleteAssetArguments { key: key.clone() });
+            deleted_assets += 1;
+        }
+    }
+
+    for details in source_assets.iter() {
+        // Pulling the previous asset awaited.
+        trap_if_readonly();
+        let local_asset = local
+            .remove(&details.key)
+            .filter(|asset| asset.content_type == details.content_type);
+        let local_encodings = match local_asset {
+            Some(asset) => {
+                if asset.tags != details.tags {
+                    do_set_asset_tags(SetAssetTagsArguments {
+                        key: details.key.clone(),
+                        tags: details.tags.clone(),
+                    });
+                }
+                if let Some(properties) = &details.properties {
+                    if *properties != asset.properties {
+                        set_properties(&details.key, properties.clone());
+                    }
+                }
+                asset.encodings
+            }
+            None => {
+                do_delete_asset(DeleteAssetArguments {
+                    key: details.key.clone(),
+                });
+                let properties = details.properties.clone().unwrap_or_default();
+                do_create_asset(CreateAssetArguments {
+                    key: details.key.clone(),
+                    content_type: details.content_type.clone(),
+                    tags: Some(details.tags.clone()),
+                    template: None,
+                    protected: properties.protected,
+                    content_disposition: properties.content_disposition,
+                    allow_raw_access: properties.allow_raw_access,
+                    certified_query_params: properties.certified_query_params,
+                    certified_queries: properties.certified_queries,
+                    certified_headers: properties.certified_headers,
+                });
+                HashMap::new()
+            }
+        };
+
+        for content_encoding in local_encodings.keys() {
+            if !details
+                .encodings
+                .iter()
+                .any(|enc| &enc.content_encoding == content_encoding)
+            {
+                do_unset_asset_content(UnsetAssetContentArguments {
+                    key: details.key.clone(),
+                    content_encoding: content_encoding.clone(),
+                });
+            }
+        }
+
+        for enc in details.encodings.iter() {
+            let unchanged = match (&enc.sha256, local_encodings.get(&enc.content_encoding)) {
+                (Some(remote), Some(local)) => remote.as_slice() == local,
+                _ => false,
+            };
+            if unchanged {
+                continue;
+            }
+            pulled_bytes += pull_encoding(source, &details.key, &enc.content_encoding).await;
+            pulled_encodings += 1;
+        }
+    }
+
+    SyncResponse {
+        pulled_encodings: Nat::from(pulled_encodings),
+        pulled_bytes: Nat::from(pulled_bytes),
+        deleted_assets: Nat::from(deleted_assets),
+    }
+}
+
+fn local_assets() -> HashMap<Key, LocalAsset> {
+    STATE.with(|s| {
+        s.assets
+            .borrow()
+            .iter()
+            .map(|(key, asset)| {
+                let local = LocalAsset {
+                    content_type: asset.content_type.clone(),
+                    tags: asset.tags.clone().unwrap_or_default(),
+                    properties: asset.properties(),
+                    encodings: asset
+                        .encodings
+                        .iter()
+                        .map(|(name, enc)| (name.clone(), enc.sha256))
+                        .collect(),
+                };
+                (key.clone(), local)
+            })
+            .collect()
+    })
+}
+
+/// Gives the asset at `key` the properties it has on the source, which may
+/// change the responses certified for it.
+fn set_properties(key: &str, properties: AssetProperties) {
+    replication::mark_changed(key);
+    STATE.with(|s| {
+        if let Some(asset) = s.assets.borrow_mut().get_mut(key) {
+            asset.set_properties(properties);
+            certification_v2::certify(key, asset);
+        }
+    })
+}
+
+/// Downloads one encoding from `source` and stores it locally, returning the
+/// number of bytes pulled.
+async fn pull_encoding(source: Principal, key: &str, content_encoding: &str) -> usize {
+    let arg = GetArg {
+        key: key.to_string(),
+        accept_encodings: vec![content_encoding.to_string()],
+    };
+    let (info,): (ChunksInfoReponse,) = call(source, "get_chunks_info", (arg,))
+        .await
+        .unwrap_or_else(|err| trap(&format!("get_chunks_info failed: {}", format_reject(err))));
+    reserve_pulled(&info);
+
+    let mut content_chunks: Vec<RcBytes> = Vec::with_capacity(info.chunks.len());
+    let mut hasher = sha2::Sha256::new();
+    for index in 0..info.chunks.len() {
+        let arg = GetChunkArg {
+            key: key.to_string(),
+            content_encoding: content_encoding.to_string(),
+            index: Nat::from(index),
+            sha256: Some(info.sha256.clone()),
+        };
+        let (GetChunkResponse { content },): (GetChunkResponse,) =
+            call(source, "get_chunk", (arg,))
+                .await
+                .unwrap_or_else(|err| trap(&format!("get_chunk failed: {}", format_reject(err))));
+        hasher.update(content.as_ref());
+        content_chunks.push(content);
+    }
+
+    let sha256: [u8; 32] = info
+        .sha256
+        .into_vec()
+        .try_into()
+        .unwrap_or_else(|_| trap("invalid SHA-256"));
+    // The source may have changed the content between the calls, or may not
+    // be trustworthy in the first place.
+    let actual: [u8; 32] = hasher.finalize().into();
+    if actual != sha256 {
+        trap(&format!(
+            "the {} content of {} pulled from {} doesn't match its SHA-256",
+            content_encoding, key, source
+        ));
+    }
+    let chunk_hashes = content_chunks.iter().map(|c| hash_bytes(c)).collect();
+    let total_length: usize = content_chunks.iter().map(|c| c.len()).sum();
+    trap_if_readonly();
+
+    STATE.with(|s| {
+        let mut assets = s.assets.borrow_mut();
+        // The asset may have been deleted while the chunks were downloaded.
+        if let Some(asset) = assets.get_mut(key) {
+            let old = asset.encodings.insert(
+                content_encoding.to_string(),
+                AssetEncoding {
+                    modified: Int::from(time() as u64),
+                    content_chunks,
+                    total_length,
+                    certified: false,
+                    sha256,
+                    chunk_hashes: Some(chunk_hashes),
+                    chunk_hash_algorithm: None,
+                    template_source: None,
+                    stable_chunks: None,
+                },
+            );
+            if let Some(mut old) = old {
+                old.release();
+            }
+            on_asset_change(key, asset);
+        }
+    });
+    total_length
+}
+
+/// Traps unless the content `info` describes fits under the heap watermark,
+/// so a large source fails the sync before any of it is downloaded.
+pub(crate) fn reserve_pulled(info: &ChunksInfoReponse) {
+    heap::reserve(info.total_length.0.to_usize().unwrap_or(usize::MAX));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Asset, ContentDisposition};
+
+    #[test]
+    fn properties_follow_the_source() {
+        ic_cdk::api::mock::reset();
+        STATE.with(|s| {
+            s.assets
+                .borrow_mut()
+                .insert("/a.pdf".to_string(), Asset::default())
+        });
+        let properties = AssetProperties {
+            protected: Some(true),
+            content_disposition: Some(ContentDisposition::Inline),
+            certified_headers: Some(crate::CertifiedHeaders::Only(vec![])),
+            ..AssetProperties::default()
+        };
+        set_properties("/a.pdf", properties.clone());
+
+        assert_eq!(local_assets()["/a.pdf"].properties, properties);
+        STATE.with(|s| s.assets.borrow_mut().clear());
+    }
+}
@@ -0,0 +1,179 @@
+//! Placeholder substitution for assets marked as templates.
+//!
+//! A template's `identity` content is rendered when it is committed, and again
+//! whenever a template variable changes. Placeholders are written `{{name}}`:
+//! `{{canister_id}}` is replaced by the id of this canister, other names by
+//! the values set with `set_template_variable`. Unknown placeholders are left
+//! untouched. The rendered content is what gets hashed and certified.
+
+#[cfg(any(feature = "upload", feature = "admin"))]
+use crate::rc_bytes::RcBytes;
+#[cfg(any(feature = "upload", feature = "admin"))]
+use crate::{hash_bytes, AssetEncoding, STATE};
+#[cfg(feature = "admin")]
+use crate::{is_readonly, is_writable, READONLY};
+#[cfg(feature = "admin")]
+use crate::{on_asset_change, Key};
+#[cfg(feature = "upload")]
+use ic_cdk::api::trap;
+#[cfg(any(feature = "upload", feature = "admin"))]
+use ic_cdk::api::{id, time};
+#[cfg(any(feature = "upload", feature = "admin"))]
+use ic_cdk::export::candid::Int;
+use ic_cdk::export::candid::{CandidType, Deserialize};
+#[cfg(feature = "admin")]
+use ic_cdk_macros::{query, update};
+#[cfg(any(feature = "upload", feature = "admin"))]
+use serde_bytes::ByteBuf;
+#[cfg(any(feature = "upload", feature = "admin"))]
+use std::collections::BTreeMap;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct SetTemplateVariableArguments {
+    name: String,
+    /// Removes the variable when `None`.
+    value: Option<String>,
+}
+
+#[cfg(feature = "admin")]
+#[update(guard = "is_writable")]
+fn set_template_variable(arg: SetTemplateVariableArguments) {
+    STATE.with(|s| {
+        let mut variables = s.template_variables.borrow_mut();
+        match arg.value {
+            Some(value) => variables.insert(arg.name, value),
+            None => variables.remove(&arg.name),
+        };
+    });
+    rerender_all();
+}
+
+#[cfg(feature = "admin")]
+#[query]
+fn validate_set_template_variable(arg: SetTemplateVariableArguments) -> Result<String, String> {
+    if is_readonly() {
+        return Err(READONLY.to_string());
+    }
+    Ok(match arg.value {
+        Some(value) => format!("set the template variable {} to {:?}", arg.name, value),
+        None => format!("remove the template variable {}", arg.name),
+    })
+}
+
+#[cfg(feature = "admin")]
+#[query]
+fn list_template_variables() -> Vec<(String, String)> {
+    STATE.with(|s| {
+        s.template_variables
+            .borrow()
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()
+    })
+}
+
+/// Keeps the content of `enc` as the template source and replaces it with the
+/// rendered output, in chunks of at most `max_chunk_size` bytes.
+#[cfg(feature = "upload")]
+pub(crate) fn render_encoding(content_encoding: &str, enc: &mut AssetEncoding) {
+    if content_encoding != "identity" {
+        trap("templates only support the identity encoding");
+    }
+    // A single chunk is shared with the source rather than copied.
+    let source = match enc.chunk_count() {
+        1 => enc.chunk(0),
+        count => {
+            let mut source = Vec::with_capacity(enc.total_length);
+            for i in 0..count {
+                source.extend_from_slice(&enc.chunk(i));
+            }
+            RcBytes::from(ByteBuf::from(source))
+        }
+    };
+    enc.template_source = Some(source);
+    rerender(enc);
+}
+
+#[cfg(any(feature = "upload", feature = "admin"))]
+fn rerender(enc: &mut AssetEncoding) {
+    let source = match enc.template_source.as_ref() {
+        Some(source) => source,
+        None => return,
+    };
+    let (rendered, max_chunk_size) = STATE.with(|s| {
+        (
+            render(source, &s.template_variables.borrow()),
+            s.config.borrow().max_chunk_size() as usize,
+        )
+    });
+    // Variables can make the output larger than the source, so it is split
+    // again in chunks the streaming strategy can serve.
+    enc.total_length = rendered.len();
+    enc.sha256 = hash_bytes(&rendered);
+    let chunks: Vec<RcBytes> = if rendered.len() <= max_chunk_size {
+        vec![RcBytes::from(ByteBuf::from(rendered))]
+    } else {
+        rendered
+            .chunks(max_chunk_size)
+            .map(|chunk| RcBytes::from(ByteBuf::from(chunk.to_vec())))
+            .collect()
+    };
+    enc.chunk_hashes = Some(chunks.iter().map(|chunk| hash_bytes(chunk)).collect());
+    enc.set_chunks(chunks);
+    enc.chunk_hash_algorithm = None;
+    enc.modified = Int::from(time() as u64);
+    enc.certified = false;
+}
+
+/// Renders every template again and certifies the new content.
+#[cfg(feature = "admin")]
+fn rerender_all() {
+    STATE.with(|s| {
+        let mut assets = s.assets.borrow_mut();
+        let templates: Vec<Key> = assets
+            .iter()
+            .filter(|(_, asset)| asset.template == Some(true))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in templates {
+            let asset = assets.get_mut(&key).unwrap();
+            for enc in asset.encodings.values_mut() {
+                enc.certified = false;
+                rerender(enc);
+            }
+            on_asset_change(&key, asset);
+        }
+    })
+}
+
+#[cfg(any(feature = "upload", feature = "admin"))]
+fn render(source: &[u8], variables: &BTreeMap<String, String>) -> Vec<u8> {
+    let mut rendered = Vec::with_capacity(source.len());
+    let mut rest = source;
+    while let Some(start) = find(rest, b"{{") {
+        let end = match find(&rest[start + 2..], b"}}") {
+            Some(len) => start + 2 + len,
+            None => break,
+        };
+        rendered.extend_from_slice(&rest[..start]);
+        let name = String::from_utf8_lossy(&rest[start + 2..end]);
+        let value = match name.trim() {
+            "canister_id" => Some(id().to_text()),
+            name => variables.get(name).cloned(),
+        };
+        match value {
+            Some(value) => rendered.extend_from_slice(value.as_bytes()),
+            None => rendered.extend_from_slice(&rest[start..end + 2]),
+        }
+        rest = &rest[end + 2..];
+    }
+    rendered.extend_from_slice(rest);
+    rendered
+}
+
+#[cfg(any(feature = "upload", feature = "admin"))]
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
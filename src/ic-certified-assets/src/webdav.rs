@@ -0,0 +1,421 @@
+//! A subset of WebDAV on top of the uploads over HTTP, enough for file
+//! managers and sync tools to mount the canister: `PROPFIND` lists the
+//! assets, `MKCOL` creates a collection, `MOVE` renames an asset or a
+//! collection, and `DELETE` also deletes collections.
+//!
+//! Collections are the key prefixes ending in `/`. Those with assets under
+//! them exist implicitly, the empty ones created by `MKCOL` are kept in the
+//! state. Like uploads, the requests are upgraded to `http_request_update`
+//! and need an upload token, which file managers can send as the password of
+//! basic authentication. `OPTIONS` doesn't, and CORS preflight requests are
+//! left to the assets.
+
+use crate::http_upload::{self, header, response};
+use crate::{
+    build_upgrade, do_delete_asset, do_move_asset, is_readonly, security_headers, url_decode,
+    Asset, DeleteAssetArguments, HttpRequest, HttpResponse, Key, RcBytes, READONLY, STATE,
+};
+use ic_cdk::api::in_replicated_execution;
+use num_traits::ToPrimitive;
+use serde_bytes::ByteBuf;
+use std::collections::BTreeSet;
+
+const METHODS: &[&str] = &["OPTIONS", "PROPFIND", "MKCOL", "MOVE", "DELETE"];
+
+/// The most entries a `PROPFIND` response lists, which keeps it well under
+/// the size of a reply.
+const MAX_PROPFIND_ENTRIES: usize = 1000;
+
+/// Returns whether `req` is a WebDAV request this module answers, including
+/// the `DELETE` requests of uploads but not CORS preflight requests.
+pub(crate) fn is_webdav(req: &HttpRequest) -> bool {
+    METHODS.iter().any(|m| req.method.eq_ignore_ascii_case(m))
+        && !(req.method.eq_ignore_ascii_case("OPTIONS")
+            && header(req, "Access-Control-Request-Method").is_some())
+}
+
+/// Answers `req`, a WebDAV request, from `http_request_update`.
+pub(crate) fn handle(req: &HttpRequest) -> HttpResponse {
+    if !in_replicated_execution() {
+        return build_upgrade();
+    }
+    let method = req.method.to_ascii_uppercase();
+    if method == "OPTIONS" {
+        return with_headers(
+            response(200, ""),
+            vec![
+                ("DAV".to_string(), "1".to_string()),
+                (
+                    "Allow".to_string(),
+                    "GET, PUT, OPTIONS, PROPFIND, MKCOL, MOVE, DELETE".to_string(),
+                ),
+            ],
+        );
+    }
+    if !http_upload::is_permitted(req) {
+        return with_headers(
+            response(401, "an upload token is required"),
+            vec![(
+                "WWW-Authenticate".to_string(),
+                "Basic realm=\"assets\"".to_string(),
+            )],
+        );
+    }
+    let path = match decode_path(&req.url) {
+        Ok(path) => path,
+        Err(response) => return response,
+    };
+    if is_readonly() && method != "PROPFIND" {
+        return response(503, READONLY);
+    }
+    match method.as_str() {
+        "PROPFIND" => propfind(req, &path),
+        "MKCOL" => mkcol(&path),
+        "MOVE" => move_resource(req, &path),
+        _ => delete(req, &path),
+    }
+}
+
+fn propfind(req: &HttpRequest, path: &str) -> HttpResponse {
+    let mut entries = vec![];
+    STATE.with(|s| {
+        let assets = s.assets.borrow();
+        if let Some(asset) = assets.get(path) {
+            entries.push(file_entry(path, asset));
+            return;
+        }
+        let dir = as_collection(path);
+        if !collection_exists(&dir) {
+            return;
+        }
+        entries.push(collection_entry(&dir));
+        // Depth: infinity is answered like depth 1, which clients accept.
+        if header(req, "Depth").as_deref() == Some("0") {
+            return;
+        }
+        let mut children = BTreeSet::new();
+        for key in assets.keys().filter(|key| key.starts_with(&dir)) {
+            match key[dir.len()..].find('/') {
+                Some(i) => {
+                    children.insert(key[..dir.len() + i + 1].to_string());
+                }
+                None => entries.push(file_entry(key, &assets[key])),
+            }
+            if entries.len() + children.len() > MAX_PROPFIND_ENTRIES {
+                return;
+            }
+        }
+        for collection in s.collections.borrow().iter() {
+            let rest = match collection.strip_prefix(dir.as_str()) {
+                Some(rest) => rest,
+                None => continue,
+            };
+            if let Some(i) = rest.find('/') {
+                if !rest[..i].is_empty() {
+                    children.insert(collection[..dir.len() + i + 1].to_string());
+                }
+            }
+        }
+        entries.extend(children.iter().map(|child| collection_entry(child)));
+    });
+    if entries.is_empty() {
+        return response(404, "not found");
+    }
+    if entries.len() > MAX_PROPFIND_ENTRIES {
+        return response(
+            507,
+            &format!(
+                "the collection has more than {} entries",
+                MAX_PROPFIND_ENTRIES
+            ),
+        );
+    }
+
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n{}</D:multistatus>\n",
+        entries.concat()
+    );
+    let mut response = with_headers(
+        response(207, ""),
+        vec![(
+            "Content-Type".to_string(),
+            "application/xml; charset=utf-8".to_string(),
+        )],
+    );
+    response.body = RcBytes::from(ByteBuf::from(body));
+    response
+}
+
+fn mkcol(path: &str) -> HttpResponse {
+    let dir = as_collection(path);
+    let is_asset = STATE.with(|s| s.assets.borrow().contains_key(path));
+    if is_asset || collection_exists(&dir) {
+        return response(405, "already exists");
+    }
+    STATE.with(|s| s.collections.borrow_mut().insert(dir));
+    response(201, "")
+}
+
+fn move_resource(req: &HttpRequest, path: &str) -> HttpResponse {
+    let destination = match header(req, "Destination") {
+        Some(destination) => destination,
+        None => return response(400, "missing Destination header"),
+    };
+    // The destination is an absolute URL.
+    let destination = match destination.find("://") {
+        Some(i) => match destination[i + 3..].find('/') {
+            Some(j) => destination[i + 3 + j..].to_string(),
+            None => "/".to_string(),
+        },
+        None => destination,
+    };
+    let destination = match decode_path(&destination) {
+        Ok(destination) => destination,
+        Err(response) => return response,
+    };
+    let overwrite = header(req, "Overwrite").map_or(true, |value| value != "F");
+
+    let moves: Vec<(Key, Key)> = STATE.with(|s| {
+        let assets = s.assets.borrow();
+        if assets.contains_key(path) {
+            return vec![(path.to_string(), destination.clone())];
+        }
+        let from = as_collection(path);
+        let to = as_collection(&destination);
+        assets
+            .keys()
+            .filter(|key| key.starts_with(&from))
+            .map(|key| (key.clone(), format!("{}{}", to, &key[from.len()..])))
+            .collect()
+    });
+    let is_collection = !STATE.with(|s| s.assets.borrow().contains_key(path));
+    if moves.is_empty() && !(is_collection && collection_exists(&as_collection(path))) {
+        return response(404, "not found");
+    }
+    let exists = STATE.with(|s| {
+        let assets = s.assets.borrow();
+        moves.iter().any(|(_, to)| assets.contains_key(to))
+    });
+    if exists && !overwrite {
+        return response(412, "the destination exists");
+    }
+
+    for (from, to) in moves.iter() {
+        do_move_asset(from, to);
+    }
+    if is_collection {
+        let from = as_collection(path);
+        let to = as_collection(&destination);
+        STATE.with(|s| {
+            let mut collections = s.collections.borrow_mut();
+            let moved: Vec<Key> = collections
+                .iter()
+                .filter(|c| c.starts_with(&from))
+                .cloned()
+                .collect();
+            for collection in moved {
+                collections.remove(&collection);
+                collections.insert(format!("{}{}", to, &collection[from.len()..]));
+            }
+        });
+    }
+    response(if exists { 204 } else { 201 }, "")
+}
+
+fn delete(req: &HttpRequest, path: &str) -> HttpResponse {
+    let dir = as_collection(path);
+    let is_asset = STATE.with(|s| s.assets.borrow().contains_key(path));
+    if is_asset || !collection_exists(&dir) {
+        return http_upload::handle(req);
+    }
+    let keys: Vec<Key> = STATE.with(|s| {
+        s.assets
+            .borrow()
+            .keys()
+            .filter(|key| key.starts_with(&dir))
+            .cloned()
+            .collect()
+    });
+    for key in keys {
+        do_delete_asset(DeleteAssetArguments { key });
+    }
+    STATE.with(|s| s.collections.borrow_mut().retain(|c| !c.starts_with(&dir)));
+    response(204, "")
+}
+
+fn decode_path(url: &str) -> Result<String, HttpResponse> {
+    let path = match url.find('?') {
+        Some(i) => &url[..i],
+        None => url,
+    };
+    url_decode(path)
+        .map_err(|err| response(400, &format!("failed to decode path '{}': {}", path, err)))
+}
+
+fn as_collection(path: &str) -> String {
+    if path.ends_with('/') {
+        path.to_string()
+    } else {
+        format!("{}/", path)
+    }
+}
+
+fn collection_exists(dir: &str) -> bool {
+    dir == "/"
+        || STATE.with(|s| {
+            s.collections.borrow().iter().any(|c| c.starts_with(dir))
+                || s.assets.borrow().keys().any(|key| key.starts_with(dir))
+        })
+}
+
+fn file_entry(key: &str, asset: &Asset) -> String {
+    let enc = asset
+        .encodings
+        .get("identity")
+        .or_else(|| asset.encodings.values().next());
+    let mut props = format!(
+        "<D:resourcetype/><D:getcontenttype>{}</D:getcontenttype>",
+        escape(&asset.content_type)
+    );
+    if let Some(enc) = enc {
+        props.push_str(&format!(
+            "<D:getcontentlength>{}</D:getcontentlength><D:getetag>\"{}\"</D:getetag>",
+            enc.total_length,
+            hex::encode(enc.sha256)
+        ));
+        if let Some(modified) = enc.modified.0.to_u64() {
+            props.push_str(&format!(
+                "<D:getlastmodified>{}</D:getlastmodified>",
+                http_date(modified)
+            ));
+        }
+    }
+    entry(key, &props)
+}
+
+fn collection_entry(dir: &str) -> String {
+    entry(dir, "<D:resourcetype><D:collection/></D:resourcetype>")
+}
+
+fn entry(path: &str, props: &str) -> String {
+    let name = path.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+    format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop><D:displayname>{}</D:displayname>{}</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n",
+        escape(&percent_encode(path)),
+        escape(name),
+        props
+    )
+}
+
+fn with_headers(mut response: HttpResponse, headers: Vec<(String, String)>) -> HttpResponse {
+    response.headers = headers;
+    response.headers.extend(security_headers());
+    response
+}
+
+fn percent_encode(path: &str) -> String {
+    let mut encoded = String::new();
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Formats `nanos`, in nanoseconds since 1970-01-01, as an HTTP date, e.g.
+/// `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn http_date(nanos: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = nanos / 1_000_000_000;
+    let days = secs / 86_400;
+    let secs_of_day = secs % 86_400;
+
+    // The civil date of a day count, from Howard Hinnant's algorithm.
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(method: &str, headers: Vec<(&str, &str)>) -> HttpRequest {
+        HttpRequest {
+            method: method.to_string(),
+            url: "/".to_string(),
+            headers: headers
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: ByteBuf::new(),
+        }
+    }
+
+    #[test]
+    fn leaves_cors_preflight_requests_to_the_assets() {
+        assert!(is_webdav(&request("OPTIONS", vec![])));
+        assert!(is_webdav(&request("propfind", vec![("Depth", "1")])));
+        assert!(!is_webdav(&request(
+            "OPTIONS",
+            vec![
+                ("Origin", "https://example.com"),
+                ("Access-Control-Request-Method", "GET"),
+            ]
+        )));
+        assert!(!is_webdav(&request("GET", vec![])));
+    }
+
+    fn propfind_status(asset_count: usize) -> u16 {
+        STATE.with(|s| {
+            let mut assets = s.assets.borrow_mut();
+            assets.clear();
+            for i in 0..asset_count {
+                assets.insert(format!("/docs/{}.txt", i), Asset::default());
+            }
+        });
+        let status = propfind(&request("PROPFIND", vec![("Depth", "1")]), "/docs/").status_code;
+        STATE.with(|s| s.assets.borrow_mut().clear());
+        status
+    }
+
+    #[test]
+    fn lists_collections_up_to_the_limit() {
+        // The collection itself is an entry too.
+        assert_eq!(propfind_status(MAX_PROPFIND_ENTRIES - 1), 207);
+        assert_eq!(propfind_status(MAX_PROPFIND_ENTRIES), 507);
+        assert_eq!(propfind_status(0), 404);
+    }
+}
@@ -0,0 +1,78 @@
+//! Cache of the serialized witnesses served in `IC-Certificate` headers.
+//!
+//! Queries can't persist anything, so the cache is only filled from
+//! [heartbeat](crate::heartbeat). Any change to the asset hashes empties it
+//! until the next heartbeat, and `http_request` falls back to building the
+//! witness itself in the meantime.
+
+use crate::{serialize_witness, Key, ASSET_HASHES, STATE};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// The maximum number of witnesses kept in the cache.
+const MAX_CACHED_WITNESSES: usize = 1024;
+
+#[derive(Default)]
+struct WitnessCache {
+    stale: bool,
+    trees: HashMap<Key, String>,
+}
+
+thread_local! {
+    static CACHE: RefCell<WitnessCache> = RefCell::new(WitnessCache::default());
+}
+
+/// Returns the serialized witness of `key`, if cached.
+pub(crate) fn get(key: &str) -> Option<String> {
+    CACHE.with(|c| c.borrow().trees.get(key).cloned())
+}
+
+/// Drops every cached witness. Must be called whenever the asset hashes
+/// change.
+pub(crate) fn invalidate() {
+    CACHE.with(|c| {
+        let mut cache = c.borrow_mut();
+        cache.trees.clear();
+        cache.stale = true;
+    })
+}
+
+/// Fills the cache again if the asset hashes changed since it was built.
+///
+/// The fallback file is cached first, then the other keys in order until the
+/// cache is full.
+pub(crate) fn refresh() {
+    if !CACHE.with(|c| c.borrow().stale) {
+        return;
+    }
+    let fallback_file = STATE.with(|s| s.config.borrow().fallback_file.clone());
+    let trees = ASSET_HASHES.with(|t| {
+        let tree = t.borrow();
+        let mut keys: Vec<&[u8]> = vec![];
+        if tree.get(fallback_file.as_bytes()).is_some() {
+            keys.push(fallback_file.as_bytes());
+        }
+        for (key, _) in tree.iter() {
+            if keys.len() >= MAX_CACHED_WITNESSES {
+                break;
+            }
+            if key.as_bytes() != fallback_file.as_bytes() {
+                keys.push(key.as_bytes());
+            }
+        }
+        keys.into_iter()
+            .map(|key| {
+                (
+                    String::from_utf8_lossy(key).into_owned(),
+                    serialize_witness(tree.witness(key)),
+                )
+            })
+            .collect()
+    });
+    CACHE.with(|c| {
+        c.replace(WitnessCache {
+            stale: false,
+            trees,
+        })
+    });
+}
@@ -0,0 +1,67 @@
+#[cfg(test)]
+mod test;
+
+use crate::hashtree::{Hash, HashTree};
+use crate::rbtree::{AsHashTree, RbTree};
+
+/// The root hash of a subtree, which stands for the whole subtree.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct SubtreeHash(Hash);
+
+impl AsHashTree for SubtreeHash {
+    fn root_hash(&self) -> Hash {
+        self.0
+    }
+
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        HashTree::Pruned(self.0)
+    }
+}
+
+/// Combines several independent trees, each under its own label, into the
+/// single hash a canister can certify.
+///
+/// Only the root hashes of the subtrees are kept. Whenever a subtree changes,
+/// its new root hash is passed to `insert` and the combined `root_hash` is
+/// certified again. A witness built from a subtree is turned into a witness of
+/// the combined tree with `witness`.
+///
+/// With a single label, the combined tree is the labeled subtree itself.
+#[derive(Clone, Default, Debug)]
+pub struct LabeledTrees {
+    roots: RbTree<Vec<u8>, SubtreeHash>,
+}
+
+impl LabeledTrees {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the root hash of the subtree under `label`.
+    pub fn insert(&mut self, label: &[u8], root_hash: Hash) {
+        self.roots.insert(label.to_vec(), SubtreeHash(root_hash));
+    }
+
+    /// Removes the subtree under `label`.
+    pub fn remove(&mut self, label: &[u8]) {
+        self.roots.delete(label);
+    }
+
+    /// Returns the root hash of the subtree under `label`.
+    pub fn get(&self, label: &[u8]) -> Option<Hash> {
+        self.roots.get(label).map(|h| h.0)
+    }
+
+    /// Returns the hash to certify.
+    pub fn root_hash(&self) -> Hash {
+        self.roots.root_hash()
+    }
+
+    /// Embeds `witness`, a witness of the subtree under `label`, into a witness
+    /// of the combined tree. The other subtrees are pruned.
+    ///
+    /// If there is no subtree under `label`, returns a proof of absence.
+    pub fn witness<'a>(&'a self, label: &[u8], witness: HashTree<'a>) -> HashTree<'a> {
+        self.roots.nested_witness(label, |_| witness)
+    }
+}
@@ -0,0 +1,61 @@
+use super::LabeledTrees;
+use crate::hashtree::labeled_hash;
+use crate::rbtree::{AsHashTree, RbTree};
+
+fn assets() -> RbTree<&'static str, Vec<u8>> {
+    let mut t = RbTree::new();
+    t.insert("/index.html", b"index".to_vec());
+    t.insert("/app.js", b"app".to_vec());
+    t
+}
+
+#[test]
+fn single_label_is_the_labeled_subtree() {
+    let assets = assets();
+    let mut trees = LabeledTrees::new();
+    trees.insert(b"http_assets", assets.root_hash());
+
+    assert_eq!(
+        trees.root_hash(),
+        labeled_hash(b"http_assets", &assets.root_hash())
+    );
+}
+
+#[test]
+fn witnesses_reconstruct_the_combined_root() {
+    let assets = assets();
+    let mut data: RbTree<&'static str, Vec<u8>> = RbTree::new();
+    data.insert("balance", b"100".to_vec());
+
+    let mut trees = LabeledTrees::new();
+    trees.insert(b"http_assets", assets.root_hash());
+    trees.insert(b"app_data", data.root_hash());
+
+    let witness = trees.witness(b"http_assets", assets.witness(b"/app.js"));
+    assert_eq!(witness.reconstruct(), trees.root_hash());
+    let witness = trees.witness(b"app_data", data.witness(b"balance"));
+    assert_eq!(witness.reconstruct(), trees.root_hash());
+    let witness = trees.witness(b"missing", assets.witness(b"/app.js"));
+    assert_eq!(witness.reconstruct(), trees.root_hash());
+}
+
+#[test]
+fn updating_a_subtree_changes_the_root() {
+    let mut assets = assets();
+    let mut trees = LabeledTrees::new();
+    trees.insert(b"http_assets", assets.root_hash());
+    trees.insert(b"app_data", [0; 32]);
+    let before = trees.root_hash();
+
+    assets.insert("/new.css", b"css".to_vec());
+    trees.insert(b"http_assets", assets.root_hash());
+    assert_ne!(trees.root_hash(), before);
+    assert_eq!(trees.get(b"http_assets"), Some(assets.root_hash()));
+
+    trees.remove(b"app_data");
+    assert_eq!(trees.get(b"app_data"), None);
+    assert_eq!(
+        trees.root_hash(),
+        labeled_hash(b"http_assets", &assets.root_hash())
+    );
+}
@@ -1,5 +1,7 @@
 mod hashtree;
+mod labeled_trees;
 mod rbtree;
 
 pub use crate::hashtree::*;
+pub use crate::labeled_trees::*;
 pub use crate::rbtree::*;
@@ -375,3 +375,31 @@ fn test_ordering() {
     assert_eq!(t1.cmp(&t3), Greater);
     assert_eq!(t1.cmp(&t4), Less);
 }
+
+#[test]
+fn test_witness_size_is_logarithmic() {
+    fn count_pruned(t: &HashTree<'_>) -> usize {
+        match t {
+            HashTree::Pruned(_) => 1,
+            HashTree::Fork(lr) => count_pruned(&lr.0) + count_pruned(&lr.1),
+            HashTree::Labeled(_, t) => count_pruned(t),
+            _ => 0,
+        }
+    }
+
+    let mut t = TreeOfBytes::new();
+    let count = 4_096u64;
+    for i in 0..count {
+        insert(&mut t, format!("/assets/{}.js", i), i.to_be_bytes());
+    }
+    // A red-black tree of n nodes is at most 2 * log2(n + 1) levels deep, and
+    // a witness prunes about one subtree per level of the paths it follows,
+    // so its size grows with the logarithm of the key count.
+    let depth = 2 * (64 - (count + 1).leading_zeros() as usize);
+    for key in &["/assets/4242.js", "/assets/4242.jsx", "/index.html"] {
+        let witness = t.witness(key.as_bytes());
+        assert_eq!(witness.reconstruct(), t.root_hash());
+        assert!(count_pruned(&witness) <= 2 * depth);
+        assert!(serde_cbor::to_vec(&witness).unwrap().len() < 2048);
+    }
+}